@@ -0,0 +1,472 @@
+use aica::WAVE_RAM_MASK;
+
+use std::cmp;
+
+/// Bytes of registers per slot
+pub const SLOT_SIZE : usize = 0x80;
+
+/// Fraction bits of the playback position
+const POSITION_SHIFT : u32 = 14;
+
+/// Fraction bits of the envelope attenuation
+const ENVELOPE_SHIFT : u32 = 16;
+
+/// Largest attenuation of the envelope, silence
+pub const MAX_ATTENUATION : u32 = 0x3FF;
+
+const ADPCM_SCALE : [i32; 8] = [230, 230, 230, 230, 307, 409, 512, 614];
+const ADPCM_DIFF  : [i32; 16] = [1, 3, 5, 7, 9, 11, 13, 15, -1, -3, -5, -7, -9, -11, -13, -15];
+
+/// LFO frequencies in Hz, selected by LFOF
+const LFO_FREQUENCIES : [f64; 32] = [
+    0.17, 0.19, 0.23, 0.27, 0.34, 0.39, 0.45, 0.55, 0.68, 0.78, 0.92, 1.10, 1.39, 1.60, 1.87, 2.27,
+    2.87, 3.31, 3.92, 4.79, 6.15, 7.18, 8.60, 10.8, 14.4, 17.2, 21.5, 28.7, 43.1, 57.4, 86.1, 172.3,
+];
+
+/// Sample format selected by PCMS
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Pcm16,
+    Pcm8,
+    Adpcm,
+    AdpcmStream,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EnvelopeState {
+    Attack,
+    Decay1,
+    Decay2,
+    Release,
+}
+
+/// The registers of a slot, decoded from the register block
+#[derive(Copy, Clone, Debug)]
+pub struct SlotParameters {
+    pub key_on: bool,
+    pub noise: bool,
+    pub looping: bool,
+    pub format: SampleFormat,
+    /// Start address in bytes, loop start and end in samples
+    pub sa: u32,
+    pub lsa: u32,
+    pub lea: u32,
+    pub ar: u32,
+    pub d1r: u32,
+    pub d2r: u32,
+    pub rr: u32,
+    pub dl: u32,
+    pub krs: u32,
+    pub lpslnk: bool,
+    pub oct: i32,
+    pub fns: u32,
+    pub lfo_reset: bool,
+    pub lfof: u32,
+    pub plfows: u32,
+    pub plfos: u32,
+    pub alfows: u32,
+    pub alfos: u32,
+    pub imxl: u32,
+    pub isel: u32,
+    pub disdl: u32,
+    pub dipan: u32,
+    pub tl: u32,
+    pub voff: bool,
+}
+
+impl SlotParameters {
+    /// Decodes the registers of a slot
+    pub fn new(regs: &[u8]) -> SlotParameters {
+        let r = |offset: usize| (regs[offset] as u32) | ((regs[offset + 1] as u32) << 8);
+
+        SlotParameters {
+            key_on: r(0x00) & (1 << 14) != 0,
+            noise: r(0x00) & (1 << 10) != 0,
+            looping: r(0x00) & (1 << 9) != 0,
+            format: match (r(0x00) >> 7) & 0x3 {
+                0 => SampleFormat::Pcm16,
+                1 => SampleFormat::Pcm8,
+                2 => SampleFormat::Adpcm,
+                _ => SampleFormat::AdpcmStream
+            },
+            sa: ((r(0x00) & 0x7F) << 16) | r(0x04),
+            lsa: r(0x08),
+            lea: r(0x0C),
+            ar: r(0x10) & 0x1F,
+            d1r: (r(0x10) >> 6) & 0x1F,
+            d2r: (r(0x10) >> 11) & 0x1F,
+            rr: r(0x14) & 0x1F,
+            dl: (r(0x14) >> 5) & 0x1F,
+            krs: (r(0x14) >> 10) & 0xF,
+            lpslnk: r(0x14) & (1 << 14) != 0,
+            // OCT is a signed 4-bit value
+            oct: (((r(0x18) >> 11) & 0xF) as i32 ^ 0x8) - 0x8,
+            fns: r(0x18) & 0x3FF,
+            lfo_reset: r(0x1C) & (1 << 15) != 0,
+            lfof: (r(0x1C) >> 10) & 0x1F,
+            plfows: (r(0x1C) >> 8) & 0x3,
+            plfos: (r(0x1C) >> 5) & 0x7,
+            alfows: (r(0x1C) >> 3) & 0x3,
+            alfos: r(0x1C) & 0x7,
+            imxl: (r(0x20) >> 4) & 0xF,
+            isel: r(0x20) & 0xF,
+            disdl: (r(0x24) >> 8) & 0xF,
+            dipan: r(0x24) & 0x1F,
+            tl: (r(0x28) >> 8) & 0xFF,
+            voff: r(0x28) & (1 << 6) != 0,
+        }
+    }
+}
+
+/// The state of one of the 64 voices
+pub struct Channel {
+    pub playing: bool,
+    /// Current sample, with a fraction of POSITION_SHIFT bits
+    pub position: u32,
+    pub envelope: EnvelopeState,
+    /// Envelope attenuation, with a fraction of ENVELOPE_SHIFT bits
+    attenuation: u32,
+    /// Set when the loop end was reached, until read through LP
+    pub looped: bool,
+    /// The last decoded sample and the one before it, for interpolation
+    current: i32,
+    previous: i32,
+    /// Index of the sample in `current`
+    decoded: u32,
+    adpcm_step: i32,
+    /// ADPCM state at the loop start, restored when looping
+    loop_state: Option<(i32, i32)>,
+    lfo_phase: u32,
+    noise: u32,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel {
+            playing: false,
+            position: 0,
+            envelope: EnvelopeState::Release,
+            attenuation: MAX_ATTENUATION << ENVELOPE_SHIFT,
+            looped: false,
+            current: 0,
+            previous: 0,
+            decoded: 0,
+            adpcm_step: 127,
+            loop_state: None,
+            lfo_phase: 0,
+            noise: 1,
+        }
+    }
+
+    pub fn key_on(&mut self, params: &SlotParameters) {
+        self.playing = true;
+        self.position = 0;
+        self.envelope = EnvelopeState::Attack;
+        // An attack rate of 31 starts at full volume right away
+        self.attenuation = if params.ar == 0x1F { 0 } else { MAX_ATTENUATION << ENVELOPE_SHIFT };
+        self.looped = false;
+        self.current = 0;
+        self.previous = 0;
+        self.decoded = u32::MAX;
+        self.adpcm_step = 127;
+        self.loop_state = None;
+        if params.lfo_reset {
+            self.lfo_phase = 0;
+        }
+    }
+
+    pub fn key_off(&mut self) {
+        if self.playing {
+            self.envelope = EnvelopeState::Release;
+        }
+    }
+
+    /// Envelope level in 10 bits, as reported through EG
+    pub fn envelope_level(&self) -> u32 {
+        self.attenuation >> ENVELOPE_SHIFT
+    }
+
+    /// The effective rate of an envelope phase, scaled by the key
+    fn effective_rate(rate: u32, params: &SlotParameters) -> u32 {
+        if rate == 0 {
+            return 0;
+        }
+        let scaling = if params.krs == 0xF { 0 } else { params.krs as i32 + params.oct };
+        let effective = rate as i32 * 2 + scaling * 2;
+        if effective < 0 { 0 } else if effective > 0x3F { 0x3F } else { effective as u32 }
+    }
+
+    /// Attenuation change per sample for a rate, with ENVELOPE_SHIFT fraction bits
+    fn envelope_step(rate: u32) -> u32 {
+        if rate < 2 { 0 } else { (4 | (rate & 3)) << (rate >> 2) }
+    }
+
+    fn step_envelope(&mut self, params: &SlotParameters) {
+        let max = MAX_ATTENUATION << ENVELOPE_SHIFT;
+
+        match self.envelope {
+            EnvelopeState::Attack => {
+                let step = Channel::envelope_step(Channel::effective_rate(params.ar, params)) * 8;
+                self.attenuation = self.attenuation.saturating_sub(step);
+                // With LPSLNK the attack lasts until the loop start is reached
+                let done = if params.lpslnk { self.position >> POSITION_SHIFT >= params.lsa } else { self.attenuation == 0 };
+                if done {
+                    self.envelope = EnvelopeState::Decay1;
+                }
+            },
+            EnvelopeState::Decay1 => {
+                let step = Channel::envelope_step(Channel::effective_rate(params.d1r, params));
+                self.attenuation = cmp::min(self.attenuation + step, max);
+                if self.attenuation >> ENVELOPE_SHIFT >= params.dl << 5 {
+                    self.envelope = EnvelopeState::Decay2;
+                }
+            },
+            EnvelopeState::Decay2 => {
+                let step = Channel::envelope_step(Channel::effective_rate(params.d2r, params));
+                self.attenuation = cmp::min(self.attenuation + step, max);
+            },
+            EnvelopeState::Release => {
+                let step = Channel::envelope_step(Channel::effective_rate(params.rr, params));
+                self.attenuation = cmp::min(self.attenuation + step, max);
+                if self.attenuation >= max {
+                    self.playing = false;
+                }
+            }
+        }
+    }
+
+    /// Steps the LFO and returns its pitch and amplitude outputs
+    fn step_lfo(&mut self, params: &SlotParameters) -> (i32, u32) {
+        let increment = (LFO_FREQUENCIES[params.lfof as usize] / 44100.0 * 4294967296.0) as u32;
+        let wrapped = self.lfo_phase.checked_add(increment).is_none();
+        self.lfo_phase = self.lfo_phase.wrapping_add(increment);
+        if wrapped {
+            self.noise = (self.noise >> 1) ^ (if self.noise & 1 != 0 { 0xB400 } else { 0 });
+        }
+
+        let phase = self.lfo_phase >> 24;
+        let wave = |form: u32| -> u32 {
+            match form {
+                // Saw, square, triangle and noise
+                0 => phase,
+                1 => if phase < 0x80 { 0xFF } else { 0 },
+                2 => if phase < 0x80 { phase * 2 } else { 0x1FF - phase * 2 },
+                _ => self.noise & 0xFF
+            }
+        };
+
+        let pitch = if params.plfos == 0 { 0 } else { ((wave(params.plfows) as i32 - 0x80) << params.plfos) >> 2 };
+        let amplitude = if params.alfos == 0 { 0 } else { (wave(params.alfows) << params.alfos) >> 3 };
+        (pitch, amplitude)
+    }
+
+    /// Decodes the sample at the given index
+    fn fetch(&mut self, index: u32, params: &SlotParameters, ram: &[u8]) -> i32 {
+        let byte = |offset: u32| ram[(offset as usize) & WAVE_RAM_MASK];
+
+        match params.format {
+            SampleFormat::Pcm16 => {
+                let offset = params.sa + index * 2;
+                ((byte(offset) as u16) | ((byte(offset + 1) as u16) << 8)) as i16 as i32
+            },
+            SampleFormat::Pcm8 => (byte(params.sa + index) as i8 as i32) << 8,
+            SampleFormat::Adpcm | SampleFormat::AdpcmStream => {
+                let data = byte(params.sa + index / 2);
+                let nibble = if index & 1 != 0 { data >> 4 } else { data & 0xF } as usize;
+
+                let sample = self.current + self.adpcm_step * ADPCM_DIFF[nibble] / 8;
+                self.adpcm_step = (self.adpcm_step * ADPCM_SCALE[nibble & 7]) >> 8;
+                self.adpcm_step = self.adpcm_step.clamp(127, 24576);
+                sample.clamp(-32768, 32767)
+            }
+        }
+    }
+
+    /// Produces the next sample of the channel, with the envelope, total
+    /// level and LFO applied but before panning
+    pub fn step(&mut self, params: &SlotParameters, ram: &[u8], attenuation_table: &[i32]) -> i32 {
+        if !self.playing {
+            return 0;
+        }
+
+        let (pitch, amplitude) = self.step_lfo(params);
+
+        // Advance the decoder up to the current position
+        let index = self.position >> POSITION_SHIFT;
+        while self.decoded != index {
+            let next = self.decoded.wrapping_add(1);
+            if next == params.lsa && self.loop_state.is_none() {
+                self.loop_state = Some((self.current, self.adpcm_step));
+            }
+            self.previous = self.current;
+            self.current = if params.noise {
+                self.noise = (self.noise >> 1) ^ (if self.noise & 1 != 0 { 0xB400 } else { 0 });
+                (self.noise as u16 as i16) as i32
+            } else {
+                self.fetch(next, params, ram)
+            };
+            self.decoded = next;
+        }
+
+        let fraction = (self.position & ((1 << POSITION_SHIFT) - 1)) as i32;
+        let sample = self.previous + (((self.current - self.previous) * fraction) >> POSITION_SHIFT);
+
+        // One octave per step of OCT, FNS adds the fraction of an octave
+        let base = ((0x400 | params.fns) << 4) as i32;
+        let base = base + ((base * pitch) >> 12);
+        let step = if params.oct >= 0 { (base as u32) << params.oct } else { (base as u32) >> -params.oct };
+        self.position = self.position.wrapping_add(step);
+
+        if self.position >> POSITION_SHIFT > params.lea {
+            self.looped = true;
+            if params.looping && params.format != SampleFormat::AdpcmStream {
+                let length = params.lea.saturating_sub(params.lsa) << POSITION_SHIFT;
+                self.position -= if length > 0 { length } else { self.position - (params.lsa << POSITION_SHIFT) };
+                if let Some((current, step)) = self.loop_state {
+                    self.current = current;
+                    self.adpcm_step = step;
+                }
+                self.decoded = params.lsa.wrapping_sub(1);
+            } else if params.looping {
+                // Streamed ADPCM keeps its decoder state over the loop
+                self.position -= (params.lea.saturating_sub(params.lsa)) << POSITION_SHIFT;
+                self.decoded = self.position >> POSITION_SHIFT;
+            } else {
+                self.playing = false;
+                self.attenuation = MAX_ATTENUATION << ENVELOPE_SHIFT;
+                return 0;
+            }
+        }
+
+        self.step_envelope(params);
+
+        // The total level counts in steps of four envelope steps
+        let total = if params.voff { 0 } else { self.envelope_level() } + params.tl * 4 + amplitude;
+        if total >= MAX_ATTENUATION {
+            return 0;
+        }
+        (sample * attenuation_table[total as usize]) >> 15
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unity gain at every attenuation, so that samples pass unchanged
+    const FLAT : [i32; 0x400] = [1 << 15; 0x400];
+
+    fn slot(control: u32, lsa: u32, lea: u32, envelope: u32, release: u32) -> SlotParameters {
+        let mut regs = [0u8; SLOT_SIZE];
+        let mut set = |offset: usize, value: u32| {
+            regs[offset] = value as u8;
+            regs[offset + 1] = (value >> 8) as u8;
+        };
+        set(0x00, (1 << 14) | control);
+        set(0x08, lsa);
+        set(0x0C, lea);
+        set(0x10, envelope);
+        set(0x14, release);
+        SlotParameters::new(&regs)
+    }
+
+    fn pcm16(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|&s| vec![s as u8, (s >> 8) as u8]).collect()
+    }
+
+    #[test]
+    fn decodes_adpcm() {
+        let params = slot(2 << 7, 0, 0xFFFF, 0x1F, 0);
+        let ram = [0x77, 0x0F];
+        let mut channel = Channel::new();
+        channel.key_on(&params);
+
+        let mut decoded = Vec::new();
+        for index in 0..4 {
+            channel.current = channel.fetch(index, &params, &ram);
+            decoded.push((channel.current, channel.adpcm_step));
+        }
+        assert_eq!(decoded, vec![(238, 304), (808, 729), (-558, 1748), (-340, 1570)]);
+    }
+
+    #[test]
+    fn clamps_adpcm_step_and_sample() {
+        let params = slot(2 << 7, 0, 0xFFFF, 0x1F, 0);
+        let mut channel = Channel::new();
+        channel.key_on(&params);
+
+        // Shrinking steps stop at 127, growing ones at 24576
+        assert_eq!(channel.fetch(0, &params, &[0x00]), 15);
+        assert_eq!(channel.adpcm_step, 127);
+        for _ in 0..32 {
+            channel.current = channel.fetch(0, &params, &[0x07]);
+        }
+        assert_eq!(channel.adpcm_step, 24576);
+        assert_eq!(channel.current, 32767);
+    }
+
+    #[test]
+    fn stops_after_the_loop_end() {
+        let params = slot(0, 0, 3, 0x1F, 0);
+        let ram = pcm16(&[100, 200, 300, 400, 500]);
+        let mut channel = Channel::new();
+        channel.key_on(&params);
+
+        // Output trails the position by one sample for interpolation
+        let output: Vec<i32> = (0..5).map(|_| channel.step(&params, &ram, &FLAT)).collect();
+        assert_eq!(output, vec![0, 100, 200, 0, 0]);
+        assert!(!channel.playing);
+        assert!(channel.looped);
+    }
+
+    #[test]
+    fn loops_between_loop_start_and_end() {
+        let params = slot(1 << 9, 1, 3, 0x1F, 0);
+        let ram = pcm16(&[100, 200, 300, 400, 500]);
+        let mut channel = Channel::new();
+        channel.key_on(&params);
+
+        let output: Vec<i32> = (0..8).map(|_| channel.step(&params, &ram, &FLAT)).collect();
+        assert_eq!(output, vec![0, 100, 200, 300, 200, 300, 200, 300]);
+        assert!(channel.playing);
+        assert!(channel.looped);
+    }
+
+    #[test]
+    fn envelope_runs_from_attack_to_release() {
+        // AR and D1R of 28 with DL of 1, then a release rate of 31
+        let params = slot(1 << 9, 0, 0xFFFF, (0x1C << 6) | 0x1C, (1 << 5) | 0x1F);
+        let ram = vec![0u8; WAVE_RAM_MASK + 1];
+        let mut channel = Channel::new();
+        channel.key_on(&params);
+        assert_eq!(channel.envelope_level(), MAX_ATTENUATION);
+
+        let mut samples = 0;
+        while channel.envelope == EnvelopeState::Attack {
+            channel.step(&params, &ram, &FLAT);
+            samples += 1;
+        }
+        assert_eq!((samples, channel.envelope, channel.envelope_level()), (128, EnvelopeState::Decay1, 0));
+
+        samples = 0;
+        while channel.envelope == EnvelopeState::Decay1 {
+            channel.step(&params, &ram, &FLAT);
+            samples += 1;
+        }
+        assert_eq!((samples, channel.envelope_level()), (32, 32));
+
+        channel.key_off();
+        samples = 0;
+        while channel.playing {
+            channel.step(&params, &ram, &FLAT);
+            samples += 1;
+        }
+        assert_eq!((samples, channel.envelope_level()), (331, MAX_ATTENUATION));
+    }
+}
@@ -0,0 +1,347 @@
+/// Offsets of the DSP program and its state in the register block
+pub const COEF       : usize = 0x3000;
+pub const MADRS      : usize = 0x3200;
+pub const MPRO       : usize = 0x3400;
+pub const TEMP       : usize = 0x4000;
+pub const MEMS       : usize = 0x4400;
+pub const MIXS       : usize = 0x4500;
+pub const EFREG      : usize = 0x4580;
+pub const EXTS       : usize = 0x45C0;
+pub const DSP_END    : usize = 0x45C7;
+/// Ring buffer length and pointer
+pub const RING_BUFFER: usize = 0x2804;
+
+pub const STEPS : usize = 128;
+
+/// Sign extends the low 24 bits
+fn sign24(value: i32) -> i32 {
+    (value << 8) >> 8
+}
+
+fn clamp24(value: i32) -> i32 {
+    value.clamp(-0x800000, 0x7FFFFF)
+}
+
+fn word(registers: &[u8], offset: usize) -> u32 {
+    (registers[offset] as u32) | ((registers[offset + 1] as u32) << 8)
+}
+
+/// Compresses a 24-bit value into the 16-bit float format of the ring
+/// buffer: a sign, a 4-bit exponent and an 11-bit mantissa
+fn pack(value: i32) -> u16 {
+    let sign = ((value >> 23) & 1) as u16;
+    let mut temp = ((value ^ (value << 1)) & 0xFFFFFF) as u32;
+    let mut exponent = 0;
+    while exponent < 12 && temp & 0x800000 == 0 {
+        temp <<= 1;
+        exponent += 1;
+    }
+
+    let mantissa = if exponent < 12 {
+        ((value << exponent) & 0x3FFFFF) >> 11
+    } else {
+        (value << 11) >> 11
+    };
+    (sign << 15) | ((exponent as u16) << 11) | (mantissa as u16 & 0x7FF)
+}
+
+fn unpack(value: u16) -> i32 {
+    let sign = ((value >> 15) & 1) as i32;
+    let mut exponent = ((value >> 11) & 0xF) as i32;
+    let mut result = ((value & 0x7FF) as i32) << 11;
+
+    if exponent > 11 {
+        exponent = 11;
+        result |= sign << 22;
+    } else {
+        result |= (sign ^ 1) << 22;
+    }
+    result |= sign << 23;
+    sign24(result) >> exponent
+}
+
+/// The effects DSP. Once per sample it runs the 128 steps of MPRO over
+/// the slot sends in MIXS, reading and writing a ring buffer in wave RAM,
+/// and leaves its outputs in EFREG.
+pub struct Dsp {
+    /// 24-bit work registers, addressed relative to the ring buffer
+    pub temp: [i32; 128],
+    /// 24-bit values read from memory
+    pub mems: [i32; 32],
+    /// 20-bit sums of the slot sends
+    pub mixs: [i32; 16],
+    pub efreg: [i16; 16],
+    /// External inputs, the CD audio stream
+    pub exts: [i32; 2],
+    /// Counts down once per sample to move through the ring buffer
+    dec: u32,
+    frc: i32,
+    adrs: i32,
+    y_reg: i32,
+    acc: i32,
+    shifted: i32,
+    mem_value: i32,
+}
+
+impl Dsp {
+    pub fn new() -> Dsp {
+        Dsp {
+            temp: [0; 128],
+            mems: [0; 32],
+            mixs: [0; 16],
+            efreg: [0; 16],
+            exts: [0; 2],
+            dec: 0,
+            frc: 0,
+            adrs: 0,
+            y_reg: 0,
+            acc: 0,
+            shifted: 0,
+            mem_value: 0,
+        }
+    }
+
+    /// Reads the DSP state exposed in the register block
+    pub fn read_register(&self, offset: usize) -> u32 {
+        // 24-bit registers are split into a low byte and a high word
+        let split = |value: i32, low_bits: u32, offset: usize| {
+            if offset & 4 == 0 {
+                (value as u32) & ((1 << low_bits) - 1)
+            } else {
+                ((value as u32) >> low_bits) & 0xFFFF
+            }
+        };
+
+        match offset {
+            TEMP ..= 0x43FF => split(self.temp[(offset - TEMP) / 8], 8, offset),
+            MEMS ..= 0x44FF => split(self.mems[(offset - MEMS) / 8], 8, offset),
+            MIXS ..= 0x457F => split(self.mixs[(offset - MIXS) / 8], 4, offset),
+            EFREG ..= 0x45BF => self.efreg[(offset - EFREG) / 4] as u16 as u32,
+            _ => (self.exts[((offset - EXTS) / 4) & 1] as u32) & 0xFFFF
+        }
+    }
+
+    /// Adds a slot's output to a send, scaled by its IMXL gain in 1.15
+    pub fn send(&mut self, input: u32, sample: i32, gain: i32) {
+        let mixs = &mut self.mixs[input as usize & 0xF];
+        *mixs = (*mixs).wrapping_add((sample * gain) >> 11);
+    }
+
+    /// Runs the program for one sample
+    pub fn step(&mut self, registers: &[u8], ram: &mut [u8]) {
+        let ring = word(registers, RING_BUFFER);
+        let ring_length = 8192u32 << ((ring >> 13) & 0x3);
+        let ring_pointer = (ring & 0xFFF) << 10;
+        let ram_words = (ram.len() / 2) as u32;
+
+        for step in 0..STEPS {
+            let base = MPRO + step * 16;
+            let program = [word(registers, base), word(registers, base + 4), word(registers, base + 8), word(registers, base + 12)];
+
+            let tra = (program[0] >> 9) & 0x7F;
+            let twt = program[0] & (1 << 8) != 0;
+            let twa = (program[0] >> 1) & 0x7F;
+
+            let xsel = program[1] & (1 << 15) != 0;
+            let ysel = (program[1] >> 13) & 0x3;
+            let ira = ((program[1] >> 7) & 0x3F) as usize;
+            let iwt = program[1] & (1 << 6) != 0;
+            let iwa = ((program[1] >> 1) & 0x1F) as usize;
+
+            let table = program[2] & (1 << 15) != 0;
+            let mwt = program[2] & (1 << 14) != 0;
+            let mrd = program[2] & (1 << 13) != 0;
+            let ewt = program[2] & (1 << 12) != 0;
+            let ewa = ((program[2] >> 8) & 0xF) as usize;
+            let adrl = program[2] & (1 << 7) != 0;
+            let frcl = program[2] & (1 << 6) != 0;
+            let shift = (program[2] >> 4) & 0x3;
+            let yrl = program[2] & (1 << 3) != 0;
+            let negb = program[2] & (1 << 2) != 0;
+            let zero = program[2] & (1 << 1) != 0;
+            let bsel = program[2] & 1 != 0;
+
+            let nofl = program[3] & (1 << 15) != 0;
+            let masa = ((program[3] >> 9) & 0x3F) as usize;
+            let adreb = program[3] & (1 << 8) != 0;
+            let nxadr = program[3] & (1 << 7) != 0;
+
+            let mut inputs = match ira {
+                0x00 ..= 0x1F => self.mems[ira],
+                0x20 ..= 0x2F => self.mixs[ira - 0x20] << 4,
+                0x30 | 0x31 => self.exts[ira - 0x30] << 8,
+                _ => 0
+            };
+            inputs = sign24(inputs);
+
+            // MEMS is written with the value read two steps earlier
+            if iwt {
+                self.mems[iwa] = self.mem_value;
+                if ira == iwa {
+                    inputs = self.mem_value;
+                }
+            }
+
+            let b = if zero {
+                0
+            } else {
+                let b = if bsel { self.acc } else { sign24(self.temp[(tra.wrapping_add(self.dec) & 0x7F) as usize]) };
+                if negb { b.wrapping_neg() } else { b }
+            };
+
+            let x = if xsel { inputs } else { sign24(self.temp[(tra.wrapping_add(self.dec) & 0x7F) as usize]) };
+
+            let y = match ysel {
+                0 => self.frc,
+                1 => (word(registers, COEF + step * 4) as i16 as i32) >> 3,
+                2 => (self.y_reg >> 11) & 0x1FFF,
+                _ => (self.y_reg >> 4) & 0x0FFF
+            };
+            let y = (y << 19) >> 19;
+
+            if yrl {
+                self.y_reg = inputs;
+            }
+
+            self.shifted = match shift {
+                0 => clamp24(self.acc),
+                1 => clamp24(self.acc.wrapping_mul(2)),
+                2 => sign24(self.acc.wrapping_mul(2)),
+                _ => sign24(self.acc)
+            };
+
+            self.acc = sign24((((x as i64) * (y as i64)) >> 12) as i32 + b);
+
+            if twt {
+                self.temp[(twa.wrapping_add(self.dec) & 0x7F) as usize] = self.shifted;
+            }
+
+            if frcl {
+                self.frc = if shift == 3 { self.shifted & 0x0FFF } else { (self.shifted >> 11) & 0x1FFF };
+            }
+
+            // Memory is only accessed on odd steps
+            if (mrd || mwt) && step & 1 != 0 {
+                let mut address = word(registers, MADRS + masa * 4);
+                if !table {
+                    address = address.wrapping_add(self.dec);
+                }
+                if adreb {
+                    address = address.wrapping_add(self.adrs as u32 & 0x0FFF);
+                }
+                if nxadr {
+                    address = address.wrapping_add(1);
+                }
+                address &= if table { 0xFFFF } else { ring_length - 1 };
+                let offset = ((address + ring_pointer) % ram_words) as usize * 2;
+
+                if mrd {
+                    let value = (ram[offset] as u16) | ((ram[offset + 1] as u16) << 8);
+                    self.mem_value = if nofl { (value as i16 as i32) << 8 } else { unpack(value) };
+                }
+                if mwt {
+                    let value = if nofl { (self.shifted >> 8) as u16 } else { pack(self.shifted) };
+                    ram[offset] = value as u8;
+                    ram[offset + 1] = (value >> 8) as u8;
+                }
+            }
+
+            if adrl {
+                self.adrs = if shift == 3 { (self.shifted >> 12) & 0xFFF } else { inputs >> 16 };
+            }
+
+            if ewt {
+                self.efreg[ewa] = (self.shifted >> 8) as i16;
+            }
+        }
+
+        self.dec = self.dec.wrapping_sub(1);
+        self.mixs = [0; 16];
+    }
+}
+
+impl Default for Dsp {
+    fn default() -> Dsp {
+        Dsp::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(registers: &mut [u8], offset: usize, value: u32) {
+        registers[offset] = value as u8;
+        registers[offset + 1] = (value >> 8) as u8;
+    }
+
+    /// Writes the four words of a program step
+    fn program(registers: &mut [u8], step: usize, words: [u32; 4]) {
+        for (i, &value) in words.iter().enumerate() {
+            set(registers, MPRO + step * 16 + i * 4, value);
+        }
+    }
+
+    #[test]
+    fn packs_ring_buffer_floats() {
+        assert_eq!(pack(0x000123), (12 << 11) | 0x123);
+        for &value in &[0x000123, -0x000123, 0x400000, -0x400000, 0] {
+            assert_eq!(unpack(pack(value)), value);
+        }
+        // Large values keep only the top bits of their mantissa
+        assert_eq!(unpack(pack(0x123456)), 0x123400);
+    }
+
+    #[test]
+    fn multiplies_and_accumulates() {
+        let mut registers = vec![0u8; 0x8000];
+        let mut ram = vec![0u8; 0x1000];
+        let mut dsp = Dsp::new();
+
+        // ACC = MIXS0 * COEF0, a half
+        program(&mut registers, 0, [0, (1 << 15) | (1 << 13) | (0x20 << 7), 1 << 1, 0]);
+        set(&mut registers, COEF, 0x4000);
+        // The result goes to EFREG0 and TEMP0
+        program(&mut registers, 1, [1 << 8, 0, (1 << 12) | 1, 0]);
+        // ACC += TEMP0 * COEF2, a quarter
+        program(&mut registers, 2, [0, 1 << 13, 1, 0]);
+        set(&mut registers, COEF + 8, 0x2000);
+        // The sum goes to EFREG1
+        program(&mut registers, 3, [0, 0, (1 << 12) | (1 << 8) | (1 << 1), 0]);
+
+        dsp.send(0, 0x100, 1 << 15);
+        assert_eq!(dsp.mixs[0], 0x1000);
+        dsp.step(&registers, &mut ram);
+
+        assert_eq!(dsp.efreg[0], 0x80);
+        assert_eq!(dsp.temp[0], 0x8000);
+        assert_eq!(dsp.efreg[1], 0xA0);
+        // The sends start over and the ring buffer moves on
+        assert_eq!(dsp.mixs[0], 0);
+        assert_eq!(dsp.dec, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn writes_and_reads_the_ring_buffer() {
+        let mut registers = vec![0u8; 0x8000];
+        let mut ram = vec![0u8; 0x1000];
+        let mut dsp = Dsp::new();
+        set(&mut registers, MADRS, 0x10);
+
+        // ACC = EXTS0
+        program(&mut registers, 0, [0, (1 << 15) | (1 << 13) | (0x30 << 7), 1 << 1, 0]);
+        set(&mut registers, COEF, 0x7FF8);
+        // Store it without float conversion at MADRS0, then read it back
+        program(&mut registers, 1, [0, 0, (1 << 15) | (1 << 14), 1 << 15]);
+        program(&mut registers, 3, [0, 0, (1 << 15) | (1 << 13), 1 << 15]);
+        // MEMS0 receives the value two steps later
+        program(&mut registers, 5, [0, 1 << 6, 0, 0]);
+
+        dsp.exts = [0x1234, 0];
+        dsp.step(&registers, &mut ram);
+
+        // EXTS times 4095/4096
+        assert_eq!(&ram[0x20..0x22], &[0x32, 0x12]);
+        assert_eq!(dsp.mems[0], 0x123200);
+    }
+}
@@ -0,0 +1,544 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use aica::channel::{Channel, SlotParameters, SLOT_SIZE};
+use aica::dsp::{Dsp, TEMP, DSP_END};
+use arm7::{Arm7, ArmBus};
+use asic::ExternalInterrupt;
+use audio::{AudioSink, SampleQueue};
+use scheduler::{Scheduler, Event, CYCLES_PER_SAMPLE};
+
+use std::sync::{Arc, Mutex};
+
+pub mod channel;
+pub mod dsp;
+pub mod rtc;
+
+pub const AICA_REGISTER_BASE : usize = 0x00700000;
+pub const AICA_REGISTER_END  : usize = 0x00707FFF;
+pub const AICA_REGISTER_SIZE : usize = 0x8000;
+
+pub const WAVE_RAM_BASE : usize = 0x00800000;
+pub const WAVE_RAM_END  : usize = 0x009FFFFF;
+pub const WAVE_RAM_SIZE : usize = 0x00200000;
+pub const WAVE_RAM_MASK : usize = WAVE_RAM_SIZE - 1;
+
+pub const CHANNELS : usize = 64;
+
+/// The sound CPU's view of wave RAM and the register block
+pub const ARM_WAVE_RAM_END     : u32 = 0x007FFFFF;
+pub const ARM_REGISTER_BASE    : u32 = 0x00800000;
+pub const ARM_REGISTER_END     : u32 = 0x0080FFFF;
+
+/// The sound CPU runs at 22.5792 MHz, 512 cycles per sample
+pub const ARM_CYCLES_PER_SAMPLE : u64 = 512;
+
+/// Offsets into the register block
+/// Output level and pan of the 16 DSP outputs and the 2 external inputs
+pub const EFFECT_OUTPUT : usize = 0x2000;
+pub const MASTER_VOLUME : usize = 0x2800;
+pub const MONITOR_SELECT: usize = 0x280C;
+pub const MONITOR_EG    : usize = 0x2810;
+pub const MONITOR_CA    : usize = 0x2814;
+pub const TIMER_A       : usize = 0x2890;
+pub const TIMER_B       : usize = 0x2894;
+pub const TIMER_C       : usize = 0x2898;
+pub const SCIEB         : usize = 0x289C;
+pub const SCIPD         : usize = 0x28A0;
+pub const SCIRE         : usize = 0x28A4;
+pub const SCILV0        : usize = 0x28A8;
+pub const SCILV1        : usize = 0x28AC;
+pub const SCILV2        : usize = 0x28B0;
+pub const MCIEB         : usize = 0x28B4;
+pub const MCIPD         : usize = 0x28B8;
+pub const MCIRE         : usize = 0x28BC;
+pub const ARMRST        : usize = 0x2C00;
+/// FIQ level of the sound CPU and its acknowledge
+pub const INTERRUPT_LEVEL : usize = 0x2D00;
+pub const INTERRUPT_CLEAR : usize = 0x2D04;
+
+/// Interrupt bits shared by the SCI and MCI registers
+pub const INTERRUPT_CPU     : u32 = 1 << 5;
+pub const INTERRUPT_TIMER_A : u32 = 1 << 6;
+pub const INTERRUPT_TIMER_B : u32 = 1 << 7;
+pub const INTERRUPT_TIMER_C : u32 = 1 << 8;
+pub const INTERRUPT_SAMPLE  : u32 = 1 << 10;
+
+/// The AICA sound chip: 64 voices playing from the 2MB wave RAM, mixed
+/// into a stereo stream at 44.1 kHz, and the ARM7 core driving them
+pub struct Aica {
+    /// The register block as seen from the SH-4, in little endian
+    pub registers: Box<[u8]>,
+    pub ram: Box<[u8]>,
+    pub channels: Vec<Channel>,
+    pub dsp: Dsp,
+    /// Sample clocks seen by each timer's prescaler
+    timer_clocks: [u32; 3],
+    /// Gain for each attenuation step in 1.15 fixed point
+    attenuation_table: Vec<i32>,
+    /// The sound CPU, held in reset while ARMRST bit 0 is set
+    pub arm: Arm7,
+    /// CD audio, feeding the two external inputs
+    pub cdda: Option<Arc<Mutex<SampleQueue>>>,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+impl Aica {
+    /// Creates the sound chip and registers its registers and wave RAM
+    /// with the memory controller
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<Aica>> {
+        let aica = Arc::new(Mutex::new(Aica::unmapped(asic)));
+        mem.register_mapped_device(MemoryRange(AICA_REGISTER_BASE, AICA_REGISTER_END), aica.clone());
+        mem.register_mapped_device(MemoryRange(WAVE_RAM_BASE, WAVE_RAM_END), aica.clone());
+
+        aica
+    }
+
+    /// Creates the sound chip without mapping it anywhere
+    fn unmapped(asic: Arc<Mutex<Asic>>) -> Aica {
+        let mut aica = Aica {
+            registers: vec![0u8; AICA_REGISTER_SIZE].into_boxed_slice(),
+            ram: vec![0u8; WAVE_RAM_SIZE].into_boxed_slice(),
+            channels: (0..CHANNELS).map(|_| Channel::new()).collect(),
+            dsp: Dsp::new(),
+            timer_clocks: [0; 3],
+            // Every 64 steps of roughly 0.094 dB halve the amplitude
+            attenuation_table: (0..0x400).map(|i| (32768.0 * 2f64.powf(-(i as f64) / 64.0)) as i32).collect(),
+            arm: Arm7::new(),
+            cdda: None,
+            asic,
+        };
+        aica.set_register(ARMRST, 1);
+        aica
+    }
+
+    #[inline(always)]
+    pub fn register(&self, offset: usize) -> u32 {
+        (self.registers[offset] as u32) | ((self.registers[offset + 1] as u32) << 8)
+    }
+
+    #[inline(always)]
+    pub fn set_register(&mut self, offset: usize, value: u32) {
+        self.registers[offset] = value as u8;
+        self.registers[offset + 1] = (value >> 8) as u8;
+    }
+
+    pub fn slot_parameters(&self, slot: usize) -> SlotParameters {
+        SlotParameters::new(&self.registers[slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE])
+    }
+
+    /// Reads from the register block, with the side effects of reading
+    pub fn read_register(&mut self, offset: usize, size: usize) -> u32 {
+        let offset = offset & (AICA_REGISTER_SIZE - 1);
+        let aligned = offset & !1;
+
+        let value = match aligned {
+            MONITOR_EG => {
+                let slot = (self.register(MONITOR_SELECT) >> 8) as usize & 0x3F;
+                let channel = &mut self.channels[slot];
+                let looped = ::std::mem::replace(&mut channel.looped, false);
+                ((looped as u32) << 15) | ((channel.envelope as u32) << 13) | channel.envelope_level()
+            },
+            MONITOR_CA => {
+                let slot = (self.register(MONITOR_SELECT) >> 8) as usize & 0x3F;
+                (self.channels[slot].position >> 14) & 0xFFFF
+            },
+            INTERRUPT_LEVEL => self.interrupt_level(),
+            TEMP ..= DSP_END => self.dsp.read_register(aligned),
+            _ => self.register(aligned)
+        };
+
+        match size {
+            1 => (value >> ((offset & 1) * 8)) & 0xFF,
+            _ => value
+        }
+    }
+
+    /// Writes to the register block, starting and stopping voices when
+    /// KYONEX is set
+    pub fn write_register(&mut self, offset: usize, value: u32, size: usize) {
+        let offset = offset & (AICA_REGISTER_SIZE - 1);
+        let aligned = offset & !1;
+        let old = self.register(aligned);
+        let value = match size {
+            1 if offset & 1 != 0 => (old & 0x00FF) | ((value & 0xFF) << 8),
+            1 => (old & 0xFF00) | (value & 0xFF),
+            _ => value & 0xFFFF
+        };
+
+        match aligned {
+            SCIRE => {
+                let pending = self.register(SCIPD) & !value;
+                self.set_register(SCIPD, pending);
+                self.update_interrupts();
+            },
+            ARMRST => {
+                // The sound CPU starts from its reset vector when released
+                if old & 1 != 0 && value & 1 == 0 {
+                    self.arm.reset();
+                }
+                self.set_register(aligned, value);
+            },
+            INTERRUPT_CLEAR => (),
+            MCIRE => {
+                let pending = self.register(MCIPD) & !value;
+                self.set_register(MCIPD, pending);
+                self.update_interrupts();
+            },
+            SCIPD | MCIPD => {
+                // Only the CPU and sample interrupts can be raised by software
+                let pending = self.register(aligned) | (value & (INTERRUPT_CPU | INTERRUPT_SAMPLE));
+                self.set_register(aligned, pending);
+                self.update_interrupts();
+            },
+            SCIEB | MCIEB => {
+                self.set_register(aligned, value);
+                self.update_interrupts();
+            },
+            MONITOR_EG | MONITOR_CA | INTERRUPT_LEVEL => (),
+            _ if aligned < CHANNELS * SLOT_SIZE && aligned.is_multiple_of(SLOT_SIZE) => {
+                // KYONEX is not stored, it applies KYONB of all slots
+                self.set_register(aligned, value & 0x7FFF);
+                if value & 0x8000 != 0 {
+                    self.key_on_execute();
+                }
+            },
+            _ => self.set_register(aligned, value)
+        }
+    }
+
+    fn key_on_execute(&mut self) {
+        for slot in 0..CHANNELS {
+            let params = self.slot_parameters(slot);
+            let channel = &mut self.channels[slot];
+            if params.key_on && !channel.playing {
+                channel.key_on(&params);
+            } else if !params.key_on && channel.playing {
+                channel.key_off();
+            }
+        }
+    }
+
+    /// Raises a pending interrupt for the SH-4 and the sound CPU
+    pub fn raise(&mut self, bits: u32) {
+        let sci = self.register(SCIPD) | bits;
+        let mci = self.register(MCIPD) | bits;
+        self.set_register(SCIPD, sci);
+        self.set_register(MCIPD, mci);
+        self.update_interrupts();
+    }
+
+    /// Level of the lowest pending and enabled sound CPU interrupt, made
+    /// of its bits in SCILV0 to SCILV2. Interrupts above bit 7 share the
+    /// level of bit 7.
+    pub fn interrupt_level(&self) -> u32 {
+        let pending = self.register(SCIPD) & self.register(SCIEB) & 0x7FF;
+        if pending == 0 {
+            return 0;
+        }
+
+        let bit = ::std::cmp::min(pending.trailing_zeros(), 7);
+        ((self.register(SCILV0) >> bit) & 1) | (((self.register(SCILV1) >> bit) & 1) << 1) | (((self.register(SCILV2) >> bit) & 1) << 2)
+    }
+
+    /// Whether the sound CPU's FIQ line is asserted
+    pub fn fiq_pending(&self) -> bool {
+        self.register(SCIPD) & self.register(SCIEB) & 0x7FF != 0
+    }
+
+    /// Forwards pending and enabled interrupts to the sound CPU and the
+    /// holly controller
+    fn update_interrupts(&mut self) {
+        self.arm.fiq = self.fiq_pending();
+
+        let pending = self.register(MCIPD) & self.register(MCIEB) != 0;
+        let mut asic = self.asic.lock().unwrap();
+        if pending {
+            asic.raise_external(ExternalInterrupt::Aica);
+        } else {
+            asic.clear_external(ExternalInterrupt::Aica);
+        }
+    }
+
+    /// Counts the timers up once per sample, as far as their prescalers allow
+    fn step_timers(&mut self) {
+        let timers = [(TIMER_A, INTERRUPT_TIMER_A), (TIMER_B, INTERRUPT_TIMER_B), (TIMER_C, INTERRUPT_TIMER_C)];
+
+        for (i, &(register, bit)) in timers.iter().enumerate() {
+            let value = self.register(register);
+            let prescale = (value >> 8) & 0x7;
+            self.timer_clocks[i] += 1;
+            if self.timer_clocks[i] < 1 << prescale {
+                continue;
+            }
+            self.timer_clocks[i] = 0;
+
+            let count = (value & 0xFF) + 1;
+            self.set_register(register, (value & 0x0700) | (count & 0xFF));
+            if count > 0xFF {
+                self.raise(bit);
+            }
+        }
+    }
+
+    /// Converts a level in steps of 3 dB, 0xF being 0 dB and 0 silence,
+    /// into a gain in 1.15 fixed point
+    fn level_gain(&self, level: u32) -> i32 {
+        if level == 0 { 0 } else { self.attenuation_table[((0xF - level) * 32) as usize] }
+    }
+
+    /// Applies an output level and pan to a sample, giving its left and
+    /// right parts. The pan attenuates one side in steps of 3 dB.
+    fn pan(&self, sample: i32, level: u32, pan: u32) -> (i32, i32) {
+        let direct = (sample * self.level_gain(level)) >> 15;
+        let mono = self.register(MASTER_VOLUME) & (1 << 15) != 0;
+        let side = if mono { 0xF } else { 0xF - (pan & 0xF) };
+
+        if pan & 0x10 != 0 {
+            (direct, (direct * self.level_gain(side)) >> 15)
+        } else {
+            ((direct * self.level_gain(side)) >> 15, direct)
+        }
+    }
+
+    /// Produces one stereo sample
+    pub fn step(&mut self) -> (i16, i16) {
+        let mut left = 0i32;
+        let mut right = 0i32;
+
+        for slot in 0..CHANNELS {
+            if !self.channels[slot].playing {
+                continue;
+            }
+
+            let params = self.slot_parameters(slot);
+            let sample = self.channels[slot].step(&params, &self.ram, &self.attenuation_table);
+            if params.imxl != 0 {
+                let gain = self.level_gain(params.imxl);
+                self.dsp.send(params.isel, sample, gain);
+            }
+
+            let (l, r) = self.pan(sample, params.disdl, params.dipan);
+            left += l;
+            right += r;
+        }
+
+        // The inputs fall silent when the drive does not keep up
+        let (cd_left, cd_right) = match self.cdda {
+            Some(ref cdda) => cdda.lock().unwrap().pop().unwrap_or((0, 0)),
+            None => (0, 0)
+        };
+        self.dsp.exts = [cd_left as i32, cd_right as i32];
+
+        self.dsp.step(&self.registers, &mut self.ram);
+
+        // The external inputs follow the 16 effect outputs
+        for output in 0..18 {
+            let control = self.register(EFFECT_OUTPUT + output * 4);
+            let sample = if output < 16 { self.dsp.efreg[output] as i32 } else { self.dsp.exts[output - 16] };
+            let (l, r) = self.pan(sample, (control >> 8) & 0xF, control & 0x1F);
+            left += l;
+            right += r;
+        }
+
+        let master = self.level_gain(self.register(MASTER_VOLUME) & 0xF);
+        let clamp = |s: i32| s.clamp(-32768, 32767) as i16;
+
+        self.step_timers();
+        (clamp((left * master) >> 15), clamp((right * master) >> 15))
+    }
+
+    pub fn arm_running(&self) -> bool {
+        self.register(ARMRST) & 1 == 0
+    }
+
+    /// Runs the sound CPU for the given number of its cycles, unless it
+    /// is held in reset
+    pub fn run_arm(&mut self, cycles: u64) {
+        if !self.arm_running() {
+            return;
+        }
+
+        // The core is taken out so that it can use the chip as its bus
+        let mut arm = ::std::mem::take(&mut self.arm);
+        let end = arm.cycles + cycles;
+        while arm.cycles < end && self.arm_running() {
+            arm.fiq = self.fiq_pending();
+            arm.step(self);
+        }
+        self.arm = arm;
+    }
+
+    /// Runs the sound CPU for one sample period, then mixes the sample
+    pub fn tick(&mut self, sink: &mut dyn AudioSink) {
+        self.run_arm(ARM_CYCLES_PER_SAMPLE);
+        let (left, right) = self.step();
+        sink.push(left, right);
+    }
+
+    /// Produces the given number of samples into the sink
+    pub fn generate(&mut self, samples: usize, sink: &mut dyn AudioSink) {
+        for _ in 0..samples {
+            self.tick(sink);
+        }
+    }
+
+    /// Schedules the first sample, interleaving the chip with the SH-4
+    pub fn start(&self, scheduler: &mut Scheduler) {
+        scheduler.schedule(CYCLES_PER_SAMPLE, Event::AicaSample);
+    }
+
+    /// Handles a sample event from the scheduler and schedules the next
+    pub fn sample_event(&mut self, scheduler: &mut Scheduler, sink: &mut dyn AudioSink) {
+        self.tick(sink);
+        scheduler.schedule(CYCLES_PER_SAMPLE, Event::AicaSample);
+    }
+
+    #[inline(always)]
+    pub fn read_ram(&self, offset: usize, size: usize) -> u32 {
+        let o = offset & WAVE_RAM_MASK;
+        let byte = |i: usize| self.ram[(o + i) & WAVE_RAM_MASK] as u32;
+        match size {
+            1 => byte(0),
+            2 => byte(0) | (byte(1) << 8),
+            _ => byte(0) | (byte(1) << 8) | (byte(2) << 16) | (byte(3) << 24)
+        }
+    }
+
+    #[inline(always)]
+    pub fn write_ram(&mut self, offset: usize, value: u32, size: usize) {
+        let o = offset & WAVE_RAM_MASK;
+        for i in 0..size {
+            self.ram[(o + i) & WAVE_RAM_MASK] = (value >> (i * 8)) as u8;
+        }
+    }
+}
+
+impl MappedDevice for Aica {
+    fn read(&mut self, address: usize, size: usize) -> u32 {
+        match address {
+            AICA_REGISTER_BASE ..= AICA_REGISTER_END => self.read_register(address - AICA_REGISTER_BASE, size),
+            WAVE_RAM_BASE ..= WAVE_RAM_END => self.read_ram(address - WAVE_RAM_BASE, size),
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, size: usize) {
+        match address {
+            AICA_REGISTER_BASE ..= AICA_REGISTER_END => self.write_register(address - AICA_REGISTER_BASE, value, size),
+            WAVE_RAM_BASE ..= WAVE_RAM_END => self.write_ram(address - WAVE_RAM_BASE, value, size),
+            _ => ()
+        }
+    }
+}
+
+/// The sound CPU's address space: wave RAM mirrored up to 8MB and the
+/// register block above it
+impl ArmBus for Aica {
+    fn read(&mut self, address: u32, size: usize) -> u32 {
+        match address {
+            0 ..= ARM_WAVE_RAM_END => self.read_ram(address as usize, size),
+            ARM_REGISTER_BASE ..= ARM_REGISTER_END => {
+                let offset = (address - ARM_REGISTER_BASE) as usize;
+                match size {
+                    4 => self.read_register(offset, 2),
+                    _ => self.read_register(offset, size)
+                }
+            },
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: u32, value: u32, size: usize) {
+        match address {
+            0 ..= ARM_WAVE_RAM_END => self.write_ram(address as usize, value, size),
+            ARM_REGISTER_BASE ..= ARM_REGISTER_END => {
+                let offset = (address - ARM_REGISTER_BASE) as usize;
+                match size {
+                    4 => self.write_register(offset, value, 2),
+                    _ => self.write_register(offset, value, size)
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asic::ExternalInterrupt;
+
+    fn aica() -> (Aica, Arc<Mutex<Asic>>) {
+        let asic = Arc::new(Mutex::new(Asic {
+            istnrm: 0,
+            istext: 0,
+            isterr: 0,
+            iml_nrm: [0; 3],
+            iml_ext: [0; 3],
+            iml_err: [0; 3],
+        }));
+        (Aica::unmapped(asic.clone()), asic)
+    }
+
+    fn holly_pending(asic: &Arc<Mutex<Asic>>) -> bool {
+        asic.lock().unwrap().istext & (1 << ExternalInterrupt::Aica as u32) != 0
+    }
+
+    #[test]
+    fn sound_cpu_interrupts_the_sh4_through_mcipd() {
+        let (mut aica, asic) = aica();
+        aica.write_register(MCIEB, INTERRUPT_CPU | INTERRUPT_TIMER_A, 2);
+
+        // Timer interrupts cannot be raised by software
+        aica.write_register(MCIPD, INTERRUPT_TIMER_A, 2);
+        assert_eq!(aica.register(MCIPD), 0);
+        assert!(!holly_pending(&asic));
+
+        aica.write_register(MCIPD, INTERRUPT_CPU, 2);
+        assert_eq!(aica.register(MCIPD), INTERRUPT_CPU);
+        assert!(holly_pending(&asic));
+
+        aica.write_register(MCIRE, INTERRUPT_CPU, 2);
+        assert_eq!(aica.register(MCIPD), 0);
+        assert!(!holly_pending(&asic));
+    }
+
+    #[test]
+    fn sh4_interrupts_the_sound_cpu_through_scipd() {
+        let (mut aica, _) = aica();
+        aica.write_register(SCILV0, INTERRUPT_CPU, 2);
+        aica.write_register(SCILV2, INTERRUPT_CPU, 2);
+
+        // Pending but not enabled
+        aica.write_register(SCIPD, INTERRUPT_CPU, 2);
+        assert!(!aica.arm.fiq);
+        assert_eq!(aica.interrupt_level(), 0);
+
+        aica.write_register(SCIEB, INTERRUPT_CPU, 2);
+        assert!(aica.arm.fiq);
+        assert_eq!(aica.read_register(INTERRUPT_LEVEL, 2), 0b101);
+
+        aica.write_register(SCIRE, INTERRUPT_CPU, 2);
+        assert!(!aica.arm.fiq);
+        assert_eq!(aica.interrupt_level(), 0);
+    }
+
+    #[test]
+    fn timer_overflow_raises_both_sides() {
+        let (mut aica, asic) = aica();
+        aica.write_register(MCIEB, INTERRUPT_TIMER_A, 2);
+        aica.write_register(TIMER_A, 0xFE, 2);
+
+        aica.step_timers();
+        assert_eq!(aica.register(TIMER_A), 0xFF);
+        assert!(!holly_pending(&asic));
+
+        aica.step_timers();
+        assert_eq!(aica.register(TIMER_A), 0x00);
+        assert_eq!(aica.register(SCIPD) & INTERRUPT_TIMER_A, INTERRUPT_TIMER_A);
+        assert!(holly_pending(&asic));
+    }
+}
@@ -0,0 +1,133 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+
+use scheduler::{Scheduler, Event, SH4_CLOCK};
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const RTC_HI     : usize = 0x00710000;
+pub const RTC_LO     : usize = 0x00710004;
+pub const RTC_ENABLE : usize = 0x00710008;
+
+pub const RTC_REGISTER_BASE : usize = 0x00710000;
+pub const RTC_REGISTER_END  : usize = 0x0071000B;
+
+/// Seconds from 1950-01-01, the RTC's epoch, to 1970-01-01
+pub const UNIX_EPOCH_OFFSET : u32 = 631152000;
+
+/// Where the clock takes its time from at power on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RtcClock {
+    /// A fixed number of seconds since 1950, so that runs are repeatable
+    Fixed(u32),
+    /// The host's current time
+    Host,
+}
+
+impl RtcClock {
+    pub fn seconds(&self) -> u32 {
+        match *self {
+            RtcClock::Fixed(seconds) => seconds,
+            RtcClock::Host => {
+                let unix = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                (unix as u32).wrapping_add(UNIX_EPOCH_OFFSET)
+            }
+        }
+    }
+}
+
+/// The real-time clock in the AICA, counting seconds since 1950. The
+/// time can only be set after writing 1 to RTC_ENABLE, and writing the
+/// low half completes the update.
+pub struct Rtc {
+    pub seconds: u32,
+    pub write_enabled: bool,
+}
+
+impl Rtc {
+    pub fn new(mem: &mut Memory, clock: RtcClock) -> Arc<Mutex<Rtc>> {
+        let rtc = Arc::new(Mutex::new(Rtc {
+            seconds: clock.seconds(),
+            write_enabled: false,
+        }));
+
+        mem.register_mapped_device(MemoryRange(RTC_REGISTER_BASE, RTC_REGISTER_END), rtc.clone());
+        rtc
+    }
+
+    /// Schedules the first tick of the clock
+    pub fn start(&self, scheduler: &mut Scheduler) {
+        scheduler.schedule(SH4_CLOCK, Event::RtcTick);
+    }
+
+    /// Handles a tick from the scheduler and schedules the next one
+    pub fn second_event(&mut self, scheduler: &mut Scheduler) {
+        self.seconds = self.seconds.wrapping_add(1);
+        scheduler.schedule(SH4_CLOCK, Event::RtcTick);
+    }
+}
+
+impl MappedDevice for Rtc {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address & !3 {
+            RTC_HI     => self.seconds >> 16,
+            RTC_LO     => self.seconds & 0xFFFF,
+            RTC_ENABLE => self.write_enabled as u32,
+            _          => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address & !3 {
+            RTC_HI if self.write_enabled => {
+                self.seconds = (self.seconds & 0xFFFF) | ((value & 0xFFFF) << 16);
+            },
+            RTC_LO if self.write_enabled => {
+                self.seconds = (self.seconds & 0xFFFF0000) | (value & 0xFFFF);
+                self.write_enabled = false;
+            },
+            RTC_ENABLE => self.write_enabled = value & 1 != 0,
+            _          => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_need_enabling() {
+        let mut rtc = Rtc { seconds: 0x12345678, write_enabled: false };
+        assert_eq!((rtc.read(RTC_HI, 4), rtc.read(RTC_LO, 4)), (0x1234, 0x5678));
+
+        rtc.write(RTC_HI, 0xAAAA, 4);
+        assert_eq!(rtc.seconds, 0x12345678);
+
+        // Writing the low half completes the update and locks the clock again
+        rtc.write(RTC_ENABLE, 1, 4);
+        rtc.write(RTC_HI, 0xAAAA, 4);
+        rtc.write(RTC_LO, 0xBBBB, 4);
+        assert_eq!(rtc.seconds, 0xAAAABBBB);
+        assert_eq!(rtc.read(RTC_ENABLE, 4), 0);
+        rtc.write(RTC_LO, 0, 4);
+        assert_eq!(rtc.seconds, 0xAAAABBBB);
+    }
+
+    #[test]
+    fn ticks_once_per_second() {
+        let mut rtc = Rtc { seconds: RtcClock::Fixed(100).seconds(), write_enabled: false };
+        let mut scheduler = Scheduler::new();
+        rtc.start(&mut scheduler);
+
+        scheduler.advance(SH4_CLOCK - 1);
+        assert_eq!(scheduler.pop_due(), None);
+        scheduler.advance(1);
+        assert_eq!(scheduler.pop_due(), Some(Event::RtcTick));
+        rtc.second_event(&mut scheduler);
+        assert_eq!(rtc.seconds, 101);
+        assert_eq!(scheduler.next_time(), Some(2 * SH4_CLOCK));
+    }
+}
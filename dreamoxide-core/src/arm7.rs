@@ -0,0 +1,847 @@
+/// Processor modes, as stored in the low bits of the CPSR
+pub const MODE_USR : u32 = 0x10;
+pub const MODE_FIQ : u32 = 0x11;
+pub const MODE_IRQ : u32 = 0x12;
+pub const MODE_SVC : u32 = 0x13;
+pub const MODE_ABT : u32 = 0x17;
+pub const MODE_UND : u32 = 0x1B;
+pub const MODE_SYS : u32 = 0x1F;
+
+pub const FLAG_N : u32 = 1 << 31;
+pub const FLAG_Z : u32 = 1 << 30;
+pub const FLAG_C : u32 = 1 << 29;
+pub const FLAG_V : u32 = 1 << 28;
+/// Interrupt disable bits
+pub const FLAG_I : u32 = 1 << 7;
+pub const FLAG_F : u32 = 1 << 6;
+
+pub const VECTOR_RESET     : u32 = 0x00;
+pub const VECTOR_UNDEFINED : u32 = 0x04;
+pub const VECTOR_SWI       : u32 = 0x08;
+pub const VECTOR_IRQ       : u32 = 0x18;
+pub const VECTOR_FIQ       : u32 = 0x1C;
+
+/// The memory an ARM core is attached to. Sizes are 1, 2 or 4 bytes
+/// and addresses are aligned to them.
+pub trait ArmBus {
+    fn read(&mut self, address: u32, size: usize) -> u32;
+    fn write(&mut self, address: u32, value: u32, size: usize);
+}
+
+/// An ARMv4 core executing ARM state code only, as found in the AICA.
+/// R15 reads as the address of the current instruction plus 8 while an
+/// instruction executes.
+pub struct Arm7 {
+    pub regs: [u32; 16],
+    pub cpsr: u32,
+    /// The saved status of the current mode
+    pub spsr: u32,
+    /// R13 and R14 of each mode not currently active
+    banked_sp_lr: [[u32; 2]; 6],
+    banked_spsr: [u32; 6],
+    /// R8 to R12 of FIQ mode and of all other modes
+    banked_fiq: [u32; 5],
+    banked_usr: [u32; 5],
+    /// State of the FIQ input line
+    pub fiq: bool,
+    pub cycles: u64,
+    branched: bool,
+}
+
+/// Index of the register bank used by a mode
+fn bank(mode: u32) -> usize {
+    match mode {
+        MODE_FIQ => 1,
+        MODE_IRQ => 2,
+        MODE_SVC => 3,
+        MODE_ABT => 4,
+        MODE_UND => 5,
+        _        => 0
+    }
+}
+
+impl Arm7 {
+    pub fn new() -> Arm7 {
+        let mut arm = Arm7 {
+            regs: [0; 16],
+            cpsr: MODE_SVC | FLAG_I | FLAG_F,
+            spsr: 0,
+            banked_sp_lr: [[0; 2]; 6],
+            banked_spsr: [0; 6],
+            banked_fiq: [0; 5],
+            banked_usr: [0; 5],
+            fiq: false,
+            cycles: 0,
+            branched: false,
+        };
+        arm.reset();
+        arm
+    }
+
+    /// Enters supervisor mode and jumps to the reset vector
+    pub fn reset(&mut self) {
+        self.switch_mode(MODE_SVC);
+        self.cpsr = MODE_SVC | FLAG_I | FLAG_F;
+        self.regs[15] = VECTOR_RESET;
+    }
+
+    /// Address of the next instruction
+    pub fn pc(&self) -> u32 {
+        self.regs[15]
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.cpsr & 0x1F
+    }
+
+    fn switch_mode(&mut self, mode: u32) {
+        let old = self.mode();
+        let (old_bank, new_bank) = (bank(old), bank(mode));
+
+        if old_bank != new_bank {
+            self.banked_sp_lr[old_bank] = [self.regs[13], self.regs[14]];
+            self.banked_spsr[old_bank] = self.spsr;
+
+            if old == MODE_FIQ {
+                self.banked_fiq.copy_from_slice(&self.regs[8..13]);
+                self.regs[8..13].copy_from_slice(&self.banked_usr);
+            } else if mode == MODE_FIQ {
+                self.banked_usr.copy_from_slice(&self.regs[8..13]);
+                self.regs[8..13].copy_from_slice(&self.banked_fiq);
+            }
+
+            self.regs[13] = self.banked_sp_lr[new_bank][0];
+            self.regs[14] = self.banked_sp_lr[new_bank][1];
+            self.spsr = self.banked_spsr[new_bank];
+        }
+
+        self.cpsr = (self.cpsr & !0x1F) | mode;
+    }
+
+    /// Replaces the CPSR, switching register banks if the mode changes
+    pub fn set_cpsr(&mut self, value: u32) {
+        self.switch_mode(value & 0x1F);
+        self.cpsr = value;
+    }
+
+    fn has_spsr(&self) -> bool {
+        self.mode() != MODE_USR && self.mode() != MODE_SYS
+    }
+
+    /// Reads a register of user mode, whatever the current mode is
+    fn user_reg(&self, n: usize) -> u32 {
+        match n {
+            8 ..= 12 if self.mode() == MODE_FIQ => self.banked_usr[n - 8],
+            13 | 14 if bank(self.mode()) != 0 => self.banked_sp_lr[0][n - 13],
+            _ => self.regs[n]
+        }
+    }
+
+    fn set_user_reg(&mut self, n: usize, value: u32) {
+        match n {
+            8 ..= 12 if self.mode() == MODE_FIQ => self.banked_usr[n - 8] = value,
+            13 | 14 if bank(self.mode()) != 0 => self.banked_sp_lr[0][n - 13] = value,
+            _ => self.set_reg(n, value)
+        }
+    }
+
+    #[inline(always)]
+    fn set_reg(&mut self, n: usize, value: u32) {
+        if n == 15 {
+            self.regs[15] = value & !3;
+            self.branched = true;
+        } else {
+            self.regs[n] = value;
+        }
+    }
+
+    fn exception(&mut self, vector: u32, mode: u32, return_address: u32) {
+        let old = self.cpsr;
+        self.switch_mode(mode);
+        self.spsr = old;
+        self.regs[14] = return_address;
+        self.cpsr |= FLAG_I;
+        if mode == MODE_FIQ {
+            self.cpsr |= FLAG_F;
+        }
+        self.regs[15] = vector;
+        self.branched = true;
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let n = self.cpsr & FLAG_N != 0;
+        let z = self.cpsr & FLAG_Z != 0;
+        let c = self.cpsr & FLAG_C != 0;
+        let v = self.cpsr & FLAG_V != 0;
+
+        match cond {
+            0x0 => z,
+            0x1 => !z,
+            0x2 => c,
+            0x3 => !c,
+            0x4 => n,
+            0x5 => !n,
+            0x6 => v,
+            0x7 => !v,
+            0x8 => c && !z,
+            0x9 => !c || z,
+            0xA => n == v,
+            0xB => n != v,
+            0xC => !z && n == v,
+            0xD => z || n != v,
+            0xE => true,
+            _   => false
+        }
+    }
+
+    fn set_nz(&mut self, result: u32) {
+        self.cpsr &= !(FLAG_N | FLAG_Z);
+        self.cpsr |= result & FLAG_N;
+        if result == 0 {
+            self.cpsr |= FLAG_Z;
+        }
+    }
+
+    fn set_flag(&mut self, flag: u32, set: bool) {
+        if set { self.cpsr |= flag; } else { self.cpsr &= !flag; }
+    }
+
+    /// Executes instructions until the given number of cycles has passed
+    pub fn run(&mut self, bus: &mut dyn ArmBus, cycles: u64) {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            self.step(bus);
+        }
+    }
+
+    /// Takes a pending FIQ or executes a single instruction
+    pub fn step(&mut self, bus: &mut dyn ArmBus) {
+        if self.fiq && self.cpsr & FLAG_F == 0 {
+            let next = self.regs[15];
+            self.exception(VECTOR_FIQ, MODE_FIQ, next.wrapping_add(4));
+            self.cycles += 3;
+            return;
+        }
+
+        let address = self.regs[15];
+        let instr = bus.read(address, 4);
+        self.regs[15] = address.wrapping_add(8);
+        self.branched = false;
+        self.cycles += 1;
+
+        if self.condition(instr >> 28) {
+            self.execute(bus, instr, address);
+        }
+
+        if self.branched {
+            self.cycles += 2;
+        } else {
+            self.regs[15] = address.wrapping_add(4);
+        }
+    }
+
+    fn execute(&mut self, bus: &mut dyn ArmBus, instr: u32, address: u32) {
+        match (instr >> 25) & 0x7 {
+            0b000 => {
+                if instr & 0x0FC000F0 == 0x00000090 {
+                    self.multiply(instr);
+                } else if instr & 0x0F8000F0 == 0x00800090 {
+                    self.multiply_long(instr);
+                } else if instr & 0x0FB00FF0 == 0x01000090 {
+                    self.swap(bus, instr);
+                } else if instr & 0x0E000090 == 0x00000090 && instr & 0x60 != 0 {
+                    self.halfword_transfer(bus, instr);
+                } else if instr & 0x0FBF0FFF == 0x010F0000 {
+                    let value = if instr & (1 << 22) != 0 { self.spsr } else { self.cpsr };
+                    self.set_reg(((instr >> 12) & 0xF) as usize, value);
+                } else if instr & 0x0FB0FFF0 == 0x0120F000 {
+                    let value = self.regs[(instr & 0xF) as usize];
+                    self.move_to_status(instr, value);
+                } else {
+                    self.data_processing(instr);
+                }
+            },
+            0b001 => {
+                if instr & 0x0FB0F000 == 0x0320F000 {
+                    let rotate = ((instr >> 8) & 0xF) * 2;
+                    self.move_to_status(instr, (instr & 0xFF).rotate_right(rotate));
+                } else {
+                    self.data_processing(instr);
+                }
+            },
+            0b010 => self.single_transfer(bus, instr),
+            0b011 => {
+                if instr & 0x10 != 0 {
+                    self.exception(VECTOR_UNDEFINED, MODE_UND, address.wrapping_add(4));
+                } else {
+                    self.single_transfer(bus, instr);
+                }
+            },
+            0b100 => self.block_transfer(bus, instr),
+            0b101 => {
+                // The offset is a signed 24-bit word count
+                let offset = ((instr << 8) as i32 >> 6) as u32;
+                if instr & (1 << 24) != 0 {
+                    self.regs[14] = address.wrapping_add(4);
+                }
+                let target = self.regs[15].wrapping_add(offset);
+                self.set_reg(15, target);
+            },
+            0b111 if instr & (1 << 24) != 0 => {
+                self.exception(VECTOR_SWI, MODE_SVC, address.wrapping_add(4));
+            },
+            // There is no coprocessor attached
+            _ => self.exception(VECTOR_UNDEFINED, MODE_UND, address.wrapping_add(4))
+        }
+    }
+
+    /// Applies a barrel shifter operation, returning the result and carry
+    fn shift(&self, value: u32, kind: u32, amount: u32, immediate: bool) -> (u32, bool) {
+        let carry = self.cpsr & FLAG_C != 0;
+
+        if immediate {
+            return match (kind, amount) {
+                (0, 0) => (value, carry),
+                (0, n) => (value << n, (value >> (32 - n)) & 1 != 0),
+                (1, 0) => (0, value >> 31 != 0),
+                (1, n) => (value >> n, (value >> (n - 1)) & 1 != 0),
+                (2, 0) => (((value as i32) >> 31) as u32, value >> 31 != 0),
+                (2, n) => (((value as i32) >> n) as u32, (value >> (n - 1)) & 1 != 0),
+                // ROR #0 encodes a rotate through carry by one bit
+                (_, 0) => (((carry as u32) << 31) | (value >> 1), value & 1 != 0),
+                (_, n) => (value.rotate_right(n), (value >> (n - 1)) & 1 != 0)
+            };
+        }
+
+        if amount == 0 {
+            return (value, carry);
+        }
+
+        match kind {
+            0 => match amount {
+                1 ..= 31 => (value << amount, (value >> (32 - amount)) & 1 != 0),
+                32 => (0, value & 1 != 0),
+                _ => (0, false)
+            },
+            1 => match amount {
+                1 ..= 31 => (value >> amount, (value >> (amount - 1)) & 1 != 0),
+                32 => (0, value >> 31 != 0),
+                _ => (0, false)
+            },
+            2 => match amount {
+                1 ..= 31 => (((value as i32) >> amount) as u32, (value >> (amount - 1)) & 1 != 0),
+                _ => (((value as i32) >> 31) as u32, value >> 31 != 0)
+            },
+            _ => {
+                let n = amount & 31;
+                if n == 0 {
+                    (value, value >> 31 != 0)
+                } else {
+                    (value.rotate_right(n), (value >> (n - 1)) & 1 != 0)
+                }
+            }
+        }
+    }
+
+    /// Decodes the second operand of a data processing instruction
+    fn operand2(&self, instr: u32) -> (u32, bool) {
+        if instr & (1 << 25) != 0 {
+            let rotate = ((instr >> 8) & 0xF) * 2;
+            let value = (instr & 0xFF).rotate_right(rotate);
+            let carry = if rotate == 0 { self.cpsr & FLAG_C != 0 } else { value >> 31 != 0 };
+            return (value, carry);
+        }
+
+        let rm = (instr & 0xF) as usize;
+        let kind = (instr >> 5) & 0x3;
+        if instr & 0x10 != 0 {
+            // With a register specified shift, the PC reads one word further
+            let value = if rm == 15 { self.regs[15].wrapping_add(4) } else { self.regs[rm] };
+            let amount = self.regs[((instr >> 8) & 0xF) as usize] & 0xFF;
+            self.shift(value, kind, amount, false)
+        } else {
+            self.shift(self.regs[rm], kind, (instr >> 7) & 0x1F, true)
+        }
+    }
+
+    fn add_with_carry(&mut self, a: u32, b: u32, carry: u32, set_flags: bool) -> u32 {
+        let wide = a as u64 + b as u64 + carry as u64;
+        let result = wide as u32;
+        if set_flags {
+            self.set_nz(result);
+            self.set_flag(FLAG_C, wide >> 32 != 0);
+            self.set_flag(FLAG_V, (!(a ^ b) & (a ^ result)) >> 31 != 0);
+        }
+        result
+    }
+
+    fn data_processing(&mut self, instr: u32) {
+        let opcode = (instr >> 21) & 0xF;
+        let set_flags = instr & (1 << 20) != 0;
+        let rn = ((instr >> 16) & 0xF) as usize;
+        let rd = ((instr >> 12) & 0xF) as usize;
+
+        let (op2, shifter_carry) = self.operand2(instr);
+        let register_shift = instr & (1 << 25) == 0 && instr & 0x10 != 0;
+        let a = if rn == 15 && register_shift { self.regs[15].wrapping_add(4) } else { self.regs[rn] };
+        let carry = (self.cpsr >> 29) & 1;
+        // Results written to the PC restore the status instead of setting flags
+        let flags = set_flags && rd != 15;
+
+        let logical = |arm: &mut Arm7, result: u32| {
+            if flags {
+                arm.set_nz(result);
+                arm.set_flag(FLAG_C, shifter_carry);
+            }
+            result
+        };
+
+        let result = match opcode {
+            0x0 | 0x8 => logical(self, a & op2),
+            0x1 | 0x9 => logical(self, a ^ op2),
+            0x2 | 0xA => self.add_with_carry(a, !op2, 1, flags),
+            0x3 => self.add_with_carry(op2, !a, 1, flags),
+            0x4 | 0xB => self.add_with_carry(a, op2, 0, flags),
+            0x5 => self.add_with_carry(a, op2, carry, flags),
+            0x6 => self.add_with_carry(a, !op2, carry, flags),
+            0x7 => self.add_with_carry(op2, !a, carry, flags),
+            0xC => logical(self, a | op2),
+            0xD => logical(self, op2),
+            0xE => logical(self, a & !op2),
+            _   => logical(self, !op2)
+        };
+
+        // TST, TEQ, CMP and CMN only set flags
+        if (0x8..=0xB).contains(&opcode) {
+            return;
+        }
+
+        if set_flags && rd == 15 && self.has_spsr() {
+            let spsr = self.spsr;
+            self.set_cpsr(spsr);
+        }
+        self.set_reg(rd, result);
+    }
+
+    fn move_to_status(&mut self, instr: u32, value: u32) {
+        let mut mask = 0;
+        for field in 0..4 {
+            if instr & (1 << (16 + field)) != 0 {
+                mask |= 0xFF << (field * 8);
+            }
+        }
+
+        if instr & (1 << 22) != 0 {
+            if self.has_spsr() {
+                self.spsr = (self.spsr & !mask) | (value & mask);
+            }
+        } else {
+            // User mode may only change the flags
+            if self.mode() == MODE_USR {
+                mask &= 0xFF000000;
+            }
+            let cpsr = (self.cpsr & !mask) | (value & mask);
+            self.set_cpsr(cpsr);
+        }
+    }
+
+    fn multiply(&mut self, instr: u32) {
+        let rd = ((instr >> 16) & 0xF) as usize;
+        let rn = ((instr >> 12) & 0xF) as usize;
+        let rs = ((instr >> 8) & 0xF) as usize;
+        let rm = (instr & 0xF) as usize;
+
+        let mut result = self.regs[rm].wrapping_mul(self.regs[rs]);
+        if instr & (1 << 21) != 0 {
+            result = result.wrapping_add(self.regs[rn]);
+        }
+        if instr & (1 << 20) != 0 {
+            self.set_nz(result);
+        }
+        self.cycles += 2;
+        self.set_reg(rd, result);
+    }
+
+    fn multiply_long(&mut self, instr: u32) {
+        let hi = ((instr >> 16) & 0xF) as usize;
+        let lo = ((instr >> 12) & 0xF) as usize;
+        let rs = ((instr >> 8) & 0xF) as usize;
+        let rm = (instr & 0xF) as usize;
+
+        let mut result = if instr & (1 << 22) != 0 {
+            ((self.regs[rm] as i32 as i64) * (self.regs[rs] as i32 as i64)) as u64
+        } else {
+            (self.regs[rm] as u64) * (self.regs[rs] as u64)
+        };
+        if instr & (1 << 21) != 0 {
+            result = result.wrapping_add(((self.regs[hi] as u64) << 32) | self.regs[lo] as u64);
+        }
+        if instr & (1 << 20) != 0 {
+            self.set_flag(FLAG_N, result >> 63 != 0);
+            self.set_flag(FLAG_Z, result == 0);
+        }
+        self.cycles += 3;
+        self.set_reg(lo, result as u32);
+        self.set_reg(hi, (result >> 32) as u32);
+    }
+
+    /// Reads a word, rotating unaligned addresses like the hardware does
+    fn read_word(&mut self, bus: &mut dyn ArmBus, address: u32) -> u32 {
+        self.cycles += 1;
+        bus.read(address & !3, 4).rotate_right((address & 3) * 8)
+    }
+
+    fn swap(&mut self, bus: &mut dyn ArmBus, instr: u32) {
+        let address = self.regs[((instr >> 16) & 0xF) as usize];
+        let rd = ((instr >> 12) & 0xF) as usize;
+        let value = self.regs[(instr & 0xF) as usize];
+
+        let old = if instr & (1 << 22) != 0 {
+            let old = bus.read(address, 1);
+            bus.write(address, value & 0xFF, 1);
+            old
+        } else {
+            let old = self.read_word(bus, address);
+            bus.write(address & !3, value, 4);
+            old
+        };
+        self.cycles += 2;
+        self.set_reg(rd, old);
+    }
+
+    fn single_transfer(&mut self, bus: &mut dyn ArmBus, instr: u32) {
+        let pre = instr & (1 << 24) != 0;
+        let up = instr & (1 << 23) != 0;
+        let byte = instr & (1 << 22) != 0;
+        let writeback = instr & (1 << 21) != 0;
+        let load = instr & (1 << 20) != 0;
+        let rn = ((instr >> 16) & 0xF) as usize;
+        let rd = ((instr >> 12) & 0xF) as usize;
+
+        let offset = if instr & (1 << 25) != 0 {
+            self.shift(self.regs[(instr & 0xF) as usize], (instr >> 5) & 0x3, (instr >> 7) & 0x1F, true).0
+        } else {
+            instr & 0xFFF
+        };
+
+        let base = self.regs[rn];
+        let moved = if up { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+        let address = if pre { moved } else { base };
+
+        // A stored PC is one word further ahead, a stored base is taken
+        // before the writeback
+        let stored = if rd == 15 { self.regs[15].wrapping_add(4) } else { self.regs[rd] };
+        if !pre || writeback {
+            self.set_reg(rn, moved);
+        }
+
+        if load {
+            let value = if byte {
+                self.cycles += 1;
+                bus.read(address, 1)
+            } else {
+                self.read_word(bus, address)
+            };
+            self.cycles += 1;
+            self.set_reg(rd, value);
+        } else {
+            self.cycles += 1;
+            if byte {
+                bus.write(address, stored & 0xFF, 1);
+            } else {
+                bus.write(address & !3, stored, 4);
+            }
+        }
+    }
+
+    fn halfword_transfer(&mut self, bus: &mut dyn ArmBus, instr: u32) {
+        let pre = instr & (1 << 24) != 0;
+        let up = instr & (1 << 23) != 0;
+        let writeback = instr & (1 << 21) != 0;
+        let load = instr & (1 << 20) != 0;
+        let rn = ((instr >> 16) & 0xF) as usize;
+        let rd = ((instr >> 12) & 0xF) as usize;
+
+        let offset = if instr & (1 << 22) != 0 {
+            ((instr >> 4) & 0xF0) | (instr & 0xF)
+        } else {
+            self.regs[(instr & 0xF) as usize]
+        };
+
+        let base = self.regs[rn];
+        let moved = if up { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+        let address = if pre { moved } else { base };
+
+        let stored = if rd == 15 { self.regs[15].wrapping_add(4) } else { self.regs[rd] };
+        if !pre || writeback {
+            self.set_reg(rn, moved);
+        }
+
+        self.cycles += 2;
+        if load {
+            let value = match (instr >> 5) & 0x3 {
+                1 => bus.read(address & !1, 2),
+                2 => bus.read(address, 1) as u8 as i8 as i32 as u32,
+                _ => bus.read(address & !1, 2) as u16 as i16 as i32 as u32
+            };
+            self.set_reg(rd, value);
+        } else {
+            bus.write(address & !1, stored & 0xFFFF, 2);
+        }
+    }
+
+    fn block_transfer(&mut self, bus: &mut dyn ArmBus, instr: u32) {
+        let pre = instr & (1 << 24) != 0;
+        let up = instr & (1 << 23) != 0;
+        let psr = instr & (1 << 22) != 0;
+        let writeback = instr & (1 << 21) != 0;
+        let load = instr & (1 << 20) != 0;
+        let rn = ((instr >> 16) & 0xF) as usize;
+        let list = instr & 0xFFFF;
+
+        let count = list.count_ones();
+        let base = self.regs[rn];
+        let (mut address, end) = if up {
+            (if pre { base.wrapping_add(4) } else { base }, base.wrapping_add(count * 4))
+        } else {
+            let start = base.wrapping_sub(count * 4);
+            (if pre { start } else { start.wrapping_add(4) }, start)
+        };
+
+        // With S set and the PC not loaded, the user bank is transferred
+        let user_bank = psr && !(load && list & 0x8000 != 0);
+
+        // Loads write the base back first, so that loading it wins. Stores
+        // write it back last, so that storing it gives its original value.
+        let writeback = writeback && !(load && list & (1 << rn) != 0);
+        if load && writeback {
+            self.set_reg(rn, end);
+        }
+
+        for r in 0..16 {
+            if list & (1 << r) == 0 {
+                continue;
+            }
+
+            self.cycles += 1;
+            if load {
+                let value = bus.read(address, 4);
+                if user_bank {
+                    self.set_user_reg(r, value);
+                } else {
+                    self.set_reg(r, value);
+                }
+            } else {
+                let value = if r == 15 {
+                    self.regs[15].wrapping_add(4)
+                } else if user_bank {
+                    self.user_reg(r)
+                } else {
+                    self.regs[r]
+                };
+                bus.write(address, value, 4);
+            }
+            address = address.wrapping_add(4);
+        }
+
+        if !load && writeback {
+            self.set_reg(rn, end);
+        }
+
+        if load && psr && list & 0x8000 != 0 && self.has_spsr() {
+            let spsr = self.spsr;
+            self.set_cpsr(spsr);
+        }
+    }
+}
+
+impl Default for Arm7 {
+    fn default() -> Arm7 {
+        Arm7::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestBus {
+        ram: Vec<u8>,
+    }
+
+    impl ArmBus for TestBus {
+        fn read(&mut self, address: u32, size: usize) -> u32 {
+            (0..size).fold(0, |acc, i| acc | ((self.ram[address as usize + i] as u32) << (i * 8)))
+        }
+
+        fn write(&mut self, address: u32, value: u32, size: usize) {
+            for i in 0..size {
+                self.ram[address as usize + i] = (value >> (i * 8)) as u8;
+            }
+        }
+    }
+
+    /// A core in supervisor mode with interrupts enabled, about to run
+    /// the given code from address 0
+    fn setup(code: &[u32]) -> (Arm7, TestBus) {
+        let mut bus = TestBus { ram: vec![0; 0x1000] };
+        for (i, &instr) in code.iter().enumerate() {
+            bus.write(i as u32 * 4, instr, 4);
+        }
+        let mut arm = Arm7::new();
+        arm.set_cpsr(MODE_SVC);
+        (arm, bus)
+    }
+
+    fn flags(arm: &Arm7) -> u32 {
+        arm.cpsr & (FLAG_N | FLAG_Z | FLAG_C | FLAG_V)
+    }
+
+    #[test]
+    fn arithmetic_sets_flags() {
+        // ADDS r2, r0, r1; SUBS r2, r0, r1; CMP r0, r1
+        let (mut arm, mut bus) = setup(&[0xE0902001, 0xE0502001, 0xE1500001]);
+
+        arm.regs[0] = 0x7FFFFFFF;
+        arm.regs[1] = 1;
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[2], 0x80000000);
+        assert_eq!(flags(&arm), FLAG_N | FLAG_V);
+
+        arm.regs[0] = 1;
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[2], 0);
+        assert_eq!(flags(&arm), FLAG_Z | FLAG_C);
+
+        arm.regs[0] = 0;
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[2], 0);
+        assert_eq!(flags(&arm), FLAG_N);
+    }
+
+    #[test]
+    fn logical_operations_take_the_shifter_carry() {
+        // MOVS r2, r0, LSL #1; MOVS r2, r0, LSR #32; MOVS r2, r0, RRX;
+        // MOVS r2, #0x80000000; ANDS r2, r0, r0, LSL r3
+        let (mut arm, mut bus) = setup(&[0xE1B02080, 0xE1B02020, 0xE1B02060, 0xE3B02102, 0xE0102310]);
+
+        arm.regs[0] = 0x80000001;
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], flags(&arm)), (2, FLAG_C));
+
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], flags(&arm)), (0, FLAG_Z | FLAG_C));
+
+        // The carry rotates in at the top, bit 0 goes out
+        arm.regs[0] = 2;
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], flags(&arm)), (0x80000001, FLAG_N));
+
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], flags(&arm)), (0x80000000, FLAG_N | FLAG_C));
+
+        // A register shift by zero leaves the carry alone
+        arm.regs[3] = 0x100;
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], flags(&arm)), (2, FLAG_C));
+    }
+
+    #[test]
+    fn block_transfers_write_back() {
+        // STMDB r13!, {r0-r2, r14}; LDMIA r13!, {r4-r7}; STMIA r0!, {r0, r1}; STMIA r1!, {r0, r1}
+        let (mut arm, mut bus) = setup(&[0xE92D4007, 0xE8BD00F0, 0xE8A00003, 0xE8A10003]);
+        arm.regs[13] = 0x100;
+        arm.regs[0] = 0x200;
+        arm.regs[1] = 0x300;
+        arm.regs[2] = 0x22;
+        arm.regs[14] = 0x44;
+
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[13], 0xF0);
+        assert_eq!((bus.read(0xF0, 4), bus.read(0xFC, 4)), (0x200, 0x44));
+
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[13], 0x100);
+        assert_eq!(&arm.regs[4..8], &[0x200, 0x300, 0x22, 0x44]);
+
+        // A stored base keeps the value it had before the transfer
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[0], 0x208);
+        assert_eq!((bus.read(0x200, 4), bus.read(0x204, 4)), (0x200, 0x300));
+
+        arm.step(&mut bus);
+        assert_eq!(arm.regs[1], 0x308);
+        assert_eq!((bus.read(0x300, 4), bus.read(0x304, 4)), (0x208, 0x300));
+    }
+
+    #[test]
+    fn single_transfers_store_the_base_before_writeback() {
+        // STR r0, [r0, #4]!; STR r1, [r1], #4; STRH r2, [r2, #2]!
+        let (mut arm, mut bus) = setup(&[0xE5A00004, 0xE4811004, 0xE1E220B2]);
+        arm.regs[0] = 0x300;
+        arm.regs[1] = 0x400;
+        arm.regs[2] = 0x500;
+
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[0], bus.read(0x304, 4)), (0x304, 0x300));
+
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[1], bus.read(0x400, 4)), (0x404, 0x400));
+
+        arm.step(&mut bus);
+        assert_eq!((arm.regs[2], bus.read(0x502, 2)), (0x502, 0x500));
+    }
+
+    #[test]
+    fn banks_registers_per_mode() {
+        let (mut arm, _) = setup(&[]);
+        arm.regs[13] = 0x5C;
+        arm.regs[8] = 0x88;
+
+        arm.set_cpsr(MODE_IRQ);
+        assert_eq!(arm.regs[13], 0);
+        arm.regs[13] = 0x1C;
+        assert_eq!(arm.regs[8], 0x88);
+
+        arm.set_cpsr(MODE_FIQ);
+        assert_eq!((arm.regs[8], arm.regs[13]), (0, 0));
+        arm.regs[8] = 0xF8;
+
+        arm.set_cpsr(MODE_SVC);
+        assert_eq!((arm.regs[8], arm.regs[13]), (0x88, 0x5C));
+        arm.set_cpsr(MODE_IRQ);
+        assert_eq!(arm.regs[13], 0x1C);
+        arm.set_cpsr(MODE_FIQ);
+        assert_eq!(arm.regs[8], 0xF8);
+    }
+
+    #[test]
+    fn fiq_enters_and_returns() {
+        let mut code = vec![0xE1A00000; 8];
+        // SUBS pc, lr, #4 at the FIQ vector
+        code[7] = 0xE25EF004;
+        let (mut arm, mut bus) = setup(&code);
+        arm.regs[8] = 0x88;
+        arm.cpsr |= FLAG_C;
+
+        arm.fiq = true;
+        arm.step(&mut bus);
+        assert_eq!(arm.mode(), MODE_FIQ);
+        assert_eq!(arm.pc(), VECTOR_FIQ);
+        assert_eq!(arm.regs[14], 4);
+        assert_eq!(arm.spsr, MODE_SVC | FLAG_C);
+        assert_eq!(arm.cpsr & (FLAG_I | FLAG_F), FLAG_I | FLAG_F);
+        assert_eq!(arm.regs[8], 0);
+
+        // Masked from here on, until the handler returns
+        arm.step(&mut bus);
+        assert_eq!(arm.mode(), MODE_SVC);
+        assert_eq!(arm.pc(), 0);
+        assert_eq!(arm.cpsr, MODE_SVC | FLAG_C);
+        assert_eq!(arm.regs[8], 0x88);
+
+        // The line is still asserted, so it is taken again
+        arm.step(&mut bus);
+        assert_eq!(arm.mode(), MODE_FIQ);
+    }
+}
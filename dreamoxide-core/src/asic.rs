@@ -0,0 +1,210 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+
+use std::sync::{Arc, Mutex};
+
+pub const SB_ISTNRM  : usize = 0x005F6900;
+pub const SB_ISTEXT  : usize = 0x005F6904;
+pub const SB_ISTERR  : usize = 0x005F6908;
+pub const SB_IML2NRM : usize = 0x005F6910;
+pub const SB_IML2EXT : usize = 0x005F6914;
+pub const SB_IML2ERR : usize = 0x005F6918;
+pub const SB_IML4NRM : usize = 0x005F6920;
+pub const SB_IML4EXT : usize = 0x005F6924;
+pub const SB_IML4ERR : usize = 0x005F6928;
+pub const SB_IML6NRM : usize = 0x005F6930;
+pub const SB_IML6EXT : usize = 0x005F6934;
+pub const SB_IML6ERR : usize = 0x005F6938;
+
+/// Normal interrupts, reported in SB_ISTNRM. The discriminant is the
+/// bit position within the status register.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    RenderDoneVideo = 0,
+    RenderDoneIsp = 1,
+    RenderDoneTsp = 2,
+    VBlankIn = 3,
+    VBlankOut = 4,
+    HBlankIn = 5,
+    YuvDone = 6,
+    OpaqueListDone = 7,
+    OpaqueModifierVolumeListDone = 8,
+    TranslucentListDone = 9,
+    TranslucentModifierVolumeListDone = 10,
+    PvrDmaDone = 11,
+    MapleDmaDone = 12,
+    MapleVBlankOver = 13,
+    GdRomDmaDone = 14,
+    AicaDmaDone = 15,
+    ExtDma1Done = 16,
+    ExtDma2Done = 17,
+    DevDmaDone = 18,
+    Ch2DmaDone = 19,
+    SortDmaDone = 20,
+    PunchThroughListDone = 21,
+}
+
+/// External interrupts, reported in SB_ISTEXT. These are level
+/// triggered and stay asserted until the device drops them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExternalInterrupt {
+    GdRom = 0,
+    Aica = 1,
+    Modem = 2,
+    Expansion = 3,
+}
+
+/// Error interrupts, reported in SB_ISTERR.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ErrorInterrupt {
+    IspOutOfCache = 0,
+    RenderHazard = 1,
+    TaIspParameterOverflow = 2,
+    TaObjectListOverflow = 3,
+    TaIllegalParameter = 4,
+    TaYuvOverflow = 5,
+    PvrDmaIllegalAddress = 6,
+    PvrDmaOverrun = 7,
+    MapleIllegalAddress = 8,
+    MapleOverrun = 9,
+    MapleWriteFifoOverflow = 10,
+    MapleIllegalCommand = 11,
+    G1IllegalAddress = 12,
+    G1Overrun = 13,
+    G1RomFlashAccess = 14,
+    G2AicaIllegalAddress = 15,
+    G2Ext1IllegalAddress = 16,
+    G2Ext2IllegalAddress = 17,
+    G2DevIllegalAddress = 18,
+    G2AicaOverrun = 19,
+    G2Ext1Overrun = 20,
+    G2Ext2Overrun = 21,
+    G2DevOverrun = 22,
+    G2AicaTimeout = 23,
+    G2Ext1Timeout = 24,
+    G2Ext2Timeout = 25,
+    G2DevTimeout = 26,
+    G2Timeout = 27,
+    SortDmaCommandError = 28,
+    DdtIfSh4Access = 30,
+    DdtIfDuringDma = 31,
+}
+
+/// The interrupt controller of the Holly system ASIC. It collects
+/// the interrupts of all peripherals and reports them to the SH-4
+/// on one of the IRL levels 2, 4 or 6.
+pub struct Asic {
+    pub istnrm: u32,
+    pub istext: u32,
+    pub isterr: u32,
+    /// Masks for level 2, 4 and 6, in that order
+    pub iml_nrm: [u32; 3],
+    pub iml_ext: [u32; 3],
+    pub iml_err: [u32; 3],
+}
+
+impl Asic {
+    /// Creates a new interrupt controller and registers
+    /// its mapped region with the memory controller
+    pub fn new(mem: &mut Memory) -> Arc<Mutex<Asic>> {
+        let asic = Arc::new(Mutex::new(Asic {
+            istnrm: 0,
+            istext: 0,
+            isterr: 0,
+            iml_nrm: [0; 3],
+            iml_ext: [0; 3],
+            iml_err: [0; 3],
+        }));
+
+        mem.register_mapped_device(MemoryRange(0x005F6900, 0x005F693F), asic.clone());
+
+        asic
+    }
+
+    /// Flags a normal interrupt as pending
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        self.istnrm |= 1 << (interrupt as u32);
+    }
+
+    /// Asserts an external interrupt line
+    pub fn raise_external(&mut self, interrupt: ExternalInterrupt) {
+        self.istext |= 1 << (interrupt as u32);
+    }
+
+    /// Drops an external interrupt line
+    pub fn clear_external(&mut self, interrupt: ExternalInterrupt) {
+        self.istext &= !(1 << (interrupt as u32));
+    }
+
+    /// Flags an error interrupt as pending
+    pub fn raise_error(&mut self, interrupt: ErrorInterrupt) {
+        self.isterr |= 1 << (interrupt as u32);
+    }
+
+    /// Checks if the given normal interrupt is still pending
+    pub fn is_pending(&self, interrupt: Interrupt) -> bool {
+        self.istnrm & (1 << (interrupt as u32)) != 0
+    }
+
+    /// Returns the highest IRL level (6, 4 or 2) with an unmasked
+    /// pending interrupt, if any
+    pub fn irl_level(&self) -> Option<u8> {
+        for &(i, level) in [(2, 6), (1, 4), (0, 2)].iter() {
+            if self.istnrm & self.iml_nrm[i] != 0
+                || self.istext & self.iml_ext[i] != 0
+                || self.isterr & self.iml_err[i] != 0 {
+                return Some(level);
+            }
+        }
+
+        None
+    }
+
+    /// The normal status register mirrors whether any external
+    /// or error interrupt is pending in its two top bits
+    fn read_istnrm(&self) -> u32 {
+        let mut value = self.istnrm & 0x003FFFFF;
+        if self.istext != 0 { value |= 1 << 30; }
+        if self.isterr != 0 { value |= 1 << 31; }
+        value
+    }
+}
+
+impl MappedDevice for Asic {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address {
+            SB_ISTNRM  => self.read_istnrm(),
+            SB_ISTEXT  => self.istext,
+            SB_ISTERR  => self.isterr,
+            SB_IML2NRM => self.iml_nrm[0],
+            SB_IML2EXT => self.iml_ext[0],
+            SB_IML2ERR => self.iml_err[0],
+            SB_IML4NRM => self.iml_nrm[1],
+            SB_IML4EXT => self.iml_ext[1],
+            SB_IML4ERR => self.iml_err[1],
+            SB_IML6NRM => self.iml_nrm[2],
+            SB_IML6EXT => self.iml_ext[2],
+            SB_IML6ERR => self.iml_err[2],
+            _          => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address {
+            // Status bits are cleared by writing a one
+            SB_ISTNRM  => self.istnrm &= !(value & 0x003FFFFF),
+            SB_ISTERR  => self.isterr &= !value,
+            SB_IML2NRM => self.iml_nrm[0] = value & 0x003FFFFF,
+            SB_IML2EXT => self.iml_ext[0] = value & 0xF,
+            SB_IML2ERR => self.iml_err[0] = value,
+            SB_IML4NRM => self.iml_nrm[1] = value & 0x003FFFFF,
+            SB_IML4EXT => self.iml_ext[1] = value & 0xF,
+            SB_IML4ERR => self.iml_err[1] = value,
+            SB_IML6NRM => self.iml_nrm[2] = value & 0x003FFFFF,
+            SB_IML6EXT => self.iml_ext[2] = value & 0xF,
+            SB_IML6ERR => self.iml_err[2] = value,
+            _          => ()
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Output rate of the sound chip
+pub const SAMPLE_RATE : u32 = 44100;
+
+/// Receives the stereo samples produced by the sound chip
+pub trait AudioSink {
+    fn push(&mut self, left: i16, right: i16);
+}
+
+/// Discards all samples
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push(&mut self, _left: i16, _right: i16) {}
+}
+
+/// Collects samples in memory, for example to compare them against
+/// a recording
+pub struct BufferSink {
+    pub samples: Vec<(i16, i16)>,
+}
+
+impl BufferSink {
+    pub fn new() -> BufferSink {
+        BufferSink { samples: Vec::new() }
+    }
+}
+
+impl Default for BufferSink {
+    fn default() -> BufferSink {
+        BufferSink::new()
+    }
+}
+
+impl AudioSink for BufferSink {
+    fn push(&mut self, left: i16, right: i16) {
+        self.samples.push((left, right));
+    }
+}
+
+/// Stereo samples handed from another device to the sound chip, such
+/// as the CD audio streamed by the GD-ROM drive. Shared by both sides.
+pub struct SampleQueue {
+    samples: VecDeque<(i16, i16)>,
+}
+
+impl SampleQueue {
+    pub fn new() -> SampleQueue {
+        SampleQueue { samples: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, left: i16, right: i16) {
+        self.samples.push_back((left, right));
+    }
+
+    pub fn pop(&mut self) -> Option<(i16, i16)> {
+        self.samples.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl Default for SampleQueue {
+    fn default() -> SampleQueue {
+        SampleQueue::new()
+    }
+}
+
+/// Writes 16-bit stereo PCM as a WAV stream. The sizes in the header
+/// are filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    frames: u32,
+    error: Option<io::Error>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Creates a WAV file at the given path
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W) -> io::Result<WavWriter<W>> {
+        WavWriter::write_header(&mut out, 0)?;
+        Ok(WavWriter { out, frames: 0, error: None })
+    }
+
+    fn write_header(out: &mut W, frames: u32) -> io::Result<()> {
+        let data_size = frames * 4;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&le32(36 + data_size));
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&le32(16));
+        // PCM, two channels
+        header.extend_from_slice(&[1, 0, 2, 0]);
+        header.extend_from_slice(&le32(SAMPLE_RATE));
+        header.extend_from_slice(&le32(SAMPLE_RATE * 4));
+        // Block align and bits per sample
+        header.extend_from_slice(&[4, 0, 16, 0]);
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&le32(data_size));
+        out.write_all(&header)
+    }
+
+    /// Number of stereo samples written so far
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Patches the header and flushes the stream. Reports the first
+    /// error that occurred while pushing samples.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        WavWriter::write_header(&mut self.out, self.frames)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn push(&mut self, left: i16, right: i16) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let frame = [left as u8, (left >> 8) as u8, right as u8, (right >> 8) as u8];
+        match self.out.write_all(&frame) {
+            Ok(()) => self.frames += 1,
+            Err(error) => self.error = Some(error)
+        }
+    }
+}
+
+fn le32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn le32_at(data: &[u8], offset: usize) -> u32 {
+        (0..4).fold(0, |acc, i| acc | ((data[offset + i] as u32) << (i * 8)))
+    }
+
+    #[test]
+    fn finish_fills_in_the_sizes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.push(1, -1);
+        writer.push(0x1234, 0x5678);
+        writer.push(0, 0);
+        assert_eq!(writer.frames(), 3);
+
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(le32_at(&data, 4), 36 + 12);
+        assert_eq!(le32_at(&data, 24), SAMPLE_RATE);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(le32_at(&data, 40), 12);
+        assert_eq!(&data[44..52], &[1, 0, 0xFF, 0xFF, 0x34, 0x12, 0x78, 0x56]);
+    }
+
+    #[test]
+    fn queue_is_first_in_first_out() {
+        let mut queue = SampleQueue::new();
+        queue.push(1, 2);
+        queue.push(3, 4);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop(), Some((1, 2)));
+        assert_eq!(queue.pop(), Some((3, 4)));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+}
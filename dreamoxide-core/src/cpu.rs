@@ -13,6 +13,24 @@ use std::ops::IndexMut;
 
 pub const FPSCR_MASK : u32 = 0x003FFFFF;
 
+/// Register values after a power-on reset. Execution starts at the
+/// boot ROM in P2, privileged, on register bank 1 with all interrupts
+/// blocked.
+pub const RESET_PC    : usize = 0xA0000000;
+pub const RESET_SR    : u32 = 0x700000F0;
+pub const RESET_FPSCR : u32 = 0x00040001;
+
+/// Host code standing in for guest routines, like the BIOS system
+/// calls when there is no boot ROM. Reaching an address a trap handles
+/// runs the trap instead, after which execution continues at PR, as
+/// after an rts.
+pub trait Trap: Send {
+    /// Whether the routine at the given physical address is serviced
+    fn handles(&self, address: usize) -> bool;
+
+    fn call(&mut self, cpu: &mut Cpu, mem: &mut Memory, address: usize);
+}
+
 pub struct Cpu {
     pub pc: usize,
     pub pr: usize,
@@ -29,14 +47,15 @@ pub struct Cpu {
     pub fpscr: GeneralRegister,
     pub fpul: GeneralRegister,
     pub max: usize,
+    pub traps: Vec<Box<dyn Trap>>,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
-            pc: 0xA0000000,
+            pc: RESET_PC,
             pr: 0,
-            status: StatusRegister { value: 0 },
+            status: StatusRegister { value: RESET_SR },
             registers: [GeneralRegister { value: 0 }; 24],
             fpu_registers: [FloatingPointRegister { value: 0.0 }; 32],
             macl: GeneralRegister { value: 0 },
@@ -46,20 +65,49 @@ impl Cpu {
             vbr: GeneralRegister { value: 0 },
             ssr: GeneralRegister { value: 0 },
             spc: GeneralRegister { value: 0 },
-            fpscr: GeneralRegister { value: 0 },
+            fpscr: GeneralRegister { value: RESET_FPSCR },
             fpul: GeneralRegister { value: 0 },
-            max: 0
+            max: 0,
+            traps: Vec::new(),
+        }
+    }
+
+    /// Puts all registers into their power-on reset state. Those the
+    /// manual leaves undefined are cleared. Installed traps stay.
+    pub fn reset(&mut self) {
+        let traps = ::std::mem::take(&mut self.traps);
+        *self = Cpu::new();
+        self.traps = traps;
+    }
+
+    /// Runs the trap for the current address, if there is one
+    fn trap(&mut self, mem: &mut Memory) -> bool {
+        let address = self.pc & 0x1FFFFFFF;
+        match self.traps.iter().position(|t| t.handles(address)) {
+            Some(i) => {
+                // The trap is taken out so that it can use the CPU
+                let mut trap = self.traps.remove(i);
+                trap.call(self, mem, address);
+                self.traps.insert(i, trap);
+                self.pc = self.pr;
+                true
+            },
+            None => false
         }
     }
 
     pub fn step(&mut self, mem: &mut Memory) {
+        if !self.traps.is_empty() && self.trap(mem) {
+            return;
+        }
+
         match mem.access(self.pc as usize) {
-            &MemoryField::InstructionCell(inst) => {
+            &MemoryField::InstructionCell(inst, _) => {
                 InstructionExecuter::execute(self, mem, inst);
             },
             &MemoryField::MemoryCell(val) => {
                 let inst = InstructionDecoder::decode(val);
-                *mem.access_mut(self.pc) = MemoryField::InstructionCell(inst);
+                *mem.access_mut(self.pc) = MemoryField::InstructionCell(inst, val);
 
                 //if inst == Instruction::Unknown {
                     //println!("[0x{:08x}] Could not decode {:04x}", self.pc, val);
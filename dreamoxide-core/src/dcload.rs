@@ -0,0 +1,345 @@
+use Cpu;
+use Memory;
+use Operand;
+use cpu::Trap;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf, Component};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// dcload leaves a magic word where programs look for it, followed by
+/// the address of the routine they make their calls through
+pub const DCLOAD_MAGIC_ADDRESS   : usize = 0x8C004004;
+pub const DCLOAD_MAGIC           : u32 = 0xDEADBEEF;
+pub const DCLOAD_SYSCALL_ADDRESS : usize = 0x8C004008;
+/// Where the routine is placed. It holds an rts, in case the trap is
+/// missing.
+pub const DCLOAD_ENTRY : usize = 0x8C004010;
+
+/// Calls, passed in r4 with their arguments in r5 to r7
+const DC_READ      : u32 = 0;
+const DC_WRITE     : u32 = 1;
+const DC_OPEN      : u32 = 2;
+const DC_CLOSE     : u32 = 3;
+const DC_LSEEK     : u32 = 9;
+const DC_FSTAT     : u32 = 10;
+const DC_TIME      : u32 = 11;
+const DC_STAT      : u32 = 12;
+const DC_EXIT      : u32 = 15;
+const DC_GDBPACKET : u32 = 20;
+
+/// Flags of open, as newlib defines them for the SH
+const O_ACCMODE : u32 = 0x0003;
+const O_WRONLY  : u32 = 0x0001;
+const O_RDWR    : u32 = 0x0002;
+const O_APPEND  : u32 = 0x0008;
+const O_CREAT   : u32 = 0x0200;
+const O_TRUNC   : u32 = 0x0400;
+const O_EXCL    : u32 = 0x0800;
+
+const S_IFDIR : u32 = 0o040000;
+const S_IFREG : u32 = 0o100000;
+
+/// Files the program opens get numbers after those of the standard
+/// streams
+const FIRST_FD : u32 = 3;
+/// Longest path read from the program's memory
+const MAX_PATH : usize = 1024;
+/// Reads and writes are copied through the host in pieces this large
+const CHUNK_SIZE : usize = 0x10000;
+
+const FAILED : u32 = 0xFFFFFFFF;
+
+fn register(cpu: &Cpu, n: u8) -> u32 {
+    cpu[Operand::RegisterOperand(n)].value
+}
+
+fn set_result(cpu: &mut Cpu, value: u32) {
+    cpu[Operand::RegisterOperand(0)].value = value;
+}
+
+/// The calls of dcload-ip and dcload-serial, with which homebrew prints
+/// and reaches files on the host. They are serviced against a host
+/// directory, out of which no path leads. The exit code the program
+/// leaves with is kept for the front end to stop on.
+pub struct Dcload {
+    root: PathBuf,
+    files: HashMap<u32, File>,
+    exit_code: Arc<Mutex<Option<i32>>>,
+}
+
+impl Dcload {
+    pub fn new<P: AsRef<Path>>(root: P, exit_code: Arc<Mutex<Option<i32>>>) -> io::Result<Dcload> {
+        Ok(Dcload {
+            root: fs::canonicalize(root)?,
+            files: HashMap::new(),
+            exit_code,
+        })
+    }
+
+    /// Leaves the magic word and the call address where programs look
+    pub fn install(mem: &mut Memory) {
+        mem.write_u32(DCLOAD_MAGIC_ADDRESS, DCLOAD_MAGIC);
+        mem.write_u32(DCLOAD_SYSCALL_ADDRESS, DCLOAD_ENTRY as u32);
+        // rts; nop
+        mem.load(DCLOAD_ENTRY, &[0x0B, 0x00, 0x09, 0x00]);
+    }
+
+    /// The host path of a path of the program, if it stays inside the
+    /// directory. Links leading out of it are refused too.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::RootDir | Component::CurDir => (),
+                _ => return None
+            }
+        }
+
+        // A file to be created only has its directory checked. A link
+        // that leads nowhere is refused, as creating the file would
+        // follow it.
+        let existing = match fs::canonicalize(&resolved) {
+            Ok(path) => path,
+            Err(_) if fs::symlink_metadata(&resolved).is_ok() => return None,
+            Err(_) => fs::canonicalize(resolved.parent()?).ok()?.join(resolved.file_name()?)
+        };
+        if existing.starts_with(&self.root) { Some(existing) } else { None }
+    }
+
+    fn read_string(mem: &Memory, address: usize) -> String {
+        let bytes: Vec<u8> = (0..MAX_PATH).map(|i| mem.read_u8(address + i)).take_while(|&b| b != 0).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Reads into a buffer, which is cut short where RAM ends
+    fn read(&mut self, mem: &mut Memory, fd: u32, buffer: usize, count: usize) -> io::Result<u32> {
+        let count = count.min(Memory::ram_left(buffer));
+        let mut chunk = vec![0; count.min(CHUNK_SIZE)];
+        let mut total = 0;
+        while total < count {
+            let size = (count - total).min(CHUNK_SIZE);
+            let read = match fd {
+                0 => io::stdin().read(&mut chunk[..size])?,
+                _ => self.file(fd)?.read(&mut chunk[..size])?
+            };
+            mem.write_bytes(buffer + total, &chunk[..read]);
+            total += read;
+            // The console gives a line at a time, which is all a read
+            // waits for
+            if read == 0 || fd == 0 {
+                break;
+            }
+        }
+        Ok(total as u32)
+    }
+
+    /// Writes from a buffer, which is cut short where RAM ends
+    fn write(&mut self, mem: &Memory, fd: u32, buffer: usize, count: usize) -> io::Result<u32> {
+        let count = count.min(Memory::ram_left(buffer));
+        let mut total = 0;
+        while total < count {
+            let data = mem.read_bytes(buffer + total, (count - total).min(CHUNK_SIZE));
+            match fd {
+                1 => io::stdout().write_all(&data)?,
+                2 => io::stderr().write_all(&data)?,
+                _ => self.file(fd)?.write_all(&data)?
+            }
+            total += data.len();
+        }
+        if fd == 1 {
+            io::stdout().flush()?;
+        }
+        Ok(total as u32)
+    }
+
+    fn open(&mut self, path: &str, flags: u32) -> io::Result<u32> {
+        let path = self.resolve(path).ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "outside of the directory"))?;
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR   => options.read(true).write(true),
+            _        => options.read(true)
+        };
+        options.append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0);
+
+        let fd = (FIRST_FD..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, options.open(path)?);
+        Ok(fd)
+    }
+
+    fn lseek(&mut self, fd: u32, offset: i32, whence: u32) -> io::Result<u32> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u32 as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown whence"))
+        };
+        Ok(self.file(fd)?.seek(position)? as u32)
+    }
+
+    fn file(&mut self, fd: u32) -> io::Result<&mut File> {
+        self.files.get_mut(&fd).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "bad file descriptor"))
+    }
+
+    /// Fills in a struct stat as newlib lays it out for the SH
+    fn write_stat(mem: &mut Memory, address: usize, metadata: &fs::Metadata) {
+        let mode = if metadata.is_dir() {
+            S_IFDIR | 0o755
+        } else if metadata.permissions().readonly() {
+            S_IFREG | 0o444
+        } else {
+            S_IFREG | 0o644
+        };
+        let modified = metadata.modified().ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as u32).unwrap_or(0);
+
+        mem.write_bytes(address, &[0; 60]);
+        mem.write_u32(address + 4, mode);
+        mem.write_u16(address + 8, 1);
+        mem.write_u32(address + 16, metadata.len() as u32);
+        for &offset in [20, 28, 36].iter() {
+            mem.write_u32(address + offset, modified);
+        }
+        mem.write_u32(address + 44, 4096);
+        mem.write_u32(address + 48, metadata.len().div_ceil(512) as u32);
+    }
+}
+
+impl Trap for Dcload {
+    fn handles(&self, address: usize) -> bool {
+        address == DCLOAD_ENTRY & 0x1FFFFFFF
+    }
+
+    fn call(&mut self, cpu: &mut Cpu, mem: &mut Memory, _address: usize) {
+        let (r5, r6, r7) = (register(cpu, 5), register(cpu, 6), register(cpu, 7));
+
+        let result = match register(cpu, 4) {
+            DC_READ  => self.read(mem, r5, r6 as usize, r7 as usize),
+            DC_WRITE => self.write(mem, r5, r6 as usize, r7 as usize),
+            DC_OPEN  => {
+                let path = Dcload::read_string(mem, r5 as usize);
+                self.open(&path, r6)
+            },
+            DC_CLOSE => self.files.remove(&r5).map(|_| 0).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "bad file descriptor")),
+            DC_LSEEK => self.lseek(r5, r6 as i32, r7),
+            DC_FSTAT => self.file(r5).and_then(|file| file.metadata()).map(|metadata| {
+                Dcload::write_stat(mem, r6 as usize, &metadata);
+                0
+            }),
+            DC_TIME  => Ok(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)),
+            DC_STAT  => {
+                let path = Dcload::read_string(mem, r5 as usize);
+                match self.resolve(&path) {
+                    Some(path) => fs::metadata(path).map(|metadata| {
+                        Dcload::write_stat(mem, r6 as usize, &metadata);
+                        0
+                    }),
+                    None => Err(io::Error::new(io::ErrorKind::PermissionDenied, "outside of the directory"))
+                }
+            },
+            DC_EXIT  => {
+                *self.exit_code.lock().unwrap() = Some(r5 as i32);
+                Ok(0)
+            },
+            // No debugger listens on the other end, so no reply comes
+            DC_GDBPACKET => Ok(0),
+            _ => Ok(FAILED)
+        };
+        set_result(cpu, result.unwrap_or(FAILED));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::image::test_dir;
+    use memory::TestMemory;
+
+    const PATH : usize = 0x8C00E000;
+
+    fn call(dcload: &mut Dcload, cpu: &mut Cpu, mem: &mut Memory, registers: [u32; 4]) -> u32 {
+        for (i, &value) in registers.iter().enumerate() {
+            cpu[Operand::RegisterOperand(4 + i as u8)].value = value;
+        }
+        dcload.call(cpu, mem, DCLOAD_ENTRY & 0x1FFFFFFF);
+        register(cpu, 0)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_paths_inside_the_directory() {
+        use std::os::unix::fs::symlink;
+
+        let dir = test_dir("dcload-paths");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("secret"), b"s").unwrap();
+        let _ = symlink(dir.join("secret"), root.join("out"));
+        let _ = symlink(root.join("a.txt"), root.join("inside"));
+        let _ = symlink(dir.join("missing"), root.join("dangling"));
+
+        let dcload = Dcload::new(&root, Arc::new(Mutex::new(None))).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        assert_eq!(dcload.resolve("/a.txt"), Some(root.join("a.txt")));
+        assert_eq!(dcload.resolve("sub/./new.txt"), Some(root.join("sub/new.txt")));
+        assert_eq!(dcload.resolve("inside"), Some(root.join("a.txt")));
+        assert_eq!(dcload.resolve("../secret"), None);
+        assert_eq!(dcload.resolve("sub/../a.txt"), None);
+        assert_eq!(dcload.resolve("out"), None);
+        assert_eq!(dcload.resolve("dangling"), None);
+        assert_eq!(dcload.resolve("nowhere/new.txt"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn clamps_reads_and_writes_at_the_end_of_ram() {
+        let dir = test_dir("dcload-files");
+        let exit_code = Arc::new(Mutex::new(None));
+        let mut dcload = Dcload::new(&dir, exit_code.clone()).unwrap();
+        let mut mem = TestMemory::new();
+        let mut cpu = Cpu::new();
+
+        mem.load(PATH, b"../escape.txt\0");
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_OPEN, PATH as u32, O_CREAT | O_WRONLY, 0o644]), FAILED);
+
+        mem.load(PATH, b"out.bin\0");
+        let fd = call(&mut dcload, &mut cpu, &mut mem, [DC_OPEN, PATH as u32, O_CREAT | O_RDWR, 0o644]);
+        assert_eq!(fd, FIRST_FD);
+
+        // The last eight bytes of RAM
+        mem.load(0x8CFFFFF8, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_WRITE, fd, 0x8CFFFFF8, 100]), 8);
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_WRITE, fd, 0x00000000, 100]), 0);
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_LSEEK, fd, 2, 0]), 2);
+        mem.load(0x8C00F000, &[0; 8]);
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_READ, fd, 0x8C00F000, 4]), 4);
+        assert_eq!(mem.read_bytes(0x8C00F000, 6), vec![3, 4, 5, 6, 0, 0]);
+        call(&mut dcload, &mut cpu, &mut mem, [DC_LSEEK, fd, 0, 0]);
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_READ, fd, 0x8CFFFFFC, 100]), 4);
+
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_FSTAT, fd, 0x8C00F100, 0]), 0);
+        assert_eq!(mem.read_u32(0x8C00F104), S_IFREG | 0o644);
+        assert_eq!(mem.read_u32(0x8C00F110), 8);
+
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_CLOSE, fd, 0, 0]), 0);
+        assert_eq!(call(&mut dcload, &mut cpu, &mut mem, [DC_CLOSE, fd, 0, 0]), FAILED);
+        call(&mut dcload, &mut cpu, &mut mem, [DC_EXIT, 3, 0, 0]);
+        assert_eq!(*exit_code.lock().unwrap(), Some(3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
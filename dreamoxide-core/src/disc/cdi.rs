@@ -0,0 +1,267 @@
+use disc::{Track, TrackMode, DiscFormat, RAW_SECTOR_SIZE, DATA_SECTOR_SIZE};
+use disc::image::{DiscImage, TrackSource, MODE2_SECTOR_SIZE, RAW_SUBCODE_SECTOR_SIZE, invalid_data};
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Versions of the DiscJuggler descriptor
+const CDI_V2  : u32 = 0x80000004;
+const CDI_V3  : u32 = 0x80000005;
+const CDI_V35 : u32 = 0x80000006;
+
+/// Precedes every track descriptor, twice
+const TRACK_START_MARK : [u8; 10] = [0, 0, 1, 0, 0, 0, 255, 255, 255, 255];
+
+/// The fields of a track descriptor that matter
+struct CdiTrack {
+    pregap: u32,
+    length: u32,
+    mode: u32,
+    start: u32,
+    total_length: u32,
+    sector_size: usize,
+}
+
+fn read_u8(file: &mut File) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16(file: &mut File) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    file.read_exact(&mut bytes)?;
+    Ok((bytes[0] as u16) | ((bytes[1] as u16) << 8))
+}
+
+fn read_u32(file: &mut File) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    file.read_exact(&mut bytes)?;
+    Ok((bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24))
+}
+
+fn skip(file: &mut File, bytes: i64) -> io::Result<()> {
+    file.seek(SeekFrom::Current(bytes)).map(|_| ())
+}
+
+fn read_track(file: &mut File, version: u32) -> io::Result<CdiTrack> {
+    // Newer versions insert extra fields in a few places
+    if read_u32(file)? != 0 {
+        skip(file, 8)?;
+    }
+
+    for _ in 0..2 {
+        let mut mark = [0u8; 10];
+        file.read_exact(&mut mark)?;
+        if mark != TRACK_START_MARK {
+            return Err(invalid_data("CDI track descriptor not found"));
+        }
+    }
+
+    skip(file, 4)?;
+    let name_length = read_u8(file)?;
+    skip(file, name_length as i64 + 19)?;
+    if read_u32(file)? == 0x80000000 {
+        skip(file, 8)?;
+    }
+    skip(file, 2)?;
+    let pregap = read_u32(file)?;
+    let length = read_u32(file)?;
+    skip(file, 6)?;
+    let mode = read_u32(file)?;
+    skip(file, 12)?;
+    let start = read_u32(file)?;
+    let total_length = read_u32(file)?;
+    skip(file, 16)?;
+    let sector_size = match read_u32(file)? {
+        0 => DATA_SECTOR_SIZE,
+        1 => MODE2_SECTOR_SIZE,
+        2 => RAW_SECTOR_SIZE,
+        4 => RAW_SUBCODE_SECTOR_SIZE,
+        _ => return Err(invalid_data("unsupported CDI sector size"))
+    };
+    skip(file, 29)?;
+    if version != CDI_V2 {
+        skip(file, 5)?;
+        if read_u32(file)? == 0xFFFFFFFF {
+            skip(file, 78)?;
+        }
+    }
+
+    Ok(CdiTrack {
+        pregap,
+        length,
+        mode,
+        start,
+        total_length,
+        sector_size,
+    })
+}
+
+/// Loads a DiscJuggler image. The track data comes first, each track
+/// with its pregap, and a descriptor of sessions and tracks is found
+/// through the last 8 bytes of the file. Self-booting discs use the
+/// MIL-CD layout of an audio session followed by a mode 2 data session.
+pub fn open(path: &Path) -> io::Result<DiscImage> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < 8 {
+        return Err(invalid_data("CDI image too short"));
+    }
+
+    file.seek(SeekFrom::End(-8))?;
+    let version = read_u32(&mut file)?;
+    let header_offset = read_u32(&mut file)? as u64;
+    let header = match version {
+        CDI_V2 | CDI_V3 => header_offset,
+        CDI_V35 => size.saturating_sub(header_offset),
+        _ => return Err(invalid_data("unknown CDI version"))
+    };
+    file.seek(SeekFrom::Start(header))?;
+
+    let sessions = read_u16(&mut file)?;
+    let mut tracks = Vec::new();
+    let mut sources = Vec::new();
+    let mut position = 0u64;
+    let mut number = 1;
+
+    for session in 1..sessions + 1 {
+        let count = read_u16(&mut file)?;
+        // The last session of a disc left open has no tracks
+        if count == 0 {
+            break;
+        }
+
+        for _ in 0..count {
+            let cdi = read_track(&mut file, version)?;
+            let mode = match cdi.mode {
+                0 => TrackMode::Audio,
+                1 => TrackMode::Mode1,
+                2 => TrackMode::Mode2,
+                _ => return Err(invalid_data("unsupported CDI track mode"))
+            };
+
+            if cdi.length > 0 {
+                tracks.push(Track {
+                    number,
+                    session: session as u8,
+                    // The start is that of the pregap, counted from the
+                    // beginning of the disc
+                    start: cdi.start + cdi.pregap,
+                    length: cdi.length,
+                    mode,
+                });
+                sources.push(TrackSource {
+                    file: 0,
+                    offset: position + cdi.pregap as u64 * cdi.sector_size as u64,
+                    sector_size: cdi.sector_size,
+                });
+                number += 1;
+            }
+            position += cdi.total_length as u64 * cdi.sector_size as u64;
+        }
+
+        skip(&mut file, if version == CDI_V2 { 12 } else { 13 })?;
+    }
+
+    if tracks.is_empty() {
+        return Err(invalid_data("CDI image without tracks"));
+    }
+
+    let format = if tracks.iter().any(|t| t.mode == TrackMode::Mode2) {
+        DiscFormat::CdRomXa
+    } else if tracks.iter().any(|t| t.mode == TrackMode::Mode1) {
+        DiscFormat::CdRom
+    } else {
+        DiscFormat::CdDa
+    };
+    Ok(DiscImage::new(format, tracks, sources, vec![file]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::Disc;
+    use disc::image::{test_dir, test_sectors};
+    use std::fs;
+
+    fn le32(value: u32) -> Vec<u8> {
+        vec![value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+    }
+
+    /// A track descriptor with raw sectors
+    fn descriptor(version: u32, pregap: u32, length: u32, mode: u32, start: u32) -> Vec<u8> {
+        let mut d = le32(0);
+        d.extend_from_slice(&TRACK_START_MARK);
+        d.extend_from_slice(&TRACK_START_MARK);
+        d.extend_from_slice(&[0; 4]);
+        // No file name
+        d.push(0);
+        d.extend_from_slice(&[0; 19]);
+        d.extend(le32(0));
+        d.extend_from_slice(&[0; 2]);
+        d.extend(le32(pregap));
+        d.extend(le32(length));
+        d.extend_from_slice(&[0; 6]);
+        d.extend(le32(mode));
+        d.extend_from_slice(&[0; 12]);
+        d.extend(le32(start));
+        d.extend(le32(pregap + length));
+        d.extend_from_slice(&[0; 16]);
+        d.extend(le32(2));
+        d.extend_from_slice(&[0; 29]);
+        if version != CDI_V2 {
+            d.extend_from_slice(&[0; 5]);
+            d.extend(le32(0));
+        }
+        d
+    }
+
+    /// A MIL-CD: an audio session and a mode 2 data session, each
+    /// track with a pregap of 150 sectors
+    fn mil_cd(version: u32) -> Vec<u8> {
+        let mut image = test_sectors(304, RAW_SECTOR_SIZE);
+        let header = image.len() as u32;
+
+        image.extend_from_slice(&[2, 0]);
+        for &(mode, start) in &[(0, 0), (2, 11552)] {
+            image.extend_from_slice(&[1, 0]);
+            image.extend(descriptor(version, 150, 2, mode, start));
+            image.extend(vec![0; if version == CDI_V2 { 12 } else { 13 }]);
+        }
+
+        let offset = if version == CDI_V35 { image.len() as u32 + 8 - header } else { header };
+        image.extend(le32(version));
+        image.extend(le32(offset));
+        image
+    }
+
+    #[test]
+    fn reads_the_sessions_of_each_version() {
+        let dir = test_dir("cdi");
+        for &version in &[CDI_V2, CDI_V3, CDI_V35] {
+            let path = dir.join(format!("{:x}.cdi", version));
+            fs::write(&path, mil_cd(version)).unwrap();
+
+            let mut disc = open(&path).unwrap();
+            assert_eq!(disc.format(), DiscFormat::CdRomXa);
+            assert_eq!(disc.tracks(), &[
+                Track { number: 1, session: 1, start: 150, length: 2, mode: TrackMode::Audio },
+                Track { number: 2, session: 2, start: 11702, length: 2, mode: TrackMode::Mode2 },
+            ]);
+
+            // Pregaps are stored but skipped
+            let mut sector = [0u8; RAW_SECTOR_SIZE];
+            disc.read_sector(151, &mut sector).unwrap();
+            assert_eq!(sector[0], 151);
+            disc.read_sector(11702, &mut sector).unwrap();
+            assert_eq!(sector[0], (152 + 150) as u8);
+        }
+
+        fs::write(dir.join("bad.cdi"), &[0u8; 16][..]).unwrap();
+        assert_eq!(open(&dir.join("bad.cdi")).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+/// Reads bits most significant first. Reading past the end yields zero
+/// bits and marks the stream as overflowed.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    position: usize,
+    overflow: bool,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0, overflow: false }
+    }
+
+    pub fn read(&mut self, bits: u32) -> u32 {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = self.position / 8;
+            let bit = match self.data.get(byte) {
+                Some(b) => (b >> (7 - self.position % 8)) & 1,
+                None => {
+                    self.overflow = true;
+                    0
+                }
+            };
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        value
+    }
+
+    /// Reads a two's complement value of the given width
+    pub fn read_signed(&mut self, bits: u32) -> i32 {
+        if bits == 0 {
+            return 0;
+        }
+        let value = self.read(bits);
+        ((value << (32 - bits)) as i32) >> (32 - bits)
+    }
+
+    /// Counts zero bits up to the next one bit, which is consumed
+    pub fn read_unary(&mut self) -> u32 {
+        let mut count = 0;
+        while self.read(1) == 0 {
+            if self.overflow {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Skips to the next byte boundary
+    pub fn align(&mut self) {
+        self.position = (self.position + 7) & !7;
+    }
+
+    /// Bytes consumed, counting a partial byte as whole
+    pub fn byte_position(&self) -> usize {
+        self.position.div_ceil(8)
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+}
+
+/// A canonical Huffman decoder as used for the CHD hunk map. Codes are
+/// assigned from the longest length down, and decoded through a table
+/// indexed by the next `max_bits` bits.
+pub struct Huffman {
+    max_bits: u32,
+    /// Value and length of the code starting with each bit pattern
+    lookup: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    /// Reads the code lengths of `codes` values in their run length
+    /// encoded form and builds the decoder
+    pub fn import_tree_rle(reader: &mut BitReader, codes: usize, max_bits: u32) -> Option<Huffman> {
+        let width = if max_bits >= 16 { 5 } else if max_bits >= 8 { 4 } else { 3 };
+        let mut lengths = Vec::with_capacity(codes);
+
+        while lengths.len() < codes {
+            let length = reader.read(width);
+            if length != 1 {
+                lengths.push(length as u8);
+                continue;
+            }
+
+            // A 1 escapes a repeat, and a double 1 is a single 1
+            let length = reader.read(width);
+            if length == 1 {
+                lengths.push(1);
+            } else {
+                let repeat = reader.read(width) + 3;
+                for _ in 0..repeat {
+                    lengths.push(length as u8);
+                }
+            }
+        }
+
+        if lengths.len() != codes || reader.overflow() {
+            return None;
+        }
+        Huffman::from_lengths(&lengths, max_bits)
+    }
+
+    pub fn from_lengths(lengths: &[u8], max_bits: u32) -> Option<Huffman> {
+        let mut histogram = [0u32; 33];
+        for &length in lengths {
+            if length as u32 > max_bits {
+                return None;
+            }
+            histogram[length as usize] += 1;
+        }
+
+        // The first code of each length, longest lengths first
+        let mut start = 0u32;
+        for length in (1..33).rev() {
+            let next = (start + histogram[length]) >> 1;
+            if length != 1 && next * 2 != start + histogram[length] {
+                return None;
+            }
+            histogram[length] = start;
+            start = next;
+        }
+
+        let mut lookup = vec![(0u16, 0u8); 1 << max_bits];
+        for (value, &length) in lengths.iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+            let code = histogram[length as usize];
+            histogram[length as usize] += 1;
+
+            let shift = max_bits - length as u32;
+            for entry in lookup[(code << shift) as usize..((code + 1) << shift) as usize].iter_mut() {
+                *entry = (value as u16, length);
+            }
+        }
+
+        Some(Huffman { max_bits, lookup })
+    }
+
+    pub fn decode(&self, reader: &mut BitReader) -> u32 {
+        // Peek the longest code, then give back the bits not used
+        let position = reader.position;
+        let bits = reader.read(self.max_bits);
+        let (value, length) = self.lookup[bits as usize];
+        reader.position = position + length as usize;
+        value as u32
+    }
+}
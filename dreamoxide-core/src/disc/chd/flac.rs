@@ -0,0 +1,260 @@
+use disc::chd::bitstream::BitReader;
+use disc::image::invalid_data;
+
+use std::io;
+
+/// Decodes a headerless run of FLAC frames holding stereo 16 bit
+/// samples, as CHD stores them. Values the frames leave to the stream
+/// header are taken from `block_size` and 16 bits per sample.
+///
+/// The samples are written interleaved to `output`, big endian if
+/// `big_endian` is set. Returns the number of input bytes consumed.
+pub fn decode(input: &[u8], output: &mut [u8], block_size: usize, big_endian: bool) -> io::Result<usize> {
+    let mut reader = BitReader::new(input);
+    let total = output.len() / 4;
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    let mut written = 0;
+
+    while written < total {
+        let samples = decode_frame(&mut reader, block_size, &mut left, &mut right)?;
+
+        for i in 0..samples.min(total - written) {
+            let offset = (written + i) * 4;
+            for (channel, sample) in [left[i], right[i]].iter().enumerate() {
+                let sample = *sample as i16 as u16;
+                let bytes = if big_endian { [(sample >> 8) as u8, sample as u8] } else { [sample as u8, (sample >> 8) as u8] };
+                output[offset + channel * 2..offset + channel * 2 + 2].copy_from_slice(&bytes);
+            }
+        }
+        written += samples;
+    }
+
+    Ok(reader.byte_position())
+}
+
+/// Decodes one frame into the two channel buffers, returning its number
+/// of samples
+fn decode_frame(reader: &mut BitReader, default_block_size: usize, left: &mut Vec<i32>, right: &mut Vec<i32>) -> io::Result<usize> {
+    if reader.read(15) != 0x7FFC {
+        return Err(invalid_data("FLAC frame sync not found"));
+    }
+    reader.read(1);
+
+    let block_size_code = reader.read(4);
+    let sample_rate_code = reader.read(4);
+    let channels = reader.read(4);
+    let bits = match reader.read(3) {
+        0 | 4 => 16,
+        1 => 8,
+        2 => 12,
+        5 => 20,
+        6 => 24,
+        _ => return Err(invalid_data("unsupported FLAC sample size"))
+    };
+    reader.read(1);
+
+    // The frame number, coded like UTF-8
+    let first = reader.read(8);
+    let extra = (!(first << 24)).leading_zeros().saturating_sub(1);
+    for _ in 0..extra {
+        reader.read(8);
+    }
+
+    let block_size = match block_size_code {
+        0 => default_block_size,
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.read(8) as usize + 1,
+        7 => reader.read(16) as usize + 1,
+        _ => 256 << (block_size_code - 8)
+    };
+    match sample_rate_code {
+        12 => { reader.read(8); },
+        13 | 14 => { reader.read(16); },
+        _ => ()
+    }
+    // Header CRC
+    reader.read(8);
+
+    // The side channel of the decorrelated modes has an extra bit
+    let (left_bits, right_bits) = match channels {
+        1      => (bits, bits),
+        8 | 10 => (bits, bits + 1),
+        9      => (bits + 1, bits),
+        _      => return Err(invalid_data("FLAC frame is not stereo"))
+    };
+    decode_subframe(reader, block_size, left_bits, left)?;
+    decode_subframe(reader, block_size, right_bits, right)?;
+
+    for i in 0..block_size {
+        let (a, b) = (left[i], right[i]);
+        match channels {
+            8 => right[i] = a - b,
+            9 => left[i] = a + b,
+            10 => {
+                let mid = (a << 1) | (b & 1);
+                left[i] = (mid + b) >> 1;
+                right[i] = (mid - b) >> 1;
+            },
+            _ => ()
+        }
+    }
+
+    // Padding and footer CRC
+    reader.align();
+    reader.read(16);
+
+    if reader.overflow() {
+        return Err(invalid_data("FLAC frame truncated"));
+    }
+    Ok(block_size)
+}
+
+fn decode_subframe(reader: &mut BitReader, block_size: usize, bits: u32, samples: &mut Vec<i32>) -> io::Result<()> {
+    samples.clear();
+    reader.read(1);
+    let kind = reader.read(6);
+    let wasted = if reader.read(1) == 1 { reader.read_unary() + 1 } else { 0 };
+    if wasted >= bits {
+        return Err(invalid_data("bad FLAC wasted bits"));
+    }
+    let bits = bits - wasted;
+
+    match kind {
+        0 => {
+            let value = reader.read_signed(bits);
+            samples.resize(block_size, value);
+        },
+        1 => {
+            for _ in 0..block_size {
+                samples.push(reader.read_signed(bits));
+            }
+        },
+        8..=12 => {
+            let order = (kind - 8) as usize;
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits));
+            }
+            decode_residual(reader, block_size, order, samples)?;
+
+            for i in order..block_size {
+                let s = &samples[i - order..i];
+                let prediction = match order {
+                    0 => 0,
+                    1 => s[0],
+                    2 => 2 * s[1] - s[0],
+                    3 => 3 * s[2] - 3 * s[1] + s[0],
+                    _ => 4 * s[3] - 6 * s[2] + 4 * s[1] - s[0]
+                };
+                samples[i] += prediction;
+            }
+        },
+        32..=63 => {
+            let order = (kind - 31) as usize;
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits));
+            }
+            let precision = reader.read(4) + 1;
+            let shift = reader.read_signed(5);
+            if precision == 16 || shift < 0 {
+                return Err(invalid_data("bad FLAC LPC subframe"));
+            }
+            let coefficients: Vec<i64> = (0..order).map(|_| reader.read_signed(precision) as i64).collect();
+            decode_residual(reader, block_size, order, samples)?;
+
+            for i in order..block_size {
+                let prediction = coefficients.iter().enumerate()
+                    .fold(0i64, |sum, (j, &c)| sum + c * samples[i - 1 - j] as i64);
+                samples[i] += (prediction >> shift) as i32;
+            }
+        },
+        _ => return Err(invalid_data("reserved FLAC subframe type"))
+    }
+
+    if wasted > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted;
+        }
+    }
+    Ok(())
+}
+
+/// Appends the Rice coded residual of a subframe after its warm-up samples
+fn decode_residual(reader: &mut BitReader, block_size: usize, order: usize, samples: &mut Vec<i32>) -> io::Result<()> {
+    let (parameter_bits, escape) = match reader.read(2) {
+        0 => (4, 15),
+        1 => (5, 31),
+        _ => return Err(invalid_data("reserved FLAC residual coding"))
+    };
+    let partition_order = reader.read(4);
+    let partitions = 1usize << partition_order;
+    if !block_size.is_multiple_of(partitions) || (block_size >> partition_order) < order {
+        return Err(invalid_data("bad FLAC residual partitions"));
+    }
+
+    for partition in 0..partitions {
+        let count = (block_size >> partition_order) - if partition == 0 { order } else { 0 };
+        let parameter = reader.read(parameter_bits);
+
+        if parameter == escape {
+            let bits = reader.read(5);
+            for _ in 0..count {
+                samples.push(reader.read_signed(bits));
+            }
+        } else {
+            for _ in 0..count {
+                let value = (reader.read_unary() << parameter) | reader.read(parameter);
+                samples.push((value >> 1) as i32 ^ -((value & 1) as i32));
+            }
+        }
+
+        if reader.overflow() {
+            return Err(invalid_data("FLAC residual truncated"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of four samples with a constant and a verbatim channel,
+    /// then one of eight samples coded as left and side, the left one
+    /// through a first order predictor
+    const FRAMES : [u8; 56] = [
+        0xFF, 0xF8, 0x60, 0x18, 0x00, 0x03, 0x10, 0x00, 0x03, 0xE8, 0x02, 0xFF, 0xFF, 0x00, 0x02, 0xFF,
+        0xFD, 0x00, 0x04, 0x98, 0x49, 0xFF, 0xF8, 0x60, 0x88, 0x01, 0x07, 0xB0, 0x12, 0x00, 0x0A, 0x00,
+        0x91, 0x9C, 0x64, 0x44, 0x02, 0x00, 0x05, 0x00, 0x04, 0x40, 0x01, 0x1F, 0xFA, 0x70, 0x03, 0xB0,
+        0x00, 0x44, 0x00, 0x26, 0x00, 0x15, 0x85, 0xB1];
+
+    fn samples() -> Vec<(i16, i16)> {
+        let left = [1000, 1000, 1000, 1000, 10, 12, 15, 11, 18, 20, 22, 24];
+        let right = [-1, 2, -3, 4, 0, -5, 7, 100, -100, 3, 3, 3];
+        left.iter().cloned().zip(right.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn decodes_stereo_frames() {
+        let mut output = [0; 48];
+        assert_eq!(decode(&FRAMES, &mut output, 4096, false).unwrap(), FRAMES.len());
+        for (i, &(left, right)) in samples().iter().enumerate() {
+            let sample = |o: usize| (output[i * 4 + o] as u16 | (output[i * 4 + o + 1] as u16) << 8) as i16;
+            assert_eq!((sample(0), sample(2)), (left, right));
+        }
+
+        let mut swapped = [0; 48];
+        decode(&FRAMES, &mut swapped, 4096, true).unwrap();
+        for i in 0..24 {
+            assert_eq!((swapped[i * 2], swapped[i * 2 + 1]), (output[i * 2 + 1], output[i * 2]));
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_frames() {
+        let mut output = [0; 48];
+        assert!(decode(&FRAMES[..40], &mut output, 4096, false).is_err());
+        assert!(decode(&FRAMES[1..], &mut output, 4096, false).is_err());
+    }
+}
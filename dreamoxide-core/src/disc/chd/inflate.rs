@@ -0,0 +1,262 @@
+use disc::image::invalid_data;
+
+use std::io;
+
+/// Base lengths and extra bits of length codes 257 to 285
+const LENGTH_BASE  : [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA : [u8; 29]  = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Base distances and extra bits of distance codes 0 to 29
+const DISTANCE_BASE  : [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA : [u8; 30]  = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order in which the code length code lengths are stored
+const CODE_LENGTH_ORDER : [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Reads bits least significant first, as deflate packs them, at most
+/// 16 at a time
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn read(&mut self, bits: u32) -> io::Result<u32> {
+        while self.count < bits {
+            let byte = match self.data.get(self.position) {
+                Some(&b) => b,
+                None => return Err(invalid_data("deflate stream truncated"))
+            };
+            self.buffer |= (byte as u32) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << bits) - 1);
+        self.buffer >>= bits;
+        self.count -= bits;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+}
+
+/// A canonical Huffman code, decoded a bit at a time by counting the
+/// codes of each length
+struct Code {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Code {
+    fn new(lengths: &[u8]) -> Code {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+
+        Code { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> io::Result<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for length in 1..16 {
+            code |= bits.read(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("bad deflate code"))
+    }
+}
+
+fn fixed_codes() -> (Code, Code) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143   => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _         => 8
+        };
+    }
+    (Code::new(&lengths), Code::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> io::Result<(Code, Code)> {
+    let literals = bits.read(5)? as usize + 257;
+    let distances = bits.read(5)? as usize + 1;
+    let code_lengths = bits.read(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for &index in CODE_LENGTH_ORDER[..code_lengths].iter() {
+        lengths[index] = bits.read(3)? as u8;
+    }
+    let code_length_code = Code::new(&lengths);
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_code.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if index == 0 {
+                    return Err(invalid_data("deflate length repeat without a length"));
+                }
+                (lengths[index - 1], 3 + bits.read(2)? as usize)
+            },
+            17 => (0, 3 + bits.read(3)? as usize),
+            _  => (0, 11 + bits.read(7)? as usize)
+        };
+        if index + repeat > lengths.len() {
+            return Err(invalid_data("deflate code lengths overrun"));
+        }
+        for length in lengths[index..index + repeat].iter_mut() {
+            *length = value;
+        }
+        index += repeat;
+    }
+
+    Ok((Code::new(&lengths[..literals]), Code::new(&lengths[literals..])))
+}
+
+/// Decompresses a raw deflate stream, without zlib header, into
+/// `output`. Returns the number of bytes produced.
+pub fn inflate(input: &[u8], output: &mut [u8]) -> io::Result<usize> {
+    let mut bits = Bits { data: input, position: 0, buffer: 0, count: 0 };
+    let mut written = 0;
+
+    loop {
+        let last = bits.read(1)? == 1;
+
+        match bits.read(2)? {
+            0 => {
+                bits.align();
+                let length = bits.read(16)? as usize;
+                if bits.read(16)? as usize != !length & 0xFFFF {
+                    return Err(invalid_data("bad deflate stored block length"));
+                }
+                if written + length > output.len() {
+                    return Err(invalid_data("deflate output overrun"));
+                }
+                for byte in output[written..written + length].iter_mut() {
+                    *byte = bits.read(8)? as u8;
+                }
+                written += length;
+            },
+            kind @ 1..=2 => {
+                let (literal_code, distance_code) = if kind == 1 { fixed_codes() } else { dynamic_codes(&mut bits)? };
+
+                loop {
+                    let symbol = literal_code.decode(&mut bits)? as usize;
+                    if symbol < 256 {
+                        if written >= output.len() {
+                            return Err(invalid_data("deflate output overrun"));
+                        }
+                        output[written] = symbol as u8;
+                        written += 1;
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+
+                    let symbol = symbol - 257;
+                    if symbol >= LENGTH_BASE.len() {
+                        return Err(invalid_data("bad deflate length code"));
+                    }
+                    let length = LENGTH_BASE[symbol] as usize + bits.read(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+                    let symbol = distance_code.decode(&mut bits)? as usize;
+                    if symbol >= DISTANCE_BASE.len() {
+                        return Err(invalid_data("bad deflate distance code"));
+                    }
+                    let distance = DISTANCE_BASE[symbol] as usize + bits.read(DISTANCE_EXTRA[symbol] as u32)? as usize;
+
+                    if distance > written || written + length > output.len() {
+                        return Err(invalid_data("bad deflate match"));
+                    }
+                    // Copied a byte at a time, as a match may overlap itself
+                    for _ in 0..length {
+                        output[written] = output[written - distance];
+                        written += 1;
+                    }
+                }
+            },
+            _ => return Err(invalid_data("bad deflate block type"))
+        }
+
+        if last {
+            return Ok(written);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT : &[u8] = b"dreamoxide, dreamoxide, dreamoxide! dreamoxide, dreamoxide, dreamoxide! dreamoxide, dreamoxide, dreamoxide! ";
+
+    #[test]
+    fn inflates_fixed_codes() {
+        let input = [0x4B, 0x29, 0x4A, 0x4D, 0xCC, 0xCD, 0xAF, 0xC8, 0x4C, 0x49, 0xD5, 0x51,
+                     0x48, 0xC1, 0xCA, 0x56, 0xC4, 0x21, 0x4E, 0xBA, 0x1A, 0x00];
+        let mut output = [0; 108];
+        assert_eq!(inflate(&input, &mut output).unwrap(), TEXT.len());
+        assert_eq!(&output[..], TEXT);
+    }
+
+    #[test]
+    fn inflates_dynamic_codes() {
+        let input = [0x05, 0xC1, 0xB1, 0x0D, 0x00, 0x40, 0x08, 0x02, 0xC0, 0x55, 0xFC, 0xDE, 0xA5, 0x4C,
+                     0xA0, 0xB0, 0x20, 0x24, 0x56, 0x8C, 0xFF, 0x77, 0x38, 0x8E, 0x9C, 0x05, 0xBB, 0x70,
+                     0x1C, 0x39, 0x0B, 0x76, 0xE1, 0x38, 0x72, 0x16, 0x7C, 0x85, 0xE3, 0xC8, 0x59, 0xB0,
+                     0x0B, 0xC7, 0x91, 0xB3, 0x60, 0x17, 0x8E, 0x23, 0x67, 0xC1, 0x57, 0x38, 0x8E, 0x9C,
+                     0x05, 0xBB, 0x70, 0x1C, 0x39, 0x0B, 0x76, 0xE1, 0x38, 0x72, 0x16, 0x7C, 0xF5, 0x01];
+        let mut output = [0; 108];
+        assert_eq!(inflate(&input, &mut output).unwrap(), TEXT.len());
+        assert_eq!(&output[..], TEXT);
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        let mut output = [0; 4];
+        assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'], &mut output).unwrap(), 3);
+        assert_eq!(&output[..3], b"abc");
+        assert!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFE, b'a', b'b', b'c'], &mut output).is_err());
+    }
+
+    #[test]
+    fn refuses_to_overrun_the_output() {
+        let mut output = [0; 2];
+        assert!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'], &mut output).is_err());
+    }
+}
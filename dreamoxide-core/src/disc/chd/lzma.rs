@@ -0,0 +1,279 @@
+use disc::image::invalid_data;
+
+use std::io;
+
+/// Literal context, literal position and position bits CHD streams are
+/// compressed with
+const LC : u32 = 3;
+const LP : u32 = 0;
+const PB : u32 = 2;
+
+const STATES : usize = 12;
+const POS_STATES_MAX : usize = 1 << 4;
+const LEN_TO_POS_STATES : usize = 4;
+const END_POS_MODEL_INDEX : u32 = 14;
+const FULL_DISTANCES : usize = 1 << (END_POS_MODEL_INDEX >> 1);
+const ALIGN_BITS : u32 = 4;
+
+const PROBABILITY_BITS : u32 = 11;
+const PROBABILITY_INIT : u16 = 1 << (PROBABILITY_BITS - 1);
+const MOVE_BITS : u32 = 5;
+const TOP : u32 = 1 << 24;
+
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> io::Result<RangeDecoder<'a>> {
+        if data.len() < 5 || data[0] != 0 {
+            return Err(invalid_data("bad LZMA stream"));
+        }
+        let code = data[1..5].iter().fold(0u32, |code, &b| (code << 8) | b as u32);
+        Ok(RangeDecoder { data, position: 5, range: 0xFFFFFFFF, code })
+    }
+
+    fn normalize(&mut self) {
+        if self.range < TOP {
+            // Past the end of the input the stream is padded with zeroes
+            let byte = self.data.get(self.position).cloned().unwrap_or(0);
+            self.position += 1;
+            self.range <<= 8;
+            self.code = (self.code << 8) | byte as u32;
+        }
+    }
+
+    fn bit(&mut self, probability: &mut u16) -> u32 {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = if self.code < bound {
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> MOVE_BITS;
+            self.range = bound;
+            0
+        } else {
+            *probability -= *probability >> MOVE_BITS;
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        self.normalize();
+        bit
+    }
+
+    fn direct_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0u32;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = if self.code >= self.range { self.code -= self.range; 1 } else { 0 };
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        value
+    }
+
+    /// Decodes `count` bits through a binary tree of probabilities, most
+    /// significant first
+    fn tree(&mut self, probabilities: &mut [u16], count: u32) -> u32 {
+        let mut m = 1usize;
+        for _ in 0..count {
+            m = (m << 1) | self.bit(&mut probabilities[m]) as usize;
+        }
+        m as u32 - (1 << count)
+    }
+
+    /// Decodes `count` bits through a binary tree, least significant first
+    fn reverse_tree(&mut self, probabilities: &mut [u16], count: u32) -> u32 {
+        let mut m = 1usize;
+        let mut value = 0;
+        for i in 0..count {
+            let bit = self.bit(&mut probabilities[m]);
+            m = (m << 1) | bit as usize;
+            value |= bit << i;
+        }
+        value
+    }
+}
+
+struct LengthDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 1 << 3]; POS_STATES_MAX],
+    mid: [[u16; 1 << 3]; POS_STATES_MAX],
+    high: [u16; 1 << 8],
+}
+
+impl LengthDecoder {
+    fn new() -> LengthDecoder {
+        LengthDecoder {
+            choice: PROBABILITY_INIT,
+            choice2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; 1 << 3]; POS_STATES_MAX],
+            mid: [[PROBABILITY_INIT; 1 << 3]; POS_STATES_MAX],
+            high: [PROBABILITY_INIT; 1 << 8],
+        }
+    }
+
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> u32 {
+        if rc.bit(&mut self.choice) == 0 {
+            return rc.tree(&mut self.low[pos_state], 3);
+        }
+        if rc.bit(&mut self.choice2) == 0 {
+            return 8 + rc.tree(&mut self.mid[pos_state], 3);
+        }
+        16 + rc.tree(&mut self.high, 8)
+    }
+}
+
+/// Decompresses a raw LZMA stream, without header, until `output` is
+/// full. CHD compresses each hunk on its own, so the output is the
+/// whole dictionary.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> io::Result<()> {
+    let mut rc = RangeDecoder::new(input)?;
+
+    let mut literals = vec![PROBABILITY_INIT; 0x300 << (LC + LP)];
+    let mut is_match = [PROBABILITY_INIT; STATES << 4];
+    let mut is_rep = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g0 = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g1 = [PROBABILITY_INIT; STATES];
+    let mut is_rep_g2 = [PROBABILITY_INIT; STATES];
+    let mut is_rep0_long = [PROBABILITY_INIT; STATES << 4];
+    let mut pos_slots = [[PROBABILITY_INIT; 1 << 6]; LEN_TO_POS_STATES];
+    let mut pos_decoders = [PROBABILITY_INIT; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize];
+    let mut align = [PROBABILITY_INIT; 1 << ALIGN_BITS];
+    let mut lengths = LengthDecoder::new();
+    let mut rep_lengths = LengthDecoder::new();
+
+    let mut state = 0usize;
+    let mut reps = [0u32; 4];
+    let mut position = 0usize;
+
+    while position < output.len() {
+        let pos_state = position & ((1 << PB) - 1);
+
+        if rc.bit(&mut is_match[(state << 4) + pos_state]) == 0 {
+            let previous = if position > 0 { output[position - 1] } else { 0 };
+            let lit_state = ((position & ((1 << LP) - 1)) << LC) + (previous >> (8 - LC)) as usize;
+            let probabilities = &mut literals[0x300 * lit_state..0x300 * (lit_state + 1)];
+
+            let mut symbol = 1usize;
+            if state >= 7 {
+                // After a match the literal is coded against the byte
+                // that would have continued it
+                let mut match_byte = output[position - reps[0] as usize - 1] as usize;
+                while symbol < 0x100 {
+                    let match_bit = (match_byte >> 7) & 1;
+                    match_byte <<= 1;
+                    let bit = rc.bit(&mut probabilities[((1 + match_bit) << 8) + symbol]) as usize;
+                    symbol = (symbol << 1) | bit;
+                    if match_bit != bit {
+                        break;
+                    }
+                }
+            }
+            while symbol < 0x100 {
+                symbol = (symbol << 1) | rc.bit(&mut probabilities[symbol]) as usize;
+            }
+
+            output[position] = symbol as u8;
+            position += 1;
+            state = if state < 4 { 0 } else if state < 10 { state - 3 } else { state - 6 };
+            continue;
+        }
+
+        let length;
+        if rc.bit(&mut is_rep[state]) != 0 {
+            if position == 0 {
+                return Err(invalid_data("LZMA repeat at start of stream"));
+            }
+            if rc.bit(&mut is_rep_g0[state]) == 0 {
+                if rc.bit(&mut is_rep0_long[(state << 4) + pos_state]) == 0 {
+                    // A single byte from the last distance
+                    state = if state < 7 { 9 } else { 11 };
+                    output[position] = output[position - reps[0] as usize - 1];
+                    position += 1;
+                    continue;
+                }
+            } else {
+                let distance;
+                if rc.bit(&mut is_rep_g1[state]) == 0 {
+                    distance = reps[1];
+                } else {
+                    if rc.bit(&mut is_rep_g2[state]) == 0 {
+                        distance = reps[2];
+                    } else {
+                        distance = reps[3];
+                        reps[3] = reps[2];
+                    }
+                    reps[2] = reps[1];
+                }
+                reps[1] = reps[0];
+                reps[0] = distance;
+            }
+            length = rep_lengths.decode(&mut rc, pos_state);
+            state = if state < 7 { 8 } else { 11 };
+        } else {
+            reps[3] = reps[2];
+            reps[2] = reps[1];
+            reps[1] = reps[0];
+            length = lengths.decode(&mut rc, pos_state);
+            state = if state < 7 { 7 } else { 10 };
+
+            let slot = rc.tree(&mut pos_slots[(length as usize).min(LEN_TO_POS_STATES - 1)], 6);
+            reps[0] = if slot < 4 {
+                slot
+            } else {
+                let direct = (slot >> 1) - 1;
+                let base = (2 | (slot & 1)) << direct;
+                if slot < END_POS_MODEL_INDEX {
+                    let offset = (base - slot) as usize;
+                    base + rc.reverse_tree(&mut pos_decoders[offset..], direct)
+                } else {
+                    base + (rc.direct_bits(direct - ALIGN_BITS) << ALIGN_BITS) + rc.reverse_tree(&mut align, ALIGN_BITS)
+                }
+            };
+
+            if reps[0] == 0xFFFFFFFF {
+                // End marker
+                break;
+            }
+        }
+
+        let length = length as usize + 2;
+        let distance = reps[0] as usize + 1;
+        if distance > position {
+            return Err(invalid_data("LZMA match before start of stream"));
+        }
+        for _ in 0..length.min(output.len() - position) {
+            output[position] = output[position - distance];
+            position += 1;
+        }
+    }
+
+    if position < output.len() {
+        return Err(invalid_data("LZMA stream ended early"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_a_stream() {
+        let input = [0x00, 0x32, 0x1C, 0x88, 0xCD, 0x87, 0x0B, 0xDC, 0x75, 0x79, 0x76, 0x4C, 0xEB, 0x33, 0xAA,
+                     0x4F, 0x11, 0x14, 0xF4, 0xC3, 0x19, 0x4A, 0xD1, 0xD7, 0xFF, 0xFF, 0x32, 0xE0, 0x00, 0x00];
+        let mut output = [0; 108];
+        decompress(&input, &mut output).unwrap();
+        assert_eq!(&output[..], &b"dreamoxide, dreamoxide, dreamoxide! dreamoxide, dreamoxide, dreamoxide! dreamoxide, dreamoxide, dreamoxide! "[..]);
+    }
+
+    #[test]
+    fn refuses_a_bad_stream() {
+        let mut output = [0; 16];
+        assert!(decompress(&[0x01, 0, 0, 0, 0], &mut output).is_err());
+        assert!(decompress(&[0x00, 0, 0], &mut output).is_err());
+    }
+}
@@ -0,0 +1,616 @@
+use disc::{Disc, DiscFormat, Track, TrackMode, FAD_OFFSET, RAW_SECTOR_SIZE, DATA_SECTOR_SIZE, HIGH_DENSITY_START};
+use disc::image::{MODE2_SECTOR_SIZE, RAW_SUBCODE_SECTOR_SIZE, invalid_data, write_header};
+use self::bitstream::{BitReader, Huffman};
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+pub mod bitstream;
+pub mod inflate;
+pub mod lzma;
+pub mod flac;
+
+const CHD_MAGIC : &[u8; 8] = b"MComprHD";
+const HEADER_V5_SIZE : usize = 124;
+
+/// Codecs, by their four character tags
+const CODEC_NONE : u32 = 0;
+const CODEC_ZLIB : u32 = 0x7A6C6962;
+const CODEC_LZMA : u32 = 0x6C7A6D61;
+const CODEC_FLAC : u32 = 0x666C6163;
+const CODEC_CDZL : u32 = 0x63647A6C;
+const CODEC_CDLZ : u32 = 0x63646C7A;
+const CODEC_CDFL : u32 = 0x6364666C;
+
+/// Metadata tags describing the tracks of CD and GD-ROM images
+const CDROM_TRACK_TAG  : u32 = 0x43485452;
+const CDROM_TRACK2_TAG : u32 = 0x43485432;
+const GDROM_TRACK_TAG  : u32 = 0x43484744;
+
+/// How a hunk is stored, as given by the map. Types below 4 name one
+/// of the four codecs of the image.
+const HUNK_NONE   : u8 = 4;
+const HUNK_SELF   : u8 = 5;
+const HUNK_PARENT : u8 = 6;
+/// Pseudo-types of the compressed map, resolved while reading it
+const HUNK_RLE_SMALL   : u8 = 7;
+const HUNK_RLE_LARGE   : u8 = 8;
+const HUNK_SELF_0      : u8 = 9;
+const HUNK_SELF_1      : u8 = 10;
+const HUNK_PARENT_SELF : u8 = 11;
+const HUNK_PARENT_0    : u8 = 12;
+const HUNK_PARENT_1    : u8 = 13;
+
+/// Bytes of subcode stored after each sector
+const SUBCODE_SIZE : usize = 96;
+/// Tracks are padded to a multiple of this many frames in the image
+const TRACK_PADDING : u32 = 4;
+/// Hunks kept decompressed
+const CACHE_HUNKS : usize = 64;
+
+const SYNC : [u8; 12] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+#[derive(Copy, Clone, Debug)]
+struct HunkEntry {
+    kind: u8,
+    length: u32,
+    /// Byte offset in the file, or the hunk referred to
+    offset: u64,
+}
+
+/// Where the sectors of a track are stored
+#[derive(Copy, Clone, Debug)]
+struct ChdTrack {
+    /// Frame of the image holding the first sector
+    frame: u32,
+    /// Bytes of sector data at the start of each frame
+    sector_size: usize,
+}
+
+/// Decompressed hunks, the most recently used last
+struct HunkCache {
+    hunks: Vec<(u32, Vec<u8>)>,
+}
+
+impl HunkCache {
+    fn get(&mut self, hunk: u32) -> Option<&[u8]> {
+        let index = self.hunks.iter().position(|&(h, _)| h == hunk)?;
+        let entry = self.hunks.remove(index);
+        self.hunks.push(entry);
+        self.hunks.last().map(|(_, data)| &data[..])
+    }
+
+    fn insert(&mut self, hunk: u32, data: Vec<u8>) {
+        if self.hunks.len() >= CACHE_HUNKS {
+            self.hunks.remove(0);
+        }
+        self.hunks.push((hunk, data));
+    }
+}
+
+/// A disc stored in a MAME compressed hunks of data image, version 5.
+/// The image is split into hunks of whole frames, each compressed on
+/// its own, and a map tells where each is and how it was compressed.
+pub struct ChdImage {
+    pub format: DiscFormat,
+    pub tracks: Vec<Track>,
+    sources: Vec<ChdTrack>,
+    file: File,
+    hunk_bytes: u32,
+    codecs: [u32; 4],
+    map: Vec<HunkEntry>,
+    cache: HunkCache,
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+fn big_endian(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &b| (value << 8) | b as u64)
+}
+
+impl ChdImage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ChdImage> {
+        let mut file = File::open(path)?;
+
+        let mut header = [0u8; HEADER_V5_SIZE];
+        read_at(&mut file, 0, &mut header)?;
+        if &header[0..8] != CHD_MAGIC {
+            return Err(invalid_data("not a CHD image"));
+        }
+        if big_endian(&header[12..16]) != 5 {
+            return Err(invalid_data("only version 5 CHD images are supported"));
+        }
+        if header[104..124].iter().any(|&b| b != 0) {
+            return Err(invalid_data("CHD images with a parent are not supported"));
+        }
+
+        let mut codecs = [0u32; 4];
+        for (i, codec) in codecs.iter_mut().enumerate() {
+            *codec = big_endian(&header[16 + i * 4..20 + i * 4]) as u32;
+        }
+        let logical_bytes = big_endian(&header[32..40]);
+        let map_offset = big_endian(&header[40..48]);
+        let meta_offset = big_endian(&header[48..56]);
+        let hunk_bytes = big_endian(&header[56..60]) as u32;
+        let unit_bytes = big_endian(&header[60..64]) as u32;
+
+        if hunk_bytes == 0 || unit_bytes as usize != RAW_SUBCODE_SECTOR_SIZE || !hunk_bytes.is_multiple_of(unit_bytes) {
+            return Err(invalid_data("CHD image does not hold a disc"));
+        }
+        let hunks = logical_bytes.div_ceil(hunk_bytes as u64) as u32;
+
+        let map = if codecs[0] == CODEC_NONE {
+            read_raw_map(&mut file, map_offset, hunks, hunk_bytes)?
+        } else {
+            read_compressed_map(&mut file, map_offset, hunks, hunk_bytes, unit_bytes)?
+        };
+        let (format, tracks, sources) = read_tracks(&mut file, meta_offset)?;
+
+        Ok(ChdImage {
+            format,
+            tracks,
+            sources,
+            file,
+            hunk_bytes,
+            codecs,
+            map,
+            cache: HunkCache { hunks: Vec::with_capacity(CACHE_HUNKS) },
+        })
+    }
+
+    fn read_hunk(&mut self, hunk: u32) -> io::Result<Vec<u8>> {
+        let entry = match self.map.get(hunk as usize) {
+            Some(&entry) => entry,
+            None => return Err(invalid_data("CHD hunk out of range"))
+        };
+        let mut data = vec![0; self.hunk_bytes as usize];
+
+        match entry.kind {
+            0..=3 => {
+                let mut compressed = vec![0; entry.length as usize];
+                read_at(&mut self.file, entry.offset, &mut compressed)?;
+                decompress(self.codecs[entry.kind as usize], &compressed, &mut data)?;
+            },
+            // An uncompressed image leaves hunks never written at offset 0
+            HUNK_NONE if entry.offset != 0 => read_at(&mut self.file, entry.offset, &mut data)?,
+            HUNK_NONE => (),
+            // A copy always refers to an earlier hunk
+            HUNK_SELF if entry.offset < hunk as u64 => return self.read_hunk(entry.offset as u32),
+            _ => return Err(invalid_data("bad CHD hunk reference"))
+        }
+
+        Ok(data)
+    }
+}
+
+impl Disc for ChdImage {
+    fn format(&self) -> DiscFormat {
+        self.format
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+        let index = match self.tracks.iter().position(|t| t.contains(fad)) {
+            Some(index) => index,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "sector outside of all tracks"))
+        };
+        let mode = self.tracks[index].mode;
+        let source = self.sources[index];
+
+        let position = (source.frame + fad - self.tracks[index].start) as u64 * RAW_SUBCODE_SECTOR_SIZE as u64;
+        let hunk = (position / self.hunk_bytes as u64) as u32;
+        let offset = (position % self.hunk_bytes as u64) as usize;
+
+        if self.cache.get(hunk).is_none() {
+            let data = self.read_hunk(hunk)?;
+            self.cache.insert(hunk, data);
+        }
+        let data = match self.cache.get(hunk) {
+            Some(data) => &data[offset..offset + source.sector_size],
+            None => unreachable!()
+        };
+
+        match (mode, source.sector_size) {
+            (TrackMode::Audio, _) => {
+                // Audio is stored big endian
+                for (sample, bytes) in buffer[..RAW_SECTOR_SIZE].chunks_mut(2).zip(data.chunks(2)) {
+                    sample[0] = bytes[1];
+                    sample[1] = bytes[0];
+                }
+            },
+            (_, RAW_SECTOR_SIZE) => buffer[..RAW_SECTOR_SIZE].copy_from_slice(data),
+            (TrackMode::Mode1, _) => {
+                write_header(fad, 1, buffer);
+                buffer[16..16 + DATA_SECTOR_SIZE].copy_from_slice(data);
+                for b in buffer[16 + DATA_SECTOR_SIZE..RAW_SECTOR_SIZE].iter_mut() {
+                    *b = 0;
+                }
+            },
+            (_, MODE2_SECTOR_SIZE) => {
+                write_header(fad, 2, buffer);
+                buffer[16..RAW_SECTOR_SIZE].copy_from_slice(data);
+            },
+            (_, size) => {
+                // Form 1 or form 2 user data, after an empty subheader
+                write_header(fad, 2, buffer);
+                for b in buffer[16..RAW_SECTOR_SIZE].iter_mut() {
+                    *b = 0;
+                }
+                buffer[24..24 + size].copy_from_slice(data);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The map of an uncompressed image: the position of each hunk, in hunks
+fn read_raw_map(file: &mut File, offset: u64, hunks: u32, hunk_bytes: u32) -> io::Result<Vec<HunkEntry>> {
+    let mut raw = vec![0u8; hunks as usize * 4];
+    read_at(file, offset, &mut raw)?;
+
+    Ok(raw.chunks(4).map(|entry| HunkEntry {
+        kind: HUNK_NONE,
+        length: hunk_bytes,
+        offset: big_endian(entry) * hunk_bytes as u64,
+    }).collect())
+}
+
+/// The map of a compressed image. The hunk types come first, Huffman
+/// and run length coded, then the lengths and references of each hunk,
+/// with offsets following from the lengths of the hunks before.
+fn read_compressed_map(file: &mut File, offset: u64, hunks: u32, hunk_bytes: u32, unit_bytes: u32) -> io::Result<Vec<HunkEntry>> {
+    let mut header = [0u8; 16];
+    read_at(file, offset, &mut header)?;
+    let length = big_endian(&header[0..4]) as usize;
+    let first_offset = big_endian(&header[4..10]);
+    let length_bits = header[12] as u32;
+    let self_bits = header[13] as u32;
+    let parent_bits = header[14] as u32;
+
+    let mut compressed = vec![0u8; length];
+    read_at(file, offset + 16, &mut compressed)?;
+    let mut reader = BitReader::new(&compressed);
+
+    let huffman = match Huffman::import_tree_rle(&mut reader, 16, 8) {
+        Some(huffman) => huffman,
+        None => return Err(invalid_data("bad CHD map"))
+    };
+
+    let mut kinds = Vec::with_capacity(hunks as usize);
+    let mut last = 0;
+    let mut repeat = 0;
+    while kinds.len() < hunks as usize {
+        if repeat > 0 {
+            kinds.push(last);
+            repeat -= 1;
+            continue;
+        }
+        // A repeat counts the hunk it starts at
+        let kind = match huffman.decode(&mut reader) as u8 {
+            HUNK_RLE_SMALL => {
+                repeat = 2 + huffman.decode(&mut reader);
+                last
+            },
+            HUNK_RLE_LARGE => {
+                repeat = 2 + 16 + (huffman.decode(&mut reader) << 4) + huffman.decode(&mut reader);
+                last
+            },
+            kind => {
+                last = kind;
+                kind
+            }
+        };
+        kinds.push(kind);
+    }
+
+    let mut map = Vec::with_capacity(hunks as usize);
+    let mut position = first_offset;
+    let mut last_self = 0u64;
+    let mut last_parent = 0u64;
+
+    for (hunk, &kind) in kinds.iter().enumerate() {
+        let entry = match kind {
+            0..=3 => {
+                let length = reader.read(length_bits);
+                // The CRC of the hunk is not checked
+                reader.read(16);
+                position += length as u64;
+                HunkEntry { kind, length, offset: position - length as u64 }
+            },
+            HUNK_NONE => {
+                reader.read(16);
+                position += hunk_bytes as u64;
+                HunkEntry { kind: HUNK_NONE, length: hunk_bytes, offset: position - hunk_bytes as u64 }
+            },
+            HUNK_SELF => {
+                last_self = reader.read(self_bits) as u64;
+                HunkEntry { kind: HUNK_SELF, length: 0, offset: last_self }
+            },
+            HUNK_PARENT => {
+                last_parent = reader.read(parent_bits) as u64;
+                HunkEntry { kind: HUNK_PARENT, length: 0, offset: last_parent }
+            },
+            HUNK_SELF_0 | HUNK_SELF_1 => {
+                if kind == HUNK_SELF_1 {
+                    last_self += 1;
+                }
+                HunkEntry { kind: HUNK_SELF, length: 0, offset: last_self }
+            },
+            HUNK_PARENT_SELF => {
+                last_parent = hunk as u64 * hunk_bytes as u64 / unit_bytes as u64;
+                HunkEntry { kind: HUNK_PARENT, length: 0, offset: last_parent }
+            },
+            HUNK_PARENT_0 | HUNK_PARENT_1 => {
+                if kind == HUNK_PARENT_1 {
+                    last_parent += (hunk_bytes / unit_bytes) as u64;
+                }
+                HunkEntry { kind: HUNK_PARENT, length: 0, offset: last_parent }
+            },
+            _ => return Err(invalid_data("bad CHD map"))
+        };
+        map.push(entry);
+    }
+
+    if reader.overflow() {
+        return Err(invalid_data("CHD map truncated"));
+    }
+    Ok(map)
+}
+
+/// A track as described by the metadata
+struct TrackMetadata {
+    number: u8,
+    mode: TrackMode,
+    sector_size: usize,
+    frames: u32,
+    pregap: u32,
+    /// Whether the pregap's sectors are stored before the track's
+    pregap_stored: bool,
+    /// Sectors on the disc between this track and the next
+    padding: u32,
+}
+
+fn parse_track(text: &str) -> io::Result<TrackMetadata> {
+    let mut track = TrackMetadata {
+        number: 0,
+        mode: TrackMode::Audio,
+        sector_size: RAW_SECTOR_SIZE,
+        frames: 0,
+        pregap: 0,
+        pregap_stored: false,
+        padding: 0,
+    };
+
+    for field in text.split_whitespace() {
+        let mut parts = field.splitn(2, ':');
+        let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let number = || value.parse::<u32>().map_err(|_| invalid_data("bad CHD track metadata"));
+
+        match key {
+            "TRACK" => track.number = number()? as u8,
+            "FRAMES" => track.frames = number()?,
+            "PREGAP" => track.pregap = number()?,
+            "PAD" => track.padding = number()?,
+            "PGTYPE" => track.pregap_stored = value.starts_with('V'),
+            "TYPE" => {
+                let (mode, sector_size) = match value {
+                    "AUDIO"          => (TrackMode::Audio, RAW_SECTOR_SIZE),
+                    "MODE1"          => (TrackMode::Mode1, DATA_SECTOR_SIZE),
+                    "MODE1_RAW"      => (TrackMode::Mode1, RAW_SECTOR_SIZE),
+                    "MODE2"          => (TrackMode::Mode2, MODE2_SECTOR_SIZE),
+                    "MODE2_FORM1"    => (TrackMode::Mode2, DATA_SECTOR_SIZE),
+                    "MODE2_FORM2"    => (TrackMode::Mode2, 2324),
+                    "MODE2_FORM_MIX" => (TrackMode::Mode2, MODE2_SECTOR_SIZE),
+                    "MODE2_RAW"      => (TrackMode::Mode2, RAW_SECTOR_SIZE),
+                    _ => return Err(invalid_data("unsupported CHD track type"))
+                };
+                track.mode = mode;
+                track.sector_size = sector_size;
+            },
+            _ => ()
+        }
+    }
+
+    Ok(track)
+}
+
+/// Reads the track metadata and lays the tracks out on the disc. On a
+/// GD-ROM the third track starts the high density area, and the
+/// padding given for each track separates it from the next.
+fn read_tracks(file: &mut File, mut offset: u64) -> io::Result<(DiscFormat, Vec<Track>, Vec<ChdTrack>)> {
+    let mut metadata = Vec::new();
+    let mut gdrom = false;
+
+    while offset != 0 {
+        let mut header = [0u8; 16];
+        read_at(file, offset, &mut header)?;
+        let tag = big_endian(&header[0..4]) as u32;
+        let length = big_endian(&header[5..8]) as usize;
+
+        if tag == CDROM_TRACK_TAG || tag == CDROM_TRACK2_TAG || tag == GDROM_TRACK_TAG {
+            let mut text = vec![0u8; length];
+            read_at(file, offset + 16, &mut text)?;
+            let text = String::from_utf8_lossy(&text);
+            metadata.push(parse_track(text.trim_end_matches('\0'))?);
+            gdrom |= tag == GDROM_TRACK_TAG;
+        }
+        offset = big_endian(&header[8..16]);
+    }
+
+    if metadata.is_empty() {
+        return Err(invalid_data("CHD image without tracks"));
+    }
+    metadata.sort_by_key(|t| t.number);
+
+    let mut tracks = Vec::new();
+    let mut sources = Vec::new();
+    let mut fad = FAD_OFFSET;
+    let mut frame = 0u32;
+
+    for (i, meta) in metadata.iter().enumerate() {
+        if gdrom && meta.number == 3 {
+            fad = HIGH_DENSITY_START;
+        } else if i > 0 {
+            fad += meta.pregap;
+        }
+        let stored = if meta.pregap_stored { meta.pregap.min(meta.frames) } else { 0 };
+
+        let track = Track {
+            number: meta.number,
+            session: if gdrom && fad >= HIGH_DENSITY_START { 2 } else { 1 },
+            start: fad,
+            length: meta.frames - stored,
+            mode: meta.mode,
+        };
+        sources.push(ChdTrack { frame: frame + stored, sector_size: meta.sector_size });
+
+        fad = track.end() + meta.padding;
+        frame += meta.frames.div_ceil(TRACK_PADDING) * TRACK_PADDING;
+        tracks.push(track);
+    }
+
+    let format = if gdrom {
+        DiscFormat::GdRom
+    } else if tracks.iter().any(|t| t.mode == TrackMode::Mode2) {
+        DiscFormat::CdRomXa
+    } else if tracks.iter().any(|t| t.mode == TrackMode::Mode1) {
+        DiscFormat::CdRom
+    } else {
+        DiscFormat::CdDa
+    };
+    Ok((format, tracks, sources))
+}
+
+/// FLAC block size the generic codec compresses with
+fn flac_block_size(bytes: usize) -> usize {
+    let mut size = bytes / 4;
+    while size > 2048 {
+        size /= 2;
+    }
+    size
+}
+
+fn decompress(codec: u32, input: &[u8], output: &mut [u8]) -> io::Result<()> {
+    match codec {
+        CODEC_ZLIB => inflate::inflate(input, output).map(|_| ()),
+        CODEC_LZMA => lzma::decompress(input, output),
+        CODEC_FLAC => {
+            // The first byte tells the byte order of the samples
+            let big = match input.first() {
+                Some(&b'B') => true,
+                Some(&b'L') => false,
+                _ => return Err(invalid_data("bad CHD FLAC hunk"))
+            };
+            let size = flac_block_size(output.len());
+            flac::decode(&input[1..], output, size, big).map(|_| ())
+        },
+        CODEC_CDZL | CODEC_CDLZ | CODEC_CDFL => decompress_cd(codec, input, output),
+        _ => Err(invalid_data("unsupported CHD codec"))
+    }
+}
+
+/// Decompresses a hunk of CD frames. The sector data and the subcode of
+/// all frames are compressed separately, the subcode always with
+/// deflate. Data sectors whose sync pattern and error correction could
+/// be regenerated have them removed, flagged by a bit per frame.
+fn decompress_cd(codec: u32, input: &[u8], output: &mut [u8]) -> io::Result<()> {
+    let frames = output.len() / RAW_SUBCODE_SECTOR_SIZE;
+    let data_bytes = frames * RAW_SECTOR_SIZE;
+    let mut buffer = vec![0u8; frames * RAW_SUBCODE_SECTOR_SIZE];
+    let ecc_bytes;
+
+    if codec == CODEC_CDFL {
+        // Audio only, stored big endian
+        let mut size = data_bytes / 4;
+        while size > RAW_SECTOR_SIZE {
+            size /= 2;
+        }
+        let consumed = flac::decode(input, &mut buffer[..data_bytes], size, true)?;
+        inflate::inflate(&input[consumed.min(input.len())..], &mut buffer[data_bytes..])?;
+        ecc_bytes = 0;
+    } else {
+        ecc_bytes = frames.div_ceil(8);
+        let length_bytes = if output.len() < 65536 { 2 } else { 3 };
+        let header = ecc_bytes + length_bytes;
+        if input.len() < header {
+            return Err(invalid_data("bad CHD CD hunk"));
+        }
+        let base_length = big_endian(&input[ecc_bytes..header]) as usize;
+        if header + base_length > input.len() {
+            return Err(invalid_data("bad CHD CD hunk"));
+        }
+
+        let base = &input[header..header + base_length];
+        if codec == CODEC_CDZL {
+            inflate::inflate(base, &mut buffer[..data_bytes])?;
+        } else {
+            lzma::decompress(base, &mut buffer[..data_bytes])?;
+        }
+        inflate::inflate(&input[header + base_length..], &mut buffer[data_bytes..])?;
+    }
+
+    for frame in 0..frames {
+        let sector = &mut output[frame * RAW_SUBCODE_SECTOR_SIZE..(frame + 1) * RAW_SUBCODE_SECTOR_SIZE];
+        sector[..RAW_SECTOR_SIZE].copy_from_slice(&buffer[frame * RAW_SECTOR_SIZE..(frame + 1) * RAW_SECTOR_SIZE]);
+        sector[RAW_SECTOR_SIZE..].copy_from_slice(&buffer[data_bytes + frame * SUBCODE_SIZE..data_bytes + (frame + 1) * SUBCODE_SIZE]);
+
+        if ecc_bytes > 0 && input[frame / 8] & (1 << (frame % 8)) != 0 {
+            sector[..12].copy_from_slice(&SYNC);
+            ecc_generate(sector);
+        }
+    }
+    Ok(())
+}
+
+/// Regenerates the P and Q parity of a data sector. The header of a
+/// mode 2 sector is counted as zero.
+fn ecc_generate(sector: &mut [u8]) {
+    let mut forward = [0u8; 256];
+    let mut backward = [0u8; 256];
+    for i in 0..256 {
+        let f = ((i << 1) ^ if i & 0x80 != 0 { 0x11D } else { 0 }) as u8;
+        forward[i] = f;
+        backward[i ^ f as usize] = i as u8;
+    }
+
+    let mut header = [0u8; 4];
+    header.copy_from_slice(&sector[12..16]);
+    if header[3] == 2 {
+        for b in sector[12..16].iter_mut() {
+            *b = 0;
+        }
+    }
+
+    // P covers 43 columns of 24 words, Q 26 diagonals of 43 words,
+    // taking in the P parity
+    for &(majors, minors, major_step, minor_step, parity) in [(86, 24, 2, 86, 0x81C), (52, 43, 86, 88, 0x8C8)].iter() {
+        let size = majors * minors;
+        for major in 0..majors {
+            let mut index = (major >> 1) * major_step + (major & 1);
+            let (mut a, mut b) = (0u8, 0u8);
+            for _ in 0..minors {
+                let value = sector[12 + index];
+                index += minor_step;
+                if index >= size {
+                    index -= size;
+                }
+                a = forward[(a ^ value) as usize];
+                b ^= value;
+            }
+            a = backward[(forward[a as usize] ^ b) as usize];
+            sector[parity + major] = a;
+            sector[parity + major + majors] = a ^ b;
+        }
+    }
+
+    sector[12..16].copy_from_slice(&header);
+}
@@ -0,0 +1,209 @@
+use disc::{Track, TrackMode, DiscFormat, FAD_OFFSET, RAW_SECTOR_SIZE, DATA_SECTOR_SIZE, HIGH_DENSITY_START, msf_to_fad};
+use disc::image::{DiscImage, TrackSource, MODE2_SECTOR_SIZE, invalid_data, split_fields};
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Sectors between the end of one session and the start of the next:
+/// the lead-out, the next lead-in and the pregap of its first track
+const SESSION_GAP : u32 = 11400;
+
+/// A track as written in the sheet, before it is placed on the disc
+struct CueTrack {
+    number: u8,
+    file: usize,
+    mode: TrackMode,
+    sector_size: usize,
+    session: u8,
+    pregap: u32,
+    index0: Option<u32>,
+    index1: u32,
+    high_density: bool,
+}
+
+fn parse_msf(text: &str) -> io::Result<u32> {
+    let parts: Vec<u8> = text.split(':').filter_map(|p| p.parse().ok()).collect();
+    if parts.len() != 3 {
+        return Err(invalid_data("bad CUE time"));
+    }
+    Ok(msf_to_fad(parts[0], parts[1], parts[2]))
+}
+
+/// Loads a CUE sheet and the BIN files it names. Sessions given with
+/// REM SESSION are separated by the usual gap, and the tracks following
+/// REM HIGH-DENSITY AREA are placed in the high density area.
+pub fn open(path: &Path) -> io::Result<DiscImage> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+
+    let mut files = Vec::new();
+    let mut sheet: Vec<CueTrack> = Vec::new();
+    let mut session = 1;
+    let mut high_density = false;
+
+    for line in text.lines() {
+        let fields = split_fields(line);
+        if fields.is_empty() {
+            continue;
+        }
+
+        match &fields[0].to_uppercase()[..] {
+            "FILE" if fields.len() >= 2 => files.push(File::open(directory.join(&fields[1]))?),
+            "REM" if fields.len() >= 3 && fields[1].eq_ignore_ascii_case("SESSION") => {
+                session = fields[2].parse().map_err(|_| invalid_data("bad CUE session"))?;
+            },
+            "REM" if fields.len() >= 2 && fields[1].eq_ignore_ascii_case("HIGH-DENSITY") => {
+                high_density = true;
+                session = 2;
+            },
+            "TRACK" if fields.len() >= 3 => {
+                if files.is_empty() {
+                    return Err(invalid_data("CUE track before any file"));
+                }
+                let (mode, sector_size) = match &fields[2].to_uppercase()[..] {
+                    "AUDIO"      => (TrackMode::Audio, RAW_SECTOR_SIZE),
+                    "MODE1/2048" => (TrackMode::Mode1, DATA_SECTOR_SIZE),
+                    "MODE1/2352" => (TrackMode::Mode1, RAW_SECTOR_SIZE),
+                    "MODE2/2336" => (TrackMode::Mode2, MODE2_SECTOR_SIZE),
+                    "MODE2/2352" => (TrackMode::Mode2, RAW_SECTOR_SIZE),
+                    _ => return Err(invalid_data("unsupported CUE track mode"))
+                };
+                sheet.push(CueTrack {
+                    number: fields[1].parse().map_err(|_| invalid_data("bad CUE track number"))?,
+                    file: files.len() - 1,
+                    mode,
+                    sector_size,
+                    session,
+                    pregap: 0,
+                    index0: None,
+                    index1: 0,
+                    high_density,
+                });
+            },
+            "PREGAP" if fields.len() >= 2 => {
+                if let Some(track) = sheet.last_mut() {
+                    track.pregap = parse_msf(&fields[1])?;
+                }
+            },
+            "INDEX" if fields.len() >= 3 => {
+                let position = parse_msf(&fields[2])?;
+                if let Some(track) = sheet.last_mut() {
+                    match &fields[1][..] {
+                        "00" | "0" => track.index0 = Some(position),
+                        "01" | "1" => track.index1 = position,
+                        _ => ()
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    if sheet.is_empty() {
+        return Err(invalid_data("CUE sheet without tracks"));
+    }
+
+    let mut tracks = Vec::new();
+    let mut sources = Vec::new();
+    // Disc address of the first sector of the current file, and the
+    // sectors that are on the disc but not in any file
+    let mut file_start = 0u32;
+    let mut gaps = 0u32;
+
+    for i in 0..sheet.len() {
+        let track = &sheet[i];
+        let file_sectors = (files[track.file].metadata()?.len() / track.sector_size as u64) as u32;
+
+        if i > 0 {
+            let previous = &sheet[i - 1];
+            if previous.file != track.file {
+                let previous_sectors = (files[previous.file].metadata()?.len() / previous.sector_size as u64) as u32;
+                file_start += previous_sectors;
+            }
+            if previous.session != track.session {
+                gaps += SESSION_GAP;
+            }
+            if track.high_density && !previous.high_density {
+                file_start = HIGH_DENSITY_START - FAD_OFFSET - track.index1;
+                gaps = 0;
+            }
+        }
+        gaps += track.pregap;
+
+        // A track ends where the next one in the same file starts
+        let end = match sheet.get(i + 1) {
+            Some(next) if next.file == track.file => next.index0.unwrap_or(next.index1),
+            _ => file_sectors
+        };
+
+        tracks.push(Track {
+            number: track.number,
+            session: track.session,
+            start: file_start + gaps + track.index1 + FAD_OFFSET,
+            length: end.saturating_sub(track.index1),
+            mode: track.mode,
+        });
+        sources.push(TrackSource {
+            file: track.file,
+            offset: track.index1 as u64 * track.sector_size as u64,
+            sector_size: track.sector_size,
+        });
+    }
+
+    let format = if sheet.iter().any(|t| t.high_density) {
+        DiscFormat::GdRom
+    } else if tracks.iter().any(|t| t.mode == TrackMode::Mode2) {
+        DiscFormat::CdRomXa
+    } else if tracks.iter().any(|t| t.mode == TrackMode::Mode1) {
+        DiscFormat::CdRom
+    } else {
+        DiscFormat::CdDa
+    };
+    Ok(DiscImage::new(format, tracks, sources, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::Disc;
+    use disc::image::{test_dir, test_sectors};
+    use std::fs;
+
+    #[test]
+    fn splits_a_file_at_its_indices() {
+        let dir = test_dir("cue-single");
+        fs::write(dir.join("disc.bin"), test_sectors(10, RAW_SECTOR_SIZE)).unwrap();
+        fs::write(dir.join("disc.cue"), "FILE \"disc.bin\" BINARY\n  TRACK 01 MODE1/2352\n    INDEX 01 00:00:00\n  \
+            TRACK 02 AUDIO\n    INDEX 00 00:00:04\n    INDEX 01 00:00:06\n").unwrap();
+
+        let mut disc = open(&dir.join("disc.cue")).unwrap();
+        assert_eq!(disc.format(), DiscFormat::CdRom);
+        assert_eq!(disc.tracks(), &[
+            Track { number: 1, session: 1, start: 150, length: 4, mode: TrackMode::Mode1 },
+            Track { number: 2, session: 1, start: 156, length: 4, mode: TrackMode::Audio },
+        ]);
+
+        let mut sector = [0u8; RAW_SECTOR_SIZE];
+        disc.read_sector(156, &mut sector).unwrap();
+        assert_eq!(sector[0], 6);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn separates_sessions() {
+        let dir = test_dir("cue-sessions");
+        fs::write(dir.join("a.bin"), test_sectors(4, RAW_SECTOR_SIZE)).unwrap();
+        fs::write(dir.join("b.bin"), test_sectors(2, RAW_SECTOR_SIZE)).unwrap();
+        fs::write(dir.join("disc.cue"), "REM SESSION 01\nFILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+            REM SESSION 02\nFILE b.bin BINARY\nTRACK 02 MODE2/2352\nINDEX 01 00:00:00\n").unwrap();
+
+        let disc = open(&dir.join("disc.cue")).unwrap();
+        assert_eq!(disc.format(), DiscFormat::CdRomXa);
+        assert_eq!(disc.tracks()[1], Track { number: 2, session: 2, start: 4 + SESSION_GAP + FAD_OFFSET, length: 2, mode: TrackMode::Mode2 });
+        assert_eq!(disc.sessions(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
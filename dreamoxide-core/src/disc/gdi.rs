@@ -0,0 +1,98 @@
+use disc::{Track, TrackMode, DiscFormat, FAD_OFFSET, HIGH_DENSITY_START};
+use disc::image::{DiscImage, TrackSource, invalid_data, detect_mode, split_fields};
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Loads a GDI image: a track count followed by one line per track with
+/// its number, start LBA, control bits, sector size, file and offset.
+/// Track files are looked up next to the GDI file.
+pub fn open(path: &Path) -> io::Result<DiscImage> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let count: usize = match lines.next().and_then(|l| l.trim().parse().ok()) {
+        Some(count) => count,
+        None => return Err(invalid_data("GDI track count missing"))
+    };
+
+    let mut tracks = Vec::new();
+    let mut sources = Vec::new();
+    let mut files = Vec::new();
+
+    for line in lines.take(count) {
+        let fields = split_fields(line);
+        if fields.len() < 5 {
+            return Err(invalid_data("GDI track line too short"));
+        }
+
+        let number: u8 = fields[0].parse().map_err(|_| invalid_data("bad GDI track number"))?;
+        let lba: u32 = fields[1].parse().map_err(|_| invalid_data("bad GDI track start"))?;
+        let control: u8 = fields[2].parse().map_err(|_| invalid_data("bad GDI track type"))?;
+        let sector_size: usize = fields[3].parse().map_err(|_| invalid_data("bad GDI sector size"))?;
+        let offset: u64 = fields.get(5).and_then(|f| f.parse().ok()).unwrap_or(0);
+
+        let mut file = File::open(directory.join(&fields[4]))?;
+        let size = file.metadata()?.len();
+        let mode = if control & 0x4 == 0 { TrackMode::Audio } else { detect_mode(&mut file, offset, sector_size)? };
+        let start = lba + FAD_OFFSET;
+
+        tracks.push(Track {
+            number,
+            session: if start >= HIGH_DENSITY_START { 2 } else { 1 },
+            start,
+            length: (size.saturating_sub(offset) / sector_size as u64) as u32,
+            mode,
+        });
+        sources.push(TrackSource { file: files.len(), offset, sector_size });
+        files.push(file);
+    }
+
+    if tracks.len() != count {
+        return Err(invalid_data("GDI track list incomplete"));
+    }
+    Ok(DiscImage::new(DiscFormat::GdRom, tracks, sources, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::{Disc, RAW_SECTOR_SIZE, DATA_SECTOR_SIZE};
+    use disc::image::{test_dir, test_sectors, write_header};
+    use std::fs;
+
+    #[test]
+    fn places_tracks_in_both_areas() {
+        let dir = test_dir("gdi");
+        let mut mode2 = test_sectors(1, RAW_SECTOR_SIZE);
+        write_header(45150, 2, &mut mode2);
+        fs::write(dir.join("track01.raw"), test_sectors(3, RAW_SECTOR_SIZE)).unwrap();
+        fs::write(dir.join("track02.bin"), test_sectors(2, DATA_SECTOR_SIZE)).unwrap();
+        fs::write(dir.join("track 03.bin"), mode2).unwrap();
+        fs::write(dir.join("disc.gdi"), "3\n1 0 0 2352 track01.raw 0\n2 450 4 2048 track02.bin 0\n3 45000 4 2352 \"track 03.bin\" 0\n").unwrap();
+
+        let mut disc = open(&dir.join("disc.gdi")).unwrap();
+        assert_eq!(disc.format(), DiscFormat::GdRom);
+        assert_eq!(disc.tracks(), &[
+            Track { number: 1, session: 1, start: 150, length: 3, mode: TrackMode::Audio },
+            Track { number: 2, session: 1, start: 600, length: 2, mode: TrackMode::Mode1 },
+            Track { number: 3, session: 2, start: 45150, length: 1, mode: TrackMode::Mode2 },
+        ]);
+
+        // Cooked sectors get a header in front
+        let mut sector = [0u8; RAW_SECTOR_SIZE];
+        disc.read_sector(601, &mut sector).unwrap();
+        assert_eq!(&sector[12..16], &[0x00, 0x08, 0x01, 1]);
+        assert_eq!(&sector[16..18], &[1, 1]);
+        disc.read_sector(152, &mut sector).unwrap();
+        assert_eq!(&sector[..2], &[2, 2]);
+
+        fs::write(dir.join("short.gdi"), "3\n1 0 0 2352 track01.raw 0\n").unwrap();
+        assert_eq!(open(&dir.join("short.gdi")).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
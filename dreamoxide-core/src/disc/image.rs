@@ -0,0 +1,179 @@
+use disc::{Disc, DiscFormat, Track, TrackMode, RAW_SECTOR_SIZE, DATA_SECTOR_SIZE, fad_to_msf, bcd};
+use disc::{gdi, cdi, cue, iso};
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Size of a mode 2 sector without its sync pattern and header
+pub const MODE2_SECTOR_SIZE : usize = 2336;
+/// Size of a raw sector followed by 96 bytes of subcode
+pub const RAW_SUBCODE_SECTOR_SIZE : usize = 2448;
+
+/// Where the sectors of a track are stored
+#[derive(Clone, Debug)]
+pub struct TrackSource {
+    /// Index into the image's files
+    pub file: usize,
+    /// Byte offset of the track's first sector
+    pub offset: u64,
+    /// Bytes stored per sector: 2048 and 2336 hold cooked sectors, 2352
+    /// and 2448 raw ones
+    pub sector_size: usize,
+}
+
+/// A disc read from image files. The loaders for the various formats
+/// produce the track list and where each track's sectors are stored.
+pub struct DiscImage {
+    pub format: DiscFormat,
+    pub tracks: Vec<Track>,
+    /// One source per track, in the same order
+    pub sources: Vec<TrackSource>,
+    files: Vec<File>,
+    scratch: Vec<u8>,
+}
+
+impl DiscImage {
+    pub fn new(format: DiscFormat, tracks: Vec<Track>, sources: Vec<TrackSource>, files: Vec<File>) -> DiscImage {
+        DiscImage {
+            format,
+            tracks,
+            sources,
+            files,
+            scratch: vec![0; RAW_SUBCODE_SECTOR_SIZE],
+        }
+    }
+
+    /// Opens an image, choosing the loader from the file extension
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<DiscImage> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+
+        match &extension[..] {
+            "gdi" => gdi::open(path),
+            "cdi" => cdi::open(path),
+            "cue" => cue::open(path),
+            "iso" => iso::open(path),
+            _     => Err(invalid_data("unknown disc image format"))
+        }
+    }
+}
+
+impl Disc for DiscImage {
+    fn format(&self) -> DiscFormat {
+        self.format
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+        let index = match self.tracks.iter().position(|t| t.contains(fad)) {
+            Some(index) => index,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "sector outside of all tracks"))
+        };
+        let track = &self.tracks[index];
+        let source = &self.sources[index];
+
+        let size = source.sector_size;
+        // Audio sectors have no header that could be synthesized
+        if track.mode == TrackMode::Audio && size != RAW_SECTOR_SIZE && size != RAW_SUBCODE_SECTOR_SIZE {
+            return Err(invalid_data("audio track stored without raw sectors"));
+        }
+
+        let file = &mut self.files[source.file];
+        file.seek(SeekFrom::Start(source.offset + (fad - track.start) as u64 * size as u64))?;
+        file.read_exact(&mut self.scratch[..size])?;
+
+        match size {
+            DATA_SECTOR_SIZE => {
+                write_header(fad, 1, buffer);
+                buffer[16..16 + DATA_SECTOR_SIZE].copy_from_slice(&self.scratch[..size]);
+                for b in buffer[16 + DATA_SECTOR_SIZE..RAW_SECTOR_SIZE].iter_mut() {
+                    *b = 0;
+                }
+            },
+            MODE2_SECTOR_SIZE => {
+                write_header(fad, 2, buffer);
+                buffer[16..RAW_SECTOR_SIZE].copy_from_slice(&self.scratch[..size]);
+            },
+            _ => buffer[..RAW_SECTOR_SIZE].copy_from_slice(&self.scratch[..RAW_SECTOR_SIZE])
+        }
+
+        Ok(())
+    }
+}
+
+/// Fills in the sync pattern and header of a raw sector. The error
+/// correction fields are left zero.
+pub fn write_header(fad: u32, mode: u8, buffer: &mut [u8]) {
+    buffer[0] = 0x00;
+    for b in buffer[1..11].iter_mut() {
+        *b = 0xFF;
+    }
+    buffer[11] = 0x00;
+
+    let (m, s, f) = fad_to_msf(fad);
+    buffer[12] = bcd(m);
+    buffer[13] = bcd(s);
+    buffer[14] = bcd(f);
+    buffer[15] = mode;
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Tells the mode of a data track from the header of its first raw sector
+pub fn detect_mode(file: &mut File, offset: u64, sector_size: usize) -> io::Result<TrackMode> {
+    match sector_size {
+        DATA_SECTOR_SIZE => Ok(TrackMode::Mode1),
+        MODE2_SECTOR_SIZE => Ok(TrackMode::Mode2),
+        _ => {
+            let mut header = [0u8; 16];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut header)?;
+            Ok(if header[15] == 2 { TrackMode::Mode2 } else { TrackMode::Mode1 })
+        }
+    }
+}
+
+/// Splits a line of a text image description into fields, keeping
+/// quoted file names with spaces together
+pub fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !field.is_empty() {
+                    fields.push(::std::mem::take(&mut field));
+                }
+            },
+            c => field.push(c)
+        }
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
+
+/// A directory of its own for the image files of a test
+#[cfg(test)]
+pub fn test_dir(name: &str) -> ::std::path::PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("dreamoxide-{}-{}", name, ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Raw sectors whose bytes all hold the low byte of their index in the file
+#[cfg(test)]
+pub fn test_sectors(count: usize, sector_size: usize) -> Vec<u8> {
+    (0..count * sector_size).map(|i| (i / sector_size) as u8).collect()
+}
@@ -0,0 +1,22 @@
+use disc::{Track, TrackMode, DiscFormat, FAD_OFFSET, DATA_SECTOR_SIZE};
+use disc::image::{DiscImage, TrackSource};
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Loads a plain ISO image: a single mode 1 track of cooked sectors
+pub fn open(path: &Path) -> io::Result<DiscImage> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let track = Track {
+        number: 1,
+        session: 1,
+        start: FAD_OFFSET,
+        length: (size / DATA_SECTOR_SIZE as u64) as u32,
+        mode: TrackMode::Mode1,
+    };
+    let source = TrackSource { file: 0, offset: 0, sector_size: DATA_SECTOR_SIZE };
+    Ok(DiscImage::new(DiscFormat::CdRom, vec![track], vec![source], vec![file]))
+}
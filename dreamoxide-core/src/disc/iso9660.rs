@@ -0,0 +1,353 @@
+use disc::{Disc, Track, DATA_SECTOR_SIZE, FAD_OFFSET, read_data, boot_track};
+use disc::image::invalid_data;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// The volume descriptors start after the 16 sectors of the system area
+const DESCRIPTOR_START : u32 = 16;
+
+const DESCRIPTOR_PRIMARY       : u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY : u8 = 2;
+const DESCRIPTOR_TERMINATOR    : u8 = 255;
+const STANDARD_ID : &[u8; 5] = b"CD001";
+
+/// Escape sequences marking a supplementary descriptor as Joliet, for
+/// its three levels of UCS-2
+const JOLIET_ESCAPES : [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+const ROOT_RECORD : usize = 156;
+/// A directory record up to its identifier
+const RECORD_HEADER : usize = 33;
+const FLAG_DIRECTORY : u8 = 0x02;
+
+/// A file or directory, as its directory record describes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// The name without the version suffix
+    pub name: String,
+    /// Logical block address of the first sector
+    pub extent: u32,
+    pub size: u32,
+    pub is_directory: bool,
+}
+
+impl DirectoryEntry {
+    /// Parses a directory record, decoding UCS-2 names on Joliet volumes
+    fn parse(record: &[u8], joliet: bool) -> io::Result<DirectoryEntry> {
+        if record.len() <= RECORD_HEADER || RECORD_HEADER + record[32] as usize > record.len() {
+            return Err(invalid_data("malformed ISO9660 directory record"));
+        }
+        let identifier = &record[RECORD_HEADER..RECORD_HEADER + record[32] as usize];
+        let mut name = if joliet {
+            let units: Vec<u16> = identifier.chunks(2).filter(|c| c.len() == 2).map(|c| ((c[0] as u16) << 8) | c[1] as u16).collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            String::from_utf8_lossy(identifier).into_owned()
+        };
+
+        // FILE.EXT;1, and FILE.;1 for names without an extension
+        if let Some(i) = name.find(';') {
+            name.truncate(i);
+        }
+        if name.ends_with('.') {
+            name.pop();
+        }
+
+        Ok(DirectoryEntry {
+            name,
+            extent: le32(record, 2),
+            size: le32(record, 10),
+            is_directory: record[25] & FLAG_DIRECTORY != 0,
+        })
+    }
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |value, i| value | ((bytes[offset + i] as u32) << (i * 8)))
+}
+
+fn sectors(size: u32) -> u32 {
+    (size as u64).div_ceil(DATA_SECTOR_SIZE as u64) as u32
+}
+
+/// An ISO9660 filesystem on a data track. GD-ROMs keep theirs in the
+/// high density area, CDs in the data track of their last session.
+/// Extents are logical block addresses counted from the start of the
+/// disc, so they hold wherever the track lies. Joliet names are used
+/// when the volume has them.
+pub struct Filesystem<'a> {
+    disc: &'a mut dyn Disc,
+    pub root: DirectoryEntry,
+    pub volume_id: String,
+    pub joliet: bool,
+}
+
+impl<'a> Filesystem<'a> {
+    /// Opens the filesystem of the track a disc boots from
+    pub fn open(disc: &'a mut dyn Disc) -> io::Result<Filesystem<'a>> {
+        let track = boot_track(disc)?;
+        Filesystem::open_track(disc, &track)
+    }
+
+    /// Opens the filesystem starting in a track
+    pub fn open_track(disc: &'a mut dyn Disc, track: &Track) -> io::Result<Filesystem<'a>> {
+        let mut primary = None;
+        let mut joliet = None;
+
+        let mut fad = track.start + DESCRIPTOR_START;
+        while fad < track.end() {
+            let descriptor = read_data(disc, fad, 1)?;
+            if &descriptor[1..6] != STANDARD_ID {
+                break;
+            }
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY if JOLIET_ESCAPES.iter().any(|e| descriptor[88..91] == e[..]) => joliet = Some(descriptor),
+                DESCRIPTOR_TERMINATOR => break,
+                _ => ()
+            }
+            fad += 1;
+        }
+
+        let primary = primary.ok_or_else(|| invalid_data("no ISO9660 filesystem on the track"))?;
+        let volume_id = String::from_utf8_lossy(&primary[40..72]).trim().to_string();
+        let is_joliet = joliet.is_some();
+        let descriptor = joliet.unwrap_or(primary);
+
+        Ok(Filesystem {
+            disc,
+            root: DirectoryEntry::parse(&descriptor[ROOT_RECORD..ROOT_RECORD + descriptor[ROOT_RECORD] as usize], is_joliet)?,
+            volume_id,
+            joliet: is_joliet,
+        })
+    }
+
+    fn read_extent(&mut self, extent: u32, size: u32) -> io::Result<Vec<u8>> {
+        let fad = extent.checked_add(FAD_OFFSET).ok_or_else(|| invalid_data("ISO9660 extent is out of range"))?;
+        let mut data = read_data(&mut *self.disc, fad, sectors(size))?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    /// The entries of a directory, without those for itself and its parent
+    pub fn read_dir(&mut self, directory: &DirectoryEntry) -> io::Result<Vec<DirectoryEntry>> {
+        if !directory.is_directory {
+            return Err(invalid_data(&format!("{} is not a directory", directory.name)));
+        }
+
+        let data = self.read_extent(directory.extent, directory.size)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let length = data[offset] as usize;
+            // Records don't cross sectors, the rest of one is padding
+            if length == 0 {
+                offset = (offset / DATA_SECTOR_SIZE + 1) * DATA_SECTOR_SIZE;
+                continue;
+            }
+            if offset + length > data.len() {
+                return Err(invalid_data("malformed ISO9660 directory record"));
+            }
+
+            let entry = DirectoryEntry::parse(&data[offset..offset + length], self.joliet)?;
+            // Identifiers 0 and 1 are the directory itself and its parent
+            if !(data[offset + 32] == 1 && data[offset + RECORD_HEADER] <= 1) {
+                entries.push(entry);
+            }
+            offset += length;
+        }
+        Ok(entries)
+    }
+
+    /// Finds an entry by its path from the root, ignoring case
+    pub fn lookup(&mut self, path: &str) -> io::Result<DirectoryEntry> {
+        let mut entry = self.root.clone();
+        for part in path.split(['/', '\\']).filter(|p| !p.is_empty()) {
+            entry = self.read_dir(&entry)?.into_iter()
+                .find(|e| e.name.eq_ignore_ascii_case(part))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not on the disc", path)))?;
+        }
+        Ok(entry)
+    }
+
+    pub fn read_file(&mut self, entry: &DirectoryEntry) -> io::Result<Vec<u8>> {
+        if entry.is_directory {
+            return Err(invalid_data(&format!("{} is a directory", entry.name)));
+        }
+        self.read_extent(entry.extent, entry.size)
+    }
+
+    /// Reads a whole file by its path
+    pub fn read(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let entry = self.lookup(path)?;
+        self.read_file(&entry)
+    }
+
+    /// Copies a file, or a directory with everything below it, to the
+    /// host
+    pub fn extract<P: AsRef<Path>>(&mut self, path: &str, destination: P) -> io::Result<()> {
+        let entry = self.lookup(path)?;
+        self.extract_entry(&entry, destination.as_ref())
+    }
+
+    fn extract_entry(&mut self, entry: &DirectoryEntry, destination: &Path) -> io::Result<()> {
+        if !entry.is_directory {
+            let data = self.read_file(entry)?;
+            return File::create(destination)?.write_all(&data);
+        }
+
+        fs::create_dir_all(destination)?;
+        for child in self.read_dir(entry)? {
+            // Names that would lead out of the destination are skipped
+            if child.name.is_empty() || child.name == "." || child.name == ".." || child.name.contains(['/', '\\']) {
+                continue;
+            }
+            self.extract_entry(&child, &destination.join(&child.name))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::{DiscFormat, TrackMode};
+
+    const ROOT : u32 = 20;
+    const SUBDIRECTORY : u32 = 22;
+
+    /// A single Mode 1 track, its sectors held in memory
+    struct MemoryDisc {
+        tracks: Vec<Track>,
+        sectors: Vec<Vec<u8>>,
+    }
+
+    impl Disc for MemoryDisc {
+        fn format(&self) -> DiscFormat {
+            DiscFormat::CdRom
+        }
+
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+            let data = self.sectors.get((fad - FAD_OFFSET) as usize)
+                .ok_or_else(|| invalid_data("past the end of the disc"))?;
+            buffer[16..16 + DATA_SECTOR_SIZE].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn record(name: &[u8], extent: u32, size: u32, directory: bool) -> Vec<u8> {
+        let length = (RECORD_HEADER + name.len() + 1) & !1;
+        let mut record = vec![0; length];
+        record[0] = length as u8;
+        for i in 0..4 {
+            record[2 + i] = (extent >> (i * 8)) as u8;
+            record[10 + i] = (size >> (i * 8)) as u8;
+        }
+        record[25] = if directory { FLAG_DIRECTORY } else { 0 };
+        record[32] = name.len() as u8;
+        record[RECORD_HEADER..RECORD_HEADER + name.len()].copy_from_slice(name);
+        record
+    }
+
+    fn directory(extent: u32, parent: u32, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = record(&[0], extent, DATA_SECTOR_SIZE as u32, true);
+        data.extend(record(&[1], parent, DATA_SECTOR_SIZE as u32, true));
+        for entry in entries.iter() {
+            data.extend_from_slice(entry);
+        }
+        data
+    }
+
+    fn disc() -> MemoryDisc {
+        let mut sectors = vec![vec![0; DATA_SECTOR_SIZE]; 28];
+        let mut store = |lba: u32, data: &[u8]| {
+            sectors[lba as usize][..data.len()].copy_from_slice(data);
+        };
+
+        let mut primary = vec![DESCRIPTOR_PRIMARY];
+        primary.extend_from_slice(STANDARD_ID);
+        primary.resize(40, 0);
+        primary.extend_from_slice(b"TESTDISC                        ");
+        primary.resize(ROOT_RECORD, 0);
+        primary.extend(record(&[0], ROOT, 2 * DATA_SECTOR_SIZE as u32, true));
+        store(16, &primary);
+        store(17, &[DESCRIPTOR_TERMINATOR, b'C', b'D', b'0', b'0', b'1']);
+
+        // The root takes two sectors, the second one holding a record
+        // that didn't fit into the first
+        store(ROOT, &directory(ROOT, ROOT, &[record(b"1ST_READ.BIN;1", 24, 5, false),
+                                             record(b"DATA", SUBDIRECTORY, DATA_SECTOR_SIZE as u32, true)]));
+        store(ROOT + 1, &record(b"ZZZ.TXT;1", 25, 3000, false));
+        store(SUBDIRECTORY, &directory(SUBDIRECTORY, ROOT, &[record(b"README.;1", 27, 4, false)]));
+
+        store(24, b"hello");
+        store(25, &[0x5A; DATA_SECTOR_SIZE]);
+        store(26, &[0xA5; DATA_SECTOR_SIZE]);
+        store(27, b"read");
+
+        MemoryDisc {
+            tracks: vec![Track { number: 1, session: 1, start: FAD_OFFSET, length: 28, mode: TrackMode::Mode1 }],
+            sectors,
+        }
+    }
+
+    #[test]
+    fn reads_the_root_directory() {
+        let mut disc = disc();
+        let mut fs = Filesystem::open(&mut disc).unwrap();
+        assert_eq!(fs.volume_id, "TESTDISC");
+        assert!(!fs.joliet);
+        assert_eq!((fs.root.extent, fs.root.is_directory), (ROOT, true));
+
+        let root = fs.root.clone();
+        let entries = fs.read_dir(&root).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["1ST_READ.BIN", "DATA", "ZZZ.TXT"]);
+        assert_eq!(entries[1], DirectoryEntry { name: "DATA".to_string(), extent: SUBDIRECTORY, size: 2048, is_directory: true });
+    }
+
+    #[test]
+    fn looks_up_files() {
+        let mut disc = disc();
+        let mut fs = Filesystem::open(&mut disc).unwrap();
+        assert_eq!(fs.read("1st_read.bin").unwrap(), b"hello");
+        assert_eq!(fs.read("/data/README").unwrap(), b"read");
+
+        let long = fs.read("ZZZ.TXT").unwrap();
+        assert_eq!(long.len(), 3000);
+        assert!(long[..2048].iter().all(|&b| b == 0x5A) && long[2048..].iter().all(|&b| b == 0xA5));
+
+        assert_eq!(fs.read("DATA/MISSING").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(fs.read("DATA").is_err());
+        assert!(fs.read("1ST_READ.BIN/X").is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_records() {
+        let mut overrun = record(b"NAME.BIN;1", 24, 5, false);
+        overrun[32] = 40;
+        assert!(DirectoryEntry::parse(&overrun, false).is_err());
+        assert!(DirectoryEntry::parse(&overrun[..RECORD_HEADER], false).is_err());
+
+        let mut disc = disc();
+        disc.sectors[SUBDIRECTORY as usize][68 + 32] = 0xFF;
+        let mut fs = Filesystem::open(&mut disc).unwrap();
+        assert!(fs.read("DATA/README").is_err());
+    }
+
+    #[test]
+    fn decodes_joliet_names() {
+        let name = [0x00, b'R', 0x00, b'e', 0x00, b'a', 0x00, b'd', 0x00, b'M', 0x00, b'e', 0x00, b';', 0x00, b'1'];
+        let entry = DirectoryEntry::parse(&record(&name, 30, 12, false), true).unwrap();
+        assert_eq!(entry.name, "ReadMe");
+        assert_eq!((entry.extent, entry.size, entry.is_directory), (30, 12, false));
+    }
+}
@@ -0,0 +1,261 @@
+use std::io;
+use std::path::Path;
+
+pub mod image;
+pub mod gdi;
+pub mod cdi;
+pub mod cue;
+pub mod iso;
+pub mod iso9660;
+pub mod chd;
+
+pub const RAW_SECTOR_SIZE  : usize = 2352;
+pub const DATA_SECTOR_SIZE : usize = 2048;
+
+/// Frame address of the first sector after the 2 second pregap
+pub const FAD_OFFSET : u32 = 150;
+/// First frame of the high density area of a GD-ROM
+pub const HIGH_DENSITY_START : u32 = 45150;
+
+/// Format of a disc, as reported by the drive
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiscFormat {
+    CdDa = 0,
+    CdRom = 1,
+    CdRomXa = 2,
+    CdI = 3,
+    GdRom = 8,
+}
+
+/// How the sectors of a track are laid out
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrackMode {
+    Audio,
+    Mode1,
+    Mode2,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub number: u8,
+    pub session: u8,
+    /// Frame address of the first sector
+    pub start: u32,
+    /// Number of sectors
+    pub length: u32,
+    pub mode: TrackMode,
+}
+
+impl Track {
+    /// Frame address following the last sector
+    pub fn end(&self) -> u32 {
+        self.start + self.length
+    }
+
+    pub fn contains(&self, fad: u32) -> bool {
+        fad >= self.start && fad < self.end()
+    }
+
+    /// The control nibble of the TOC, telling data tracks apart
+    pub fn control(&self) -> u8 {
+        match self.mode {
+            TrackMode::Audio => 0x0,
+            _                => 0x4
+        }
+    }
+
+    pub fn is_high_density(&self) -> bool {
+        self.start >= HIGH_DENSITY_START
+    }
+}
+
+/// A disc in the drive. Images in any format provide their tracks and
+/// raw sectors through this.
+pub trait Disc: Send {
+    fn format(&self) -> DiscFormat;
+
+    fn tracks(&self) -> &[Track];
+
+    /// Reads the raw 2352 byte sector at a frame address
+    fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()>;
+
+    fn track_at(&self, fad: u32) -> Option<&Track> {
+        self.tracks().iter().find(|t| t.contains(fad))
+    }
+
+    /// Frame address following the last track
+    fn lead_out(&self) -> u32 {
+        self.tracks().iter().map(|t| t.end()).max().unwrap_or(FAD_OFFSET)
+    }
+
+    fn sessions(&self) -> u8 {
+        self.tracks().iter().map(|t| t.session).max().unwrap_or(0)
+    }
+
+    /// Synthesizes the Q subchannel of a sector from the table of
+    /// contents: position within the track and on the disc, and its CRC
+    fn subcode_q(&self, fad: u32) -> [u8; 12] {
+        let mut q = [0u8; 12];
+        let track = self.track_at(fad).or_else(|| self.tracks().iter().find(|t| t.start > fad));
+
+        if let Some(track) = track {
+            // Sectors before a track's start are its pregap, counting down
+            let (index, relative) = if fad < track.start { (0, track.start - fad) } else { (1, fad - track.start) };
+            let (rm, rs, rf) = fad_to_msf(relative);
+            let (am, as_, af) = fad_to_msf(fad);
+            q = [(track.control() << 4) | 0x1, bcd(track.number), index, bcd(rm), bcd(rs), bcd(rf), 0, bcd(am), bcd(as_), bcd(af), 0, 0];
+        }
+
+        let crc = !crc16(&q[..10]);
+        q[10] = (crc >> 8) as u8;
+        q[11] = crc as u8;
+        q
+    }
+
+    /// Synthesizes the 96 bytes of raw subcode of a sector, one bit of
+    /// each subchannel per byte. Only P and Q carry information.
+    fn subcode(&self, fad: u32) -> [u8; 96] {
+        let q = self.subcode_q(fad);
+        let pause = q[2] == 0;
+        let mut raw = [0u8; 96];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            let q_bit = (q[i / 8] >> (7 - i % 8)) & 1;
+            *byte = ((pause as u8) << 7) | (q_bit << 6);
+        }
+        raw
+    }
+}
+
+/// Opens a disc image of any supported format, choosing by the file
+/// extension
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Disc>> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) if e.eq_ignore_ascii_case("chd") => Ok(Box::new(chd::ChdImage::open(path)?)),
+        _ => Ok(Box::new(image::DiscImage::open(path)?))
+    }
+}
+
+/// CRC-16 with the CCITT polynomial, as used by the Q subchannel
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+pub fn fad_to_msf(fad: u32) -> (u8, u8, u8) {
+    ((fad / 75 / 60) as u8, (fad / 75 % 60) as u8, (fad % 75) as u8)
+}
+
+pub fn msf_to_fad(minutes: u8, seconds: u8, frames: u8) -> u32 {
+    (minutes as u32 * 60 + seconds as u32) * 75 + frames as u32
+}
+
+/// The user data of a raw sector, depending on the mode in its header.
+/// Audio sectors have no header and are returned whole.
+pub fn user_data(raw: &[u8], mode: TrackMode) -> &[u8] {
+    match mode {
+        TrackMode::Audio => &raw[..RAW_SECTOR_SIZE],
+        TrackMode::Mode1 => &raw[16..16 + DATA_SECTOR_SIZE],
+        TrackMode::Mode2 => &raw[24..24 + DATA_SECTOR_SIZE]
+    }
+}
+
+/// Reads the user data of consecutive sectors
+pub fn read_data(disc: &mut dyn Disc, start: u32, count: u32) -> io::Result<Vec<u8>> {
+    let end = start.checked_add(count).ok_or_else(|| image::invalid_data("the sectors run past the end of the disc"))?;
+    let mut data = Vec::new();
+    let mut sector = [0u8; RAW_SECTOR_SIZE];
+    for fad in start..end {
+        let mode = disc.track_at(fad).map(|t| t.mode)
+            .ok_or_else(|| image::invalid_data(&format!("sector {} is outside every track", fad)))?;
+        disc.read_sector(fad, &mut sector)?;
+        data.extend_from_slice(user_data(&sector, mode));
+    }
+    Ok(data)
+}
+
+/// The track holding IP.BIN and the filesystem: the first data track
+/// of the last session
+pub fn boot_track(disc: &dyn Disc) -> io::Result<Track> {
+    let session = disc.sessions();
+    disc.tracks().iter().find(|t| t.session == session && t.mode != TrackMode::Audio).cloned()
+        .ok_or_else(|| image::invalid_data("the disc has no data track to boot from"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TocDisc {
+        tracks: Vec<Track>,
+    }
+
+    impl Disc for TocDisc {
+        fn format(&self) -> DiscFormat {
+            DiscFormat::CdRom
+        }
+
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector(&mut self, _fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+            for byte in buffer.iter_mut() {
+                *byte = 0;
+            }
+            Ok(())
+        }
+    }
+
+    fn disc() -> TocDisc {
+        TocDisc {
+            tracks: vec![
+                Track { number: 1, session: 1, start: 150, length: 4500, mode: TrackMode::Mode1 },
+                Track { number: 2, session: 1, start: 4800, length: 750, mode: TrackMode::Audio },
+            ],
+        }
+    }
+
+    #[test]
+    fn crc16_is_ccitt() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn synthesizes_the_q_subchannel() {
+        let disc = disc();
+
+        // 10 seconds into the first track, 12 seconds into the disc
+        let q = disc.subcode_q(900);
+        assert_eq!(&q[..10], &[0x41, 0x01, 0x01, 0x00, 0x10, 0x00, 0x00, 0x00, 0x12, 0x00]);
+        let crc = !crc16(&q[..10]);
+        assert_eq!((q[10], q[11]), ((crc >> 8) as u8, crc as u8));
+
+        // The gap before the second track counts down in index 0
+        let q = disc.subcode_q(4700);
+        assert_eq!(&q[..6], &[0x01, 0x02, 0x00, 0x00, 0x01, 0x25]);
+
+        // P marks the pause, Q follows bit by bit
+        let raw = disc.subcode(4700);
+        assert_eq!(raw[0], 0x80);
+        assert_eq!(raw[7], 0xC0);
+    }
+
+    #[test]
+    fn refuses_to_read_outside_the_tracks() {
+        let mut disc = disc();
+        assert_eq!(read_data(&mut disc, 150, 2).unwrap().len(), 2 * DATA_SECTOR_SIZE);
+        assert_eq!(read_data(&mut disc, 100, 1).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_data(&mut disc, 5549, 2).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,252 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use asic::Interrupt;
+
+use std::sync::{Arc, Mutex};
+
+/// Registers of the first DMA channel, the others follow every 0x20 bytes
+pub const SB_ADSTAG : usize = 0x005F7800;
+pub const SB_ADSTAR : usize = 0x005F7804;
+pub const SB_ADLEN  : usize = 0x005F7808;
+pub const SB_ADDIR  : usize = 0x005F780C;
+pub const SB_ADTSEL : usize = 0x005F7810;
+pub const SB_ADEN   : usize = 0x005F7814;
+pub const SB_ADST   : usize = 0x005F7818;
+pub const SB_ADSUSP : usize = 0x005F781C;
+
+pub const SB_G2ID   : usize = 0x005F7880;
+pub const SB_G2DSTO : usize = 0x005F7890;
+pub const SB_G2TRTO : usize = 0x005F7894;
+pub const SB_G2MDMTO: usize = 0x005F7898;
+pub const SB_G2MDMW : usize = 0x005F789C;
+pub const SB_G2APRO : usize = 0x005F78BC;
+
+pub const SB_G2FIFO : usize = 0x005F688C;
+
+pub const G2_REGISTER_BASE : usize = 0x005F7800;
+pub const G2_REGISTER_END  : usize = 0x005F78FF;
+
+pub const CHANNELS : usize = 4;
+pub const CHANNEL_STRIDE : usize = 0x20;
+
+/// Set in SB_ADLEN to disable the channel once the transfer is done
+const DMA_END_DISABLE : u32 = 1 << 31;
+
+/// One of the G2 DMA channels, between system memory and a G2 device.
+/// Channel 0 serves the AICA, 1 and 2 the expansion port and 3 the
+/// development box.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct G2Channel {
+    pub adstag: u32,
+    pub adstar: u32,
+    pub adlen: u32,
+    pub addir: u32,
+    pub adtsel: u32,
+    pub aden: u32,
+    pub adst: u32,
+    pub adsusp: u32,
+}
+
+/// The G2 bus interface: its DMA channels, timeouts and the write FIFO
+/// status polled before every AICA register access.
+///
+/// Like the PVR channel, a transfer started with SB_ADST is carried out
+/// by the owner of the memory calling `run`.
+pub struct G2 {
+    pub channels: [G2Channel; CHANNELS],
+    pub dsto: u32,
+    pub trto: u32,
+    pub mdmto: u32,
+    pub mdmw: u32,
+    pub apro: u32,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+impl G2 {
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<G2>> {
+        let g2 = Arc::new(Mutex::new(G2 {
+            channels: [G2Channel::default(); CHANNELS],
+            dsto: 0,
+            trto: 0,
+            mdmto: 0,
+            mdmw: 0,
+            apro: 0,
+            asic,
+        }));
+
+        mem.register_mapped_device(MemoryRange(G2_REGISTER_BASE, G2_REGISTER_END), g2.clone());
+        mem.register_mapped_device(MemoryRange(SB_G2FIFO, SB_G2FIFO + 3), g2.clone());
+        g2
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.channels.iter().any(|c| c.adst & 1 != 0)
+    }
+
+    /// Performs the pending transfers and raises their completion interrupts
+    pub fn run(&mut self, mem: &mut Memory) {
+        let interrupts = [Interrupt::AicaDmaDone, Interrupt::ExtDma1Done, Interrupt::ExtDma2Done, Interrupt::DevDmaDone];
+
+        for (channel, &interrupt) in self.channels.iter_mut().zip(interrupts.iter()) {
+            if channel.adst & 1 == 0 {
+                continue;
+            }
+
+            let g2 = (channel.adstag & 0x1FFFFFE0) as usize;
+            let system = (channel.adstar & 0x1FFFFFE0) as usize;
+            let length = channel.adlen & 0x7FFFFFE0;
+            let to_system = channel.addir & 1 != 0;
+
+            for word in 0..(length as usize / 4) {
+                let offset = word * 4;
+                if to_system {
+                    let value = mem.read_u32(g2 + offset);
+                    mem.write_u32(system + offset, value);
+                } else {
+                    let value = mem.read_u32(system + offset);
+                    mem.write_u32(g2 + offset, value);
+                }
+            }
+
+            channel.adstag = channel.adstag.wrapping_add(length);
+            channel.adstar = channel.adstar.wrapping_add(length);
+            if channel.adlen & DMA_END_DISABLE != 0 {
+                channel.aden = 0;
+            }
+            channel.adlen = 0;
+            channel.adst = 0;
+            self.asic.lock().unwrap().raise(interrupt);
+        }
+    }
+}
+
+impl MappedDevice for G2 {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address & !3 {
+            // Writes go through immediately, so the FIFOs are always empty
+            SB_G2FIFO  => 0,
+            SB_G2ID    => 0x12,
+            SB_G2DSTO  => self.dsto,
+            SB_G2TRTO  => self.trto,
+            SB_G2MDMTO => self.mdmto,
+            SB_G2MDMW  => self.mdmw,
+            SB_ADSTAG ..= 0x005F787F => {
+                let offset = address - G2_REGISTER_BASE;
+                let channel = &self.channels[offset / CHANNEL_STRIDE];
+                match (offset % CHANNEL_STRIDE) & !3 {
+                    0x00 => channel.adstag,
+                    0x04 => channel.adstar,
+                    0x08 => channel.adlen,
+                    0x0C => channel.addir,
+                    0x10 => channel.adtsel,
+                    0x14 => channel.aden,
+                    0x18 => channel.adst,
+                    _    => channel.adsusp
+                }
+            },
+            _          => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address & !3 {
+            SB_G2DSTO  => self.dsto = value,
+            SB_G2TRTO  => self.trto = value,
+            SB_G2MDMTO => self.mdmto = value,
+            SB_G2MDMW  => self.mdmw = value,
+            SB_G2APRO  => self.apro = value,
+            SB_ADSTAG ..= 0x005F787F => {
+                let offset = address - G2_REGISTER_BASE;
+                let channel = &mut self.channels[offset / CHANNEL_STRIDE];
+                match (offset % CHANNEL_STRIDE) & !3 {
+                    0x00 => channel.adstag = value & 0x1FFFFFE0,
+                    0x04 => channel.adstar = value & 0x1FFFFFE0,
+                    0x08 => channel.adlen = value & 0xFFFFFFE0,
+                    0x0C => channel.addir = value & 1,
+                    0x10 => channel.adtsel = value & 0x7,
+                    0x14 => channel.aden = value & 1,
+                    0x18 => if value & 1 != 0 && channel.aden & 1 != 0 {
+                        channel.adst = 1;
+                    },
+                    _    => channel.adsusp = value & 0x7
+                }
+            },
+            _          => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aica::Aica;
+    use memory::TestMemory;
+
+    #[test]
+    fn copies_into_wave_ram() {
+        let mut mem = TestMemory::new();
+        let asic = Asic::new(&mut mem);
+        let aica = Aica::new(&mut mem, asic.clone());
+        let g2 = G2::new(&mut mem, asic.clone());
+
+        for i in 0..8 {
+            mem.write_u32(0x0C004000 + i * 4, 0x01010101 * i as u32);
+        }
+
+        {
+            let mut g2 = g2.lock().unwrap();
+            g2.write(SB_ADSTAG, 0x00801000, 4);
+            g2.write(SB_ADSTAR, 0x0C004000, 4);
+            g2.write(SB_ADLEN, DMA_END_DISABLE | 0x20, 4);
+            g2.write(SB_ADDIR, 0, 4);
+
+            // Starting a disabled channel does nothing
+            g2.write(SB_ADST, 1, 4);
+            assert!(!g2.is_pending());
+            g2.write(SB_ADEN, 1, 4);
+            g2.write(SB_ADST, 1, 4);
+            assert!(g2.is_pending());
+            g2.run(&mut mem);
+
+            assert_eq!(g2.read(SB_ADST, 4), 0);
+            assert_eq!(g2.read(SB_ADSTAG, 4), 0x00801020);
+            assert_eq!(g2.read(SB_ADSTAR, 4), 0x0C004020);
+            // End-of-transfer disable was requested
+            assert_eq!(g2.read(SB_ADEN, 4), 0);
+            // Channels other than the first are left alone
+            assert_eq!(g2.read(SB_ADSTAG + CHANNEL_STRIDE, 4), 0);
+        }
+
+        assert_eq!(aica.lock().unwrap().read_ram(0x1000 + 7 * 4, 4), 0x07070707);
+        assert!(asic.lock().unwrap().istnrm & (1 << Interrupt::AicaDmaDone as u32) != 0);
+    }
+
+    #[test]
+    fn channels_are_laid_out_every_0x20_bytes() {
+        let mut g2 = G2 {
+            channels: [G2Channel::default(); CHANNELS],
+            dsto: 0,
+            trto: 0,
+            mdmto: 0,
+            mdmw: 0,
+            apro: 0,
+            asic: Arc::new(Mutex::new(Asic {
+                istnrm: 0,
+                istext: 0,
+                isterr: 0,
+                iml_nrm: [0; 3],
+                iml_ext: [0; 3],
+                iml_err: [0; 3],
+            })),
+        };
+
+        g2.write(SB_ADSTAR + 3 * CHANNEL_STRIDE, 0x0C00003F, 4);
+        assert_eq!(g2.channels[3].adstar, 0x0C000020);
+        g2.write(SB_ADSUSP + 2 * CHANNEL_STRIDE, 0xFF, 4);
+        assert_eq!(g2.read(SB_ADSUSP + 2 * CHANNEL_STRIDE, 4), 0x7);
+        assert_eq!(g2.read(SB_G2FIFO, 4), 0);
+    }
+}
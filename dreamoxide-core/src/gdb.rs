@@ -0,0 +1,527 @@
+use Cpu;
+use Memory;
+use MemoryField;
+use MemoryRange;
+use Operand;
+use Instruction;
+use InstructionDecoder;
+use memory::{Watchpoint, WatchKind};
+use machine::Machine;
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Registers in the order sh-elf-gdb numbers them for the SH-4: the
+/// general registers of the current bank, the control registers, FR0
+/// to FR15 of the current bank, SSR and SPC, then both banks of R0 to
+/// R7. The XF registers follow, for `p` and `P` only.
+const REGISTER_PC    : usize = 16;
+const REGISTER_PR    : usize = 17;
+const REGISTER_GBR   : usize = 18;
+const REGISTER_VBR   : usize = 19;
+const REGISTER_MACH  : usize = 20;
+const REGISTER_MACL  : usize = 21;
+const REGISTER_SR    : usize = 22;
+const REGISTER_FPUL  : usize = 23;
+const REGISTER_FPSCR : usize = 24;
+const REGISTER_FR    : usize = 25;
+const REGISTER_SSR   : usize = 41;
+const REGISTER_SPC   : usize = 42;
+const REGISTER_BANK0 : usize = 43;
+const REGISTER_BANK1 : usize = 51;
+const REGISTER_XF    : usize = 59;
+/// Registers sent by `g`
+const G_REGISTERS : usize = REGISTER_XF;
+const REGISTERS   : usize = REGISTER_XF + 16;
+
+/// FPSCR.FR, which swaps the FR and XF banks
+const FPSCR_FR : u32 = 0x200000;
+
+const SIGINT  : u8 = 2;
+const SIGTRAP : u8 = 5;
+
+/// Instructions run between looks for an interrupt from the debugger
+const POLL_INTERVAL : usize = 0x10000;
+const PACKET_SIZE : usize = 0x4000;
+/// Most bytes a memory read replies with, two hex digits each
+const MAX_READ : usize = PACKET_SIZE / 2;
+
+/// Why the guest stopped
+enum Stop {
+    Signal(u8),
+    Watch(WatchKind, usize),
+    Exited(i32),
+}
+
+/// Whether an instruction has a delay slot
+fn is_delayed_branch(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Bfs(_) | Instruction::Bts(_) | Instruction::Bra(..) | Instruction::Braf(_) |
+        Instruction::Bsr(..) | Instruction::Bsrf(_) | Instruction::Jmp(_) | Instruction::Jsr(_) |
+        Instruction::Rts)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.as_bytes().chunks(2)
+        .map(|pair| ::std::str::from_utf8(pair).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+        .collect()
+}
+
+/// Registers go over the wire in the guest's byte order
+fn hex_u32(value: u32) -> String {
+    hex(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    (0..4).fold(0, |value, i| value | ((bytes[i] as u32) << (i * 8)))
+}
+
+/// The checksum closing a packet, the sum of its bytes modulo 256
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Parses the `address,length` that memory and breakpoint packets start
+/// with
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+/// A server for the GDB remote serial protocol, debugging the guest
+/// with sh-elf-gdb. Breakpoints are kept here rather than written into
+/// memory, so software and hardware ones are the same. A branch runs
+/// together with its delay slot, as on the chip a single step can't
+/// stop between them, so a breakpoint in a delay slot stops at its
+/// branch.
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<usize>,
+}
+
+impl GdbStub {
+    /// Waits for the debugger to connect to a port on the local host
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            breakpoints: Vec::new(),
+        })
+    }
+
+    /// Serves the debugger until it detaches or kills the guest. The
+    /// guest is stopped at first, waiting for its commands.
+    pub fn run(&mut self, machine: &mut Machine) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            let reply = match packet.as_bytes().first() {
+                Some(&b'?') => self.stop_reply(&Stop::Signal(SIGTRAP)),
+                Some(&b'g') => (0..G_REGISTERS).map(|n| hex_u32(register(&machine.cpu, n))).collect(),
+                Some(&b'G') => match unhex(&packet[1..]) {
+                    Some(ref data) if data.len() >= G_REGISTERS * 4 => {
+                        for (n, value) in data.chunks(4).take(G_REGISTERS).enumerate() {
+                            set_register(&mut machine.cpu, n, le32(value));
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string()
+                },
+                Some(&b'p') => match parse_hex(&packet[1..]) {
+                    Some(n) if n < REGISTERS => hex_u32(register(&machine.cpu, n)),
+                    _ => "E01".to_string()
+                },
+                Some(&b'P') => {
+                    let mut parts = packet[1..].splitn(2, '=');
+                    match (parts.next().and_then(parse_hex), parts.next().and_then(unhex)) {
+                        (Some(n), Some(ref value)) if n < REGISTERS && value.len() == 4 => {
+                            set_register(&mut machine.cpu, n, le32(value));
+                            "OK".to_string()
+                        },
+                        _ => "E01".to_string()
+                    }
+                },
+                Some(&b'm') => match parse_range(&packet[1..]) {
+                    Some((address, length)) => {
+                        let data: Option<Vec<u8>> = (0..length.min(MAX_READ)).map(|i| peek(&machine.mem, address.checked_add(i)?)).collect();
+                        data.map(|data| hex(&data)).unwrap_or("E01".to_string())
+                    },
+                    None => "E01".to_string()
+                },
+                Some(&b'M') => {
+                    let mut parts = packet[1..].splitn(2, ':');
+                    match (parts.next().and_then(parse_range), parts.next().and_then(unhex)) {
+                        (Some((address, length)), Some(ref data)) if data.len() == length => {
+                            // Nothing is written unless all of it can be
+                            let writable = (0..length).all(|i| address.checked_add(i).is_some_and(|a| peek(&machine.mem, a).is_some()));
+                            if writable {
+                                for (i, &byte) in data.iter().enumerate() {
+                                    poke(&mut machine.mem, address + i, byte);
+                                }
+                                "OK".to_string()
+                            } else {
+                                "E01".to_string()
+                            }
+                        },
+                        _ => "E01".to_string()
+                    }
+                },
+                Some(&b'c') | Some(&b's') => {
+                    if let Some(address) = parse_hex(&packet[1..]) {
+                        machine.cpu.pc = address;
+                    }
+                    let stop = if packet.starts_with('s') { self.step(machine) } else { self.resume(machine)? };
+                    let reply = self.stop_reply(&stop);
+                    if let Stop::Exited(_) = stop {
+                        self.send(&reply)?;
+                        return Ok(());
+                    }
+                    reply
+                },
+                Some(&b'Z') | Some(&b'z') => self.breakpoint(&mut machine.mem, &packet),
+                Some(&b'D') => {
+                    self.send("OK")?;
+                    return self.detach(machine);
+                },
+                Some(&b'k') => return self.detach(machine),
+                Some(&b'H') => "OK".to_string(),
+                _ if packet.starts_with("qSupported") => format!("PacketSize={:x}", PACKET_SIZE),
+                _ if packet == "qAttached" => "1".to_string(),
+                _ if packet == "qC" => "QC1".to_string(),
+                _ if packet == "qfThreadInfo" => "m1".to_string(),
+                _ if packet == "qsThreadInfo" => "l".to_string(),
+                // Anything else is unsupported, which an empty reply says
+                _ => String::new()
+            };
+            self.send(&reply)?;
+        }
+        self.detach(machine)
+    }
+
+    /// Lets the guest run on without the debugger
+    fn detach(&mut self, machine: &mut Machine) -> io::Result<()> {
+        machine.mem.watchpoints.clear();
+        machine.mem.watch_hit.set(None);
+        Ok(())
+    }
+
+    fn stop_reply(&self, stop: &Stop) -> String {
+        match *stop {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Watch(kind, address) => {
+                let name = match kind {
+                    WatchKind::Write  => "watch",
+                    WatchKind::Read   => "rwatch",
+                    WatchKind::Access => "awatch"
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+            },
+            Stop::Exited(code) => format!("W{:02x}", code as u8)
+        }
+    }
+
+    /// Runs one instruction, or a branch and its delay slot
+    fn step(&mut self, machine: &mut Machine) -> Stop {
+        machine.mem.watch_hit.set(None);
+        machine.step();
+        if let Some(code) = *machine.exit_code.lock().unwrap() {
+            return Stop::Exited(code);
+        }
+        match machine.mem.watch_hit.get() {
+            Some((address, kind)) => Stop::Watch(kind, address),
+            None => Stop::Signal(SIGTRAP)
+        }
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, the guest exits or
+    /// the debugger interrupts
+    fn resume(&mut self, machine: &mut Machine) -> io::Result<Stop> {
+        // The breakpoint the guest stopped at is stepped over first
+        let mut steps = 0;
+        loop {
+            match self.step(machine) {
+                Stop::Signal(_) => (),
+                stop => return Ok(stop)
+            }
+            if self.is_breakpoint(&machine.mem, machine.cpu.pc) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && self.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    /// Whether the instruction at an address, or the delay slot of a
+    /// branch there, has a breakpoint
+    fn is_breakpoint(&self, mem: &Memory, pc: usize) -> bool {
+        let pc = pc & 0x1FFFFFFF;
+        self.breakpoints.iter().any(|&address| {
+            address == pc || (address == pc + 2 && is_delayed_branch(InstructionDecoder::decode(mem.read_u16_raw(pc))))
+        })
+    }
+
+    fn breakpoint(&mut self, mem: &mut Memory, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let mut parts = packet[1..].splitn(2, ',');
+        let (kind, range) = (parts.next(), parts.next().and_then(parse_range));
+        let (address, length) = match range {
+            Some((address, length)) => (address & 0x1FFFFFFF, length.max(1)),
+            None => return "E01".to_string()
+        };
+        let end = match address.checked_add(length - 1) {
+            Some(end) => end,
+            None => return "E01".to_string()
+        };
+
+        let kind = match kind {
+            // Software and hardware breakpoints
+            Some("0") | Some("1") => {
+                if insert {
+                    self.breakpoints.push(address);
+                } else if let Some(i) = self.breakpoints.iter().position(|&a| a == address) {
+                    self.breakpoints.remove(i);
+                }
+                return "OK".to_string();
+            },
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new()
+        };
+
+        let watchpoint = Watchpoint { range: MemoryRange(address, end), kind };
+        if insert {
+            mem.watchpoints.push(watchpoint);
+        } else if let Some(i) = mem.watchpoints.iter().position(|w| *w == watchpoint) {
+            mem.watchpoints.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    /// Looks for the interrupt byte the debugger sends on Ctrl-C,
+    /// without waiting for it
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the debugger disconnected")),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0]))
+        }
+    }
+
+    /// Waits for the next packet, acknowledging it. None when the
+    /// debugger disconnects.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acknowledgements and interrupts outside of a run are skipped
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte)
+                }
+            }
+            let mut digits = [0; 2];
+            self.stream.read_exact(&mut digits)?;
+
+            let expected = ::std::str::from_utf8(&digits).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.stream.write_all(format!("${}#{:02x}", data, checksum(data.as_bytes())).as_bytes())
+    }
+}
+
+/// The value of a register as the debugger numbers them
+fn register(cpu: &Cpu, n: usize) -> u32 {
+    let fr_bank = if cpu.fpscr.value & FPSCR_FR != 0 { 16 } else { 0 };
+    match n {
+        n if n < 16 => cpu[Operand::RegisterOperand(n as u8)].value,
+        REGISTER_PC    => cpu.pc as u32,
+        REGISTER_PR    => cpu.pr as u32,
+        REGISTER_GBR   => cpu.gbr.value,
+        REGISTER_VBR   => cpu.vbr.value,
+        REGISTER_MACH  => cpu.mach.value,
+        REGISTER_MACL  => cpu.macl.value,
+        REGISTER_SR    => cpu.status.value,
+        REGISTER_FPUL  => cpu.fpul.value,
+        REGISTER_FPSCR => cpu.fpscr.value,
+        REGISTER_SSR   => cpu.ssr.value,
+        REGISTER_SPC   => cpu.spc.value,
+        n if n < REGISTER_SSR => cpu.fpu_registers[fr_bank + n - REGISTER_FR].value.to_bits(),
+        n if n < REGISTER_BANK1 => cpu.registers[n - REGISTER_BANK0].value,
+        n if n < REGISTER_XF => cpu.registers[16 + n - REGISTER_BANK1].value,
+        n if n < REGISTERS => cpu.fpu_registers[(fr_bank ^ 16) + n - REGISTER_XF].value.to_bits(),
+        _              => 0
+    }
+}
+
+fn set_register(cpu: &mut Cpu, n: usize, value: u32) {
+    let fr_bank = if cpu.fpscr.value & FPSCR_FR != 0 { 16 } else { 0 };
+    match n {
+        n if n < 16 => cpu[Operand::RegisterOperand(n as u8)].value = value,
+        REGISTER_PC    => cpu.pc = value as usize,
+        REGISTER_PR    => cpu.pr = value as usize,
+        REGISTER_GBR   => cpu.gbr.value = value,
+        REGISTER_VBR   => cpu.vbr.value = value,
+        REGISTER_MACH  => cpu.mach.value = value,
+        REGISTER_MACL  => cpu.macl.value = value,
+        REGISTER_SR    => cpu.status.value = value,
+        REGISTER_FPUL  => cpu.fpul.value = value,
+        REGISTER_FPSCR => cpu.fpscr.value = value,
+        REGISTER_SSR   => cpu.ssr.value = value,
+        REGISTER_SPC   => cpu.spc.value = value,
+        n if n < REGISTER_SSR => cpu.fpu_registers[fr_bank + n - REGISTER_FR].value = f32::from_bits(value),
+        n if n < REGISTER_BANK1 => cpu.registers[n - REGISTER_BANK0].value = value,
+        n if n < REGISTER_XF => cpu.registers[16 + n - REGISTER_BANK1].value = value,
+        n if n < REGISTERS => cpu.fpu_registers[(fr_bank ^ 16) + n - REGISTER_XF].value = f32::from_bits(value),
+        _              => ()
+    }
+}
+
+/// Whether a device answers at an address, which reading would have
+/// effects on
+fn is_io(mem: &Memory, address: usize) -> bool {
+    let address = Memory::map(address);
+    mem.is_io_register(address) && (mem.devices.iter().any(|d| d.range.is_within(address)) || mem.mapped.iter().any(|m| m.range.is_within(address)))
+}
+
+/// Reads a byte for the debugger straight from the cells, without
+/// touching devices or watchpoints. None for registers and for what
+/// lies beyond the address space.
+fn peek(mem: &Memory, address: usize) -> Option<u8> {
+    if address > 0xFFFFFFFF || is_io(mem, address) {
+        return None;
+    }
+    match *mem.access(address) {
+        MemoryField::MemoryCell(v) | MemoryField::InstructionCell(_, v) => Some((v >> ((address & 1) * 8)) as u8),
+        _ => None
+    }
+}
+
+/// Writes a byte for the debugger where `peek` can read, replacing the
+/// decoded instruction there if there was one
+fn poke(mem: &mut Memory, address: usize, value: u8) {
+    let shift = (address & 1) * 8;
+    let half = mem.read_u16_raw(address);
+    *mem.access_mut(address) = MemoryField::MemoryCell((half & !(0xFF << shift)) | ((value as u16) << shift));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_packets() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), 0x9A);
+        assert_eq!(checksum(b"qSupported"), 0x37);
+    }
+
+    #[test]
+    fn frames_and_acknowledges_packets() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stub = GdbStub { stream: listener.accept().unwrap().0, breakpoints: Vec::new() };
+
+        // A corrupted packet is refused and the retransmission taken
+        client.write_all(b"+$OK#00$OK#9a").unwrap();
+        assert_eq!(stub.receive().unwrap(), Some("OK".to_string()));
+        stub.send("S05").unwrap();
+
+        let mut reply = [0; 9];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"-+$S05#b8");
+
+        drop(client);
+        assert_eq!(stub.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn encodes_registers_in_guest_order() {
+        assert_eq!(hex_u32(0x12345678), "78563412");
+        assert_eq!(le32(&unhex("78563412").unwrap()), 0x12345678);
+        assert_eq!(unhex("7g"), None);
+        assert_eq!(unhex("123"), None);
+        assert_eq!(parse_range("8c010000,4"), Some((0x8C010000, 4)));
+        assert_eq!(parse_range("8c010000"), None);
+    }
+
+    #[test]
+    fn round_trips_every_register() {
+        let mut cpu = Cpu::new();
+        cpu.status.value = 0;
+        cpu.fpscr.value = 0;
+
+        for n in 0..REGISTERS {
+            let value = 0x3F800000 + n as u32;
+            set_register(&mut cpu, n, value);
+            assert_eq!(register(&cpu, n), value, "register {}", n);
+            // Leave the banks where they were for the registers after
+            if n == REGISTER_SR || n == REGISTER_FPSCR {
+                set_register(&mut cpu, n, 0);
+            }
+        }
+        assert_eq!(register(&cpu, REGISTERS), 0);
+    }
+
+    #[test]
+    fn follows_the_register_banks() {
+        let mut cpu = Cpu::new();
+        cpu.status.value = 0;
+        cpu.fpscr.value = 0;
+        set_register(&mut cpu, 0, 0xAAAA);
+        set_register(&mut cpu, REGISTER_FR, 1.5f32.to_bits());
+
+        // With RB and MD set, R0 is the one of bank 1
+        set_register(&mut cpu, REGISTER_SR, 0x60000000);
+        set_register(&mut cpu, 0, 0xBBBB);
+        assert_eq!(register(&cpu, REGISTER_BANK0), 0xAAAA);
+        assert_eq!(register(&cpu, REGISTER_BANK1), 0xBBBB);
+
+        // With FR set, FR0 and XF0 trade places
+        set_register(&mut cpu, REGISTER_FPSCR, FPSCR_FR);
+        assert_eq!(register(&cpu, REGISTER_XF), 1.5f32.to_bits());
+        assert_eq!(register(&cpu, REGISTER_FR), 0);
+    }
+}
@@ -0,0 +1,1060 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use asic::{Interrupt, ExternalInterrupt};
+use audio::SampleQueue;
+use disc::{Disc, DiscFormat, TrackMode, msf_to_fad, user_data, RAW_SECTOR_SIZE};
+use scheduler::{Scheduler, Event, SH4_CLOCK};
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+/// ATA registers of the drive on the G1 bus. Several addresses mean
+/// one register when read and another when written.
+pub const GD_ALTSTAT_DEVCTRL : usize = 0x005F7018;
+pub const GD_DATA            : usize = 0x005F7080;
+pub const GD_ERROR_FEATURES  : usize = 0x005F7084;
+pub const GD_IREASON_SECTCNT : usize = 0x005F7088;
+pub const GD_SECTNUM         : usize = 0x005F708C;
+pub const GD_BYCTLLO         : usize = 0x005F7090;
+pub const GD_BYCTLHI         : usize = 0x005F7094;
+pub const GD_DRVSEL          : usize = 0x005F7098;
+pub const GD_STATUS_COMMAND  : usize = 0x005F709C;
+
+/// GD-ROM DMA registers of the system bus
+pub const SB_GDSTAR  : usize = 0x005F7404;
+pub const SB_GDLEN   : usize = 0x005F7408;
+pub const SB_GDDIR   : usize = 0x005F740C;
+pub const SB_GDEN    : usize = 0x005F7414;
+pub const SB_GDST    : usize = 0x005F7418;
+pub const SB_GDSTARD : usize = 0x005F74F8;
+pub const SB_GDLEND  : usize = 0x005F74FC;
+
+pub const GD_REGISTER_BASE  : usize = 0x005F7000;
+pub const GD_REGISTER_END   : usize = 0x005F70FF;
+pub const GD_DMA_REGISTER_BASE : usize = 0x005F7400;
+pub const GD_DMA_REGISTER_END  : usize = 0x005F74FF;
+
+/// Bits of the ATA status register
+pub const STATUS_BSY   : u8 = 0x80;
+pub const STATUS_DRDY  : u8 = 0x40;
+pub const STATUS_DRQ   : u8 = 0x08;
+pub const STATUS_CHECK : u8 = 0x01;
+
+/// Bits of the interrupt reason register
+pub const IREASON_COD : u8 = 0x01;
+pub const IREASON_IO  : u8 = 0x02;
+
+/// ATA commands
+pub const ATA_NOP          : u8 = 0x00;
+pub const ATA_SOFT_RESET   : u8 = 0x08;
+pub const ATA_DIAGNOSTIC   : u8 = 0x90;
+pub const ATA_PACKET       : u8 = 0xA0;
+pub const ATA_IDENTIFY     : u8 = 0xA1;
+pub const ATA_SET_FEATURES : u8 = 0xEF;
+
+/// SPI packet commands
+pub const SPI_TEST_UNIT : u8 = 0x00;
+pub const SPI_REQ_STAT  : u8 = 0x10;
+pub const SPI_REQ_MODE  : u8 = 0x11;
+pub const SPI_SET_MODE  : u8 = 0x12;
+pub const SPI_REQ_ERROR : u8 = 0x13;
+pub const SPI_GET_TOC   : u8 = 0x14;
+pub const SPI_REQ_SES   : u8 = 0x15;
+pub const SPI_CD_PLAY   : u8 = 0x20;
+pub const SPI_CD_SEEK   : u8 = 0x21;
+pub const SPI_CD_READ   : u8 = 0x30;
+pub const SPI_GET_SCD   : u8 = 0x40;
+
+/// Sense keys reported by REQ_ERROR
+pub const SENSE_NONE            : u8 = 0x0;
+pub const SENSE_NOT_READY       : u8 = 0x2;
+pub const SENSE_MEDIUM_ERROR    : u8 = 0x3;
+pub const SENSE_ILLEGAL_REQUEST : u8 = 0x5;
+
+/// Audio status reported by GET_SCD
+pub const AUDIO_PLAYING   : u8 = 0x11;
+pub const AUDIO_PAUSED    : u8 = 0x12;
+pub const AUDIO_COMPLETED : u8 = 0x13;
+pub const AUDIO_NO_STATUS : u8 = 0x15;
+
+/// Audio sectors are played at 75 a second, each 588 stereo samples
+pub const SECTORS_PER_SECOND : u64 = 75;
+pub const SAMPLES_PER_SECTOR : usize = 588;
+const CYCLES_PER_SECTOR : u64 = SH4_CLOCK / SECTORS_PER_SECOND;
+/// Sectors of audio buffered before the oldest are dropped, should the
+/// sound chip not take them
+const MAX_BUFFERED_SECTORS : usize = 4;
+
+const PACKET_SIZE : usize = 12;
+/// Most bytes handed over between two PIO interrupts
+const MAX_PIO_CHUNK : usize = 0x8000;
+
+/// Drive states reported in the sector number register and REQ_STAT
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DriveStatus {
+    Busy = 0,
+    Pause = 1,
+    Standby = 2,
+    Play = 3,
+    Seek = 4,
+    Scan = 5,
+    Open = 6,
+    NoDisc = 7,
+    Retry = 8,
+    Error = 9,
+}
+
+/// Phase of the ATA protocol the drive is in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Receiving the 12 bytes of a packet command
+    Packet,
+    /// Sending data to the host through the data register
+    PioIn,
+    /// Receiving data from the host through the data register
+    PioOut,
+    /// Waiting for the GD-ROM DMA to take the data
+    Dma,
+}
+
+/// Audio playback requested by CD_PLAY
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Playback {
+    pub start: u32,
+    pub end: u32,
+    /// Times to play the range again, 0xF repeating forever
+    pub repeat: u8,
+}
+
+/// The GD-ROM drive and its DMA channel. Commands arrive as ATA
+/// commands, most of them as SPI packets, and data moves either
+/// through the data register or by DMA into system memory.
+pub struct GdRom {
+    pub disc: Option<Box<dyn Disc>>,
+    pub status: u8,
+    pub error: u8,
+    pub features: u8,
+    pub interrupt_reason: u8,
+    pub sector_count: u8,
+    pub byte_count: u16,
+    pub drive_select: u8,
+    pub device_control: u8,
+    pub drive_status: DriveStatus,
+    /// Frame address of the pickup
+    pub fad: u32,
+    pub playback: Option<Playback>,
+    pub audio_status: u8,
+    /// Samples of the audio being played, taken by the sound chip
+    pub cdda: Arc<Mutex<SampleQueue>>,
+    phase: Phase,
+    packet: Vec<u8>,
+    /// Data being transferred and the position within it
+    data: Vec<u8>,
+    position: usize,
+    /// Bytes left before the next PIO interrupt
+    chunk: usize,
+    /// Where SET_MODE data goes in the mode page
+    mode_offset: usize,
+    mode: [u8; 32],
+    sense_key: u8,
+    sense_code: u8,
+    pub gdstar: u32,
+    pub gdlen: u32,
+    pub gddir: u32,
+    pub gden: u32,
+    pub gdst: u32,
+    pub gdstard: u32,
+    pub gdlend: u32,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+/// Contents of the mode page at power on
+const DEFAULT_MODE : [u8; 32] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xB4, 0x19, 0x00, 0x00, 0x08, b'S', b'E', b' ', b' ', b' ',
+    b' ', b' ', b' ', b'R', b'e', b'v', b' ', b'6', b'.', b'4', b'3', b'9', b'9', b'0', b'4', b'0',
+];
+
+impl GdRom {
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<GdRom>> {
+        let gdrom = Arc::new(Mutex::new(GdRom::unmapped(asic)));
+        mem.register_mapped_device(MemoryRange(GD_REGISTER_BASE, GD_REGISTER_END), gdrom.clone());
+        mem.register_mapped_device(MemoryRange(GD_DMA_REGISTER_BASE, GD_DMA_REGISTER_END), gdrom.clone());
+        gdrom
+    }
+
+    /// Creates the drive without mapping it anywhere
+    fn unmapped(asic: Arc<Mutex<Asic>>) -> GdRom {
+        GdRom {
+            disc: None,
+            status: STATUS_DRDY,
+            error: 0,
+            features: 0,
+            interrupt_reason: 0,
+            sector_count: 0,
+            byte_count: 0,
+            drive_select: 0,
+            device_control: 0,
+            drive_status: DriveStatus::NoDisc,
+            fad: 0,
+            playback: None,
+            audio_status: AUDIO_NO_STATUS,
+            cdda: Arc::new(Mutex::new(SampleQueue::new())),
+            phase: Phase::Idle,
+            packet: Vec::with_capacity(PACKET_SIZE),
+            data: Vec::new(),
+            position: 0,
+            chunk: 0,
+            mode_offset: 0,
+            mode: DEFAULT_MODE,
+            sense_key: SENSE_NONE,
+            sense_code: 0,
+            gdstar: 0,
+            gdlen: 0,
+            gddir: 0,
+            gden: 0,
+            gdst: 0,
+            gdstard: 0,
+            gdlend: 0,
+            asic,
+        }
+    }
+
+    /// Puts a disc into the drive, or empties it
+    pub fn insert(&mut self, disc: Option<Box<dyn Disc>>) {
+        self.drive_status = if disc.is_some() { DriveStatus::Standby } else { DriveStatus::NoDisc };
+        self.fad = 0;
+        self.disc = disc;
+        self.stop_audio(AUDIO_NO_STATUS);
+    }
+
+    /// Ends audio playback, dropping the samples not yet played
+    pub fn stop_audio(&mut self, status: u8) {
+        self.playback = None;
+        self.audio_status = status;
+        self.cdda.lock().unwrap().clear();
+    }
+
+    pub fn disc_format(&self) -> DiscFormat {
+        self.disc.as_ref().map(|d| d.format()).unwrap_or(DiscFormat::CdDa)
+    }
+
+    fn interrupt(&mut self) {
+        // nIEN masks the interrupt line
+        if self.device_control & 0x2 == 0 {
+            self.asic.lock().unwrap().raise_external(ExternalInterrupt::GdRom);
+        }
+    }
+
+    /// Ends a command, reporting an error if the sense key is set
+    fn complete(&mut self) {
+        self.phase = Phase::Idle;
+        self.status = STATUS_DRDY;
+        if self.sense_key != SENSE_NONE {
+            self.status |= STATUS_CHECK;
+            self.error = self.sense_key << 4;
+        }
+        self.interrupt_reason = IREASON_IO | IREASON_COD;
+        self.interrupt();
+    }
+
+    fn fail(&mut self, sense_key: u8, sense_code: u8) {
+        self.sense_key = sense_key;
+        self.sense_code = sense_code;
+        self.complete();
+    }
+
+    /// Starts sending data to the host, by PIO or by DMA as selected in
+    /// the features register
+    fn send(&mut self, data: Vec<u8>) {
+        self.data = data;
+        self.position = 0;
+
+        if self.features & 1 != 0 {
+            self.phase = Phase::Dma;
+            self.status = STATUS_DRDY | STATUS_DRQ;
+        } else {
+            self.phase = Phase::PioIn;
+            self.next_chunk();
+        }
+    }
+
+    /// Sends a reply, truncated to the length the host allocated
+    fn reply(&mut self, mut data: Vec<u8>, offset: usize, length: usize) {
+        let offset = cmp::min(offset, data.len());
+        data.drain(..offset);
+        data.truncate(length);
+        self.send(data);
+    }
+
+    fn next_chunk(&mut self) {
+        let remaining = self.data.len() - self.position;
+        if remaining == 0 {
+            self.complete();
+            return;
+        }
+
+        self.chunk = cmp::min(remaining, MAX_PIO_CHUNK);
+        self.byte_count = self.chunk as u16;
+        self.status = STATUS_DRDY | STATUS_DRQ;
+        self.interrupt_reason = IREASON_IO;
+        self.interrupt();
+    }
+
+    /// Starts receiving the given number of bytes from the host
+    fn receive(&mut self, length: usize) {
+        if length == 0 {
+            self.complete();
+            return;
+        }
+
+        self.phase = Phase::PioOut;
+        self.data.clear();
+        self.chunk = length;
+        self.byte_count = length as u16;
+        self.status = STATUS_DRDY | STATUS_DRQ;
+        self.interrupt_reason = 0;
+        self.interrupt();
+    }
+
+    fn read_data(&mut self) -> u32 {
+        if self.phase != Phase::PioIn {
+            return 0;
+        }
+
+        let byte = |i: usize| self.data.get(i).cloned().unwrap_or(0) as u32;
+        let value = byte(self.position) | (byte(self.position + 1) << 8);
+        self.position += 2;
+        self.chunk = self.chunk.saturating_sub(2);
+        if self.chunk == 0 {
+            self.next_chunk();
+        }
+        value
+    }
+
+    fn write_data(&mut self, value: u32) {
+        match self.phase {
+            Phase::Packet => {
+                self.packet.push(value as u8);
+                self.packet.push((value >> 8) as u8);
+                if self.packet.len() >= PACKET_SIZE {
+                    self.status = STATUS_BSY;
+                    let packet = ::std::mem::replace(&mut self.packet, Vec::with_capacity(PACKET_SIZE));
+                    self.execute_packet(&packet);
+                }
+            },
+            Phase::PioOut => {
+                self.data.push(value as u8);
+                self.data.push((value >> 8) as u8);
+                self.chunk = self.chunk.saturating_sub(2);
+                if self.chunk == 0 {
+                    // Only SET_MODE takes data from the host
+                    for (i, &byte) in self.data.iter().enumerate() {
+                        if let Some(slot) = self.mode.get_mut(self.mode_offset + i) {
+                            *slot = byte;
+                        }
+                    }
+                    self.complete();
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn execute_ata(&mut self, command: u8) {
+        self.error = 0;
+        match command {
+            ATA_NOP | ATA_SET_FEATURES => self.complete(),
+            ATA_SOFT_RESET => {
+                self.phase = Phase::Idle;
+                self.packet.clear();
+                self.sense_key = SENSE_NONE;
+                self.status = STATUS_DRDY;
+                self.interrupt_reason = 0;
+            },
+            ATA_DIAGNOSTIC => {
+                self.complete();
+                self.error = 0x01;
+            },
+            ATA_PACKET => {
+                self.phase = Phase::Packet;
+                self.packet.clear();
+                self.status = STATUS_DRDY | STATUS_DRQ;
+                self.interrupt_reason = IREASON_COD;
+            },
+            ATA_IDENTIFY => {
+                let mut data = vec![0x00, 0xB4, 0x19, 0x00, 0x00, 0x08];
+                data.extend_from_slice(b"SE              CD-ROM DRIVE    6.43990316");
+                data.resize(0x50, b' ');
+                self.send(data);
+            },
+            _ => {
+                self.phase = Phase::Idle;
+                self.status = STATUS_DRDY | STATUS_CHECK;
+                // ABRT
+                self.error = 0x04;
+                self.interrupt_reason = IREASON_IO | IREASON_COD;
+                self.interrupt();
+            }
+        }
+    }
+
+    fn execute_packet(&mut self, packet: &[u8]) {
+        let be16 = |i: usize| ((packet[i] as usize) << 8) | packet[i + 1] as usize;
+        let be24 = |i: usize| ((packet[i] as u32) << 16) | ((packet[i + 1] as u32) << 8) | packet[i + 2] as u32;
+
+        // The sense data describes the last command only
+        if packet[0] != SPI_REQ_ERROR {
+            self.sense_key = SENSE_NONE;
+            self.sense_code = 0;
+        }
+
+        match packet[0] {
+            SPI_TEST_UNIT => {
+                if self.disc.is_none() {
+                    self.fail(SENSE_NOT_READY, 0x3A);
+                } else {
+                    self.complete();
+                }
+            },
+            SPI_REQ_STAT => {
+                let (control, track) = match self.disc.as_ref().and_then(|d| d.track_at(self.fad)) {
+                    Some(t) => (t.control(), t.number),
+                    None => (0, 0)
+                };
+                let repeat = self.playback.map(|p| p.repeat).unwrap_or(0);
+                let data = vec![
+                    self.drive_status as u8,
+                    ((self.disc_format() as u8) << 4) | (repeat & 0xF),
+                    (control << 4) | 0x1,
+                    track,
+                    0x01,
+                    (self.fad >> 16) as u8,
+                    (self.fad >> 8) as u8,
+                    self.fad as u8,
+                    0x00,
+                    0x00,
+                ];
+                self.reply(data, packet[2] as usize, packet[4] as usize);
+            },
+            SPI_REQ_MODE => {
+                let data = self.mode.to_vec();
+                self.reply(data, packet[2] as usize, packet[4] as usize);
+            },
+            SPI_SET_MODE => {
+                self.mode_offset = packet[2] as usize;
+                self.receive(packet[4] as usize);
+            },
+            SPI_REQ_ERROR => {
+                let data = vec![0xF0, 0x00, self.sense_key, 0x00, 0x00, 0x00, 0x00, 0x0A, self.sense_code, 0x00];
+                self.sense_key = SENSE_NONE;
+                self.sense_code = 0;
+                self.reply(data, 0, packet[4] as usize);
+            },
+            SPI_GET_TOC => {
+                match self.toc(packet[1] & 1 != 0) {
+                    Some(toc) => self.reply(toc, 0, be16(3)),
+                    None => self.fail(SENSE_NOT_READY, 0x3A)
+                }
+            },
+            SPI_REQ_SES => {
+                let session = packet[2];
+                let info = self.disc.as_ref().and_then(|disc| {
+                    if session == 0 {
+                        Some((disc.sessions(), disc.lead_out()))
+                    } else {
+                        disc.tracks().iter().find(|t| t.session == session).map(|t| (t.number, t.start))
+                    }
+                });
+                match info {
+                    Some((value, fad)) => {
+                        let data = vec![self.drive_status as u8, 0x00, value, (fad >> 16) as u8, (fad >> 8) as u8, fad as u8];
+                        self.reply(data, 0, packet[4] as usize);
+                    },
+                    None => self.fail(SENSE_ILLEGAL_REQUEST, 0x21)
+                }
+            },
+            SPI_CD_PLAY => {
+                if self.disc.is_none() {
+                    self.fail(SENSE_NOT_READY, 0x3A);
+                    return;
+                }
+
+                let address = |i: usize| match packet[1] & 0xF {
+                    2 => msf_to_fad(packet[i], packet[i + 1], packet[i + 2]),
+                    _ => be24(i)
+                };
+                // Type 7 resumes the previous playback where it paused
+                let playback = match (packet[1] & 0xF, self.playback) {
+                    (7, Some(p)) => p,
+                    _ => {
+                        let p = Playback { start: address(2), end: address(8), repeat: packet[6] & 0xF };
+                        self.fad = p.start;
+                        p
+                    }
+                };
+                self.playback = Some(playback);
+                self.audio_status = AUDIO_PLAYING;
+                self.drive_status = DriveStatus::Play;
+                self.complete();
+            },
+            SPI_CD_SEEK => {
+                match packet[1] & 0xF {
+                    1 => self.fad = be24(2),
+                    2 => self.fad = msf_to_fad(packet[2], packet[3], packet[4]),
+                    3 => {
+                        self.stop_audio(AUDIO_NO_STATUS);
+                        self.drive_status = DriveStatus::Standby;
+                        self.complete();
+                        return;
+                    },
+                    _ => ()
+                }
+                // Playback stays set up, to be resumed where it stopped
+                if self.playback.is_some() {
+                    self.audio_status = AUDIO_PAUSED;
+                }
+                self.drive_status = DriveStatus::Pause;
+                self.complete();
+            },
+            SPI_CD_READ => {
+                let start = if packet[1] & 1 != 0 { msf_to_fad(packet[2], packet[3], packet[4]) } else { be24(2) };
+                let count = be24(8);
+                // Data select 0xF asks for whole raw sectors
+                let raw = packet[1] >> 4 == 0xF;
+                match self.read_sectors(start, count, raw) {
+                    Ok(data) => {
+                        self.fad = start + count;
+                        self.stop_audio(AUDIO_NO_STATUS);
+                        self.drive_status = DriveStatus::Pause;
+                        self.send(data);
+                    },
+                    Err((key, code)) => self.fail(key, code)
+                }
+            },
+            SPI_GET_SCD => {
+                let disc = match self.disc {
+                    Some(ref disc) => disc,
+                    None => {
+                        self.fail(SENSE_NOT_READY, 0x3A);
+                        return;
+                    }
+                };
+                // Format 0 is the raw subcode of all channels, format 1
+                // the decoded Q channel
+                let subcode = match packet[1] & 0xF {
+                    0 => disc.subcode(self.fad).to_vec(),
+                    1 => disc.subcode_q(self.fad)[..10].to_vec(),
+                    _ => {
+                        self.fail(SENSE_ILLEGAL_REQUEST, 0x24);
+                        return;
+                    }
+                };
+                let length = subcode.len() + 4;
+                let mut data = vec![0x00, self.audio_status, (length >> 8) as u8, length as u8];
+                data.extend_from_slice(&subcode);
+                self.reply(data, 0, be16(3));
+            },
+            _ => self.fail(SENSE_ILLEGAL_REQUEST, 0x20)
+        }
+    }
+
+    /// Builds the table of contents of one density area: 99 track
+    /// entries, the first and last track and the lead-out
+    pub fn toc(&self, high_density: bool) -> Option<Vec<u8>> {
+        let disc = match self.disc {
+            Some(ref disc) => disc,
+            None => return None
+        };
+        let tracks: Vec<_> = disc.tracks().iter().filter(|t| t.is_high_density() == high_density).collect();
+        if tracks.is_empty() {
+            return None;
+        }
+
+        let mut toc = vec![0xFFu8; 102 * 4];
+        let entry = |toc: &mut Vec<u8>, index: usize, control: u8, value: u32| {
+            toc[index * 4] = (control << 4) | 0x1;
+            toc[index * 4 + 1] = (value >> 16) as u8;
+            toc[index * 4 + 2] = (value >> 8) as u8;
+            toc[index * 4 + 3] = value as u8;
+        };
+
+        for track in tracks.iter().filter(|t| t.number >= 1 && t.number <= 99) {
+            entry(&mut toc, track.number as usize - 1, track.control(), track.start);
+        }
+        let first = tracks[0];
+        let last = tracks[tracks.len() - 1];
+        entry(&mut toc, 99, first.control(), (first.number as u32) << 16);
+        entry(&mut toc, 100, last.control(), (last.number as u32) << 16);
+        entry(&mut toc, 101, last.control(), last.end());
+        Some(toc)
+    }
+
+    /// Reads sectors, keeping their user data unless raw sectors are asked for
+    pub fn read_sectors(&mut self, start: u32, count: u32, raw: bool) -> Result<Vec<u8>, (u8, u8)> {
+        let disc = match self.disc {
+            Some(ref mut disc) => disc,
+            None => return Err((SENSE_NOT_READY, 0x3A))
+        };
+
+        let end = match start.checked_add(count) {
+            Some(end) => end,
+            None => return Err((SENSE_ILLEGAL_REQUEST, 0x21))
+        };
+
+        let mut data = Vec::new();
+        let mut sector = [0u8; RAW_SECTOR_SIZE];
+        for fad in start..end {
+            let mode = match disc.track_at(fad) {
+                Some(track) => track.mode,
+                None => return Err((SENSE_ILLEGAL_REQUEST, 0x21))
+            };
+            if disc.read_sector(fad, &mut sector).is_err() {
+                return Err((SENSE_MEDIUM_ERROR, 0x11));
+            }
+            data.extend_from_slice(if raw { &sector[..] } else { user_data(&sector, mode) });
+        }
+        Ok(data)
+    }
+
+    /// Schedules the first sector period of audio playback
+    pub fn start(&self, scheduler: &mut Scheduler) {
+        scheduler.schedule(CYCLES_PER_SECTOR, Event::CddaSector);
+    }
+
+    /// Handles a sector period from the scheduler and schedules the next
+    pub fn sector_event(&mut self, scheduler: &mut Scheduler) {
+        self.play_sector();
+        scheduler.schedule(CYCLES_PER_SECTOR, Event::CddaSector);
+    }
+
+    /// Streams the sector under the pickup to the sound chip while
+    /// playing, moving on to the next. At the end of the range it is
+    /// played again as often as asked, then the drive pauses.
+    fn play_sector(&mut self) {
+        let playback = match self.playback {
+            Some(playback) if self.drive_status == DriveStatus::Play => playback,
+            _ => return
+        };
+
+        let mut sector = [0u8; RAW_SECTOR_SIZE];
+        let audio = match self.disc {
+            Some(ref mut disc) => {
+                let is_audio = disc.track_at(self.fad).map(|t| t.mode == TrackMode::Audio).unwrap_or(false);
+                is_audio && disc.read_sector(self.fad, &mut sector).is_ok()
+            },
+            None => false
+        };
+        // Data sectors play as silence
+        if !audio {
+            sector = [0u8; RAW_SECTOR_SIZE];
+        }
+
+        {
+            let mut cdda = self.cdda.lock().unwrap();
+            while cdda.len() >= MAX_BUFFERED_SECTORS * SAMPLES_PER_SECTOR {
+                cdda.pop();
+            }
+            for frame in sector.chunks(4) {
+                let left = (frame[0] as u16 | ((frame[1] as u16) << 8)) as i16;
+                let right = (frame[2] as u16 | ((frame[3] as u16) << 8)) as i16;
+                cdda.push(left, right);
+            }
+        }
+
+        self.fad += 1;
+        if self.fad >= playback.end {
+            match playback.repeat {
+                0 => {
+                    self.playback = None;
+                    self.audio_status = AUDIO_COMPLETED;
+                    self.drive_status = DriveStatus::Pause;
+                },
+                repeat => {
+                    self.fad = playback.start;
+                    // 0xF repeats forever
+                    if repeat != 0xF {
+                        self.playback = Some(Playback { repeat: repeat - 1, ..playback });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the drive waits for the GD-ROM DMA to be started
+    pub fn is_pending(&self) -> bool {
+        self.gdst & 1 != 0 && self.phase == Phase::Dma
+    }
+
+    /// Moves the drive's data into system memory for a started DMA,
+    /// raising the DMA interrupt and, once all data is taken, ending the
+    /// command
+    pub fn run(&mut self, mem: &mut Memory) {
+        if !self.is_pending() {
+            return;
+        }
+
+        let address = (self.gdstar & 0x1FFFFFE0) as usize;
+        let length = cmp::min(self.gdlen as usize, self.data.len() - self.position);
+        for (i, bytes) in self.data[self.position..self.position + length].chunks(4).enumerate() {
+            if bytes.len() == 4 {
+                let word = (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24);
+                mem.write_u32(address + i * 4, word);
+            } else {
+                for (j, &byte) in bytes.iter().enumerate() {
+                    mem.write_u8(address + i * 4 + j, byte);
+                }
+            }
+        }
+        self.position += length;
+
+        self.gdstard = (address + length) as u32;
+        self.gdlend = length as u32;
+        self.gdst = 0;
+        self.asic.lock().unwrap().raise(Interrupt::GdRomDmaDone);
+
+        if self.position >= self.data.len() {
+            self.complete();
+        }
+    }
+}
+
+impl MappedDevice for GdRom {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address & !3 {
+            GD_ALTSTAT_DEVCTRL => self.status as u32,
+            GD_DATA            => self.read_data(),
+            GD_ERROR_FEATURES  => self.error as u32,
+            GD_IREASON_SECTCNT => self.interrupt_reason as u32,
+            GD_SECTNUM         => ((self.disc_format() as u32) << 4) | self.drive_status as u32,
+            GD_BYCTLLO         => (self.byte_count & 0xFF) as u32,
+            GD_BYCTLHI         => (self.byte_count >> 8) as u32,
+            GD_DRVSEL          => self.drive_select as u32,
+            GD_STATUS_COMMAND  => {
+                // Reading the status acknowledges the interrupt
+                self.asic.lock().unwrap().clear_external(ExternalInterrupt::GdRom);
+                self.status as u32
+            },
+            SB_GDSTAR          => self.gdstar,
+            SB_GDLEN           => self.gdlen,
+            SB_GDDIR           => self.gddir,
+            SB_GDEN            => self.gden,
+            SB_GDST            => self.gdst,
+            SB_GDSTARD         => self.gdstard,
+            SB_GDLEND          => self.gdlend,
+            _                  => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address & !3 {
+            GD_ALTSTAT_DEVCTRL => self.device_control = value as u8,
+            GD_DATA            => self.write_data(value),
+            GD_ERROR_FEATURES  => self.features = value as u8,
+            GD_IREASON_SECTCNT => self.sector_count = value as u8,
+            GD_BYCTLLO         => self.byte_count = (self.byte_count & 0xFF00) | (value & 0xFF) as u16,
+            GD_BYCTLHI         => self.byte_count = (self.byte_count & 0x00FF) | ((value & 0xFF) << 8) as u16,
+            GD_DRVSEL          => self.drive_select = value as u8,
+            GD_STATUS_COMMAND  => self.execute_ata(value as u8),
+            SB_GDSTAR          => self.gdstar = value & 0x1FFFFFE0,
+            SB_GDLEN           => self.gdlen = value & 0x01FFFFFE,
+            SB_GDDIR           => self.gddir = value & 1,
+            SB_GDEN            => self.gden = value & 1,
+            SB_GDST if value & 1 != 0 && self.gden & 1 != 0 => self.gdst = 1,
+            _                  => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::{Track, DATA_SECTOR_SIZE};
+    use memory::TestMemory;
+    use std::io;
+
+    /// A GD-ROM with an audio and a data track in the low density area
+    /// and a data track in the high density one. Every byte of a sector
+    /// holds the low byte of its frame address.
+    struct TestDisc {
+        tracks: Vec<Track>,
+    }
+
+    impl TestDisc {
+        fn new() -> TestDisc {
+            TestDisc {
+                tracks: vec![
+                    Track { number: 1, session: 1, start: 150, length: 10, mode: TrackMode::Audio },
+                    Track { number: 2, session: 1, start: 160, length: 20, mode: TrackMode::Mode1 },
+                    Track { number: 3, session: 2, start: 45150, length: 10, mode: TrackMode::Mode1 },
+                ],
+            }
+        }
+    }
+
+    impl Disc for TestDisc {
+        fn format(&self) -> DiscFormat {
+            DiscFormat::GdRom
+        }
+
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+            for byte in buffer.iter_mut() {
+                *byte = fad as u8;
+            }
+            Ok(())
+        }
+    }
+
+    fn asic() -> Arc<Mutex<Asic>> {
+        Arc::new(Mutex::new(Asic {
+            istnrm: 0,
+            istext: 0,
+            isterr: 0,
+            iml_nrm: [0; 3],
+            iml_ext: [0; 3],
+            iml_err: [0; 3],
+        }))
+    }
+
+    fn drive() -> GdRom {
+        let mut gdrom = GdRom::unmapped(asic());
+        gdrom.insert(Some(Box::new(TestDisc::new())));
+        gdrom
+    }
+
+    fn send_packet(gdrom: &mut GdRom, packet: [u8; 12]) {
+        gdrom.write(GD_STATUS_COMMAND, ATA_PACKET as u32, 1);
+        assert_eq!(gdrom.read(GD_IREASON_SECTCNT, 1) as u8, IREASON_COD);
+        for pair in packet.chunks(2) {
+            gdrom.write(GD_DATA, pair[0] as u32 | ((pair[1] as u32) << 8), 2);
+        }
+    }
+
+    /// Reads everything the drive has to send through the data register
+    fn read_reply(gdrom: &mut GdRom) -> Vec<u8> {
+        let mut data = Vec::new();
+        while gdrom.status & STATUS_DRQ != 0 {
+            let value = gdrom.read(GD_DATA, 2);
+            data.push(value as u8);
+            data.push((value >> 8) as u8);
+        }
+        data
+    }
+
+    fn cd_read(start: u32, count: u32) -> [u8; 12] {
+        [SPI_CD_READ, 0x20, (start >> 16) as u8, (start >> 8) as u8, start as u8, 0, 0, 0,
+         (count >> 16) as u8, (count >> 8) as u8, count as u8, 0]
+    }
+
+    #[test]
+    fn reports_the_drive_status() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, [SPI_CD_SEEK, 1, 0, 0, 165, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(gdrom.asic.lock().unwrap().istext & (1 << ExternalInterrupt::GdRom as u32) != 0);
+        // Reading the status acknowledges the interrupt
+        assert_eq!(gdrom.read(GD_STATUS_COMMAND, 1) as u8, STATUS_DRDY);
+        assert_eq!(gdrom.asic.lock().unwrap().istext, 0);
+
+        send_packet(&mut gdrom, [SPI_REQ_STAT, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(gdrom.byte_count, 10);
+        assert_eq!(read_reply(&mut gdrom), vec![DriveStatus::Pause as u8, 0x80, 0x41, 2, 1, 0, 0, 165, 0, 0]);
+        assert_eq!(gdrom.interrupt_reason, IREASON_IO | IREASON_COD);
+
+        // An offset and a shorter allocation cut the reply
+        send_packet(&mut gdrom, [SPI_REQ_STAT, 0, 2, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_reply(&mut gdrom), vec![0x41, 2]);
+    }
+
+    #[test]
+    fn builds_the_toc_of_each_area() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, [SPI_GET_TOC, 0, 0, 0x01, 0x98, 0, 0, 0, 0, 0, 0, 0]);
+        let toc = read_reply(&mut gdrom);
+        assert_eq!(toc.len(), 408);
+        assert_eq!(&toc[0..8], &[0x01, 0, 0, 150, 0x41, 0, 0, 160]);
+        assert_eq!(&toc[8..12], &[0xFF; 4]);
+        assert_eq!(&toc[396..408], &[0x01, 1, 0, 0, 0x41, 2, 0, 0, 0x41, 0, 0, 180]);
+
+        send_packet(&mut gdrom, [SPI_GET_TOC, 1, 0, 0x01, 0x98, 0, 0, 0, 0, 0, 0, 0]);
+        let toc = read_reply(&mut gdrom);
+        assert_eq!(&toc[0..8], &[0xFF; 8]);
+        assert_eq!(&toc[8..12], &[0x41, 0x00, 0xB0, 0x5E]);
+        assert_eq!(&toc[396..408], &[0x41, 3, 0, 0, 0x41, 3, 0, 0, 0x41, 0x00, 0xB0, 0x68]);
+    }
+
+    #[test]
+    fn reads_sectors_by_pio_in_chunks() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, cd_read(160, 20));
+        assert_eq!(gdrom.byte_count as usize, MAX_PIO_CHUNK);
+        assert_eq!(gdrom.interrupt_reason, IREASON_IO);
+
+        let mut data = Vec::new();
+        for _ in 0..MAX_PIO_CHUNK / 2 {
+            let value = gdrom.read(GD_DATA, 2);
+            data.push(value as u8);
+            data.push((value >> 8) as u8);
+        }
+        // The rest follows after another interrupt
+        assert_eq!(gdrom.byte_count as usize, 20 * DATA_SECTOR_SIZE - MAX_PIO_CHUNK);
+        data.extend(read_reply(&mut gdrom));
+
+        assert_eq!(data.len(), 20 * DATA_SECTOR_SIZE);
+        assert!(data[..DATA_SECTOR_SIZE].iter().all(|&b| b == 160));
+        assert!(data[19 * DATA_SECTOR_SIZE..].iter().all(|&b| b == 179));
+        assert_eq!(gdrom.status, STATUS_DRDY);
+        assert_eq!(gdrom.fad, 180);
+    }
+
+    #[test]
+    fn reads_sectors_by_dma() {
+        let mut mem = TestMemory::new();
+        let asic = Asic::new(&mut mem);
+        let gdrom = GdRom::new(&mut mem, asic.clone());
+        let mut gdrom = gdrom.lock().unwrap();
+        gdrom.insert(Some(Box::new(TestDisc::new())));
+
+        gdrom.write(GD_ERROR_FEATURES, 1, 1);
+        send_packet(&mut gdrom, cd_read(161, 2));
+        assert_eq!(gdrom.status, STATUS_DRDY | STATUS_DRQ);
+        assert!(!gdrom.is_pending());
+
+        // Half of the data, then the rest
+        gdrom.write(SB_GDSTAR, 0x0C010000, 4);
+        gdrom.write(SB_GDLEN, DATA_SECTOR_SIZE as u32, 4);
+        gdrom.write(SB_GDEN, 1, 4);
+        gdrom.write(SB_GDST, 1, 4);
+        gdrom.run(&mut mem);
+        assert_eq!(gdrom.read(SB_GDST, 4), 0);
+        assert_eq!(gdrom.read(SB_GDLEND, 4), DATA_SECTOR_SIZE as u32);
+        assert!(asic.lock().unwrap().istnrm & (1 << Interrupt::GdRomDmaDone as u32) != 0);
+        assert_eq!(gdrom.status, STATUS_DRDY | STATUS_DRQ);
+
+        gdrom.write(SB_GDSTAR, 0x0C010800, 4);
+        gdrom.write(SB_GDST, 1, 4);
+        gdrom.run(&mut mem);
+        assert_eq!(gdrom.status, STATUS_DRDY);
+        assert_eq!(gdrom.read(SB_GDSTARD, 4), 0x0C011000);
+
+        assert_eq!(mem.read_u32(0x0C010000), 0xA1A1A1A1);
+        assert_eq!(mem.read_u32(0x0C0107FC), 0xA1A1A1A1);
+        assert_eq!(mem.read_u32(0x0C010800), 0xA2A2A2A2);
+        assert_eq!(mem.read_u32(0x0C011000), 0);
+    }
+
+    #[test]
+    fn reports_errors_through_req_error() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, cd_read(200, 1));
+        assert_eq!(gdrom.status, STATUS_DRDY | STATUS_CHECK);
+        assert_eq!(gdrom.error, SENSE_ILLEGAL_REQUEST << 4);
+
+        send_packet(&mut gdrom, [SPI_REQ_ERROR, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_reply(&mut gdrom), vec![0xF0, 0, SENSE_ILLEGAL_REQUEST, 0, 0, 0, 0, 0x0A, 0x21, 0]);
+        assert_eq!(gdrom.status, STATUS_DRDY);
+
+        // The sense is cleared once read
+        send_packet(&mut gdrom, [SPI_REQ_ERROR, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read_reply(&mut gdrom)[2], SENSE_NONE);
+
+        gdrom.insert(None);
+        send_packet(&mut gdrom, [SPI_TEST_UNIT, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        send_packet(&mut gdrom, [SPI_REQ_ERROR, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0]);
+        let sense = read_reply(&mut gdrom);
+        assert_eq!((sense[2], sense[8]), (SENSE_NOT_READY, 0x3A));
+    }
+    fn cd_play(start: u32, end: u32, repeat: u8) -> [u8; 12] {
+        [SPI_CD_PLAY, 1, (start >> 16) as u8, (start >> 8) as u8, start as u8, 0, repeat, 0,
+         (end >> 16) as u8, (end >> 8) as u8, end as u8, 0]
+    }
+
+    /// The stereo sample of a sector whose bytes all hold the same value
+    fn sample(byte: u8) -> (i16, i16) {
+        let value = (byte as u16 * 0x101) as i16;
+        (value, value)
+    }
+
+    fn audio_status(gdrom: &mut GdRom) -> u8 {
+        send_packet(gdrom, [SPI_GET_SCD, 1, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0]);
+        read_reply(gdrom)[1]
+    }
+
+    #[test]
+    fn repeats_audio_then_completes() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, cd_play(150, 152, 1));
+        assert_eq!(gdrom.drive_status, DriveStatus::Play);
+        assert_eq!(audio_status(&mut gdrom), AUDIO_PLAYING);
+
+        gdrom.play_sector();
+        gdrom.play_sector();
+        assert_eq!(gdrom.fad, 150);
+        assert_eq!(gdrom.playback, Some(Playback { start: 150, end: 152, repeat: 0 }));
+
+        gdrom.play_sector();
+        gdrom.play_sector();
+        assert_eq!(gdrom.playback, None);
+        assert_eq!(gdrom.drive_status, DriveStatus::Pause);
+        assert_eq!(audio_status(&mut gdrom), AUDIO_COMPLETED);
+
+        // Each sector turns into little endian stereo samples
+        let mut cdda = gdrom.cdda.lock().unwrap();
+        assert_eq!(cdda.len(), 4 * SAMPLES_PER_SECTOR);
+        assert_eq!(cdda.pop(), Some(sample(0x96)));
+        for _ in 0..SAMPLES_PER_SECTOR {
+            cdda.pop();
+        }
+        assert_eq!(cdda.pop(), Some(sample(0x97)));
+    }
+
+    #[test]
+    fn pauses_and_resumes_where_it_stopped() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, cd_play(150, 160, 0xF));
+        gdrom.play_sector();
+        gdrom.play_sector();
+
+        send_packet(&mut gdrom, [SPI_CD_SEEK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(gdrom.drive_status, DriveStatus::Pause);
+        assert_eq!(audio_status(&mut gdrom), AUDIO_PAUSED);
+        gdrom.play_sector();
+        assert_eq!(gdrom.fad, 152);
+        assert_eq!(gdrom.cdda.lock().unwrap().len(), 2 * SAMPLES_PER_SECTOR);
+
+        send_packet(&mut gdrom, [SPI_CD_PLAY, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(audio_status(&mut gdrom), AUDIO_PLAYING);
+        for _ in 0..8 {
+            gdrom.play_sector();
+        }
+        // Repeating forever, back at the start
+        assert_eq!(gdrom.fad, 150);
+        assert_eq!(gdrom.playback, Some(Playback { start: 150, end: 160, repeat: 0xF }));
+
+        // Stopping forgets the playback
+        send_packet(&mut gdrom, [SPI_CD_SEEK, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(gdrom.playback, None);
+        assert_eq!(gdrom.drive_status, DriveStatus::Standby);
+        assert_eq!(audio_status(&mut gdrom), AUDIO_NO_STATUS);
+    }
+
+    #[test]
+    fn plays_data_sectors_as_silence() {
+        let mut gdrom = drive();
+        send_packet(&mut gdrom, cd_play(159, 161, 0));
+        gdrom.play_sector();
+        gdrom.play_sector();
+        let mut cdda = gdrom.cdda.lock().unwrap();
+        assert_eq!(cdda.pop(), Some(sample(0x9F)));
+        for _ in 0..SAMPLES_PER_SECTOR - 1 {
+            cdda.pop();
+        }
+        assert_eq!(cdda.pop(), Some((0, 0)));
+    }
+}
@@ -0,0 +1,475 @@
+use Cpu;
+use Memory;
+use Operand;
+use GdRom;
+use cpu::Trap;
+use disc::{DiscFormat, boot_track};
+use disc::iso9660::Filesystem;
+use ip_bin::IpBin;
+use loader::descramble;
+use disc::image::invalid_data;
+use gdrom::{Playback, DriveStatus, AUDIO_PLAYING, AUDIO_PAUSED, AUDIO_NO_STATUS, SENSE_NOT_READY};
+use machine::Machine;
+use rom::{Flash, FLASH_SIZE, FLASH_PARTITIONS, FLASH_FACTORY_PARTITION};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Vectors the BIOS fills with the entry points of its system calls
+pub const VECTOR_SYSINFO  : usize = 0x8C0000B0;
+pub const VECTOR_ROMFONT  : usize = 0x8C0000B4;
+pub const VECTOR_FLASHROM : usize = 0x8C0000B8;
+pub const VECTOR_GDROM    : usize = 0x8C0000BC;
+pub const VECTOR_MISC     : usize = 0x8C0000E0;
+
+/// Where the entry points are placed, one word apart. Each holds an
+/// rts, so that nothing breaks should the trap be missing.
+const ENTRY_BASE : usize = 0x8C000800;
+const ENTRIES : usize = (VECTOR_MISC - VECTOR_SYSINFO) / 4 + 1;
+
+/// Where the bootstrap and the program are loaded
+pub const IP_BIN_ADDRESS  : usize = 0x8C008000;
+pub const PROGRAM_ADDRESS : usize = 0x8C010000;
+/// The bootstrap code in IP.BIN, which shows the license screen and
+/// starts the program
+const BOOTSTRAP_ADDRESS : usize = 0xAC008300;
+
+/// State the BIOS leaves the CPU in
+const BOOT_SR    : u32 = 0x600000F0;
+const BOOT_VBR   : u32 = 0x8C000000;
+const BOOT_STACK : u32 = 0x8C00F400;
+
+/// Where SYSINFO_INIT copies the console's unique id to
+const SYSTEM_ID_ADDRESS : usize = 0x8C000068;
+const FLASH_SYSTEM_ID   : usize = FLASH_FACTORY_PARTITION + 0x56;
+
+/// GD-ROM commands sent through the system call
+const GD_PIOREAD  : u32 = 16;
+const GD_DMAREAD  : u32 = 17;
+const GD_GETTOC   : u32 = 18;
+const GD_GETTOC2  : u32 = 19;
+const GD_PLAY     : u32 = 20;
+const GD_PLAY2    : u32 = 21;
+const GD_PAUSE    : u32 = 22;
+const GD_RELEASE  : u32 = 23;
+const GD_SEEK     : u32 = 27;
+const GD_STOP     : u32 = 33;
+
+/// States of a GD-ROM command, as CHECK_COMMAND returns them
+const COMMAND_NONE      : u32 = 0;
+const COMMAND_COMPLETED : u32 = 2;
+const COMMAND_FAILED    : u32 = 0xFFFFFFFF;
+
+fn register(cpu: &Cpu, n: u8) -> u32 {
+    cpu[Operand::RegisterOperand(n)].value
+}
+
+fn set_result(cpu: &mut Cpu, value: u32) {
+    cpu[Operand::RegisterOperand(0)].value = value;
+}
+
+/// The BIOS system calls, serviced on the host. GD-ROM commands are
+/// carried out at once, so they are complete by the first check.
+pub struct Hle {
+    gdrom: Arc<Mutex<GdRom>>,
+    flash: Arc<Mutex<Flash>>,
+    /// Set when the program asks for the BIOS menu
+    menu: Arc<Mutex<bool>>,
+    /// Id of the last GD-ROM command and the sense key it failed with
+    request: u32,
+    error: Option<u8>,
+}
+
+impl Hle {
+    pub fn new(gdrom: Arc<Mutex<GdRom>>, flash: Arc<Mutex<Flash>>, menu: Arc<Mutex<bool>>) -> Hle {
+        Hle {
+            gdrom,
+            flash,
+            menu,
+            request: 0,
+            error: None,
+        }
+    }
+
+    /// Points the vectors at the entry points the trap serves
+    pub fn install_vectors(mem: &mut Memory) {
+        for i in 0..ENTRIES {
+            let entry = ENTRY_BASE + i * 4;
+            mem.write_u32(VECTOR_SYSINFO + i * 4, entry as u32);
+            // rts; nop
+            mem.load(entry, &[0x0B, 0x00, 0x09, 0x00]);
+        }
+    }
+
+    fn sysinfo(&mut self, cpu: &mut Cpu, mem: &mut Memory) {
+        let result = match register(cpu, 7) {
+            // INIT
+            0 => {
+                let flash = self.flash.lock().unwrap();
+                mem.write_bytes(SYSTEM_ID_ADDRESS, &flash.data[FLASH_SYSTEM_ID..FLASH_SYSTEM_ID + 8]);
+                0
+            },
+            // ID
+            3 => SYSTEM_ID_ADDRESS as u32,
+            // ICON, which lives in the boot ROM
+            _ => COMMAND_FAILED
+        };
+        set_result(cpu, result);
+    }
+
+    fn romfont(&mut self, cpu: &mut Cpu) {
+        // ADDRESS fails, as the font is in the boot ROM there is none of.
+        // LOCK and UNLOCK always succeed.
+        let result = match register(cpu, 1) {
+            0 => COMMAND_FAILED,
+            _ => 0
+        };
+        set_result(cpu, result);
+    }
+
+    fn flashrom(&mut self, cpu: &mut Cpu, mem: &mut Memory) {
+        let (r4, r5, r6) = (register(cpu, 4) as usize, register(cpu, 5) as usize, register(cpu, 6) as usize);
+        let mut flash = self.flash.lock().unwrap();
+
+        let result = match register(cpu, 7) {
+            // INFO: the offset and size of a partition
+            0 => match FLASH_PARTITIONS.get(r4) {
+                Some(&(offset, size)) => {
+                    mem.write_u32(r5, offset as u32);
+                    mem.write_u32(r5 + 4, size as u32);
+                    0
+                },
+                None => COMMAND_FAILED
+            },
+            // READ
+            1 => {
+                let end = (r4 + r6).min(flash.data.len());
+                mem.write_bytes(r5, &flash.data[r4.min(end)..end]);
+                0
+            },
+            // WRITE
+            2 => {
+                let data = mem.read_bytes(r5, r6.min(FLASH_SIZE.saturating_sub(r4)));
+                flash.program(r4, &data) as u32
+            },
+            // DELETE, erasing the partition starting at an offset
+            3 => match FLASH_PARTITIONS.iter().find(|&&(offset, _)| offset == r4) {
+                Some(&(offset, _)) => {
+                    flash.erase_sector(offset);
+                    0
+                },
+                None => COMMAND_FAILED
+            },
+            _ => COMMAND_FAILED
+        };
+        set_result(cpu, result);
+    }
+
+    fn gdrom(&mut self, cpu: &mut Cpu, mem: &mut Memory) {
+        let (r4, r5) = (register(cpu, 4) as usize, register(cpu, 5) as usize);
+
+        let result = match register(cpu, 7) {
+            // SEND_COMMAND, returning the id to check it by
+            0 => {
+                let params: Vec<u32> = (0..4).map(|i| mem.read_u32(r5 + i * 4)).collect();
+                self.error = self.command(r4 as u32, &params, mem).err();
+                self.request = self.request.wrapping_add(1).max(1);
+                self.request
+            },
+            // CHECK_COMMAND
+            1 => {
+                if r4 as u32 != self.request {
+                    COMMAND_NONE
+                } else {
+                    mem.write_u32(r5, self.error.unwrap_or(0) as u32);
+                    for i in 1..4 {
+                        mem.write_u32(r5 + i * 4, 0);
+                    }
+                    if self.error.is_some() { COMMAND_FAILED } else { COMMAND_COMPLETED }
+                }
+            },
+            // CHECK_DRIVE: the drive status and the disc format
+            4 => {
+                let gdrom = self.gdrom.lock().unwrap();
+                mem.write_u32(r4, gdrom.drive_status as u32);
+                mem.write_u32(r4 + 4, (gdrom.disc_format() as u32) << 4);
+                0
+            },
+            // MAINLOOP, INIT, RESET, ABORT, the DMA calls and SECTOR_MODE
+            _ => 0
+        };
+        set_result(cpu, result);
+    }
+
+    /// Carries out a GD-ROM command, failing with a sense key
+    fn command(&mut self, command: u32, params: &[u32], mem: &mut Memory) -> Result<(), u8> {
+        let mut gdrom = self.gdrom.lock().unwrap();
+        if gdrom.disc.is_none() {
+            return Err(SENSE_NOT_READY);
+        }
+
+        match command {
+            GD_PIOREAD | GD_DMAREAD => {
+                let (start, count, buffer) = (params[0], params[1], params[2] as usize);
+                let data = gdrom.read_sectors(start, count, false).map_err(|(key, _)| key)?;
+                mem.write_bytes(buffer, &data);
+                gdrom.fad = start + count;
+                gdrom.stop_audio(AUDIO_NO_STATUS);
+                gdrom.drive_status = DriveStatus::Pause;
+            },
+            // The table of contents, as one word per entry
+            GD_GETTOC | GD_GETTOC2 => {
+                let high_density = command == GD_GETTOC2 && params[0] == 1;
+                let toc = gdrom.toc(high_density).ok_or(SENSE_NOT_READY)?;
+                for (i, entry) in toc.chunks(4).enumerate() {
+                    let word = ((entry[0] as u32) << 24) | ((entry[1] as u32) << 16) | ((entry[2] as u32) << 8) | entry[3] as u32;
+                    mem.write_u32(params[1] as usize + i * 4, word);
+                }
+            },
+            GD_PLAY | GD_PLAY2 => {
+                let (start, end) = if command == GD_PLAY {
+                    let tracks = gdrom.disc.as_ref().map(|d| d.tracks().to_vec()).unwrap_or_default();
+                    let track = |number: u32| tracks.iter().find(|t| t.number as u32 == number).cloned();
+                    match (track(params[0]), track(params[1])) {
+                        (Some(first), Some(last)) => (first.start, last.end()),
+                        _ => return Err(SENSE_NOT_READY)
+                    }
+                } else {
+                    (params[0], params[1])
+                };
+                gdrom.fad = start;
+                gdrom.playback = Some(Playback { start, end, repeat: params[2] as u8 & 0xF });
+                gdrom.audio_status = AUDIO_PLAYING;
+                gdrom.drive_status = DriveStatus::Play;
+            },
+            GD_PAUSE => {
+                if gdrom.playback.is_some() {
+                    gdrom.audio_status = AUDIO_PAUSED;
+                }
+                gdrom.drive_status = DriveStatus::Pause;
+            },
+            GD_RELEASE if gdrom.playback.is_some() => {
+                gdrom.audio_status = AUDIO_PLAYING;
+                gdrom.drive_status = DriveStatus::Play;
+            },
+            GD_SEEK => {
+                gdrom.fad = params[0];
+                gdrom.drive_status = DriveStatus::Pause;
+            },
+            GD_STOP => {
+                gdrom.stop_audio(AUDIO_NO_STATUS);
+                gdrom.drive_status = DriveStatus::Standby;
+            },
+            // INIT and the commands without effect here
+            _ => ()
+        }
+        Ok(())
+    }
+
+    fn misc(&mut self, cpu: &mut Cpu) {
+        // 1 asks to go back to the BIOS menu, which there is none of
+        if register(cpu, 4) == 1 {
+            *self.menu.lock().unwrap() = true;
+        }
+        set_result(cpu, 0);
+    }
+}
+
+impl Trap for Hle {
+    fn handles(&self, address: usize) -> bool {
+        let base = ENTRY_BASE & 0x1FFFFFFF;
+        address >= base && address < base + ENTRIES * 4
+    }
+
+    fn call(&mut self, cpu: &mut Cpu, mem: &mut Memory, address: usize) {
+        let vector = VECTOR_SYSINFO + (address - (ENTRY_BASE & 0x1FFFFFFF));
+        match vector {
+            VECTOR_SYSINFO  => self.sysinfo(cpu, mem),
+            VECTOR_ROMFONT  => self.romfont(cpu),
+            VECTOR_FLASHROM => self.flashrom(cpu, mem),
+            // The super function in r6 selects the GD-ROM calls with 0
+            VECTOR_GDROM if register(cpu, 6) == 0 => self.gdrom(cpu, mem),
+            VECTOR_MISC     => self.misc(cpu),
+            _               => set_result(cpu, 0)
+        }
+    }
+}
+
+/// Boots the disc in the drive without a boot ROM: loads IP.BIN and the
+/// program it names, descrambled when the disc is a CD, sets up the
+/// system call vectors and leaves the CPU as the BIOS would, about to
+/// run the bootstrap in IP.BIN.
+pub fn boot(machine: &mut Machine) -> io::Result<()> {
+    let (ip, program) = {
+        let mut gdrom = machine.gdrom.lock().unwrap();
+        let disc = gdrom.disc.as_mut().ok_or_else(|| invalid_data("there is no disc to boot"))?;
+        let track = boot_track(&**disc)?;
+
+        let (info, ip) = IpBin::read(&mut **disc)?;
+        let program = Filesystem::open_track(&mut **disc, &track)?.read(&info.boot_filename)?;
+        // Only programs on GD-ROMs are stored as they are
+        match disc.format() {
+            DiscFormat::GdRom => (ip, program),
+            _ => (ip, descramble(&program))
+        }
+    };
+
+    machine.mem.load(IP_BIN_ADDRESS, &ip);
+    machine.mem.load(PROGRAM_ADDRESS, &program);
+    prepare(machine);
+    machine.cpu.pc = BOOTSTRAP_ADDRESS;
+    Ok(())
+}
+
+/// Sets up the system call vectors and their trap, and the registers
+/// as the BIOS leaves them when starting a program
+pub fn prepare(machine: &mut Machine) {
+    Hle::install_vectors(&mut machine.mem);
+
+    let cpu = &mut machine.cpu;
+    cpu.reset();
+    // A trap installed by an earlier boot is replaced
+    cpu.traps.retain(|t| !t.handles(ENTRY_BASE & 0x1FFFFFFF));
+    cpu.traps.push(Box::new(Hle::new(machine.gdrom.clone(), machine.flash.clone(), machine.bios_menu.clone())));
+    cpu.status.value = BOOT_SR;
+    cpu.vbr.value = BOOT_VBR;
+    cpu[Operand::RegisterOperand(15)].value = BOOT_STACK;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Asic;
+    use disc::{Disc, Track, TrackMode, DATA_SECTOR_SIZE};
+    use gdrom::SENSE_ILLEGAL_REQUEST;
+    use memory::TestMemory;
+
+    /// A CD with one data track, every byte of a sector holding the low
+    /// byte of its frame address
+    struct TestDisc {
+        tracks: Vec<Track>,
+    }
+
+    impl Disc for TestDisc {
+        fn format(&self) -> DiscFormat {
+            DiscFormat::CdRom
+        }
+
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector(&mut self, fad: u32, buffer: &mut [u8]) -> io::Result<()> {
+            for byte in buffer.iter_mut() {
+                *byte = fad as u8;
+            }
+            Ok(())
+        }
+    }
+
+    const PARAMS : usize = 0x8C00E000;
+    const BUFFER : usize = 0x8C010000;
+
+    fn hle(mem: &mut Memory) -> Hle {
+        let asic = Asic::new(mem);
+        let gdrom = GdRom::new(mem, asic);
+        gdrom.lock().unwrap().insert(Some(Box::new(TestDisc {
+            tracks: vec![Track { number: 1, session: 1, start: 150, length: 100, mode: TrackMode::Mode1 }],
+        })));
+        Hle::new(gdrom, Arc::new(Mutex::new(Flash::blank())), Arc::new(Mutex::new(false)))
+    }
+
+    fn call(cpu: &mut Cpu, registers: &[(u8, u32)]) {
+        for &(n, value) in registers {
+            cpu[Operand::RegisterOperand(n)].value = value;
+        }
+    }
+
+    /// Sends a GD-ROM command and checks it, returning the state and
+    /// the first status word
+    fn gd_command(hle: &mut Hle, cpu: &mut Cpu, mem: &mut Memory, command: u32, params: &[u32]) -> (u32, u32) {
+        for (i, &param) in params.iter().enumerate() {
+            mem.write_u32(PARAMS + i * 4, param);
+        }
+        call(cpu, &[(4, command), (5, PARAMS as u32), (7, 0)]);
+        hle.gdrom(cpu, mem);
+        let request = register(cpu, 0);
+
+        call(cpu, &[(4, request), (5, PARAMS as u32), (7, 1)]);
+        hle.gdrom(cpu, mem);
+        (register(cpu, 0), mem.read_u32(PARAMS))
+    }
+
+    #[test]
+    fn reads_sectors_into_ram() {
+        let mut mem = TestMemory::new();
+        let mut hle = hle(&mut mem);
+        let mut cpu = Cpu::new();
+
+        assert_eq!(gd_command(&mut hle, &mut cpu, &mut mem, GD_PIOREAD, &[160, 2, BUFFER as u32, 0]), (COMMAND_COMPLETED, 0));
+        assert_eq!(mem.read_u32(BUFFER), 0xA0A0A0A0);
+        assert_eq!(mem.read_u32(BUFFER + DATA_SECTOR_SIZE), 0xA1A1A1A1);
+        assert_eq!(mem.read_u32(BUFFER + 2 * DATA_SECTOR_SIZE), 0);
+        {
+            let gdrom = hle.gdrom.lock().unwrap();
+            assert_eq!(gdrom.fad, 162);
+            assert_eq!(gdrom.drive_status, DriveStatus::Pause);
+        }
+
+        // Outside the disc the command fails with the drive's sense key
+        let result = gd_command(&mut hle, &mut cpu, &mut mem, GD_PIOREAD, &[300, 1, BUFFER as u32, 0]);
+        assert_eq!(result, (COMMAND_FAILED, SENSE_ILLEGAL_REQUEST as u32));
+
+        // An older request is no longer known
+        call(&mut cpu, &[(4, 1), (5, PARAMS as u32), (7, 1)]);
+        hle.gdrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), COMMAND_NONE);
+
+        hle.gdrom.lock().unwrap().insert(None);
+        let result = gd_command(&mut hle, &mut cpu, &mut mem, GD_PIOREAD, &[160, 1, BUFFER as u32, 0]);
+        assert_eq!(result, (COMMAND_FAILED, SENSE_NOT_READY as u32));
+    }
+
+    #[test]
+    fn keeps_flash_calls_in_bounds() {
+        let mut mem = TestMemory::new();
+        let mut hle = hle(&mut mem);
+        let mut cpu = Cpu::new();
+
+        // INFO
+        call(&mut cpu, &[(4, 0), (5, PARAMS as u32), (7, 0)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), 0);
+        assert_eq!((mem.read_u32(PARAMS), mem.read_u32(PARAMS + 4)), (0x1A000, 0x2000));
+        call(&mut cpu, &[(4, FLASH_PARTITIONS.len() as u32)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), COMMAND_FAILED);
+
+        // WRITE stops at the end of the flash
+        mem.write_u32(BUFFER, 0x12345678);
+        mem.write_u32(BUFFER + 4, 0);
+        call(&mut cpu, &[(4, FLASH_SIZE as u32 - 2), (5, BUFFER as u32), (6, 8), (7, 2)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), 2);
+        assert_eq!(&hle.flash.lock().unwrap().data[FLASH_SIZE - 2..], &[0x78, 0x56]);
+
+        // READ as well
+        mem.write_u32(BUFFER, 0xAAAAAAAA);
+        call(&mut cpu, &[(4, FLASH_SIZE as u32 - 2), (5, BUFFER as u32), (6, 8), (7, 1)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), 0);
+        assert_eq!(mem.read_u32(BUFFER), 0xAAAA5678);
+        call(&mut cpu, &[(4, FLASH_SIZE as u32 + 0x100)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(mem.read_u32(BUFFER), 0xAAAA5678);
+
+        // DELETE only takes the start of a partition
+        call(&mut cpu, &[(4, 0x1C001), (7, 3)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), COMMAND_FAILED);
+        call(&mut cpu, &[(4, 0x1C000)]);
+        hle.flashrom(&mut cpu, &mut mem);
+        assert_eq!(register(&cpu, 0), 0);
+        assert_eq!(hle.flash.lock().unwrap().data[FLASH_SIZE - 1], 0xFF);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Largest payload of a stored deflate block
+const STORED_BLOCK_SIZE : usize = 0xFFFF;
+
+/// An ARGB8888 image, used to dump textures and frames for inspection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, x: u32, y: u32, argb: u32) {
+        self.pixels[(y * self.width + x) as usize] = argb;
+    }
+
+    /// Encodes the image as an RGBA PNG. The pixel data is stored
+    /// uncompressed, which every decoder understands.
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A])?;
+
+        let mut header = Vec::with_capacity(13);
+        push_u32_be(&mut header, self.width);
+        push_u32_be(&mut header, self.height);
+        // 8 bits per channel, RGBA, deflate, no filter, no interlace
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_chunk(out, b"IHDR", &header)?;
+
+        let mut raw = Vec::with_capacity(((self.width * 4 + 1) * self.height) as usize);
+        for y in 0..self.height {
+            // Filter type none
+            raw.push(0);
+            for x in 0..self.width {
+                let argb = self.get(x, y);
+                raw.extend_from_slice(&[(argb >> 16) as u8, (argb >> 8) as u8, argb as u8, (argb >> 24) as u8]);
+            }
+        }
+        write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(out, b"IEND", &[])
+    }
+
+    /// Writes the image as a PNG file
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        self.write_png(&mut file)
+    }
+
+    /// Encodes the image as a binary PPM, dropping alpha
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+
+        let mut raw = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for &argb in self.pixels.iter() {
+            raw.extend_from_slice(&[(argb >> 16) as u8, (argb >> 8) as u8, argb as u8]);
+        }
+        out.write_all(&raw)
+    }
+
+    /// Writes the image as a PPM file
+    pub fn save_ppm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        self.write_ppm(&mut file)
+    }
+
+    /// A 64-bit FNV-1a hash over the size and the pixels. It does not
+    /// depend on the platform or compiler, so it can be stored and
+    /// compared against later runs.
+    pub fn hash(&self) -> u64 {
+        let mut hash = 0xCBF29CE484222325u64;
+        let mut feed = |value: u32| {
+            for i in 0..4 {
+                hash ^= ((value >> (i * 8)) & 0xFF) as u64;
+                hash = hash.wrapping_mul(0x00000100000001B3);
+            }
+        };
+
+        feed(self.width);
+        feed(self.height);
+        for &argb in self.pixels.iter() {
+            feed(argb);
+        }
+        hash
+    }
+}
+
+fn push_u32_be(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut length = Vec::with_capacity(4);
+    push_u32_be(&mut length, data.len() as u32);
+    out.write_all(&length)?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let mut crc = Vec::with_capacity(4);
+    push_u32_be(&mut crc, crc32_update(crc32_update(0xFFFFFFFF, kind), data) ^ 0xFFFFFFFF);
+    out.write_all(&crc)
+}
+
+/// Wraps data into a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / STORED_BLOCK_SIZE * 5 + 16);
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut chunks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(chunk);
+    }
+
+    push_u32_be(&mut out, adler32(data));
+    out
+}
+
+/// Continues a CRC-32 as used by PNG and gzip. Start with 0xFFFFFFFF and
+/// invert the result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// The checksum trailing every zlib stream
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,136 @@
+use disc::{Disc, read_data, boot_track};
+use disc::image::invalid_data;
+
+use std::fmt;
+use std::io;
+
+/// Size of the metadata at the start of IP.BIN
+pub const META_SIZE : usize = 0x100;
+/// IP.BIN takes up the first 16 sectors of the boot track
+pub const IP_BIN_SECTORS : u32 = 16;
+
+const HARDWARE_ID : &str = "SEGA SEGAKATANA";
+
+/// Peripherals a disc supports or needs, as bits of the peripheral field
+pub const PERIPHERAL_WINDOWS_CE    : u32 = 1 << 0;
+pub const PERIPHERAL_VGA           : u32 = 1 << 4;
+pub const PERIPHERAL_EXPANSION     : u32 = 1 << 8;
+pub const PERIPHERAL_PURU_PURU     : u32 = 1 << 9;
+pub const PERIPHERAL_MICROPHONE    : u32 = 1 << 10;
+pub const PERIPHERAL_MEMORY_CARD   : u32 = 1 << 11;
+pub const PERIPHERAL_START_A_B_PAD : u32 = 1 << 12;
+pub const PERIPHERAL_C_BUTTON      : u32 = 1 << 13;
+pub const PERIPHERAL_D_BUTTON      : u32 = 1 << 14;
+pub const PERIPHERAL_X_BUTTON      : u32 = 1 << 15;
+pub const PERIPHERAL_Y_BUTTON      : u32 = 1 << 16;
+pub const PERIPHERAL_Z_BUTTON      : u32 = 1 << 17;
+pub const PERIPHERAL_SECOND_PAD    : u32 = 1 << 18;
+pub const PERIPHERAL_R_TRIGGER     : u32 = 1 << 19;
+pub const PERIPHERAL_L_TRIGGER     : u32 = 1 << 20;
+pub const PERIPHERAL_ANALOG_X      : u32 = 1 << 21;
+pub const PERIPHERAL_ANALOG_Y      : u32 = 1 << 22;
+pub const PERIPHERAL_ANALOG_X2     : u32 = 1 << 23;
+pub const PERIPHERAL_ANALOG_Y2     : u32 = 1 << 24;
+pub const PERIPHERAL_GUN           : u32 = 1 << 25;
+pub const PERIPHERAL_KEYBOARD      : u32 = 1 << 26;
+pub const PERIPHERAL_MOUSE         : u32 = 1 << 27;
+
+/// Regions a disc boots in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Regions {
+    pub japan: bool,
+    pub usa: bool,
+    pub europe: bool,
+}
+
+/// The metadata of a disc, from the start of its IP.BIN. Text fields
+/// are padded with spaces, which are trimmed here.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpBin {
+    pub hardware_id: String,
+    pub maker_id: String,
+    /// CRC of the product number and version, and which disc of a set
+    /// this is, like "GD-ROM1/2"
+    pub device_info: String,
+    pub regions: Regions,
+    pub peripherals: u32,
+    pub product_number: String,
+    pub product_version: String,
+    /// As YYYYMMDD
+    pub release_date: String,
+    pub boot_filename: String,
+    pub software_maker: String,
+    pub title: String,
+}
+
+fn text(data: &[u8], offset: usize, length: usize) -> String {
+    String::from_utf8_lossy(&data[offset..offset + length]).trim().to_string()
+}
+
+impl IpBin {
+    pub fn parse(data: &[u8]) -> io::Result<IpBin> {
+        if data.len() < META_SIZE {
+            return Err(invalid_data("IP.BIN is truncated"));
+        }
+        if !data.starts_with(HARDWARE_ID.as_bytes()) {
+            return Err(invalid_data("IP.BIN doesn't start with the Dreamcast hardware id"));
+        }
+
+        // Each region has its own column, left blank when not supported
+        let areas = &data[0x30..0x38];
+        Ok(IpBin {
+            hardware_id: text(data, 0x00, 16),
+            maker_id: text(data, 0x10, 16),
+            device_info: text(data, 0x20, 16),
+            regions: Regions {
+                japan: areas[0] == b'J',
+                usa: areas[1] == b'U',
+                europe: areas[2] == b'E',
+            },
+            peripherals: u32::from_str_radix(&text(data, 0x38, 8), 16).unwrap_or(0),
+            product_number: text(data, 0x40, 10),
+            product_version: text(data, 0x4A, 6),
+            release_date: text(data, 0x50, 16),
+            boot_filename: text(data, 0x60, 16),
+            software_maker: text(data, 0x70, 16),
+            title: text(data, 0x80, 128),
+        })
+    }
+
+    /// Reads IP.BIN from the boot track of a disc
+    pub fn read(disc: &mut dyn Disc) -> io::Result<(IpBin, Vec<u8>)> {
+        let track = boot_track(disc)?;
+        let data = read_data(disc, track.start, IP_BIN_SECTORS)?;
+        Ok((IpBin::parse(&data)?, data))
+    }
+
+    pub fn supports(&self, peripherals: u32) -> bool {
+        self.peripherals & peripherals == peripherals
+    }
+
+    /// The number of this disc and of discs in the set
+    pub fn disc_number(&self) -> Option<(u32, u32)> {
+        let numbers = self.device_info.rsplit(|c: char| !c.is_ascii_digit() && c != '/').next().unwrap_or("");
+        let mut parts = numbers.split('/').map(|n| n.parse::<u32>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(disc)), Some(Ok(discs))) => Some((disc, discs)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for IpBin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regions: String = [(self.regions.japan, 'J'), (self.regions.usa, 'U'), (self.regions.europe, 'E')]
+            .iter().filter(|&&(on, _)| on).map(|&(_, c)| c).collect();
+
+        writeln!(f, "Title:       {}", self.title)?;
+        writeln!(f, "Product:     {} {}", self.product_number, self.product_version)?;
+        writeln!(f, "Maker:       {}", self.software_maker)?;
+        writeln!(f, "Released:    {}", self.release_date)?;
+        writeln!(f, "Device:      {}", self.device_info)?;
+        writeln!(f, "Regions:     {}", regions)?;
+        writeln!(f, "Peripherals: {:07X}", self.peripherals)?;
+        write!(f, "Boot file:   {}", self.boot_filename)
+    }
+}
@@ -12,18 +12,34 @@ pub use memory_field::MemoryField;
 pub use memory::Memory;
 pub use memory::MemoryRange;
 pub use memory::MappedIO;
+pub use memory::MappedDevice;
 pub use bsc::Bsc;
-pub use dsp::Dsp;
+pub use asic::Asic;
+pub use pvr::Pvr;
+pub use pvr::dma::PvrDma;
+pub use aica::Aica;
+pub use aica::rtc::Rtc;
+pub use arm7::Arm7;
+pub use g2::G2;
+pub use gdrom::GdRom;
+pub use maple::Maple;
+pub use disc::image::DiscImage;
+pub use disc::iso9660::Filesystem;
+pub use scheduler::Scheduler;
+pub use image::Image;
 pub use instruction_executer::InstructionExecuter;
 pub use cpu::Cpu;
 pub use cpu::FPSCR_MASK;
+pub use cpu::Trap;
+pub use rom::{BootRom, Flash};
+pub use machine::Machine;
+pub use gdb::GdbStub;
 pub use instruction_decoder::InstructionDecoder;
 
 extern crate latest;
 
 pub mod operand;
 pub mod bsc;
-pub mod dsp;
 pub mod instruction;
 pub mod instruction_executer;
 pub mod instruction_decoder;
@@ -31,3 +47,21 @@ pub mod memory_field;
 pub mod register;
 pub mod cpu;
 pub mod memory;
+pub mod asic;
+pub mod pvr;
+pub mod image;
+pub mod audio;
+pub mod aica;
+pub mod arm7;
+pub mod g2;
+pub mod disc;
+pub mod gdrom;
+pub mod maple;
+pub mod scheduler;
+pub mod rom;
+pub mod machine;
+pub mod hle;
+pub mod loader;
+pub mod ip_bin;
+pub mod dcload;
+pub mod gdb;
@@ -0,0 +1,424 @@
+use Memory;
+use disc::image::invalid_data;
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Where raw binaries are loaded and started, as 1ST_READ.BIN is
+pub const RAW_LOAD_ADDRESS : usize = 0x8C010000;
+
+const ELF_MAGIC : &[u8; 4] = b"\x7FELF";
+const ELF_CLASS_32      : u8 = 1;
+const ELF_DATA_LSB      : u8 = 1;
+const ELF_TYPE_EXEC     : u16 = 2;
+const ELF_MACHINE_SH    : u16 = 42;
+
+const PT_LOAD : u32 = 1;
+
+const SHT_SYMTAB : u32 = 2;
+const SYMBOL_SIZE : usize = 16;
+const STT_OBJECT : u8 = 1;
+const STT_FUNC   : u8 = 2;
+
+/// Largest block the scrambling shuffles, and the slices it shuffles
+const SCRAMBLE_CHUNK : usize = 2048 * 1024;
+const SCRAMBLE_SLICE : usize = 32;
+
+/// A named function or object of a program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// The symbols of a program, ordered by address, for the debugger and
+/// tracer to show names rather than addresses
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    pub symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        symbols.sort_by_key(|symbol| symbol.address);
+        SymbolTable { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The symbol an address falls in and the offset into it. Addresses
+    /// in P1 and P2 match the same symbol.
+    pub fn lookup(&self, address: usize) -> Option<(&Symbol, usize)> {
+        let address = address & 0x1FFFFFFF;
+        let index = match self.symbols.binary_search_by(|s| (s.address & 0x1FFFFFFF).cmp(&address)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1
+        };
+        let symbol = &self.symbols[index];
+        let offset = address - (symbol.address & 0x1FFFFFFF);
+        // Symbols without a size cover everything up to the next one
+        if symbol.size == 0 || offset < symbol.size {
+            Some((symbol, offset))
+        } else {
+            None
+        }
+    }
+
+    /// An address as `name+offset`, or in hex when no symbol covers it
+    pub fn describe(&self, address: usize) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => symbol.name.clone(),
+            Some((symbol, offset)) => format!("{}+0x{:x}", symbol.name, offset),
+            None => format!("0x{:08x}", address)
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+/// A program placed in memory
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub entry: usize,
+    pub symbols: SymbolTable,
+}
+
+fn le16(data: &[u8], offset: usize) -> io::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| (b[0] as u16) | ((b[1] as u16) << 8))
+        .ok_or_else(|| invalid_data("ELF file is truncated"))
+}
+
+fn le32(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24))
+        .ok_or_else(|| invalid_data("ELF file is truncated"))
+}
+
+fn slice(data: &[u8], offset: usize, size: usize) -> io::Result<&[u8]> {
+    data.get(offset..offset + size).ok_or_else(|| invalid_data("ELF file is truncated"))
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+/// Loads the segments of an SH-4 ELF executable, clearing the part of
+/// each that isn't in the file, and reads its symbol table
+pub fn load_elf(mem: &mut Memory, data: &[u8]) -> io::Result<Program> {
+    if !is_elf(data) {
+        return Err(invalid_data("not an ELF file"));
+    }
+    if data.get(4) != Some(&ELF_CLASS_32) || data.get(5) != Some(&ELF_DATA_LSB) {
+        return Err(invalid_data("not a 32-bit little endian ELF file"));
+    }
+    if le16(data, 16)? != ELF_TYPE_EXEC {
+        return Err(invalid_data("the ELF file is not an executable"));
+    }
+    if le16(data, 18)? != ELF_MACHINE_SH {
+        return Err(invalid_data("the ELF file is not built for the SH-4"));
+    }
+
+    let entry = le32(data, 24)? as usize;
+    let (program_headers, program_header_size, program_header_count) = (le32(data, 28)? as usize, le16(data, 42)? as usize, le16(data, 44)? as usize);
+
+    for i in 0..program_header_count {
+        let header = program_headers + i * program_header_size;
+        if le32(data, header)? != PT_LOAD {
+            continue;
+        }
+
+        let offset = le32(data, header + 4)? as usize;
+        let address = le32(data, header + 8)? as usize;
+        let file_size = le32(data, header + 16)? as usize;
+        let memory_size = le32(data, header + 20)? as usize;
+        if memory_size.max(file_size) > Memory::ram_left(address) {
+            return Err(invalid_data(&format!("the segment at 0x{:08x} doesn't fit in RAM", address)));
+        }
+
+        mem.load(address, slice(data, offset, file_size)?);
+        // The part not in the file starts right after the last byte
+        // that is, even when that ends halfway through a cell
+        if memory_size > file_size {
+            mem.load(address + file_size, &vec![0; memory_size - file_size]);
+        }
+    }
+
+    Ok(Program {
+        entry,
+        symbols: read_symbols(data)?,
+    })
+}
+
+/// Reads the functions and objects of the symbol table, if the file
+/// still has one
+fn read_symbols(data: &[u8]) -> io::Result<SymbolTable> {
+    let (section_headers, section_header_size, section_header_count) = (le32(data, 32)? as usize, le16(data, 46)? as usize, le16(data, 48)? as usize);
+    let section = |i: usize| section_headers + i * section_header_size;
+
+    let mut symbols = Vec::new();
+    for i in 0..section_header_count {
+        if le32(data, section(i) + 4)? != SHT_SYMTAB {
+            continue;
+        }
+
+        let table = slice(data, le32(data, section(i) + 16)? as usize, le32(data, section(i) + 20)? as usize)?;
+        // The names are in the string table the section links to
+        let strings = section(le32(data, section(i) + 24)? as usize);
+        let strings = slice(data, le32(data, strings + 16)? as usize, le32(data, strings + 20)? as usize)?;
+
+        for entry in table.chunks(SYMBOL_SIZE).filter(|e| e.len() == SYMBOL_SIZE) {
+            let kind = entry[12] & 0xF;
+            if kind != STT_FUNC && kind != STT_OBJECT {
+                continue;
+            }
+
+            let name = &strings[(le32(entry, 0)? as usize).min(strings.len())..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if name.is_empty() {
+                continue;
+            }
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                address: le32(entry, 4)? as usize,
+                size: le32(entry, 8)? as usize,
+            });
+        }
+    }
+
+    Ok(SymbolTable::new(symbols))
+}
+
+/// Loads a flat binary, which starts at its first byte
+pub fn load_raw(mem: &mut Memory, data: &[u8], address: usize) -> io::Result<Program> {
+    if data.len() > Memory::ram_left(address) {
+        return Err(invalid_data("the binary doesn't fit in RAM"));
+    }
+    mem.load(address, data);
+    Ok(Program {
+        entry: address,
+        symbols: SymbolTable::default(),
+    })
+}
+
+/// The generator the boot ROM shuffles with, seeded with the file size
+struct ScrambleRandom {
+    seed: u32,
+}
+
+impl ScrambleRandom {
+    fn next(&mut self) -> u32 {
+        self.seed = (self.seed * 2109 + 9273) & 0x7FFF;
+        (self.seed + 0xC000) & 0xFFFF
+    }
+}
+
+/// Undoes the scrambling of a program booted from a CD rather than a
+/// GD-ROM, as the boot ROM does while loading it. The file is cut into
+/// blocks, the largest power of two at most 2 MB that fits the rest,
+/// and the 32 byte slices of each block were shuffled. What is left
+/// after the last slice stays in place.
+pub fn descramble(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; data.len()];
+    let mut random = ScrambleRandom { seed: data.len() as u32 & 0xFFFF };
+    let mut position = 0;
+
+    let mut chunk = SCRAMBLE_CHUNK;
+    while chunk >= SCRAMBLE_SLICE {
+        while data.len() - position >= chunk {
+            // The slices are read in order and stored where the shuffled
+            // index table says
+            let slices = chunk / SCRAMBLE_SLICE;
+            let mut index: Vec<usize> = (0..slices).collect();
+            for i in (0..slices).rev() {
+                let x = ((random.next() * i as u32) >> 16) as usize;
+                index.swap(i, x);
+
+                let source = position + (slices - 1 - i) * SCRAMBLE_SLICE;
+                let destination = position + index[i] * SCRAMBLE_SLICE;
+                out[destination..destination + SCRAMBLE_SLICE].copy_from_slice(&data[source..source + SCRAMBLE_SLICE]);
+            }
+            position += chunk;
+        }
+        chunk >>= 1;
+    }
+
+    out[position..].copy_from_slice(&data[position..]);
+    out
+}
+
+/// Loads an ELF executable, or otherwise a raw binary at 0x8C010000
+pub fn load_file<P: AsRef<Path>>(mem: &mut Memory, path: P) -> io::Result<Program> {
+    let mut data = Vec::new();
+    File::open(path.as_ref())?.read_to_end(&mut data)?;
+
+    if is_elf(&data) {
+        load_elf(mem, &data)
+    } else {
+        load_raw(mem, &data, RAW_LOAD_ADDRESS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::TestMemory;
+
+    /// Fills every slice with its number, followed by a tail counting up
+    fn numbered(length: usize) -> Vec<u8> {
+        (0..length).map(|i| {
+            if i < length / SCRAMBLE_SLICE * SCRAMBLE_SLICE {
+                ((i / SCRAMBLE_SLICE) >> ((i % 4) * 8)) as u8
+            } else {
+                (i % SCRAMBLE_SLICE) as u8
+            }
+        }).collect()
+    }
+
+    fn slice(data: &[u8], n: usize) -> u32 {
+        let s = &data[n * SCRAMBLE_SLICE..];
+        (s[0] as u32) | (s[1] as u32) << 8 | (s[2] as u32) << 16 | (s[3] as u32) << 24
+    }
+
+    #[test]
+    fn descrambles_small_blocks() {
+        // Blocks of four, two and one slice, then five bytes left over
+        let out = descramble(&numbered(7 * SCRAMBLE_SLICE + 5));
+        let order: Vec<u32> = (0..7).map(|n| slice(&out, n)).collect();
+        assert_eq!(order, vec![2, 1, 0, 3, 4, 5, 6]);
+        assert_eq!(&out[7 * SCRAMBLE_SLICE..], &[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn descrambles_large_blocks() {
+        let length = SCRAMBLE_CHUNK + 0x1000 + 40;
+        let out = descramble(&numbered(length));
+        let order: Vec<u32> = [0, 1, 0xFFFF, 0x10000, 0x10001, 0x1007F, 0x10080].iter().map(|&n| slice(&out, n)).collect();
+        assert_eq!(order, vec![0x4F07, 0x7BA5, 0xD625, 0x10069, 0x10004, 0x10003, 0x10080]);
+        assert_eq!(&out[length - 8..], &[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        // Every slice ends up somewhere, exactly once
+        let mut seen = vec![false; length / SCRAMBLE_SLICE];
+        for n in 0..seen.len() {
+            seen[slice(&out, n) as usize] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    fn put16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    fn put32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    /// An executable with one segment of some bytes, taking up more in
+    /// memory, and a symbol table of (name, address, size, type)
+    fn elf(address: u32, bytes: &[u8], memory_size: u32, symbols: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+        let mut strings = vec![0u8];
+        let mut table = vec![0u8; SYMBOL_SIZE];
+        for &(name, address, size, kind) in symbols {
+            let mut entry = [0u8; SYMBOL_SIZE];
+            put32(&mut entry, 0, strings.len() as u32);
+            put32(&mut entry, 4, address);
+            put32(&mut entry, 8, size);
+            entry[12] = 0x10 | kind;
+            table.extend_from_slice(&entry);
+            strings.extend(name.bytes().chain(Some(0)));
+        }
+
+        // Header, program header, segment, symbols, strings, sections
+        let (segment, symbols_at) = (84, 84 + bytes.len());
+        let strings_at = symbols_at + table.len();
+        let sections = strings_at + strings.len();
+        let mut data = vec![0u8; sections + 3 * 40];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4..7].copy_from_slice(&[ELF_CLASS_32, ELF_DATA_LSB, 1]);
+        put16(&mut data, 16, ELF_TYPE_EXEC);
+        put16(&mut data, 18, ELF_MACHINE_SH);
+        put32(&mut data, 24, address);
+        put32(&mut data, 28, 52);
+        put32(&mut data, 32, sections as u32);
+        put16(&mut data, 42, 32);
+        put16(&mut data, 44, 1);
+        put16(&mut data, 46, 40);
+        put16(&mut data, 48, 3);
+
+        put32(&mut data, 52, PT_LOAD);
+        put32(&mut data, 56, segment as u32);
+        put32(&mut data, 60, address);
+        put32(&mut data, 68, bytes.len() as u32);
+        put32(&mut data, 72, memory_size);
+        data[segment..symbols_at].copy_from_slice(bytes);
+        data[symbols_at..strings_at].copy_from_slice(&table);
+        data[strings_at..sections].copy_from_slice(&strings);
+
+        // Section 1 is the symbol table, linking to the strings in 2
+        put32(&mut data, sections + 40 + 4, SHT_SYMTAB);
+        put32(&mut data, sections + 40 + 16, symbols_at as u32);
+        put32(&mut data, sections + 40 + 20, table.len() as u32);
+        put32(&mut data, sections + 40 + 24, 2);
+        put32(&mut data, sections + 80 + 4, 3);
+        put32(&mut data, sections + 80 + 16, strings_at as u32);
+        put32(&mut data, sections + 80 + 20, strings.len() as u32);
+        data
+    }
+
+    #[test]
+    fn loads_segments_and_clears_their_bss() {
+        let mut mem = TestMemory::new();
+        mem.load(RAW_LOAD_ADDRESS, &[0xAA; 16]);
+
+        let program = load_elf(&mut mem, &elf(RAW_LOAD_ADDRESS as u32, &[1, 2, 3], 8, &[])).unwrap();
+        assert_eq!(program.entry, RAW_LOAD_ADDRESS);
+        assert!(program.symbols.is_empty());
+        assert_eq!(mem.read_bytes(RAW_LOAD_ADDRESS, 10), vec![1, 2, 3, 0, 0, 0, 0, 0, 0xAA, 0xAA]);
+
+        // A segment running past the end of RAM is refused
+        let error = load_elf(&mut mem, &elf(0x8CFFFFF0, &[1, 2, 3], 0x20, &[])).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(load_raw(&mut mem, &[0; 0x20], 0x8CFFFFF0).is_err());
+
+        let mut truncated = elf(RAW_LOAD_ADDRESS as u32, &[1, 2, 3], 8, &[]);
+        truncated.truncate(60);
+        assert!(load_elf(&mut mem, &truncated).is_err());
+        assert!(load_elf(&mut mem, b"\x7FELF").is_err());
+    }
+
+    #[test]
+    fn reads_functions_and_objects() {
+        let symbols = read_symbols(&elf(RAW_LOAD_ADDRESS as u32, &[0; 4], 4, &[
+            ("start", 0x8C010100, 0, STT_FUNC),
+            ("main", 0x8C010000, 8, STT_FUNC),
+            ("file.c", 0, 0, 4),
+            ("counter", 0x8C020000, 4, STT_OBJECT),
+        ])).unwrap();
+        let names: Vec<&str> = symbols.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "start", "counter"]);
+
+        // Addresses in P2 match as well
+        assert_eq!(symbols.describe(0xAC010004), "main+0x4");
+        assert_eq!(symbols.describe(0x8C010000), "main");
+        assert_eq!(symbols.describe(0x8C010008), "0x8c010008");
+        // A symbol without a size reaches the next one
+        assert_eq!(symbols.describe(0x8C01FFFF), "start+0xfeff");
+        assert_eq!(symbols.describe(0x8C020004), "0x8c020004");
+        assert_eq!(symbols.lookup(0x8C000000), None);
+        assert_eq!(symbols.find("counter").map(|s| s.address), Some(0x8C020000));
+    }
+
+    #[test]
+    fn keeps_short_files() {
+        assert_eq!(descramble(&[1, 2, 3]), vec![1, 2, 3]);
+        assert!(descramble(&[]).is_empty());
+    }
+}
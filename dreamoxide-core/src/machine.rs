@@ -0,0 +1,188 @@
+use Cpu;
+use Memory;
+use MemoryRange;
+use Bsc;
+use Asic;
+use Pvr;
+use PvrDma;
+use Aica;
+use Rtc;
+use G2;
+use GdRom;
+use Maple;
+use Scheduler;
+use aica::rtc::RtcClock;
+use audio::{AudioSink, NullSink};
+use rom::{BootRom, Flash, BOOT_ROM_BASE, BOOT_ROM_END, FLASH_BASE, FLASH_END};
+use scheduler::Event;
+use disc::Disc;
+use hle;
+use dcload::{Dcload, DCLOAD_ENTRY};
+use gdb::GdbStub;
+use loader;
+use loader::SymbolTable;
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A whole Dreamcast: the SH-4 with its memory, the devices on the
+/// system bus and the scheduler interleaving them.
+pub struct Machine {
+    pub cpu: Cpu,
+    pub mem: Memory,
+    pub scheduler: Scheduler,
+    pub asic: Arc<Mutex<Asic>>,
+    pub pvr: Arc<Mutex<Pvr>>,
+    pub pvr_dma: Arc<Mutex<PvrDma>>,
+    pub aica: Arc<Mutex<Aica>>,
+    pub rtc: Arc<Mutex<Rtc>>,
+    pub g2: Arc<Mutex<G2>>,
+    pub gdrom: Arc<Mutex<GdRom>>,
+    pub maple: Arc<Mutex<Maple>>,
+    /// Absent when booting with the high-level BIOS
+    pub boot_rom: Option<Arc<Mutex<BootRom>>>,
+    pub flash: Arc<Mutex<Flash>>,
+    /// Where the sound chip's output goes
+    pub audio: Box<dyn AudioSink>,
+    /// Names of the loaded program's functions, when it came with them
+    pub symbols: SymbolTable,
+    /// Set when a program exits through dcload
+    pub exit_code: Arc<Mutex<Option<i32>>>,
+    /// Set when a program booted without a boot ROM asks for the BIOS
+    /// menu
+    pub bios_menu: Arc<Mutex<bool>>,
+}
+
+impl Machine {
+    /// Builds the hardware around a boot ROM, if there is one, and a
+    /// flash, in its power-on state
+    pub fn new(boot_rom: Option<BootRom>, flash: Flash, clock: RtcClock) -> Machine {
+        let mut mem = Memory::new();
+
+        // The bus controller answers on its own thread
+        let mut bsc = Bsc::new(&mut mem);
+        thread::spawn(move || bsc.run());
+
+        let asic = Asic::new(&mut mem);
+        let pvr = Pvr::new(&mut mem, asic.clone());
+        let pvr_dma = PvrDma::new(&mut mem, asic.clone());
+        let aica = Aica::new(&mut mem, asic.clone());
+        let rtc = Rtc::new(&mut mem, clock);
+        let g2 = G2::new(&mut mem, asic.clone());
+        let gdrom = GdRom::new(&mut mem, asic.clone());
+        let maple = Maple::new(&mut mem, asic.clone());
+        aica.lock().unwrap().cdda = Some(gdrom.lock().unwrap().cdda.clone());
+
+        // Code runs from the cells, while data accesses go to the devices
+        let boot_rom = boot_rom.map(|boot_rom| {
+            mem.load(BOOT_ROM_BASE, &boot_rom.data);
+            let boot_rom = Arc::new(Mutex::new(boot_rom));
+            mem.register_mapped_device(MemoryRange(BOOT_ROM_BASE, BOOT_ROM_END), boot_rom.clone());
+            boot_rom
+        });
+        let flash = Arc::new(Mutex::new(flash));
+        mem.register_mapped_device(MemoryRange(FLASH_BASE, FLASH_END), flash.clone());
+
+        let mut machine = Machine {
+            cpu: Cpu::new(),
+            mem,
+            scheduler: Scheduler::new(),
+            asic,
+            pvr,
+            pvr_dma,
+            aica,
+            rtc,
+            g2,
+            gdrom,
+            maple,
+            boot_rom,
+            flash,
+            audio: Box::new(NullSink),
+            symbols: SymbolTable::default(),
+            exit_code: Arc::new(Mutex::new(None)),
+            bios_menu: Arc::new(Mutex::new(false)),
+        };
+        machine.reset();
+        machine
+    }
+
+    /// Loads the boot ROM and flash images and powers the machine on.
+    /// The clock starts at the host's time.
+    pub fn power_on<P: AsRef<Path>, Q: AsRef<Path>>(boot_rom: P, flash: Q) -> io::Result<Machine> {
+        let boot_rom = BootRom::open(boot_rom)?;
+        let flash = Flash::open(flash)?;
+        Ok(Machine::new(Some(boot_rom), flash, RtcClock::Host))
+    }
+
+    /// Boots a disc without a boot ROM, the BIOS being emulated on the
+    /// host. Without a flash image an erased one is used.
+    pub fn boot_hle(disc: Box<dyn Disc>, flash: Option<Flash>) -> io::Result<Machine> {
+        let mut machine = Machine::new(None, flash.unwrap_or_else(Flash::blank), RtcClock::Host);
+        machine.gdrom.lock().unwrap().insert(Some(disc));
+        hle::boot(&mut machine)?;
+        Ok(machine)
+    }
+
+    /// Loads an ELF executable or raw binary and starts it directly, with
+    /// the system calls emulated on the host
+    pub fn load_program<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let program = loader::load_file(&mut self.mem, path)?;
+        hle::prepare(self);
+        self.cpu.pc = program.entry;
+        self.symbols = program.symbols;
+        Ok(())
+    }
+
+    /// Serves the dcload calls of homebrew, with the files of a host
+    /// directory
+    pub fn enable_dcload<P: AsRef<Path>>(&mut self, root: P) -> io::Result<()> {
+        let dcload = Dcload::new(root, self.exit_code.clone())?;
+        Dcload::install(&mut self.mem);
+        self.cpu.traps.retain(|t| !t.handles(DCLOAD_ENTRY & 0x1FFFFFFF));
+        self.cpu.traps.push(Box::new(dcload));
+        Ok(())
+    }
+
+    /// Waits for sh-elf-gdb to connect to a local port and lets it
+    /// control the guest until it detaches
+    pub fn debug(&mut self, port: u16) -> io::Result<()> {
+        GdbStub::listen(port)?.run(self)
+    }
+
+    /// Resets the CPU and restarts the devices' periodic events, as
+    /// pressing the reset button does
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.scheduler = Scheduler::new();
+        self.aica.lock().unwrap().start(&mut self.scheduler);
+        self.rtc.lock().unwrap().start(&mut self.scheduler);
+        self.gdrom.lock().unwrap().start(&mut self.scheduler);
+    }
+
+    /// Executes one instruction, counted as one cycle, then dispatches
+    /// the events that became due and carries out started DMAs
+    pub fn step(&mut self) {
+        self.cpu.step(&mut self.mem);
+        self.scheduler.advance(1);
+
+        while let Some(event) = self.scheduler.pop_due() {
+            match event {
+                Event::AicaSample => self.aica.lock().unwrap().sample_event(&mut self.scheduler, &mut *self.audio),
+                Event::RtcTick    => self.rtc.lock().unwrap().second_event(&mut self.scheduler),
+                Event::CddaSector => self.gdrom.lock().unwrap().sector_event(&mut self.scheduler)
+            }
+        }
+
+        self.maple.lock().unwrap().run(&mut self.mem);
+        self.gdrom.lock().unwrap().run(&mut self.mem);
+        self.pvr_dma.lock().unwrap().run(&mut self.mem);
+        self.g2.lock().unwrap().run(&mut self.mem);
+    }
+
+    /// Saves the flash if the game changed it
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flash.lock().unwrap().flush()
+    }
+}
@@ -0,0 +1,293 @@
+use maple::{MapleDevice, DeviceInfo, Response, SEGA_LICENSE, FUNCTION_CONTROLLER, FUNCTION_GUN, FUNCTION_UNSUPPORTED};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Buttons, as bits of the condition. The game sees them active low.
+pub const BUTTON_C      : u16 = 1 << 0;
+pub const BUTTON_B      : u16 = 1 << 1;
+pub const BUTTON_A      : u16 = 1 << 2;
+pub const BUTTON_START  : u16 = 1 << 3;
+pub const BUTTON_UP     : u16 = 1 << 4;
+pub const BUTTON_DOWN   : u16 = 1 << 5;
+pub const BUTTON_LEFT   : u16 = 1 << 6;
+pub const BUTTON_RIGHT  : u16 = 1 << 7;
+pub const BUTTON_Z      : u16 = 1 << 8;
+pub const BUTTON_Y      : u16 = 1 << 9;
+pub const BUTTON_X      : u16 = 1 << 10;
+pub const BUTTON_D      : u16 = 1 << 11;
+pub const BUTTON_UP2    : u16 = 1 << 12;
+pub const BUTTON_DOWN2  : u16 = 1 << 13;
+pub const BUTTON_LEFT2  : u16 = 1 << 14;
+pub const BUTTON_RIGHT2 : u16 = 1 << 15;
+
+/// Resting position of the analog axes
+pub const AXIS_CENTER : u8 = 0x80;
+
+/// The state of a controller's buttons and axes at one moment
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ControllerState {
+    /// Pressed buttons
+    pub buttons: u16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub stick_x: u8,
+    pub stick_y: u8,
+    /// Beam position a light gun points at, as latched into
+    /// SPG_TRIGGER_POS, or None when it points off the screen
+    pub aim: Option<(u16, u16)>,
+}
+
+impl Default for ControllerState {
+    fn default() -> ControllerState {
+        ControllerState {
+            buttons: 0,
+            left_trigger: 0,
+            right_trigger: 0,
+            stick_x: AXIS_CENTER,
+            stick_y: AXIS_CENTER,
+            aim: None,
+        }
+    }
+}
+
+/// Input of a controller, shared between the host and the device on
+/// the bus. The host either changes the current state or queues states
+/// for the frames to come: each time the game polls the controller it
+/// takes the next queued state, and the last one holds once the queue
+/// runs out.
+pub struct ControllerInput {
+    pub current: ControllerState,
+    queue: VecDeque<ControllerState>,
+}
+
+impl ControllerInput {
+    pub fn new() -> ControllerInput {
+        ControllerInput {
+            current: ControllerState::default(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn press(&mut self, buttons: u16) {
+        self.current.buttons |= buttons;
+    }
+
+    pub fn release(&mut self, buttons: u16) {
+        self.current.buttons &= !buttons;
+    }
+
+    pub fn set_stick(&mut self, x: u8, y: u8) {
+        self.current.stick_x = x;
+        self.current.stick_y = y;
+    }
+
+    pub fn set_triggers(&mut self, left: u8, right: u8) {
+        self.current.left_trigger = left;
+        self.current.right_trigger = right;
+    }
+
+    pub fn set_aim(&mut self, aim: Option<(u16, u16)>) {
+        self.current.aim = aim;
+    }
+
+    /// Queues a state to be seen for the given number of polls
+    pub fn queue(&mut self, state: ControllerState, frames: usize) {
+        for _ in 0..frames {
+            self.queue.push_back(state);
+        }
+    }
+
+    /// Queues pressing buttons for some frames, then releasing them for
+    /// as many, as needed to make a menu register one press
+    pub fn queue_press(&mut self, buttons: u16, frames: usize) {
+        let base = self.queue.back().cloned().unwrap_or(self.current);
+        self.queue(ControllerState { buttons: base.buttons | buttons, ..base }, frames);
+        self.queue(ControllerState { buttons: base.buttons & !buttons, ..base }, frames);
+    }
+
+    /// Number of polls until the queue runs out
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    /// The state for the next poll
+    pub fn poll(&mut self) -> ControllerState {
+        if let Some(state) = self.queue.pop_front() {
+            self.current = state;
+        }
+        self.current
+    }
+}
+
+impl Default for ControllerInput {
+    fn default() -> ControllerInput {
+        ControllerInput::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControllerKind {
+    /// The standard controller, with analog stick and triggers
+    Standard,
+    /// Digital stick and six buttons
+    ArcadeStick,
+    LightGun,
+}
+
+/// A controller on the Maple bus. Its input is changed by the host
+/// through the shared `ControllerInput`.
+pub struct Controller {
+    pub kind: ControllerKind,
+    input: Arc<Mutex<ControllerInput>>,
+}
+
+impl Controller {
+    pub fn new(kind: ControllerKind) -> Controller {
+        Controller {
+            kind,
+            input: Arc::new(Mutex::new(ControllerInput::new())),
+        }
+    }
+
+    /// The handle through which the host feeds input
+    pub fn input(&self) -> Arc<Mutex<ControllerInput>> {
+        self.input.clone()
+    }
+
+    /// Buttons the controller has
+    fn supported_buttons(&self) -> u16 {
+        match self.kind {
+            ControllerKind::Standard    => BUTTON_A | BUTTON_B | BUTTON_X | BUTTON_Y | BUTTON_START | 0xF0,
+            ControllerKind::ArcadeStick => BUTTON_A | BUTTON_B | BUTTON_C | BUTTON_X | BUTTON_Y | BUTTON_Z | BUTTON_START | 0xF0,
+            ControllerKind::LightGun    => BUTTON_A | BUTTON_B | BUTTON_START | 0xF0
+        }
+    }
+}
+
+impl MapleDevice for Controller {
+    fn info(&self) -> DeviceInfo {
+        // The function data lists the buttons and axes present
+        let (functions, function_data, product_name, standby_power, max_power) = match self.kind {
+            ControllerKind::Standard    => (FUNCTION_CONTROLLER, [0xFE060F00, 0, 0], "Dreamcast Controller", 0x01AE, 0x01F4),
+            ControllerKind::ArcadeStick => (FUNCTION_CONTROLLER, [0xFF070000, 0, 0], "Arcade Stick", 0x010E, 0x01F4),
+            ControllerKind::LightGun    => (FUNCTION_GUN | FUNCTION_CONTROLLER, [0, 0xFE000000, 0], "Dreamcast Gun", 0x0069, 0x0120)
+        };
+
+        DeviceInfo {
+            functions,
+            function_data,
+            area_code: 0xFF,
+            connector_direction: 0,
+            product_name,
+            license: SEGA_LICENSE,
+            standby_power,
+            max_power,
+        }
+    }
+
+    fn get_condition(&mut self, function: u32) -> Response {
+        if function != FUNCTION_CONTROLLER {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        let state = self.input.lock().unwrap().poll();
+        let buttons = !(state.buttons & self.supported_buttons());
+        let (left, right, x, y) = match self.kind {
+            ControllerKind::Standard => (state.left_trigger, state.right_trigger, state.stick_x, state.stick_y),
+            _ => (0, 0, AXIS_CENTER, AXIS_CENTER)
+        };
+
+        Response::Data(vec![
+            FUNCTION_CONTROLLER,
+            buttons as u32 | ((right as u32) << 16) | ((left as u32) << 24),
+            x as u32 | ((y as u32) << 8) | ((AXIS_CENTER as u32) << 16) | ((AXIS_CENTER as u32) << 24),
+        ])
+    }
+
+    fn light_gun_position(&self) -> Option<(u16, u16)> {
+        match self.kind {
+            ControllerKind::LightGun => self.input.lock().unwrap().current.aim,
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(controller: &mut Controller) -> Vec<u32> {
+        match controller.get_condition(FUNCTION_CONTROLLER) {
+            Response::Data(words) => words,
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+
+    #[test]
+    fn holds_the_last_queued_state() {
+        let mut input = ControllerInput::new();
+        input.press(BUTTON_B);
+        input.queue_press(BUTTON_A, 2);
+        assert_eq!(input.queued(), 4);
+
+        assert_eq!(input.poll().buttons, BUTTON_A | BUTTON_B);
+        assert_eq!(input.poll().buttons, BUTTON_A | BUTTON_B);
+        assert_eq!(input.poll().buttons, BUTTON_B);
+        assert_eq!(input.poll().buttons, BUTTON_B);
+        assert_eq!(input.queued(), 0);
+        assert_eq!(input.poll().buttons, BUTTON_B);
+
+        // Queued presses add to the state at the back of the queue
+        input.queue(ControllerState { buttons: BUTTON_X, ..ControllerState::default() }, 1);
+        input.queue_press(BUTTON_Y, 1);
+        input.poll();
+        assert_eq!(input.poll().buttons, BUTTON_X | BUTTON_Y);
+        input.clear_queue();
+        assert_eq!(input.poll().buttons, BUTTON_X | BUTTON_Y);
+    }
+
+    #[test]
+    fn reports_buttons_active_low_with_the_axes() {
+        let mut controller = Controller::new(ControllerKind::Standard);
+        {
+            let input = controller.input();
+            let mut input = input.lock().unwrap();
+            input.press(BUTTON_A | BUTTON_START | BUTTON_Z);
+            input.set_triggers(0x10, 0xFF);
+            input.set_stick(0x00, 0xC0);
+        }
+        // Z isn't on a standard controller
+        assert_eq!(condition(&mut controller), vec![FUNCTION_CONTROLLER, 0x10FFFFF3, 0x8080C000]);
+
+        let mut stick = Controller::new(ControllerKind::ArcadeStick);
+        stick.input().lock().unwrap().press(BUTTON_Z | BUTTON_UP);
+        stick.input().lock().unwrap().set_stick(0, 0);
+        assert_eq!(condition(&mut stick), vec![FUNCTION_CONTROLLER, 0x0000FEEF, 0x80808080]);
+        assert_eq!(stick.get_condition(FUNCTION_GUN), Response::Error(FUNCTION_UNSUPPORTED));
+    }
+
+    #[test]
+    fn takes_a_queued_state_at_each_poll() {
+        let mut controller = Controller::new(ControllerKind::Standard);
+        controller.input().lock().unwrap().queue_press(BUTTON_START, 1);
+        assert_eq!(condition(&mut controller)[1] & 0xFFFF, 0xFFF7);
+        assert_eq!(condition(&mut controller)[1] & 0xFFFF, 0xFFFF);
+    }
+
+    #[test]
+    fn aims_only_as_a_light_gun() {
+        let gun = Controller::new(ControllerKind::LightGun);
+        gun.input().lock().unwrap().set_aim(Some((320, 240)));
+        assert_eq!(gun.light_gun_position(), Some((320, 240)));
+        assert_eq!(gun.info().functions, FUNCTION_GUN | FUNCTION_CONTROLLER);
+
+        let controller = Controller::new(ControllerKind::Standard);
+        controller.input().lock().unwrap().set_aim(Some((320, 240)));
+        assert_eq!(controller.light_gun_position(), None);
+    }
+}
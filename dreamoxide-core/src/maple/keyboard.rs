@@ -0,0 +1,284 @@
+use maple::{MapleDevice, DeviceInfo, Response, SEGA_LICENSE, words, FUNCTION_KEYBOARD, FUNCTION_UNSUPPORTED};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Modifier keys, as bits of the first byte of the condition
+pub const MOD_LEFT_CTRL   : u8 = 0x01;
+pub const MOD_LEFT_SHIFT  : u8 = 0x02;
+pub const MOD_LEFT_ALT    : u8 = 0x04;
+pub const MOD_S1          : u8 = 0x08;
+pub const MOD_RIGHT_CTRL  : u8 = 0x10;
+pub const MOD_RIGHT_SHIFT : u8 = 0x20;
+pub const MOD_RIGHT_ALT   : u8 = 0x40;
+pub const MOD_S2          : u8 = 0x80;
+
+/// Lock lights, as bits of the second byte
+pub const LED_NUM_LOCK    : u8 = 0x01;
+pub const LED_CAPS_LOCK   : u8 = 0x02;
+pub const LED_SCROLL_LOCK : u8 = 0x04;
+
+/// Keys are identified by their USB HID usage codes, which the
+/// Dreamcast keyboard uses as well. A few that are handy for driving
+/// menus:
+pub const KEY_A         : u8 = 0x04;
+pub const KEY_1         : u8 = 0x1E;
+pub const KEY_ENTER     : u8 = 0x28;
+pub const KEY_ESCAPE    : u8 = 0x29;
+pub const KEY_BACKSPACE : u8 = 0x2A;
+pub const KEY_TAB       : u8 = 0x2B;
+pub const KEY_SPACE     : u8 = 0x2C;
+pub const KEY_F1        : u8 = 0x3A;
+pub const KEY_RIGHT     : u8 = 0x4F;
+pub const KEY_LEFT      : u8 = 0x50;
+pub const KEY_DOWN      : u8 = 0x51;
+pub const KEY_UP        : u8 = 0x52;
+
+/// Reported in every key slot when more keys are held than fit
+const KEY_ROLLOVER : u8 = 0x01;
+/// Keys reported at once
+const MAX_KEYS : usize = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct KeyboardState {
+    pub modifiers: u8,
+    pub leds: u8,
+    /// Held keys, in the order they were pressed
+    pub keys: Vec<u8>,
+}
+
+/// Input of a keyboard, shared between the host and the device. Works
+/// like `ControllerInput`: states queued by the host are taken one per
+/// poll, and the last one holds.
+pub struct KeyboardInput {
+    pub current: KeyboardState,
+    queue: VecDeque<KeyboardState>,
+}
+
+impl KeyboardInput {
+    pub fn new() -> KeyboardInput {
+        KeyboardInput {
+            current: KeyboardState::default(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn press(&mut self, key: u8) {
+        if !self.current.keys.contains(&key) {
+            self.current.keys.push(key);
+        }
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.current.keys.retain(|&k| k != key);
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: u8) {
+        self.current.modifiers = modifiers;
+    }
+
+    pub fn queue(&mut self, state: KeyboardState, frames: usize) {
+        for _ in 0..frames {
+            self.queue.push_back(state.clone());
+        }
+    }
+
+    /// Queues typing a text, each character held for some frames and
+    /// released for as many. Characters without a key on a US layout
+    /// are skipped.
+    pub fn queue_text(&mut self, text: &str, frames: usize) {
+        let base = self.queue.back().cloned().unwrap_or_else(|| self.current.clone());
+        for c in text.chars() {
+            if let Some((key, shift)) = key_for_char(c) {
+                let mut state = base.clone();
+                state.keys = vec![key];
+                if shift {
+                    state.modifiers |= MOD_LEFT_SHIFT;
+                }
+                self.queue(state, frames);
+                self.queue(base.clone(), frames);
+            }
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+
+    pub fn poll(&mut self) -> KeyboardState {
+        if let Some(state) = self.queue.pop_front() {
+            self.current = state;
+        }
+        self.current.clone()
+    }
+}
+
+impl Default for KeyboardInput {
+    fn default() -> KeyboardInput {
+        KeyboardInput::new()
+    }
+}
+
+/// The key and whether shift is needed to type a character
+pub fn key_for_char(c: char) -> Option<(u8, bool)> {
+    // 0x32 is a key of non-US layouts only
+    const UNSHIFTED : &str = "-=[]\\\0;'`,./";
+    const SHIFTED   : &str = "_+{}|\0:\"~<>?";
+
+    match c {
+        'a' ..= 'z' => Some((KEY_A + (c as u8 - b'a'), false)),
+        'A' ..= 'Z' => Some((KEY_A + (c as u8 - b'A'), true)),
+        '1' ..= '9' => Some((KEY_1 + (c as u8 - b'1'), false)),
+        '0'  => Some((0x27, false)),
+        '\n' => Some((KEY_ENTER, false)),
+        '\t' => Some((KEY_TAB, false)),
+        ' '  => Some((KEY_SPACE, false)),
+        '\0' => None,
+        _ => {
+            // Symbols on the digit row, then those from 0x2D on
+            if let Some(i) = "!@#$%^&*()".find(c) {
+                return Some((KEY_1 + i as u8, true));
+            }
+            UNSHIFTED.find(c).map(|i| (0x2D + i as u8, false))
+                .or_else(|| SHIFTED.find(c).map(|i| (0x2D + i as u8, true)))
+        }
+    }
+}
+
+/// The Dreamcast keyboard
+pub struct Keyboard {
+    input: Arc<Mutex<KeyboardInput>>,
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard {
+            input: Arc::new(Mutex::new(KeyboardInput::new())),
+        }
+    }
+
+    pub fn input(&self) -> Arc<Mutex<KeyboardInput>> {
+        self.input.clone()
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Keyboard {
+        Keyboard::new()
+    }
+}
+
+impl MapleDevice for Keyboard {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            functions: FUNCTION_KEYBOARD,
+            // US layout
+            function_data: [0x80000502, 0, 0],
+            area_code: 0xFF,
+            connector_direction: 0,
+            product_name: "Keyboard",
+            license: SEGA_LICENSE,
+            standby_power: 0x012C,
+            max_power: 0x0190,
+        }
+    }
+
+    fn get_condition(&mut self, function: u32) -> Response {
+        if function != FUNCTION_KEYBOARD {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        let state = self.input.lock().unwrap().poll();
+        let mut condition = [0; 2 + MAX_KEYS];
+        condition[0] = state.modifiers;
+        condition[1] = state.leds;
+        if state.keys.len() > MAX_KEYS {
+            for key in condition[2..].iter_mut() {
+                *key = KEY_ROLLOVER;
+            }
+        } else {
+            condition[2..2 + state.keys.len()].copy_from_slice(&state.keys);
+        }
+
+        let mut data = vec![FUNCTION_KEYBOARD];
+        data.extend(words(&condition));
+        Response::Data(data)
+    }
+
+    /// Sets the lock lights
+    fn set_condition(&mut self, function: u32, data: &[u32]) -> Response {
+        if function != FUNCTION_KEYBOARD {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        let leds = data.first().cloned().unwrap_or(0) as u8;
+        self.input.lock().unwrap().current.leds = leds;
+        Response::Ack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maple::{bytes, FUNCTION_MOUSE};
+
+    fn condition(keyboard: &mut Keyboard) -> Vec<u8> {
+        match keyboard.get_condition(FUNCTION_KEYBOARD) {
+            Response::Data(words) => {
+                assert_eq!(words[0], FUNCTION_KEYBOARD);
+                bytes(&words[1..])
+            },
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+
+    #[test]
+    fn maps_characters_to_us_keys() {
+        assert_eq!(key_for_char('a'), Some((KEY_A, false)));
+        assert_eq!(key_for_char('Z'), Some((KEY_A + 25, true)));
+        assert_eq!(key_for_char('0'), Some((0x27, false)));
+        assert_eq!(key_for_char('!'), Some((KEY_1, true)));
+        assert_eq!(key_for_char('-'), Some((0x2D, false)));
+        assert_eq!(key_for_char('_'), Some((0x2D, true)));
+        assert_eq!(key_for_char('?'), Some((0x38, true)));
+        assert_eq!(key_for_char('\0'), None);
+        assert_eq!(key_for_char('\u{e9}'), None);
+    }
+
+    #[test]
+    fn reports_modifiers_lights_and_keys() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.set_condition(FUNCTION_KEYBOARD, &[LED_CAPS_LOCK as u32]), Response::Ack);
+        {
+            let input = keyboard.input();
+            let mut input = input.lock().unwrap();
+            input.set_modifiers(MOD_LEFT_SHIFT);
+            input.press(KEY_A);
+            input.press(KEY_ENTER);
+            input.press(KEY_A);
+        }
+        assert_eq!(condition(&mut keyboard), vec![MOD_LEFT_SHIFT, LED_CAPS_LOCK, KEY_A, KEY_ENTER, 0, 0, 0, 0]);
+
+        // Too many keys roll over
+        for key in 0x10..0x16 {
+            keyboard.input().lock().unwrap().press(key);
+        }
+        assert_eq!(&condition(&mut keyboard)[2..], &[KEY_ROLLOVER; MAX_KEYS]);
+        assert_eq!(keyboard.get_condition(FUNCTION_MOUSE), Response::Error(FUNCTION_UNSUPPORTED));
+    }
+
+    #[test]
+    fn types_queued_text() {
+        let mut keyboard = Keyboard::new();
+        keyboard.input().lock().unwrap().queue_text("Hi\u{e9}", 1);
+        assert_eq!(keyboard.input().lock().unwrap().queued(), 4);
+        assert_eq!(&condition(&mut keyboard)[..3], &[MOD_LEFT_SHIFT, 0, KEY_A + 7]);
+        assert_eq!(&condition(&mut keyboard)[..3], &[0, 0, 0]);
+        assert_eq!(&condition(&mut keyboard)[..3], &[0, 0, KEY_A + 8]);
+        assert_eq!(&condition(&mut keyboard)[..3], &[0, 0, 0]);
+    }
+}
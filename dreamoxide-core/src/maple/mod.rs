@@ -0,0 +1,507 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use asic::Interrupt;
+use pvr::SPG_TRIGGER_POS;
+
+use std::sync::{Arc, Mutex};
+
+pub mod controller;
+pub mod keyboard;
+pub mod mouse;
+pub mod puru_puru;
+pub mod vmu;
+
+/// Maple DMA registers of the system bus
+pub const SB_MDSTAR : usize = 0x005F6C04;
+pub const SB_MDTSEL : usize = 0x005F6C10;
+pub const SB_MDEN   : usize = 0x005F6C14;
+pub const SB_MDST   : usize = 0x005F6C18;
+pub const SB_MSYS   : usize = 0x005F6C80;
+pub const SB_MST    : usize = 0x005F6C84;
+pub const SB_MSHTCL : usize = 0x005F6C88;
+pub const SB_MDAPRO : usize = 0x005F6C8C;
+pub const SB_MMSEL  : usize = 0x005F6CE8;
+
+pub const MAPLE_REGISTER_BASE : usize = 0x005F6C00;
+pub const MAPLE_REGISTER_END  : usize = 0x005F6CFF;
+
+/// Commands sent to a peripheral
+pub const DEVICE_REQUEST     : u8 = 0x01;
+pub const ALL_STATUS_REQUEST : u8 = 0x02;
+pub const DEVICE_RESET       : u8 = 0x03;
+pub const DEVICE_KILL        : u8 = 0x04;
+pub const GET_CONDITION      : u8 = 0x09;
+pub const GET_MEDIA_INFO     : u8 = 0x0A;
+pub const BLOCK_READ         : u8 = 0x0B;
+pub const BLOCK_WRITE        : u8 = 0x0C;
+pub const GET_LAST_ERROR     : u8 = 0x0D;
+pub const SET_CONDITION      : u8 = 0x0E;
+
+/// Responses of a peripheral
+pub const DEVICE_STATUS      : u8 = 0x05;
+pub const DEVICE_ALL_STATUS  : u8 = 0x06;
+pub const DEVICE_REPLY       : u8 = 0x07;
+pub const DATA_TRANSFER      : u8 = 0x08;
+pub const FUNCTION_UNSUPPORTED : u8 = 0xFE;
+pub const UNKNOWN_COMMAND    : u8 = 0xFD;
+pub const TRANSMIT_AGAIN     : u8 = 0xFC;
+pub const FILE_ERROR         : u8 = 0xFB;
+
+/// Function codes, as the 32-bit words the host reads. On the bus they
+/// are sent most significant byte first.
+pub const FUNCTION_VIBRATION  : u32 = 0x00010000;
+pub const FUNCTION_MOUSE      : u32 = 0x00020000;
+pub const FUNCTION_CONTROLLER : u32 = 0x01000000;
+pub const FUNCTION_STORAGE    : u32 = 0x02000000;
+pub const FUNCTION_LCD        : u32 = 0x04000000;
+pub const FUNCTION_CLOCK      : u32 = 0x08000000;
+pub const FUNCTION_MICROPHONE : u32 = 0x10000000;
+pub const FUNCTION_AR_GUN     : u32 = 0x20000000;
+pub const FUNCTION_KEYBOARD   : u32 = 0x40000000;
+pub const FUNCTION_GUN        : u32 = 0x80000000;
+
+pub const PORTS : usize = 4;
+/// Sub-peripherals that fit a main peripheral, like the two slots of
+/// a controller
+pub const SUB_UNITS : usize = 5;
+
+/// Frame list patterns
+const PATTERN_NORMAL    : u32 = 0;
+const PATTERN_LIGHT_GUN : u32 = 2;
+
+/// License text of first party peripherals
+pub const SEGA_LICENSE : &str = "Produced By or Under License From SEGA ENTERPRISES,LTD.";
+
+/// Frames one DMA processes at most, one for each unit on the bus, so
+/// that a list without its last frame marked ends
+const MAX_FRAMES : usize = PORTS * (SUB_UNITS + 1);
+
+/// Area 3, where system RAM and its mirrors hold the frame list
+const SYSTEM_RAM_AREA : MemoryRange = MemoryRange(0x0C000000, 0x0FFFFFFF);
+
+/// Written to the receive buffer when no device answers
+const NO_RESPONSE : u32 = 0xFFFFFFFF;
+
+/// What a peripheral reports about itself to DEVICE_REQUEST
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// All the functions of the device, ORed together
+    pub functions: u32,
+    /// Function specific data, for each function from the highest bit
+    pub function_data: [u32; 3],
+    pub area_code: u8,
+    pub connector_direction: u8,
+    pub product_name: &'static str,
+    pub license: &'static str,
+    /// Current drawn, in 0.1 mA
+    pub standby_power: u16,
+    pub max_power: u16,
+}
+
+impl DeviceInfo {
+    /// The 28 words of the device status response
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut bytes = Vec::with_capacity(112);
+        for &word in [self.functions].iter().chain(self.function_data.iter()) {
+            bytes.extend_from_slice(&le32(word));
+        }
+        bytes.push(self.area_code);
+        bytes.push(self.connector_direction);
+        // Text fields are padded with spaces
+        for &(text, length) in [(self.product_name, 30), (self.license, 60)].iter() {
+            bytes.extend(text.bytes().chain(::std::iter::repeat(b' ')).take(length));
+        }
+        bytes.extend_from_slice(&[self.standby_power as u8, (self.standby_power >> 8) as u8]);
+        bytes.extend_from_slice(&[self.max_power as u8, (self.max_power >> 8) as u8]);
+        words(&bytes)
+    }
+}
+
+/// A peripheral's answer to a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// DATA_TRANSFER with the given words, starting with the function code
+    Data(Vec<u32>),
+    /// DEVICE_REPLY, acknowledging the command
+    Ack,
+    /// One of the error responses
+    Error(u8),
+}
+
+/// A peripheral on the Maple bus. The bus answers the device request
+/// from `info` and hands the other commands to the methods below, whose
+/// defaults refuse them.
+pub trait MapleDevice: Send {
+    fn info(&self) -> DeviceInfo;
+
+    fn reset(&mut self) {}
+
+    fn get_condition(&mut self, _function: u32) -> Response {
+        Response::Error(FUNCTION_UNSUPPORTED)
+    }
+
+    fn get_media_info(&mut self, _function: u32, _partition: u32) -> Response {
+        Response::Error(FUNCTION_UNSUPPORTED)
+    }
+
+    fn block_read(&mut self, _function: u32, _location: u32) -> Response {
+        Response::Error(FUNCTION_UNSUPPORTED)
+    }
+
+    fn block_write(&mut self, _function: u32, _location: u32, _data: &[u32]) -> Response {
+        Response::Error(FUNCTION_UNSUPPORTED)
+    }
+
+    /// Ends a block write that was split into phases
+    fn get_last_error(&mut self, _function: u32, _location: u32) -> Response {
+        Response::Ack
+    }
+
+    fn set_condition(&mut self, _function: u32, _data: &[u32]) -> Response {
+        Response::Error(FUNCTION_UNSUPPORTED)
+    }
+
+    /// Beam position a light gun sees, horizontal and vertical, or None
+    /// for devices that aren't one or point off the screen
+    fn light_gun_position(&self) -> Option<(u16, u16)> {
+        None
+    }
+}
+
+/// The devices plugged into one port: a main peripheral and those
+/// plugged into it
+pub struct MaplePort {
+    pub main: Option<Box<dyn MapleDevice>>,
+    pub sub: Vec<Option<Box<dyn MapleDevice>>>,
+}
+
+impl MaplePort {
+    fn new() -> MaplePort {
+        MaplePort {
+            main: None,
+            sub: (0..SUB_UNITS).map(|_| None).collect(),
+        }
+    }
+
+    /// The device at a unit, 0 being the main peripheral
+    pub fn unit(&mut self, unit: usize) -> Option<&mut Box<dyn MapleDevice>> {
+        match unit {
+            0 => self.main.as_mut(),
+            _ => self.sub.get_mut(unit - 1).and_then(|d| d.as_mut())
+        }
+    }
+
+    /// Bits of the sub-peripherals present, as the main peripheral
+    /// reports them in its address
+    fn sub_mask(&self) -> u8 {
+        self.sub.iter().enumerate().filter(|&(_, d)| d.is_some()).fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+}
+
+/// The Maple bus controller. A DMA started through SB_MDST, by software
+/// or at vertical blank, walks a list of command frames in system
+/// memory, sends each to a port and stores the response where the frame
+/// says.
+///
+/// Like the other DMA channels, the transfer is carried out by the owner
+/// of the memory calling `run`.
+pub struct Maple {
+    pub ports: Vec<MaplePort>,
+    pub mdstar: u32,
+    pub mdtsel: u32,
+    pub mden: u32,
+    pub mdst: u32,
+    pub msys: u32,
+    pub mdapro: u32,
+    pub mmsel: u32,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+impl Maple {
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<Maple>> {
+        let maple = Arc::new(Mutex::new(Maple {
+            ports: (0..PORTS).map(|_| MaplePort::new()).collect(),
+            mdstar: 0,
+            mdtsel: 0,
+            mden: 0,
+            mdst: 0,
+            msys: 0,
+            mdapro: 0,
+            mmsel: 0,
+            asic,
+        }));
+
+        mem.register_mapped_device(MemoryRange(MAPLE_REGISTER_BASE, MAPLE_REGISTER_END), maple.clone());
+        maple
+    }
+
+    /// Plugs a device into a port, as its main peripheral for unit 0 or
+    /// into one of its slots, or unplugs it
+    pub fn connect(&mut self, port: usize, unit: usize, device: Option<Box<dyn MapleDevice>>) {
+        match unit {
+            0 => self.ports[port].main = device,
+            _ => self.ports[port].sub[unit - 1] = device
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.mdst & 1 != 0
+    }
+
+    /// Starts the DMA at vertical blank if SB_MDTSEL asks for it
+    pub fn vblank(&mut self) {
+        if self.mden & 1 != 0 && self.mdtsel & 1 != 0 {
+            self.mdst = 1;
+        }
+    }
+
+    /// Processes the frame list and raises the DMA end interrupt. The
+    /// list ends at the frame marked last, after a frame for each unit
+    /// or where it runs off system RAM.
+    pub fn run(&mut self, mem: &mut Memory) {
+        if !self.is_pending() {
+            return;
+        }
+
+        let mut address = (self.mdstar & 0x1FFFFFE0) as usize;
+        for _ in 0..MAX_FRAMES {
+            if !SYSTEM_RAM_AREA.is_within(address + 3) {
+                break;
+            }
+            let instruction = mem.read_u32(address);
+            let last = instruction & 0x80000000 != 0;
+
+            let port = ((instruction >> 16) & 3) as usize;
+
+            // Patterns other than 0 drive the bus lines without a frame
+            match (instruction >> 8) & 7 {
+                PATTERN_NORMAL => {
+                    let length = (instruction & 0xFF) as usize + 1;
+                    // A frame running off RAM ends the list
+                    if !SYSTEM_RAM_AREA.is_within(address + 8 + length * 4 - 1) {
+                        break;
+                    }
+                    let receive = (mem.read_u32(address + 4) & 0x1FFFFFE0) as usize;
+                    let frame: Vec<u32> = (0..length).map(|i| mem.read_u32(address + 8 + i * 4)).collect();
+
+                    for (i, &word) in self.transfer(port, &frame).iter().enumerate() {
+                        mem.write_u32(receive + i * 4, word);
+                    }
+                    address += 8 + length * 4;
+                },
+                PATTERN_LIGHT_GUN => {
+                    // The gun's sensor latches the beam counters when it
+                    // sees the raster
+                    let position = self.ports[port].main.as_ref().and_then(|d| d.light_gun_position());
+                    if let Some((x, y)) = position {
+                        mem.write_u32(SPG_TRIGGER_POS, (x as u32 & 0x3FF) | ((y as u32 & 0x3FF) << 16));
+                    }
+                    address += 4;
+                },
+                _ => address += 4
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.mdst = 0;
+        self.asic.lock().unwrap().raise(Interrupt::MapleDmaDone);
+    }
+
+    /// Sends a command frame to a port, returning the response frame
+    fn transfer(&mut self, port: usize, frame: &[u32]) -> Vec<u32> {
+        let command = frame[0] as u8;
+        let recipient = (frame[0] >> 8) as u8;
+        let sender = (frame[0] >> 16) as u8;
+        let data = &frame[1..];
+        let argument = |i: usize| data.get(i).cloned().unwrap_or(0);
+
+        // Bit 5 of the address selects the main peripheral, bits 0 to 4
+        // the sub-peripherals
+        let unit = match recipient & 0x3F {
+            r if r & 0x20 != 0 || r == 0 => 0,
+            r => r.trailing_zeros() as usize + 1
+        };
+        let sub_mask = self.ports[port].sub_mask();
+        let source = ((port as u8) << 6) | if unit == 0 { 0x20 | sub_mask } else { 1 << (unit - 1) };
+
+        let device = match self.ports[port].unit(unit) {
+            Some(device) => device,
+            None => return vec![NO_RESPONSE]
+        };
+
+        let response = match command {
+            DEVICE_REQUEST     => Response::Data(device.info().to_words()),
+            ALL_STATUS_REQUEST => Response::Data(device.info().to_words()),
+            DEVICE_RESET       => {
+                device.reset();
+                Response::Ack
+            },
+            DEVICE_KILL        => Response::Ack,
+            GET_CONDITION      => device.get_condition(argument(0)),
+            GET_MEDIA_INFO     => device.get_media_info(argument(0), argument(1)),
+            BLOCK_READ         => device.block_read(argument(0), argument(1)),
+            BLOCK_WRITE        => device.block_write(argument(0), argument(1), data.get(2..).unwrap_or(&[])),
+            GET_LAST_ERROR     => device.get_last_error(argument(0), argument(1)),
+            SET_CONDITION      => device.set_condition(argument(0), data.get(1..).unwrap_or(&[])),
+            _                  => Response::Error(UNKNOWN_COMMAND)
+        };
+
+        let (code, words) = match response {
+            Response::Data(words) => {
+                let code = match command {
+                    DEVICE_REQUEST     => DEVICE_STATUS,
+                    ALL_STATUS_REQUEST => DEVICE_ALL_STATUS,
+                    _                  => DATA_TRANSFER
+                };
+                (code, words)
+            },
+            Response::Ack => (DEVICE_REPLY, Vec::new()),
+            Response::Error(code) => (code, Vec::new())
+        };
+
+        let mut reply = Vec::with_capacity(words.len() + 1);
+        reply.push(code as u32 | ((sender as u32) << 8) | ((source as u32) << 16) | ((words.len() as u32) << 24));
+        reply.extend(words);
+        reply
+    }
+}
+
+impl MappedDevice for Maple {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address & !3 {
+            SB_MDSTAR => self.mdstar,
+            SB_MDTSEL => self.mdtsel,
+            SB_MDEN   => self.mden,
+            SB_MDST   => self.mdst,
+            SB_MSYS   => self.msys,
+            SB_MDAPRO => self.mdapro,
+            SB_MMSEL  => self.mmsel,
+            _         => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address & !3 {
+            SB_MDSTAR => self.mdstar = value & 0x1FFFFFE0,
+            SB_MDTSEL => self.mdtsel = value & 1,
+            SB_MDEN   => self.mden = value & 1,
+            SB_MDST if value & 1 != 0 && self.mden & 1 != 0 => self.mdst = 1,
+            SB_MSYS   => self.msys = value,
+            // Only written with the 0x6155 code in the upper half
+            SB_MDAPRO if value >> 16 == 0x6155 => self.mdapro = value & 0x7F7F,
+            SB_MMSEL  => self.mmsel = value & 1,
+            _         => ()
+        }
+    }
+}
+
+fn le32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+/// Packs bytes into little endian words, in the order they are stored
+pub fn words(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks(4).map(|b| b.iter().rev().fold(0u32, |word, &byte| (word << 8) | byte as u32)).collect()
+}
+
+/// Unpacks little endian words into the bytes they hold
+pub fn bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|&word| le32(word).to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::TestMemory;
+
+    struct TestDevice;
+
+    impl MapleDevice for TestDevice {
+        fn info(&self) -> DeviceInfo {
+            DeviceInfo {
+                functions: FUNCTION_CONTROLLER,
+                function_data: [0xFE060F00, 0, 0],
+                area_code: 0xFF,
+                connector_direction: 0,
+                product_name: "Test Device",
+                license: SEGA_LICENSE,
+                standby_power: 0x01AE,
+                max_power: 0x01F4,
+            }
+        }
+    }
+
+    const LIST : usize = 0x0C001000;
+    const RECEIVE : usize = 0x0C002000;
+
+    fn start(maple: &Arc<Mutex<Maple>>, list: usize) {
+        let mut maple = maple.lock().unwrap();
+        maple.write(SB_MDSTAR, list as u32, 4);
+        maple.write(SB_MDEN, 1, 4);
+        maple.write(SB_MDST, 1, 4);
+    }
+
+    #[test]
+    fn answers_a_device_request_and_ends_the_dma() {
+        let mut mem = TestMemory::new();
+        let asic = Asic::new(&mut mem);
+        let maple = Maple::new(&mut mem, asic.clone());
+        maple.lock().unwrap().connect(0, 0, Some(Box::new(TestDevice)));
+        maple.lock().unwrap().connect(0, 2, Some(Box::new(TestDevice)));
+
+        // A device request to port A, then one to the empty port B
+        // ending the list
+        mem.write_u32(LIST, 0x00000000);
+        mem.write_u32(LIST + 4, RECEIVE as u32);
+        mem.write_u32(LIST + 8, DEVICE_REQUEST as u32 | (0x20 << 8));
+        mem.write_u32(LIST + 12, 0x80010000);
+        mem.write_u32(LIST + 16, (RECEIVE + 0x100) as u32);
+        mem.write_u32(LIST + 20, DEVICE_REQUEST as u32 | (0x60 << 8) | (0x40 << 16));
+        start(&maple, LIST);
+        maple.lock().unwrap().run(&mut mem);
+
+        // The sender is told which sub-peripherals are plugged in
+        assert_eq!(mem.read_u32(RECEIVE), DEVICE_STATUS as u32 | (0x22 << 16) | (28 << 24));
+        assert_eq!(mem.read_u32(RECEIVE + 4), FUNCTION_CONTROLLER);
+        assert_eq!(mem.read_u32(RECEIVE + 8), 0xFE060F00);
+        assert_eq!(mem.read_u32(RECEIVE + 20), 0x655400FF);
+        assert_eq!(mem.read_u32(RECEIVE + 0x100), NO_RESPONSE);
+
+        assert_eq!(maple.lock().unwrap().read(SB_MDST, 4), 0);
+        assert!(asic.lock().unwrap().istnrm & (1 << Interrupt::MapleDmaDone as u32) != 0);
+    }
+
+    #[test]
+    fn ends_an_unterminated_list() {
+        let mut mem = TestMemory::new();
+        let asic = Asic::new(&mut mem);
+        let maple = Maple::new(&mut mem, asic.clone());
+
+        for i in 0..MAX_FRAMES + 4 {
+            mem.write_u32(LIST + i * 12, 0);
+            mem.write_u32(LIST + i * 12 + 4, (RECEIVE + i * 32) as u32);
+            mem.write_u32(LIST + i * 12 + 8, DEVICE_REQUEST as u32 | (0x20 << 8));
+        }
+        start(&maple, LIST);
+        maple.lock().unwrap().run(&mut mem);
+        assert_eq!(mem.read_u32(RECEIVE + (MAX_FRAMES - 1) * 32), NO_RESPONSE);
+        assert_eq!(mem.read_u32(RECEIVE + MAX_FRAMES * 32), 0);
+        assert_eq!(maple.lock().unwrap().mdst, 0);
+
+        // A frame running past the end of RAM isn't sent
+        asic.lock().unwrap().istnrm = 0;
+        mem.write_u32(0x0FFFFFE0, 0x80000007);
+        mem.write_u32(0x0FFFFFE4, RECEIVE as u32);
+        mem.write_u32(RECEIVE, 0);
+        start(&maple, 0x0FFFFFE0);
+        maple.lock().unwrap().run(&mut mem);
+        assert_eq!(mem.read_u32(RECEIVE), 0);
+        assert!(asic.lock().unwrap().istnrm & (1 << Interrupt::MapleDmaDone as u32) != 0);
+    }
+}
@@ -0,0 +1,165 @@
+use maple::{MapleDevice, DeviceInfo, Response, SEGA_LICENSE, words, FUNCTION_MOUSE, FUNCTION_UNSUPPORTED};
+
+use std::sync::{Arc, Mutex};
+
+/// Buttons, active low on the bus
+pub const MOUSE_RIGHT  : u32 = 1 << 1;
+pub const MOUSE_LEFT   : u32 = 1 << 2;
+pub const MOUSE_MIDDLE : u32 = 1 << 3;
+
+/// Axes report movement since the last poll as an offset from this
+const AXIS_CENTER : i32 = 0x200;
+const AXES : usize = 8;
+
+/// Input of a mouse, shared between the host and the device. Movement
+/// adds up until the game polls the mouse, which reports and clears it.
+pub struct MouseInput {
+    /// Held buttons
+    pub buttons: u32,
+    pub dx: i32,
+    pub dy: i32,
+    pub wheel: i32,
+}
+
+impl MouseInput {
+    pub fn new() -> MouseInput {
+        MouseInput {
+            buttons: 0,
+            dx: 0,
+            dy: 0,
+            wheel: 0,
+        }
+    }
+
+    pub fn press(&mut self, buttons: u32) {
+        self.buttons |= buttons;
+    }
+
+    pub fn release(&mut self, buttons: u32) {
+        self.buttons &= !buttons;
+    }
+
+    /// Moves the mouse, right and down being positive
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.dx += dx;
+        self.dy += dy;
+    }
+
+    pub fn scroll(&mut self, amount: i32) {
+        self.wheel += amount;
+    }
+
+    /// Takes the movement since the last poll. What doesn't fit an axis
+    /// is left for the next one.
+    fn take(value: &mut i32) -> u16 {
+        let delta = (*value).clamp(-AXIS_CENTER, AXIS_CENTER - 1);
+        *value -= delta;
+        (AXIS_CENTER + delta) as u16
+    }
+}
+
+impl Default for MouseInput {
+    fn default() -> MouseInput {
+        MouseInput::new()
+    }
+}
+
+/// The Dreamcast mouse
+pub struct Mouse {
+    input: Arc<Mutex<MouseInput>>,
+}
+
+impl Mouse {
+    pub fn new() -> Mouse {
+        Mouse {
+            input: Arc::new(Mutex::new(MouseInput::new())),
+        }
+    }
+
+    pub fn input(&self) -> Arc<Mutex<MouseInput>> {
+        self.input.clone()
+    }
+}
+
+impl Default for Mouse {
+    fn default() -> Mouse {
+        Mouse::new()
+    }
+}
+
+impl MapleDevice for Mouse {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            functions: FUNCTION_MOUSE,
+            // Three buttons and three axes
+            function_data: [0x00071000, 0, 0],
+            area_code: 0xFF,
+            connector_direction: 0,
+            product_name: "Dreamcast Mouse",
+            license: SEGA_LICENSE,
+            standby_power: 0x0069,
+            max_power: 0x0120,
+        }
+    }
+
+    fn get_condition(&mut self, function: u32) -> Response {
+        if function != FUNCTION_MOUSE {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        let mut input = self.input.lock().unwrap();
+        let mut axes = [AXIS_CENTER as u16; AXES];
+        axes[0] = MouseInput::take(&mut input.dx);
+        axes[1] = MouseInput::take(&mut input.dy);
+        axes[2] = MouseInput::take(&mut input.wheel);
+
+        let mut data = vec![FUNCTION_MOUSE, !input.buttons];
+        let bytes: Vec<u8> = axes.iter().flat_map(|&axis| vec![axis as u8, (axis >> 8) as u8]).collect();
+        data.extend(words(&bytes));
+        Response::Data(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(mouse: &mut Mouse) -> Vec<u32> {
+        match mouse.get_condition(FUNCTION_MOUSE) {
+            Response::Data(words) => words,
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+
+    #[test]
+    fn reports_movement_since_the_last_poll() {
+        let mut mouse = Mouse::new();
+        {
+            let input = mouse.input();
+            let mut input = input.lock().unwrap();
+            input.press(MOUSE_LEFT | MOUSE_RIGHT);
+            input.release(MOUSE_RIGHT);
+            input.move_by(5, -3);
+            input.move_by(1, 0);
+            input.scroll(-1);
+        }
+        let words = condition(&mut mouse);
+        assert_eq!(words.len(), 2 + AXES / 2);
+        assert_eq!(words[1], !MOUSE_LEFT);
+        assert_eq!(words[2], 0x01FD0206);
+        assert_eq!(words[3], 0x020001FF);
+        assert_eq!(words[4..], [0x02000200, 0x02000200]);
+
+        // Nothing moved since
+        assert_eq!(condition(&mut mouse)[2], 0x02000200);
+    }
+
+    #[test]
+    fn carries_over_what_an_axis_cannot_hold() {
+        let mut mouse = Mouse::new();
+        mouse.input().lock().unwrap().move_by(1000, -1000);
+        assert_eq!(condition(&mut mouse)[2], 0x000003FF);
+        assert_eq!(condition(&mut mouse)[2], 0x00180200 + 489);
+        assert_eq!(mouse.input().lock().unwrap().dx, 0);
+    }
+}
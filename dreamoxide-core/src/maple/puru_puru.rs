@@ -0,0 +1,141 @@
+use maple::{MapleDevice, DeviceInfo, Response, SEGA_LICENSE, FUNCTION_VIBRATION, FUNCTION_UNSUPPORTED};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A rumble command sent by the game
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Vibration {
+    /// Strength from 0, stopped, to 7
+    pub power: u8,
+    /// Raw frequency setting; the motor turns at (frequency + 1) / 2 Hz
+    pub frequency: u8,
+    /// Raw inclination, how the power ramps over the vibration
+    pub inclination: u8,
+    /// Vibrates until told otherwise rather than once
+    pub continuous: bool,
+    /// The condition word as received, control byte lowest
+    pub raw: u32,
+}
+
+impl Vibration {
+    pub fn parse(word: u32) -> Vibration {
+        let control = word as u8;
+        let power = (word >> 8) as u8;
+
+        // Forward and reverse power, of which one is used
+        Vibration {
+            power: ((power >> 4) & 7).max(power & 7),
+            frequency: (word >> 16) as u8,
+            inclination: (word >> 24) as u8,
+            continuous: control & 1 != 0,
+            raw: word,
+        }
+    }
+}
+
+/// The Puru Puru (Jump) pack, plugged into a controller. The commands it
+/// receives are queued for the host to drive a rumble motor with.
+pub struct PuruPuru {
+    condition: u32,
+    events: Arc<Mutex<VecDeque<Vibration>>>,
+}
+
+impl PuruPuru {
+    pub fn new() -> PuruPuru {
+        PuruPuru {
+            condition: 0,
+            events: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// The queue of vibrations, oldest first, which the host drains
+    pub fn events(&self) -> Arc<Mutex<VecDeque<Vibration>>> {
+        self.events.clone()
+    }
+}
+
+impl Default for PuruPuru {
+    fn default() -> PuruPuru {
+        PuruPuru::new()
+    }
+}
+
+impl MapleDevice for PuruPuru {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            functions: FUNCTION_VIBRATION,
+            // A single vibration source
+            function_data: [0x00000101, 0, 0],
+            area_code: 0xFF,
+            connector_direction: 0,
+            product_name: "Puru Puru Pack",
+            license: SEGA_LICENSE,
+            standby_power: 0x00C8,
+            max_power: 0x0640,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.condition = 0;
+    }
+
+    fn get_condition(&mut self, function: u32) -> Response {
+        match function {
+            FUNCTION_VIBRATION => Response::Data(vec![FUNCTION_VIBRATION, self.condition]),
+            _ => Response::Error(FUNCTION_UNSUPPORTED)
+        }
+    }
+
+    /// The range of settings the motor supports
+    fn get_media_info(&mut self, function: u32, _partition: u32) -> Response {
+        match function {
+            FUNCTION_VIBRATION => Response::Data(vec![FUNCTION_VIBRATION, 0x3B07E010]),
+            _ => Response::Error(FUNCTION_UNSUPPORTED)
+        }
+    }
+
+    fn set_condition(&mut self, function: u32, data: &[u32]) -> Response {
+        if function != FUNCTION_VIBRATION {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        self.condition = data.first().cloned().unwrap_or(0);
+        self.events.lock().unwrap().push_back(Vibration::parse(self.condition));
+        Response::Ack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_condition_word() {
+        let forward = Vibration::parse(0x20110701);
+        assert_eq!(forward, Vibration { power: 7, frequency: 0x11, inclination: 0x20, continuous: true, raw: 0x20110701 });
+        let reverse = Vibration::parse(0x00003000);
+        assert_eq!((reverse.power, reverse.continuous), (3, false));
+    }
+
+    #[test]
+    fn queues_the_vibrations_it_is_sent() {
+        let mut pack = PuruPuru::new();
+        assert_eq!(pack.set_condition(FUNCTION_VIBRATION, &[0x00110701]), Response::Ack);
+        assert_eq!(pack.set_condition(FUNCTION_VIBRATION, &[0x00000000]), Response::Ack);
+        assert_eq!(pack.get_condition(FUNCTION_VIBRATION), Response::Data(vec![FUNCTION_VIBRATION, 0]));
+
+        {
+            let events = pack.events();
+            let mut events = events.lock().unwrap();
+            assert_eq!(events.pop_front().map(|v| v.power), Some(7));
+            assert_eq!(events.pop_front().map(|v| v.power), Some(0));
+            assert!(events.is_empty());
+        }
+
+        pack.set_condition(FUNCTION_VIBRATION, &[0x00110701]);
+        pack.reset();
+        assert_eq!(pack.get_condition(FUNCTION_VIBRATION), Response::Data(vec![FUNCTION_VIBRATION, 0]));
+        assert!(pack.set_condition(0, &[1]) != Response::Ack);
+    }
+}
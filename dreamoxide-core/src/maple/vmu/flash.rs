@@ -0,0 +1,699 @@
+use disc::bcd;
+use disc::image::invalid_data;
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const BLOCK_SIZE : usize = 512;
+pub const BLOCKS     : usize = 256;
+pub const FLASH_SIZE : usize = BLOCK_SIZE * BLOCKS;
+
+/// Layout of a formatted flash. The directory occupies the blocks
+/// below its first one, and the user blocks start at 0.
+pub const ROOT_BLOCK       : u16 = 255;
+pub const FAT_BLOCK        : u16 = 254;
+pub const DIRECTORY_BLOCK  : u16 = 253;
+pub const DIRECTORY_BLOCKS : u16 = 13;
+pub const USER_BLOCKS      : u16 = 200;
+
+/// FAT entries of blocks that are free, and of the last block of a file
+pub const FAT_FREE : u16 = 0xFFFC;
+pub const FAT_END  : u16 = 0xFFFA;
+
+const DIRECTORY_ENTRY_SIZE : usize = 32;
+/// Written over the first bytes of the root block by formatting
+const FORMAT_MARKER : u8 = 0x55;
+
+/// Size of the .VMI description of a .VMS file
+pub const VMI_SIZE : usize = 108;
+
+/// A date, as stored in BCD in the root block and the directory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Monday
+    pub weekday: u8,
+}
+
+impl Timestamp {
+    /// The date of seconds since 1970-01-01
+    pub fn from_unix(seconds: u64) -> Timestamp {
+        let days = seconds / 86400;
+        let time = seconds % 86400;
+
+        // Civil date from days, with years starting in March
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Timestamp {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 3) % 7) as u8,
+        }
+    }
+
+    /// The host's current time
+    pub fn now() -> Timestamp {
+        Timestamp::from_unix(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+    }
+
+    pub fn to_bcd(&self) -> [u8; 8] {
+        [bcd((self.year / 100) as u8), bcd((self.year % 100) as u8), bcd(self.month), bcd(self.day),
+         bcd(self.hour), bcd(self.minute), bcd(self.second), bcd(self.weekday)]
+    }
+
+    pub fn from_bcd(bytes: &[u8]) -> Timestamp {
+        let value = |byte: u8| (byte >> 4) * 10 + (byte & 0xF);
+        Timestamp {
+            year: value(bytes[0]) as u16 * 100 + value(bytes[1]) as u16,
+            month: value(bytes[2]),
+            day: value(bytes[3]),
+            hour: value(bytes[4]),
+            minute: value(bytes[5]),
+            second: value(bytes[6]),
+            weekday: value(bytes[7]),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    /// A save, stored from the top of the user blocks down
+    Data,
+    /// A minigame, stored in consecutive blocks from block 0. There is
+    /// at most one.
+    Game,
+}
+
+/// A file's entry in the directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub file_type: FileType,
+    pub copy_protected: bool,
+    pub first_block: u16,
+    /// Up to 12 characters
+    pub name: String,
+    pub created: Timestamp,
+    /// Length in blocks
+    pub size: u16,
+    /// Block of the file header: 1 for games, whose first block holds
+    /// the interrupt vectors, 0 for data
+    pub header_offset: u16,
+}
+
+impl DirectoryEntry {
+    pub fn parse(bytes: &[u8]) -> Option<DirectoryEntry> {
+        let file_type = match bytes[0] {
+            0x33 => FileType::Data,
+            0xCC => FileType::Game,
+            _    => return None
+        };
+        let name: String = bytes[4..16].iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+
+        Some(DirectoryEntry {
+            file_type,
+            copy_protected: bytes[1] == 0xFF,
+            first_block: le16(&bytes[2..]),
+            name: name.trim_end().to_string(),
+            created: Timestamp::from_bcd(&bytes[16..24]),
+            size: le16(&bytes[24..]),
+            header_offset: le16(&bytes[26..]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; DIRECTORY_ENTRY_SIZE] {
+        let mut bytes = [0; DIRECTORY_ENTRY_SIZE];
+        bytes[0] = match self.file_type {
+            FileType::Data => 0x33,
+            FileType::Game => 0xCC
+        };
+        bytes[1] = if self.copy_protected { 0xFF } else { 0x00 };
+        bytes[2..4].copy_from_slice(&le16_bytes(self.first_block));
+        // Names are padded with spaces
+        for (i, c) in self.name.bytes().chain(::std::iter::repeat(b' ')).take(12).enumerate() {
+            bytes[4 + i] = c;
+        }
+        bytes[16..24].copy_from_slice(&self.created.to_bcd());
+        bytes[24..26].copy_from_slice(&le16_bytes(self.size));
+        bytes[26..28].copy_from_slice(&le16_bytes(self.header_offset));
+        bytes
+    }
+}
+
+/// The 128 KB flash of a VMU, holding the file system described in the
+/// VMS documentation: a root block, a FAT with a 16-bit entry per block
+/// chaining the blocks of each file, and a directory of 32-byte entries.
+///
+/// A flash opened from a host file is written back by `flush` when it
+/// changed.
+pub struct VmuFlash {
+    pub data: Vec<u8>,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl VmuFlash {
+    /// A freshly formatted flash that only lives in memory
+    pub fn new() -> VmuFlash {
+        let mut flash = VmuFlash {
+            data: vec![0; FLASH_SIZE],
+            path: None,
+            dirty: false,
+        };
+        flash.format(Timestamp::now());
+        flash
+    }
+
+    /// A flash holding an image, which must be formatted with its
+    /// file system in bounds
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<VmuFlash> {
+        if data.len() != FLASH_SIZE {
+            return Err(invalid_data("VMU flash images are 128 KB"));
+        }
+
+        let flash = VmuFlash {
+            data,
+            path: None,
+            dirty: false,
+        };
+        if !flash.is_formatted() {
+            return Err(invalid_data("the VMU flash isn't formatted"));
+        }
+        let directory = flash.root_field(0x4A);
+        let directory_blocks = flash.root_field(0x4C);
+        if flash.fat_block() as usize >= BLOCKS || directory as usize >= BLOCKS || directory_blocks > directory + 1 {
+            return Err(invalid_data("the VMU flash's FAT or directory is out of bounds"));
+        }
+        if flash.root_field(0x50) as usize > BLOCKS {
+            return Err(invalid_data("the VMU flash has more user blocks than blocks"));
+        }
+        Ok(flash)
+    }
+
+    /// Opens the flash image stored in a host file, creating a formatted
+    /// one if the file doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<VmuFlash> {
+        let path = path.as_ref();
+        let mut flash = if path.exists() {
+            let mut data = Vec::new();
+            File::open(path)?.read_to_end(&mut data)?;
+            VmuFlash::from_bytes(data)?
+        } else {
+            let mut flash = VmuFlash::new();
+            flash.dirty = true;
+            flash
+        };
+
+        flash.path = Some(path.to_path_buf());
+        flash.flush()?;
+        Ok(flash)
+    }
+
+    /// Writes the image back to its host file, if it has one and changed
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let (true, Some(path)) = (self.dirty, self.path.as_ref()) {
+            File::create(path)?.write_all(&self.data)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Notes a change made through `data`, to be flushed
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn block(&self, block: u16) -> &[u8] {
+        let start = block as usize * BLOCK_SIZE;
+        &self.data[start..start + BLOCK_SIZE]
+    }
+
+    pub fn block_mut(&mut self, block: u16) -> &mut [u8] {
+        self.dirty = true;
+        let start = block as usize * BLOCK_SIZE;
+        &mut self.data[start..start + BLOCK_SIZE]
+    }
+
+    /// Erases everything and writes an empty file system
+    pub fn format(&mut self, time: Timestamp) {
+        for byte in self.data.iter_mut() {
+            *byte = 0;
+        }
+
+        {
+            let root = self.block_mut(ROOT_BLOCK);
+            for byte in root[..0x10].iter_mut() {
+                *byte = FORMAT_MARKER;
+            }
+            root[0x30..0x38].copy_from_slice(&time.to_bcd());
+            // The media info returned to the console
+            let fields = [BLOCKS as u16 - 1, 0, ROOT_BLOCK, FAT_BLOCK, 1, DIRECTORY_BLOCK, DIRECTORY_BLOCKS, 0, USER_BLOCKS, 0x1F];
+            for (i, &field) in fields.iter().enumerate() {
+                root[0x40 + i * 2..0x42 + i * 2].copy_from_slice(&le16_bytes(field));
+            }
+        }
+
+        for block in 0..BLOCKS as u16 {
+            self.set_fat_entry(block, FAT_FREE);
+        }
+        self.set_fat_entry(ROOT_BLOCK, FAT_END);
+        self.set_fat_entry(FAT_BLOCK, FAT_END);
+        let last_directory = DIRECTORY_BLOCK - (DIRECTORY_BLOCKS - 1);
+        for block in last_directory + 1..=DIRECTORY_BLOCK {
+            self.set_fat_entry(block, block - 1);
+        }
+        self.set_fat_entry(last_directory, FAT_END);
+    }
+
+    pub fn is_formatted(&self) -> bool {
+        self.block(ROOT_BLOCK)[..0x10].iter().all(|&b| b == FORMAT_MARKER)
+    }
+
+    fn root_field(&self, offset: usize) -> u16 {
+        le16(&self.block(ROOT_BLOCK)[offset..])
+    }
+
+    fn fat_block(&self) -> u16 {
+        self.root_field(0x46)
+    }
+
+    /// The FAT entry of a block. Blocks past the end, or a FAT moved
+    /// out of bounds by the game writing the root block, read as the
+    /// end of a chain.
+    pub fn fat_entry(&self, block: u16) -> u16 {
+        let fat = self.fat_block();
+        if block as usize >= BLOCKS || fat as usize >= BLOCKS {
+            return FAT_END;
+        }
+        le16(&self.block(fat)[block as usize * 2..])
+    }
+
+    pub fn set_fat_entry(&mut self, block: u16, entry: u16) {
+        let fat = self.fat_block();
+        if block as usize >= BLOCKS || fat as usize >= BLOCKS {
+            return;
+        }
+        self.block_mut(fat)[block as usize * 2..block as usize * 2 + 2].copy_from_slice(&le16_bytes(entry));
+    }
+
+    /// The blocks of a chain in the FAT, starting with the given one
+    fn chain(&self, first: u16) -> Vec<u16> {
+        let mut blocks = Vec::new();
+        let mut block = first;
+        // The length bound stops on loops in a corrupted FAT
+        while (block as usize) < BLOCKS && blocks.len() < BLOCKS {
+            blocks.push(block);
+            block = self.fat_entry(block);
+        }
+        blocks
+    }
+
+    /// Byte offsets of the directory slots
+    fn directory_slots(&self) -> Vec<usize> {
+        let blocks = self.chain(self.root_field(0x4A));
+        let count = self.root_field(0x4C) as usize;
+        blocks.iter().take(count)
+            .flat_map(|&block| (0..BLOCK_SIZE / DIRECTORY_ENTRY_SIZE).map(move |i| block as usize * BLOCK_SIZE + i * DIRECTORY_ENTRY_SIZE))
+            .collect()
+    }
+
+    pub fn directory(&self) -> Vec<DirectoryEntry> {
+        self.directory_slots().iter().filter_map(|&offset| DirectoryEntry::parse(&self.data[offset..offset + DIRECTORY_ENTRY_SIZE])).collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<DirectoryEntry> {
+        self.directory().into_iter().find(|entry| entry.name == name)
+    }
+
+    /// Number of user blocks not used by any file
+    pub fn free_blocks(&self) -> usize {
+        (0..self.root_field(0x50)).filter(|&block| self.fat_entry(block) == FAT_FREE).count()
+    }
+
+    /// The contents of a file, a whole number of blocks
+    pub fn read_file(&self, name: &str) -> Option<Vec<u8>> {
+        self.find(name).map(|entry| {
+            self.chain(entry.first_block).iter().take(entry.size as usize).flat_map(|&block| self.block(block).to_vec()).collect()
+        })
+    }
+
+    /// Stores a file, padding it to whole blocks. The entry's first
+    /// block and size are filled in.
+    pub fn write_file(&mut self, entry: &DirectoryEntry, data: &[u8]) -> io::Result<()> {
+        if self.find(&entry.name).is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists on the VMU", entry.name)));
+        }
+
+        let slot = self.directory_slots().into_iter().find(|&offset| DirectoryEntry::parse(&self.data[offset..]).is_none())
+            .ok_or_else(|| invalid_data("the VMU directory is full"))?;
+
+        let size = data.len().div_ceil(BLOCK_SIZE);
+        let user_blocks = self.root_field(0x50);
+        let blocks: Vec<u16> = match entry.file_type {
+            FileType::Game => {
+                if self.directory().iter().any(|e| e.file_type == FileType::Game) {
+                    return Err(invalid_data("the VMU already holds a game"));
+                }
+                let blocks: Vec<u16> = (0..size as u16).collect();
+                if size > user_blocks as usize || blocks.iter().any(|&b| self.fat_entry(b) != FAT_FREE) {
+                    return Err(invalid_data("not enough consecutive free blocks for the game"));
+                }
+                blocks
+            },
+            FileType::Data => {
+                let blocks: Vec<u16> = (0..user_blocks).rev().filter(|&b| self.fat_entry(b) == FAT_FREE).take(size).collect();
+                if blocks.len() < size {
+                    return Err(invalid_data("not enough free blocks on the VMU"));
+                }
+                blocks
+            }
+        };
+
+        for (i, &block) in blocks.iter().enumerate() {
+            let chunk = &data[i * BLOCK_SIZE..data.len().min((i + 1) * BLOCK_SIZE)];
+            let contents = self.block_mut(block);
+            contents[..chunk.len()].copy_from_slice(chunk);
+            for byte in contents[chunk.len()..].iter_mut() {
+                *byte = 0;
+            }
+            let next = blocks.get(i + 1).cloned().unwrap_or(FAT_END);
+            self.set_fat_entry(block, next);
+        }
+
+        let mut entry = entry.clone();
+        entry.first_block = blocks.first().cloned().unwrap_or(0);
+        entry.size = size as u16;
+        self.data[slot..slot + DIRECTORY_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Removes a file, returning whether it existed
+    pub fn delete_file(&mut self, name: &str) -> bool {
+        let slot = self.directory_slots().into_iter()
+            .find(|&offset| DirectoryEntry::parse(&self.data[offset..]).is_some_and(|entry| entry.name == name));
+
+        match slot {
+            Some(offset) => {
+                let entry = DirectoryEntry::parse(&self.data[offset..]).unwrap();
+                for block in self.chain(entry.first_block).into_iter().take(entry.size as usize) {
+                    self.set_fat_entry(block, FAT_FREE);
+                }
+                for byte in self.data[offset..offset + DIRECTORY_ENTRY_SIZE].iter_mut() {
+                    *byte = 0;
+                }
+                self.dirty = true;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Stores a .DCI file: the directory entry followed by the file's
+    /// blocks, with the bytes of every 32-bit word reversed
+    pub fn import_dci(&mut self, dci: &[u8]) -> io::Result<DirectoryEntry> {
+        if dci.len() < DIRECTORY_ENTRY_SIZE {
+            return Err(invalid_data("DCI file too short"));
+        }
+
+        let entry = DirectoryEntry::parse(dci).ok_or_else(|| invalid_data("DCI file without a valid directory entry"))?;
+        let data = swap_words(&dci[DIRECTORY_ENTRY_SIZE..]);
+        if data.len() < entry.size as usize * BLOCK_SIZE {
+            return Err(invalid_data("DCI file shorter than its entry says"));
+        }
+
+        self.write_file(&entry, &data[..entry.size as usize * BLOCK_SIZE])?;
+        Ok(entry)
+    }
+
+    pub fn export_dci(&self, name: &str) -> Option<Vec<u8>> {
+        let entry = self.find(name)?;
+        let mut dci = entry.to_bytes().to_vec();
+        dci.extend(swap_words(&self.read_file(name)?));
+        Some(dci)
+    }
+
+    /// Stores a .VMS file, as described by its .VMI
+    pub fn import_vms(&mut self, vms: &[u8], vmi: &[u8]) -> io::Result<DirectoryEntry> {
+        if vmi.len() < VMI_SIZE {
+            return Err(invalid_data("VMI file too short"));
+        }
+
+        let name: String = vmi[0x58..0x64].iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+        let mode = le16(&vmi[0x64..]);
+        let game = mode & 2 != 0;
+        let size = le32(&vmi[0x68..]) as usize;
+        if vms.len() < size {
+            return Err(invalid_data("VMS file shorter than its VMI says"));
+        }
+
+        let entry = DirectoryEntry {
+            file_type: if game { FileType::Game } else { FileType::Data },
+            copy_protected: mode & 1 != 0,
+            first_block: 0,
+            name: name.trim_end().to_string(),
+            created: Timestamp {
+                year: le16(&vmi[0x44..]),
+                month: vmi[0x46],
+                day: vmi[0x47],
+                hour: vmi[0x48],
+                minute: vmi[0x49],
+                second: vmi[0x4A],
+                // The VMI counts from Sunday
+                weekday: (vmi[0x4B] + 6) % 7,
+            },
+            size: 0,
+            header_offset: if game { 1 } else { 0 },
+        };
+
+        self.write_file(&entry, &vms[..size])?;
+        self.find(&entry.name).ok_or_else(|| invalid_data("VMS file was not stored"))
+    }
+
+    /// The .VMS and .VMI files of a file. The resource name is the
+    /// .VMS file name without extension, which the .VMI refers to.
+    pub fn export_vms(&self, name: &str, resource: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+        let entry = self.find(name)?;
+        let vms = self.read_file(name)?;
+
+        let mut vmi = vec![0; VMI_SIZE];
+        let resource = resource.as_bytes();
+        for (i, &c) in b"SEGA".iter().enumerate() {
+            vmi[i] = resource.get(i).cloned().unwrap_or(0) & c;
+        }
+        // Descriptions come from the file header
+        let header = entry.header_offset as usize * BLOCK_SIZE;
+        if vms.len() >= header + 0x40 {
+            vmi[0x04..0x24].copy_from_slice(&vms[header + 0x10..header + 0x30]);
+            vmi[0x24..0x34].copy_from_slice(&vms[header + 0x30..header + 0x40]);
+        }
+        let created = entry.created;
+        vmi[0x44..0x46].copy_from_slice(&le16_bytes(created.year));
+        vmi[0x46..0x4C].copy_from_slice(&[created.month, created.day, created.hour, created.minute, created.second, (created.weekday + 1) % 7]);
+        vmi[0x4E..0x50].copy_from_slice(&le16_bytes(1));
+        for (i, &c) in resource.iter().take(8).enumerate() {
+            vmi[0x50 + i] = c;
+        }
+        for (i, c) in entry.name.bytes().take(12).enumerate() {
+            vmi[0x58 + i] = c;
+        }
+        let mode = (if entry.file_type == FileType::Game { 2 } else { 0 }) | (if entry.copy_protected { 1 } else { 0 });
+        vmi[0x64..0x66].copy_from_slice(&le16_bytes(mode));
+        vmi[0x68..0x6C].copy_from_slice(&le32_bytes(vms.len() as u32));
+
+        Some((vms, vmi))
+    }
+}
+
+impl Default for VmuFlash {
+    fn default() -> VmuFlash {
+        VmuFlash::new()
+    }
+}
+
+fn swap_words(data: &[u8]) -> Vec<u8> {
+    data.chunks(4).flat_map(|word| word.iter().rev().cloned().collect::<Vec<u8>>()).collect()
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | ((bytes[1] as u16) << 8)
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24)
+}
+
+fn le16_bytes(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn le32_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, file_type: FileType) -> DirectoryEntry {
+        DirectoryEntry {
+            file_type,
+            copy_protected: false,
+            first_block: 0,
+            name: name.to_string(),
+            created: Timestamp::from_unix(946684800),
+            size: 0,
+            header_offset: if file_type == FileType::Game { 1 } else { 0 },
+        }
+    }
+
+    /// Blocks whose bytes hold their index in the file
+    fn contents(blocks: usize) -> Vec<u8> {
+        (0..blocks * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8 + 1).collect()
+    }
+
+    #[test]
+    fn converts_dates() {
+        // A Saturday
+        let time = Timestamp::from_unix(946684800 + 13 * 3600 + 14 * 60 + 15);
+        assert_eq!(time, Timestamp { year: 2000, month: 1, day: 1, hour: 13, minute: 14, second: 15, weekday: 5 });
+        assert_eq!(time.to_bcd(), [0x20, 0x00, 0x01, 0x01, 0x13, 0x14, 0x15, 0x05]);
+        assert_eq!(Timestamp::from_bcd(&time.to_bcd()), time);
+    }
+
+    #[test]
+    fn formats_an_empty_file_system() {
+        let flash = VmuFlash::new();
+        assert!(flash.is_formatted());
+        assert!(flash.directory().is_empty());
+        assert_eq!(flash.free_blocks(), USER_BLOCKS as usize);
+        assert_eq!(flash.fat_entry(ROOT_BLOCK), FAT_END);
+        assert_eq!(flash.fat_entry(FAT_BLOCK), FAT_END);
+        // The directory chains downwards
+        assert_eq!(flash.fat_entry(DIRECTORY_BLOCK), DIRECTORY_BLOCK - 1);
+        assert_eq!(flash.fat_entry(DIRECTORY_BLOCK - DIRECTORY_BLOCKS + 1), FAT_END);
+        assert_eq!(flash.fat_entry(0), FAT_FREE);
+        assert_eq!(flash.directory_slots().len(), DIRECTORY_BLOCKS as usize * BLOCK_SIZE / DIRECTORY_ENTRY_SIZE);
+    }
+
+    #[test]
+    fn writes_and_deletes_files() {
+        let mut flash = VmuFlash::new();
+        flash.write_file(&entry("SAVE", FileType::Data), &contents(2)[..BLOCK_SIZE + 1]).unwrap();
+        flash.write_file(&entry("GAME", FileType::Game), &contents(3)).unwrap();
+
+        // Saves fill the user blocks from the top, games from block 0
+        let save = flash.find("SAVE").unwrap();
+        assert_eq!((save.first_block, save.size), (USER_BLOCKS - 1, 2));
+        assert_eq!(flash.fat_entry(USER_BLOCKS - 1), USER_BLOCKS - 2);
+        assert_eq!(flash.fat_entry(USER_BLOCKS - 2), FAT_END);
+        let game = flash.find("GAME").unwrap();
+        assert_eq!((game.first_block, game.size), (0, 3));
+        assert_eq!(flash.free_blocks(), USER_BLOCKS as usize - 5);
+
+        // The last block is padded with zeros
+        let data = flash.read_file("SAVE").unwrap();
+        assert_eq!(data.len(), 2 * BLOCK_SIZE);
+        assert_eq!((data[BLOCK_SIZE], data[BLOCK_SIZE + 1]), (2, 0));
+        assert_eq!(flash.read_file("GAME").unwrap(), contents(3));
+
+        assert_eq!(flash.write_file(&entry("SAVE", FileType::Data), &[0]).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert!(flash.write_file(&entry("OTHER", FileType::Game), &[0]).is_err());
+        assert!(flash.write_file(&entry("HUGE", FileType::Data), &contents(USER_BLOCKS as usize)).is_err());
+
+        assert!(flash.delete_file("SAVE"));
+        assert!(!flash.delete_file("SAVE"));
+        assert_eq!(flash.find("SAVE"), None);
+        assert_eq!(flash.fat_entry(USER_BLOCKS - 1), FAT_FREE);
+        assert_eq!(flash.free_blocks(), USER_BLOCKS as usize - 3);
+    }
+
+    #[test]
+    fn moves_files_as_dci() {
+        let mut flash = VmuFlash::new();
+        flash.write_file(&entry("SAVE", FileType::Data), &contents(2)).unwrap();
+        let dci = flash.export_dci("SAVE").unwrap();
+        assert_eq!(dci.len(), DIRECTORY_ENTRY_SIZE + 2 * BLOCK_SIZE);
+        // Words are stored byte-reversed
+        assert_eq!(&dci[DIRECTORY_ENTRY_SIZE..DIRECTORY_ENTRY_SIZE + 4], &[1, 1, 1, 1]);
+        assert_eq!(dci[0], 0x33);
+
+        let mut other = VmuFlash::new();
+        let imported = other.import_dci(&dci).unwrap();
+        assert_eq!(imported.name, "SAVE");
+        assert_eq!(other.read_file("SAVE").unwrap(), contents(2));
+        assert_eq!(other.find("SAVE").unwrap().created, flash.find("SAVE").unwrap().created);
+
+        assert!(other.import_dci(&dci[..DIRECTORY_ENTRY_SIZE + BLOCK_SIZE]).is_err());
+    }
+
+    #[test]
+    fn moves_files_as_vms() {
+        let mut flash = VmuFlash::new();
+        let mut game = contents(3);
+        game[BLOCK_SIZE + 0x10..BLOCK_SIZE + 0x14].copy_from_slice(b"DEMO");
+        flash.write_file(&DirectoryEntry { copy_protected: true, ..entry("MINIGAME", FileType::Game) }, &game).unwrap();
+
+        let (vms, vmi) = flash.export_vms("MINIGAME", "DEMO").unwrap();
+        assert_eq!(vms, game);
+        assert_eq!(vmi.len(), VMI_SIZE);
+        assert_eq!(&vmi[0x04..0x08], b"DEMO");
+        assert_eq!(&vmi[0x50..0x54], b"DEMO");
+        assert_eq!(le16(&vmi[0x64..]), 3);
+        assert_eq!(le32(&vmi[0x68..]), vms.len() as u32);
+
+        let mut other = VmuFlash::new();
+        let imported = other.import_vms(&vms, &vmi).unwrap();
+        assert_eq!(imported, flash.find("MINIGAME").unwrap());
+        assert_eq!(other.read_file("MINIGAME").unwrap(), game);
+
+        assert!(other.import_vms(&vms[..10], &vmi).is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_images() {
+        assert!(VmuFlash::from_bytes(VmuFlash::new().data).is_ok());
+        assert_eq!(VmuFlash::from_bytes(vec![0; 1024]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        // Erased flash was never formatted
+        assert_eq!(VmuFlash::from_bytes(vec![0xFF; FLASH_SIZE]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let root = ROOT_BLOCK as usize * BLOCK_SIZE;
+        for &(offset, value) in &[(0x46, 0x100), (0x4A, 0x1234), (0x4C, 0x200), (0x50, 0x101)] {
+            let mut data = VmuFlash::new().data;
+            data[root + offset..root + offset + 2].copy_from_slice(&le16_bytes(value));
+            assert_eq!(VmuFlash::from_bytes(data).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn survives_a_root_block_overwritten_at_run_time() {
+        let mut flash = VmuFlash::new();
+        for byte in flash.block_mut(ROOT_BLOCK).iter_mut() {
+            *byte = 0xFF;
+        }
+        assert_eq!(flash.fat_entry(0), FAT_END);
+        assert!(flash.directory().is_empty());
+        assert_eq!(flash.free_blocks(), 0);
+        assert!(flash.write_file(&entry("SAVE", FileType::Data), &[0]).is_err());
+    }
+}
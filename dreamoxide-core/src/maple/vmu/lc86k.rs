@@ -0,0 +1,1063 @@
+use maple::vmu::{VmuLcd, LCD_FRAME_SIZE, LCD_WIDTH};
+use maple::vmu::flash::{VmuFlash, FileType, Timestamp};
+use disc::image::invalid_data;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Special function registers, at their addresses in the data space
+pub const ACC   : usize = 0x100;
+pub const PSW   : usize = 0x101;
+pub const B     : usize = 0x102;
+pub const C     : usize = 0x103;
+pub const TRL   : usize = 0x104;
+pub const TRH   : usize = 0x105;
+pub const SP    : usize = 0x106;
+pub const PCON  : usize = 0x107;
+pub const IE    : usize = 0x108;
+pub const IP    : usize = 0x109;
+pub const EXT   : usize = 0x10D;
+pub const OCR   : usize = 0x10E;
+pub const T0CON : usize = 0x110;
+pub const T0PRR : usize = 0x111;
+pub const T0L   : usize = 0x112;
+pub const T0LR  : usize = 0x113;
+pub const T0H   : usize = 0x114;
+pub const T0HR  : usize = 0x115;
+pub const T1CNT : usize = 0x118;
+pub const T1LC  : usize = 0x11A;
+/// Reads the counter, writes the reload value
+pub const T1L   : usize = 0x11B;
+pub const T1HC  : usize = 0x11C;
+pub const T1H   : usize = 0x11D;
+pub const MCR   : usize = 0x120;
+pub const XBNK  : usize = 0x125;
+pub const VCCR  : usize = 0x127;
+pub const P3    : usize = 0x14C;
+pub const P3INT : usize = 0x14E;
+pub const FPR   : usize = 0x154;
+pub const BTCR  : usize = 0x17F;
+/// Start of the banked XRAM holding the LCD image and icons
+pub const XRAM  : usize = 0x180;
+
+pub const PSW_CY     : u8 = 0x80;
+pub const PSW_AC     : u8 = 0x40;
+pub const PSW_OV     : u8 = 0x04;
+pub const PSW_RAMBK0 : u8 = 0x02;
+pub const PSW_P      : u8 = 0x01;
+
+/// Buttons, as bits of port 3. The port reads them active low.
+pub const BUTTON_UP    : u8 = 0x01;
+pub const BUTTON_DOWN  : u8 = 0x02;
+pub const BUTTON_LEFT  : u8 = 0x04;
+pub const BUTTON_RIGHT : u8 = 0x08;
+pub const BUTTON_A     : u8 = 0x10;
+pub const BUTTON_B     : u8 = 0x20;
+pub const BUTTON_MODE  : u8 = 0x40;
+pub const BUTTON_SLEEP : u8 = 0x80;
+
+pub const VECTOR_RESET      : u16 = 0x00;
+pub const VECTOR_T0L        : u16 = 0x13;
+pub const VECTOR_BASE_TIMER : u16 = 0x1B;
+pub const VECTOR_T0H        : u16 = 0x23;
+pub const VECTOR_T1         : u16 = 0x2B;
+pub const VECTOR_P3         : u16 = 0x4B;
+
+/// BIOS routines. A game reaches them from a stub at the same address
+/// that flips bit 0 of EXT, so that execution continues in the ROM, and
+/// is followed by a far jump only there for the assembler's sake. The
+/// ROM flips back and the game continues after that jump.
+pub const BIOS_WRITE_FLASH  : u16 = 0x100;
+pub const BIOS_VERIFY_FLASH : u16 = 0x110;
+pub const BIOS_READ_FLASH   : u16 = 0x120;
+pub const BIOS_TIMER        : u16 = 0x130;
+pub const BIOS_EXIT         : u16 = 0x1F0;
+
+pub const BIOS_SIZE : usize = 0x10000;
+
+/// Oscillators the system clock is taken from
+pub const QUARTZ_CLOCK : u64 = 32768;
+pub const RC_CLOCK     : u64 = 879236;
+
+/// Where the BIOS keeps the date in RAM bank 0: year in two bytes, then
+/// month, day, hour, minute, second and a half second flag, in binary
+const CLOCK_RAM : usize = 0x17;
+
+/// Bytes the flash routines of the BIOS transfer, from RAM 0x80
+const FLASH_PAGE : usize = 128;
+
+/// The Sanyo LC8670 microcontroller of a VMU, running on its own with
+/// its timers, LCD, buttons and buzzer.
+///
+/// Program memory is the 64 KB BIOS ROM or the first 64 KB of the
+/// flash, as selected by bit 0 of EXT. Without a BIOS image a game in
+/// the flash runs directly, and its calls into the BIOS are carried out
+/// here.
+pub struct Lc86k {
+    pub pc: u16,
+    /// The two banks of general RAM, selected by PSW
+    pub ram: [[u8; 0x100]; 2],
+    pub sfr: [u8; 0x80],
+    /// LCD image in banks 0 and 1, icons in bank 2
+    pub xram: [[u8; 0x80]; 3],
+    pub bios: Option<Vec<u8>>,
+    flash: Arc<Mutex<VmuFlash>>,
+    lcd: Arc<Mutex<VmuLcd>>,
+    pub cycles: u64,
+    /// Buttons held, active high
+    buttons: u8,
+    t0_prescaler: u8,
+    t1l_reload: u8,
+    t1h_reload: u8,
+    /// Oscillator periods times the quartz clock, counting towards the
+    /// next half second
+    base_timer: u64,
+    /// Interrupt being serviced, until RETI
+    in_interrupt: bool,
+    /// Progress of the flash write command sequence, and the bytes left
+    /// to program once it is complete
+    flash_state: u8,
+    flash_remaining: usize,
+    /// Set when a game without BIOS exits to the menu
+    pub exited: bool,
+}
+
+impl Lc86k {
+    /// A VMU running from the given flash, either through its BIOS or,
+    /// without one, by starting the game in the flash directly
+    pub fn new(flash: Arc<Mutex<VmuFlash>>, bios: Option<Vec<u8>>) -> io::Result<Lc86k> {
+        match bios {
+            Some(ref rom) if rom.len() != BIOS_SIZE => return Err(invalid_data("the VMU BIOS is 64 KB")),
+            None if !flash.lock().unwrap().directory().iter().any(|entry| entry.file_type == FileType::Game) => {
+                return Err(invalid_data("no VMU BIOS and no game in the flash"));
+            },
+            _ => ()
+        }
+
+        let mut cpu = Lc86k {
+            pc: 0,
+            ram: [[0; 0x100]; 2],
+            sfr: [0; 0x80],
+            xram: [[0; 0x80]; 3],
+            bios,
+            flash,
+            lcd: Arc::new(Mutex::new(VmuLcd::new())),
+            cycles: 0,
+            buttons: 0,
+            t0_prescaler: 0,
+            t1l_reload: 0,
+            t1h_reload: 0,
+            base_timer: 0,
+            in_interrupt: false,
+            flash_state: 0,
+            flash_remaining: 0,
+            exited: false,
+        };
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    pub fn lcd(&self) -> Arc<Mutex<VmuLcd>> {
+        self.lcd.clone()
+    }
+
+    pub fn flash(&self) -> Arc<Mutex<VmuFlash>> {
+        self.flash.clone()
+    }
+
+    pub fn reset(&mut self) {
+        self.pc = VECTOR_RESET;
+        self.ram = [[0; 0x100]; 2];
+        self.sfr = [0; 0x80];
+        self.xram = [[0; 0x80]; 3];
+        self.in_interrupt = false;
+        self.exited = false;
+        self.flash_state = 0;
+        self.set_sfr(SP, 0x7F);
+
+        if self.bios.is_none() {
+            // The state the BIOS leaves a game in: running from flash
+            // on the quartz clock, with the display and base timer on
+            self.set_sfr(EXT, 1);
+            self.set_sfr(OCR, 0xA1);
+            self.set_sfr(MCR, 0x09);
+            self.set_sfr(VCCR, 0x80);
+            self.set_sfr(BTCR, 0x41);
+            self.set_time(Timestamp::now());
+        }
+    }
+
+    /// Sets the date the BIOS keeps in RAM
+    pub fn set_time(&mut self, time: Timestamp) {
+        let clock = [(time.year >> 8) as u8, time.year as u8, time.month, time.day, time.hour, time.minute, time.second, 0];
+        self.ram[0][CLOCK_RAM..CLOCK_RAM + 8].copy_from_slice(&clock);
+    }
+
+    pub fn press(&mut self, buttons: u8) {
+        // Pressing a button wakes the CPU through the port 3 interrupt
+        if buttons & !self.buttons != 0 {
+            self.sfr[P3INT - 0x100] |= 0x02;
+        }
+        self.buttons |= buttons;
+    }
+
+    pub fn release(&mut self, buttons: u8) {
+        self.buttons &= !buttons;
+    }
+
+    fn sfr(&self, address: usize) -> u8 {
+        self.sfr[address - 0x100]
+    }
+
+    fn set_sfr(&mut self, address: usize, value: u8) {
+        self.sfr[address - 0x100] = value;
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.sfr(PSW) & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, set: bool) {
+        let psw = self.sfr(PSW);
+        self.set_sfr(PSW, if set { psw | flag } else { psw & !flag });
+    }
+
+    fn acc(&self) -> u8 {
+        self.sfr(ACC)
+    }
+
+    fn set_acc(&mut self, value: u8) {
+        self.write(ACC, value);
+    }
+
+    /// Reads the data space: RAM, special function registers and XRAM
+    pub fn read(&self, address: usize) -> u8 {
+        match address & 0x1FF {
+            a @ 0x000 ..= 0x0FF => self.ram[((self.sfr(PSW) & PSW_RAMBK0) >> 1) as usize][a],
+            P3                  => !self.buttons,
+            a @ XRAM ..= 0x1FF  => self.xram[(self.sfr(XBNK) % 3) as usize][a - XRAM],
+            a                   => self.sfr[a - 0x100]
+        }
+    }
+
+    pub fn write(&mut self, address: usize, value: u8) {
+        match address & 0x1FF {
+            a @ 0x000 ..= 0x0FF => self.ram[((self.sfr(PSW) & PSW_RAMBK0) >> 1) as usize][a] = value,
+            ACC => {
+                // The parity flag follows the accumulator
+                self.set_sfr(ACC, value);
+                self.set_flag(PSW_P, value.count_ones() & 1 != 0);
+            },
+            T1L => self.t1l_reload = value,
+            T1H => self.t1h_reload = value,
+            a @ XRAM ..= 0x1FF  => self.xram[(self.sfr(XBNK) % 3) as usize][a - XRAM] = value,
+            a                   => self.sfr[a - 0x100] = value
+        }
+    }
+
+    fn push(&mut self, value: u8) {
+        let sp = self.sfr(SP).wrapping_add(1);
+        self.set_sfr(SP, sp);
+        self.ram[0][sp as usize] = value;
+    }
+
+    fn pop(&mut self) -> u8 {
+        let sp = self.sfr(SP);
+        self.set_sfr(SP, sp.wrapping_sub(1));
+        self.ram[0][sp as usize]
+    }
+
+    fn push_pc(&mut self, pc: u16) {
+        self.push(pc as u8);
+        self.push((pc >> 8) as u8);
+    }
+
+    fn pop_pc(&mut self) -> u16 {
+        let high = self.pop() as u16;
+        (high << 8) | self.pop() as u16
+    }
+
+    /// Reads program memory
+    fn fetch(&self, flash: &VmuFlash, address: u16) -> u8 {
+        match (self.sfr(EXT) & 1, self.bios.as_ref()) {
+            (0, Some(rom)) => rom[address as usize],
+            _              => flash.data[address as usize]
+        }
+    }
+
+    /// Address of @Rj: the indirection registers are in RAM, in the set
+    /// selected by PSW, and R2 and R3 point into the upper half
+    fn indirect(&self, op: u8) -> usize {
+        let j = (op & 3) as usize;
+        let register = ((self.sfr(PSW) >> 3) & 3) as usize * 4 + j;
+        self.read(register) as usize | ((j & 2) << 7)
+    }
+
+    /// Address of the flash byte TRH:TRL in the bank FPR selects
+    fn flash_address(&self) -> usize {
+        ((self.sfr(FPR) as usize & 1) << 16) | ((self.sfr(TRH) as usize) << 8) | self.sfr(TRL) as usize
+    }
+
+    fn oscillator(&self) -> u64 {
+        if self.sfr(OCR) & 0x20 != 0 { QUARTZ_CLOCK } else { RC_CLOCK }
+    }
+
+    /// Cycles of the system clock per second
+    pub fn clock(&self) -> u64 {
+        self.oscillator() / self.divider()
+    }
+
+    fn divider(&self) -> u64 {
+        if self.sfr(OCR) & 0x80 != 0 { 6 } else { 12 }
+    }
+
+    /// Frequency of the buzzer, driven by timer 1, if it sounds
+    pub fn buzzer(&self) -> Option<f64> {
+        let t1cnt = self.sfr(T1CNT);
+        let period = 256 - self.t1l_reload as u64;
+        if t1cnt & 0x50 != 0x50 || self.sfr(T1LC) == self.t1l_reload {
+            return None;
+        }
+        Some(self.clock() as f64 / period as f64)
+    }
+
+    /// Runs for the given number of system clock cycles and updates the
+    /// LCD
+    pub fn run(&mut self, cycles: u64) {
+        let flash = self.flash.clone();
+        let mut flash = flash.lock().unwrap();
+        let end = self.cycles + cycles;
+        while self.cycles < end && !self.exited {
+            self.step(&mut flash);
+        }
+        self.update_lcd();
+    }
+
+    /// Runs for a time in seconds, at the current clock
+    pub fn run_seconds(&mut self, seconds: f64) {
+        let cycles = (seconds * self.clock() as f64) as u64;
+        self.run(cycles);
+    }
+
+    /// Takes a pending interrupt or executes a single instruction
+    pub fn step(&mut self, flash: &mut VmuFlash) {
+        let cycles = if let Some(vector) = self.pending_interrupt() {
+            self.set_sfr(PCON, self.sfr(PCON) & !1);
+            if self.sfr(IE) & 0x80 != 0 && !self.in_interrupt {
+                let pc = self.pc;
+                self.push_pc(pc);
+                self.pc = vector;
+                self.in_interrupt = true;
+                2
+            } else {
+                self.execute(flash)
+            }
+        } else if self.sfr(PCON) & 1 != 0 {
+            // Halted until an interrupt
+            1
+        } else if self.sfr(EXT) & 1 == 0 && self.bios.is_none() {
+            self.bios_call(flash);
+            1
+        } else {
+            self.execute(flash)
+        };
+
+        self.cycles += cycles;
+        self.tick(cycles);
+    }
+
+    fn pending_interrupt(&self) -> Option<u16> {
+        let t0con = self.sfr(T0CON);
+        let t1cnt = self.sfr(T1CNT);
+        let btcr = self.sfr(BTCR);
+        // Each source has a flag and an enable bit next to it
+        let requested = |flags: u8| flags & (flags >> 1) & 0x55 != 0;
+
+        if requested(t0con & 0x03) {
+            Some(VECTOR_T0L)
+        } else if requested(btcr & 0x0F) {
+            Some(VECTOR_BASE_TIMER)
+        } else if requested(t0con & 0x0C) {
+            Some(VECTOR_T0H)
+        } else if requested(t1cnt & 0x0F) {
+            Some(VECTOR_T1)
+        } else if requested(self.sfr(P3INT) & 0x03) {
+            Some(VECTOR_P3)
+        } else {
+            None
+        }
+    }
+
+    /// Advances the timers by some cycles
+    fn tick(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            // Timer 0 counts the prescaler's overflows
+            self.t0_prescaler = self.t0_prescaler.wrapping_add(1);
+            if self.t0_prescaler == 0 {
+                self.t0_prescaler = self.sfr(T0PRR);
+                let t0con = self.sfr(T0CON);
+                let long = t0con & 0x20 != 0;
+                let mut carry = !long;
+                if t0con & 0x40 != 0 {
+                    let (low, overflow) = self.sfr(T0L).overflowing_add(1);
+                    self.set_sfr(T0L, if overflow { self.sfr(T0LR) } else { low });
+                    if overflow {
+                        self.set_sfr(T0CON, self.sfr(T0CON) | 0x02);
+                        carry = true;
+                    }
+                }
+                if t0con & 0x80 != 0 && carry {
+                    let (high, overflow) = self.sfr(T0H).overflowing_add(1);
+                    self.set_sfr(T0H, if overflow { self.sfr(T0HR) } else { high });
+                    if overflow {
+                        self.set_sfr(T0CON, self.sfr(T0CON) | 0x08);
+                    }
+                }
+            }
+
+            let t1cnt = self.sfr(T1CNT);
+            if t1cnt & 0x40 != 0 {
+                let (low, overflow) = self.sfr(T1L).overflowing_add(1);
+                self.set_sfr(T1L, if overflow { self.t1l_reload } else { low });
+                if overflow {
+                    self.set_sfr(T1CNT, self.sfr(T1CNT) | 0x02);
+                }
+            }
+            if t1cnt & 0x80 != 0 {
+                let (high, overflow) = self.sfr(T1H).overflowing_add(1);
+                self.set_sfr(T1H, if overflow { self.t1h_reload } else { high });
+                if overflow {
+                    self.set_sfr(T1CNT, self.sfr(T1CNT) | 0x08);
+                }
+            }
+        }
+
+        // The base timer runs from the quartz, whatever clocks the CPU,
+        // and requests an interrupt every half second
+        self.base_timer += cycles * self.divider() * QUARTZ_CLOCK;
+        let half_second = QUARTZ_CLOCK / 2 * self.oscillator();
+        while self.base_timer >= half_second {
+            self.base_timer -= half_second;
+            if self.sfr(BTCR) & 0x40 != 0 {
+                self.set_sfr(BTCR, self.sfr(BTCR) | 0x02);
+            }
+        }
+    }
+
+    /// Copies the XRAM to the LCD while the display is on
+    fn update_lcd(&mut self) {
+        let mut frame = [0; LCD_FRAME_SIZE];
+        if self.sfr(VCCR) & 0x80 != 0 {
+            let row_bytes = LCD_WIDTH / 8;
+            for (y, row) in frame.chunks_mut(row_bytes).enumerate() {
+                // Each bank holds 16 rows, in pairs 16 bytes apart
+                let offset = (y % 16) / 2 * 16 + (y % 2) * row_bytes;
+                row.copy_from_slice(&self.xram[y / 16][offset..offset + row_bytes]);
+            }
+        }
+
+        let mut lcd = self.lcd.lock().unwrap();
+        if lcd.pixels[..] != frame[..] {
+            lcd.pixels = frame;
+            lcd.frames += 1;
+        }
+    }
+
+    /// Carries out the BIOS routine whose stub switched to the ROM, then
+    /// goes back to the flash after the stub's far jump
+    fn bios_call(&mut self, flash: &mut VmuFlash) {
+        // The flash routines take their parameters in RAM bank 1, above
+        // the stack's bank
+        let params = &mut self.ram[1];
+        let address = ((params[0x7D] as usize & 1) << 16) | ((params[0x7E] as usize) << 8) | params[0x7F] as usize;
+        let page = address..(address + FLASH_PAGE).min(flash.data.len());
+        let buffer = 0x80..0x80 + page.len();
+        let mut acc = None;
+
+        match self.pc & 0xFFF0 {
+            BIOS_WRITE_FLASH => {
+                flash.data[page].copy_from_slice(&params[buffer]);
+                flash.mark_dirty();
+                acc = Some(0);
+            },
+            BIOS_VERIFY_FLASH => acc = Some(if flash.data[page] == params[buffer] { 0 } else { 0xFF }),
+            BIOS_READ_FLASH => params[buffer].copy_from_slice(&flash.data[page]),
+            BIOS_TIMER => self.advance_clock(),
+            BIOS_EXIT => self.exited = true,
+            _ => ()
+        }
+
+        if let Some(value) = acc {
+            self.set_acc(value);
+        }
+
+        let ext = self.sfr(EXT);
+        self.set_sfr(EXT, ext | 1);
+        self.pc = self.pc.wrapping_add(3);
+    }
+
+    /// Counts half a second on the date kept in RAM
+    fn advance_clock(&mut self) {
+        let clock = &mut self.ram[0][CLOCK_RAM..CLOCK_RAM + 8];
+        clock[7] ^= 1;
+        if clock[7] != 0 {
+            return;
+        }
+
+        let year = (clock[0] as u16) << 8 | clock[1] as u16;
+        let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+        let days = match clock[2] {
+            2 if leap     => 29,
+            2             => 28,
+            4 | 6 | 9 | 11 => 30,
+            _             => 31
+        };
+
+        clock[6] += 1;
+        if clock[6] < 60 { return; }
+        clock[6] = 0;
+        clock[5] += 1;
+        if clock[5] < 60 { return; }
+        clock[5] = 0;
+        clock[4] += 1;
+        if clock[4] < 24 { return; }
+        clock[4] = 0;
+        clock[3] += 1;
+        if clock[3] <= days { return; }
+        clock[3] = 1;
+        clock[2] += 1;
+        if clock[2] <= 12 { return; }
+        clock[2] = 1;
+        let year = year + 1;
+        clock[0] = (year >> 8) as u8;
+        clock[1] = year as u8;
+    }
+
+    fn add(&mut self, value: u8, carry: bool) {
+        let acc = self.acc();
+        let c = carry as u8;
+        let result = acc as u16 + value as u16 + c as u16;
+        self.set_flag(PSW_CY, result > 0xFF);
+        self.set_flag(PSW_AC, (acc & 0xF) + (value & 0xF) + c > 0xF);
+        self.set_flag(PSW_OV, (acc ^ value) & 0x80 == 0 && (acc ^ result as u8) & 0x80 != 0);
+        self.set_acc(result as u8);
+    }
+
+    fn sub(&mut self, value: u8, borrow: bool) {
+        let acc = self.acc();
+        let b = borrow as u8;
+        let result = (acc as i16) - (value as i16) - (b as i16);
+        self.set_flag(PSW_CY, result < 0);
+        self.set_flag(PSW_AC, (acc & 0xF) < (value & 0xF) + b);
+        self.set_flag(PSW_OV, (acc ^ value) & 0x80 != 0 && (acc ^ result as u8) & 0x80 != 0);
+        self.set_acc(result as u8);
+    }
+
+    /// Programs a byte through the flash command sequence
+    fn store_flash(&mut self, flash: &mut VmuFlash) {
+        let address = self.flash_address();
+        let value = self.acc();
+
+        self.flash_state = match (self.flash_state, address & 0xFFFF, value) {
+            (3, _, _) => {
+                flash.data[address] = value;
+                flash.mark_dirty();
+                self.flash_remaining -= 1;
+                if self.flash_remaining == 0 { 0 } else { 3 }
+            },
+            // The command bytes are only taken while FPR unlocks them
+            _ if self.sfr(FPR) & 2 == 0 => 0,
+            (0, 0x5555, 0xAA) => 1,
+            (1, 0x2AAA, 0x55) => 2,
+            (2, 0x5555, 0xA0) => {
+                self.flash_remaining = FLASH_PAGE;
+                3
+            },
+            _ => 0
+        };
+    }
+
+    /// Executes the instruction at the program counter, returning the
+    /// cycles it took
+    fn execute(&mut self, flash: &mut VmuFlash) -> u64 {
+        let pc = self.pc;
+        let op = self.fetch(flash, pc);
+        let byte1 = self.fetch(flash, pc.wrapping_add(1));
+        let byte2 = self.fetch(flash, pc.wrapping_add(2));
+
+        // Operand forms shared by most instructions
+        let d9 = ((op as usize & 1) << 8) | byte1 as usize;
+        let bit_address = ((op as usize & 0x10) << 4) | byte1 as usize;
+        let bit = 1u8 << (op & 7);
+        let a12 = ((op as u16 & 0x10) << 7) | ((op as u16 & 7) << 8) | byte1 as u16;
+        let relative = |next: u16, offset: u8| next.wrapping_add(offset as i8 as u16);
+
+        let (length, cycles) = match op & 0xF {
+            0x0 | 0x1 => match op {
+                0x10 | 0x11 | 0x20 | 0x21 | 0x31 | 0x41 => (3, 2),
+                0x00 | 0x30 | 0x40 | 0x50 | 0x51 | 0xA0 | 0xB0 | 0xC0 | 0xC1 | 0xD0 | 0xE0 | 0xF0 => (1, 1),
+                _ => (2, 1)
+            },
+            0x2 | 0x3 => match op >> 4 {
+                0x2 ..= 0x5 => (3, 2),
+                _ => (2, 1)
+            },
+            0x4 ..= 0x7 => match op >> 4 {
+                0x2 | 0x5 => (2, 1),
+                0x3 | 0x4 => (3, 2),
+                _ => (1, 1)
+            },
+            _ => match op >> 4 {
+                0x4 ..= 0x9 => (3, 2),
+                _ => (2, 1)
+            }
+        };
+        let next = pc.wrapping_add(length);
+        self.pc = next;
+
+        match op {
+            0x00 => (),
+            0x01 => self.pc = relative(next, byte1),
+            // The 16-bit relative forms count from the last byte
+            0x10 => {
+                self.push_pc(next);
+                self.pc = next.wrapping_sub(1).wrapping_add((byte2 as u16) << 8 | byte1 as u16);
+            },
+            0x11 => self.pc = next.wrapping_sub(1).wrapping_add((byte2 as u16) << 8 | byte1 as u16),
+            0x20 => {
+                self.push_pc(next);
+                self.pc = (byte1 as u16) << 8 | byte2 as u16;
+            },
+            0x21 => self.pc = (byte1 as u16) << 8 | byte2 as u16,
+            0x30 => {
+                let product = ((self.acc() as u32) << 8 | self.sfr(C) as u32) * self.sfr(B) as u32;
+                self.set_sfr(C, product as u8);
+                self.set_acc((product >> 8) as u8);
+                self.set_sfr(B, (product >> 16) as u8);
+                self.set_flag(PSW_CY, false);
+                self.set_flag(PSW_OV, product > 0xFFFF);
+                return 7;
+            },
+            0x40 => {
+                let dividend = (self.acc() as u32) << 8 | self.sfr(C) as u32;
+                let divisor = self.sfr(B) as u32;
+                match dividend.checked_div(divisor) {
+                    Some(quotient) => {
+                        self.set_sfr(C, quotient as u8);
+                        self.set_acc((quotient >> 8) as u8);
+                        self.set_sfr(B, (dividend % divisor) as u8);
+                        self.set_flag(PSW_OV, false);
+                    },
+                    None => {
+                        self.set_acc(0xFF);
+                        self.set_flag(PSW_OV, true);
+                    }
+                }
+                self.set_flag(PSW_CY, false);
+                return 7;
+            },
+            0x31 | 0x32 | 0x33 | 0x34 ..= 0x37 | 0x41 | 0x42 | 0x43 | 0x44 ..= 0x47 => {
+                // Compares the accumulator, or @Rj for the indirect forms
+                let (left, right) = match op & 0xF {
+                    0x1       => (self.acc(), byte1),
+                    0x2 | 0x3 => (self.acc(), self.read(d9)),
+                    _         => (self.read(self.indirect(op)), byte1)
+                };
+                self.set_flag(PSW_CY, left < right);
+                if (left == right) == (op < 0x40) {
+                    self.pc = relative(next, byte2);
+                }
+            },
+            0x50 => {
+                let address = self.flash_address();
+                self.set_acc(flash.data[address]);
+            },
+            0x51 => self.store_flash(flash),
+            0x52 ..= 0x57 => {
+                let (address, offset) = if op < 0x54 { (d9, byte2) } else { (self.indirect(op), byte1) };
+                let value = self.read(address).wrapping_sub(1);
+                self.write(address, value);
+                if value != 0 {
+                    self.pc = relative(next, offset);
+                }
+            },
+            0x60 | 0x61 => {
+                let value = self.read(d9);
+                self.push(value);
+            },
+            0x70 | 0x71 => {
+                let value = self.pop();
+                self.write(d9, value);
+            },
+            0x80 => if self.acc() == 0 {
+                self.pc = relative(next, byte1);
+            },
+            0x90 => if self.acc() != 0 {
+                self.pc = relative(next, byte1);
+            },
+            0xA0 => self.pc = self.pop_pc(),
+            0xB0 => {
+                self.pc = self.pop_pc();
+                self.in_interrupt = false;
+            },
+            0xC0 => {
+                let acc = self.acc();
+                self.set_acc(acc.rotate_right(1));
+            },
+            0xD0 => {
+                let acc = self.acc();
+                let carry = self.flag(PSW_CY) as u8;
+                self.set_flag(PSW_CY, acc & 1 != 0);
+                self.set_acc((acc >> 1) | (carry << 7));
+            },
+            0xE0 => {
+                let acc = self.acc();
+                self.set_acc(acc.rotate_left(1));
+            },
+            0xF0 => {
+                let acc = self.acc();
+                let carry = self.flag(PSW_CY) as u8;
+                self.set_flag(PSW_CY, acc & 0x80 != 0);
+                self.set_acc((acc << 1) | carry);
+            },
+            0xC1 => {
+                let address = ((self.sfr(TRH) as u16) << 8 | self.sfr(TRL) as u16).wrapping_add(self.acc() as u16);
+                let value = self.fetch(flash, address);
+                self.set_acc(value);
+            },
+            0x22 | 0x23 => self.write(d9, byte2),
+            0x24 ..= 0x27 => {
+                let address = self.indirect(op);
+                self.write(address, byte1);
+            },
+            0x08 ..= 0x0F | 0x18 ..= 0x1F => {
+                self.push_pc(next);
+                self.pc = (next & 0xF000) | a12;
+            },
+            0x28 ..= 0x2F | 0x38 ..= 0x3F => self.pc = (next & 0xF000) | a12,
+            0x48 ..= 0x4F | 0x58 ..= 0x5F | 0x68 ..= 0x6F | 0x78 ..= 0x7F | 0x88 ..= 0x8F | 0x98 ..= 0x9F => {
+                let value = self.read(bit_address);
+                let set = value & bit != 0;
+                let taken = match op >> 5 {
+                    // BPC clears the bit it tests
+                    2 => {
+                        if set {
+                            self.write(bit_address, value & !bit);
+                        }
+                        set
+                    },
+                    3 => set,
+                    _ => !set
+                };
+                if taken {
+                    self.pc = relative(next, byte2);
+                }
+            },
+            0xA8 ..= 0xAF | 0xB8 ..= 0xBF | 0xC8 ..= 0xCF | 0xD8 ..= 0xDF | 0xE8 ..= 0xEF | 0xF8 ..= 0xFF => {
+                let value = self.read(bit_address);
+                let result = match op >> 5 {
+                    5 => value ^ bit,
+                    6 => value & !bit,
+                    _ => value | bit
+                };
+                self.write(bit_address, result);
+            },
+            _ => {
+                // The remaining opcodes combine the accumulator with an
+                // immediate, a direct or an indirect operand
+                let operand = match op & 0xF {
+                    0x1       => Operand::Immediate(byte1),
+                    0x2 | 0x3 => Operand::Address(d9),
+                    _         => Operand::Address(self.indirect(op))
+                };
+                let value = match operand {
+                    Operand::Immediate(value) => value,
+                    Operand::Address(address) => self.read(address)
+                };
+
+                match (op >> 4, operand) {
+                    (0x0, _) => self.set_acc(value),
+                    (0x1, Operand::Address(address)) => {
+                        let acc = self.acc();
+                        self.write(address, acc);
+                    },
+                    (0x6, Operand::Address(address)) => self.write(address, value.wrapping_add(1)),
+                    (0x7, Operand::Address(address)) => self.write(address, value.wrapping_sub(1)),
+                    (0x8, _) => self.add(value, false),
+                    (0x9, _) => {
+                        let carry = self.flag(PSW_CY);
+                        self.add(value, carry);
+                    },
+                    (0xA, _) => self.sub(value, false),
+                    (0xB, _) => {
+                        let borrow = self.flag(PSW_CY);
+                        self.sub(value, borrow);
+                    },
+                    (0xC, Operand::Address(address)) => {
+                        let acc = self.acc();
+                        self.write(address, acc);
+                        self.set_acc(value);
+                    },
+                    (0xD, _) => {
+                        let acc = self.acc();
+                        self.set_acc(acc | value);
+                    },
+                    (0xE, _) => {
+                        let acc = self.acc();
+                        self.set_acc(acc & value);
+                    },
+                    (0xF, _) => {
+                        let acc = self.acc();
+                        self.set_acc(acc ^ value);
+                    },
+                    _ => ()
+                }
+            }
+        }
+
+        cycles
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Operand {
+    Immediate(u8),
+    Address(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maple::vmu::flash::DirectoryEntry;
+
+    /// A VMU without BIOS, running a game from the start of the flash
+    fn with_game(program: &[u8]) -> Lc86k {
+        let mut flash = VmuFlash::new();
+        let entry = DirectoryEntry {
+            file_type: FileType::Game,
+            copy_protected: false,
+            first_block: 0,
+            name: "TEST".to_string(),
+            created: Timestamp::from_unix(0),
+            size: 0,
+            header_offset: 1,
+        };
+        flash.write_file(&entry, program).unwrap();
+        Lc86k::new(Arc::new(Mutex::new(flash)), None).unwrap()
+    }
+
+    fn step(cpu: &mut Lc86k, steps: usize) {
+        let flash = cpu.flash();
+        let mut flash = flash.lock().unwrap();
+        for _ in 0..steps {
+            cpu.step(&mut flash);
+        }
+    }
+
+    #[test]
+    fn needs_a_game_without_bios() {
+        let mut flash = VmuFlash::new();
+        assert!(Lc86k::new(Arc::new(Mutex::new(VmuFlash::new())), None).is_err());
+        assert!(Lc86k::new(Arc::new(Mutex::new(VmuFlash::new())), Some(vec![0; 16])).is_err());
+
+        // An erased flash has no file system to find a game in
+        flash.data = vec![0xFF; flash.data.len()];
+        let flash = Arc::new(Mutex::new(flash));
+        assert!(Lc86k::new(flash.clone(), None).is_err());
+        assert!(Lc86k::new(flash, Some(vec![0; BIOS_SIZE])).is_ok());
+    }
+
+    #[test]
+    fn sets_arithmetic_flags() {
+        let mut cpu = with_game(&[
+            0x23, 0x00, 0x7F, // mov #$7F, acc
+            0x81, 0x01,       // add #1
+            0xA1, 0x81,       // sub #$81
+            0x91, 0x00,       // addc #0
+        ]);
+        step(&mut cpu, 2);
+        assert_eq!(cpu.read(ACC), 0x80);
+        assert_eq!(cpu.read(PSW), PSW_AC | PSW_OV | PSW_P);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.read(ACC), 0xFF);
+        assert_eq!(cpu.read(PSW), PSW_CY | PSW_AC);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.read(ACC), 0x00);
+        assert_eq!(cpu.read(PSW), PSW_CY | PSW_AC);
+        assert_eq!(cpu.pc, 9);
+    }
+
+    #[test]
+    fn multiplies_and_divides_across_three_registers() {
+        let mut cpu = with_game(&[
+            0x23, 0x00, 0x12, // mov #$12, acc
+            0x23, 0x03, 0x34, // mov #$34, c
+            0x23, 0x02, 0x10, // mov #$10, b
+            0x30,             // mul
+            0x23, 0x00, 0x12,
+            0x23, 0x03, 0x34,
+            0x23, 0x02, 0x10,
+            0x40,             // div
+        ]);
+        step(&mut cpu, 4);
+        assert_eq!((cpu.read(B), cpu.read(ACC), cpu.read(C)), (0x01, 0x23, 0x40));
+        assert!(cpu.read(PSW) & PSW_OV != 0);
+        step(&mut cpu, 4);
+        assert_eq!((cpu.read(ACC), cpu.read(C), cpu.read(B)), (0x01, 0x23, 0x04));
+        assert!(cpu.read(PSW) & PSW_OV == 0);
+    }
+
+    #[test]
+    fn loops_calls_and_returns() {
+        let mut cpu = with_game(&[
+            0x22, 0x10, 0x05, // mov #5, $10
+            0x62, 0x11,       // loop: inc $11
+            0x52, 0x10, 0xFB, // dbnz $10, loop
+            0x08, 0x10,       // call $010
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xA0,             // ret
+        ]);
+        step(&mut cpu, 11);
+        assert_eq!((cpu.read(0x10), cpu.read(0x11)), (0, 5));
+        assert_eq!(cpu.pc, 8);
+
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x10);
+        assert_eq!(cpu.read(SP), 0x81);
+        assert_eq!((cpu.ram[0][0x80], cpu.ram[0][0x81]), (10, 0));
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, 10);
+        assert_eq!(cpu.read(SP), 0x7F);
+    }
+
+    #[test]
+    fn counts_with_timer_0_and_takes_its_interrupt() {
+        let mut cpu = with_game(&[0x00; 4]);
+        cpu.write(T0PRR, 0xFF);
+        cpu.write(T0L, 0xFE);
+        cpu.write(T0LR, 0x80);
+        cpu.write(T0CON, 0x41);
+        cpu.t0_prescaler = 0xFF;
+
+        // Each cycle overflows the prescaler, reloaded with $FF
+        cpu.tick(1);
+        assert_eq!(cpu.read(T0L), 0xFF);
+        cpu.tick(1);
+        assert_eq!(cpu.read(T0L), 0x80);
+        assert_eq!(cpu.pending_interrupt(), Some(VECTOR_T0L));
+
+        cpu.write(IE, 0x80);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, VECTOR_T0L);
+        assert_eq!(cpu.read(SP), 0x81);
+    }
+
+    #[test]
+    fn sounds_the_buzzer_from_timer_1() {
+        let mut cpu = with_game(&[0x00; 4]);
+        cpu.write(T1L, 0xF0);
+        cpu.write(T1CNT, 0x40);
+        cpu.tick(256);
+        assert_eq!(cpu.read(T1L), 0xF0);
+        assert!(cpu.read(T1CNT) & 0x02 != 0);
+        assert_eq!(cpu.buzzer(), None);
+
+        cpu.write(T1LC, 0xF8);
+        cpu.write(T1CNT, 0x50);
+        assert_eq!(cpu.buzzer(), Some((QUARTZ_CLOCK / 6) as f64 / 16.0));
+    }
+
+    #[test]
+    fn requests_the_base_timer_every_half_second() {
+        let mut cpu = with_game(&[0x00; 4]);
+        let half_second = QUARTZ_CLOCK / 2 / 6;
+        cpu.tick(half_second);
+        assert_eq!(cpu.pending_interrupt(), None);
+        cpu.tick(1);
+        assert_eq!(cpu.pending_interrupt(), Some(VECTOR_BASE_TIMER));
+    }
+
+    #[test]
+    fn programs_the_flash_after_the_command_sequence() {
+        let mut cpu = with_game(&[0x00; 4]);
+        let flash = cpu.flash();
+        let mut flash = flash.lock().unwrap();
+        let mut store = |cpu: &mut Lc86k, address: u16, value: u8| {
+            cpu.write(TRH, (address >> 8) as u8);
+            cpu.write(TRL, address as u8);
+            cpu.write(ACC, value);
+            cpu.store_flash(&mut flash);
+        };
+
+        // Ignored while FPR keeps the commands locked
+        store(&mut cpu, 0x5555, 0xAA);
+        assert_eq!(cpu.flash_state, 0);
+
+        cpu.write(FPR, 0x03);
+        store(&mut cpu, 0x5555, 0xAA);
+        store(&mut cpu, 0x2AAA, 0x55);
+        store(&mut cpu, 0x5555, 0xA0);
+        assert_eq!(cpu.flash_state, 3);
+        store(&mut cpu, 0x2000, 0x42);
+        assert_eq!(cpu.flash_remaining, FLASH_PAGE - 1);
+        assert_eq!(flash.data[0x12000], 0x42);
+    }
+
+    #[test]
+    fn carries_out_bios_calls_without_a_bios() {
+        let mut cpu = with_game(&[0x00; 4]);
+        cpu.ram[1][0x7D..0x80].copy_from_slice(&[1, 0x20, 0x00]);
+        for (i, byte) in cpu.ram[1][0x80..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // The game's stub switches to the ROM, then jumps past it
+        cpu.pc = BIOS_WRITE_FLASH;
+        cpu.write(EXT, 0);
+        step(&mut cpu, 1);
+        assert_eq!(cpu.pc, BIOS_WRITE_FLASH + 3);
+        assert_eq!(cpu.read(EXT) & 1, 1);
+        assert_eq!(cpu.read(ACC), 0);
+        assert_eq!(cpu.flash().lock().unwrap().data[0x1207F], 0x7F);
+
+        cpu.pc = BIOS_EXIT;
+        cpu.write(EXT, 0);
+        step(&mut cpu, 1);
+        assert!(cpu.exited);
+    }
+
+    #[test]
+    fn advances_the_clock_into_a_new_year() {
+        let mut cpu = with_game(&[0x00; 4]);
+        cpu.set_time(Timestamp { year: 2000, month: 12, day: 31, hour: 23, minute: 59, second: 59, weekday: 6 });
+        cpu.advance_clock();
+        assert_eq!(cpu.ram[0][CLOCK_RAM + 6], 59);
+        cpu.advance_clock();
+        assert_eq!(&cpu.ram[0][CLOCK_RAM..CLOCK_RAM + 8], &[0x07, 0xD1, 1, 1, 0, 0, 0, 0]);
+
+        // 2000 is a leap year
+        cpu.set_time(Timestamp { year: 2000, month: 2, day: 28, hour: 23, minute: 59, second: 59, weekday: 0 });
+        cpu.advance_clock();
+        cpu.advance_clock();
+        assert_eq!(&cpu.ram[0][CLOCK_RAM + 2..CLOCK_RAM + 4], &[2, 29]);
+    }
+
+    #[test]
+    fn shows_the_xram_rows_on_the_lcd() {
+        let mut cpu = with_game(&[0x00; 4]);
+        cpu.xram[0][0] = 0x80;
+        cpu.xram[0][6] = 0x01;
+        cpu.xram[0][16] = 0x80;
+        cpu.xram[1][0] = 0x80;
+        cpu.update_lcd();
+
+        let lcd = cpu.lcd();
+        let lcd = lcd.lock().unwrap();
+        assert!(lcd.pixel(0, 0));
+        assert!(lcd.pixel(7, 1));
+        assert!(lcd.pixel(0, 2));
+        assert!(lcd.pixel(0, 16));
+        assert!(!lcd.pixel(1, 0));
+        assert_eq!(lcd.frames, 1);
+    }
+}
@@ -0,0 +1,208 @@
+use maple::{MapleDevice, DeviceInfo, Response, SEGA_LICENSE, words, bytes};
+use maple::{FUNCTION_STORAGE, FUNCTION_LCD, FUNCTION_CLOCK, FUNCTION_UNSUPPORTED, FILE_ERROR};
+use image::Image;
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub mod flash;
+pub mod lc86k;
+
+use self::flash::{VmuFlash, BLOCKS, BLOCK_SIZE, ROOT_BLOCK};
+
+pub const LCD_WIDTH  : usize = 48;
+pub const LCD_HEIGHT : usize = 32;
+/// Bytes of a frame, a bit per pixel
+pub const LCD_FRAME_SIZE : usize = LCD_WIDTH * LCD_HEIGHT / 8;
+
+/// Colors of the pixels in captured LCD frames
+pub const LCD_ON  : u32 = 0xFF081810;
+pub const LCD_OFF : u32 = 0xFFA8C0A0;
+
+/// A block write is split into this many phases
+const WRITE_PHASES : usize = 4;
+
+/// The monochrome LCD of a VMU. Pixels are a bit each, the most
+/// significant bit of each byte leftmost, rows from the top as seen on
+/// the VMU held in hand; docked in a controller it shows upside down.
+pub struct VmuLcd {
+    pub pixels: [u8; LCD_FRAME_SIZE],
+    /// Number of frames received, to tell when the display changed
+    pub frames: u64,
+}
+
+impl VmuLcd {
+    pub fn new() -> VmuLcd {
+        VmuLcd {
+            pixels: [0; LCD_FRAME_SIZE],
+            frames: 0,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * LCD_WIDTH / 8 + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Captures the display
+    pub fn image(&self) -> Image {
+        let mut image = Image::new(LCD_WIDTH as u32, LCD_HEIGHT as u32);
+        for y in 0..LCD_HEIGHT {
+            for x in 0..LCD_WIDTH {
+                image.set(x as u32, y as u32, if self.pixel(x, y) { LCD_ON } else { LCD_OFF });
+            }
+        }
+        image
+    }
+}
+
+impl Default for VmuLcd {
+    fn default() -> VmuLcd {
+        VmuLcd::new()
+    }
+}
+
+/// A Visual Memory Unit plugged into a controller, with its storage,
+/// LCD and clock functions. The flash and LCD are shared with the host
+/// to move saves around and show the display.
+pub struct Vmu {
+    flash: Arc<Mutex<VmuFlash>>,
+    lcd: Arc<Mutex<VmuLcd>>,
+}
+
+impl Vmu {
+    /// A VMU whose flash only lives in memory
+    pub fn new() -> Vmu {
+        Vmu::with_flash(VmuFlash::new())
+    }
+
+    /// A VMU whose flash is persisted to a host file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Vmu> {
+        Ok(Vmu::with_flash(VmuFlash::open(path)?))
+    }
+
+    pub fn with_flash(flash: VmuFlash) -> Vmu {
+        Vmu {
+            flash: Arc::new(Mutex::new(flash)),
+            lcd: Arc::new(Mutex::new(VmuLcd::new())),
+        }
+    }
+
+    pub fn flash(&self) -> Arc<Mutex<VmuFlash>> {
+        self.flash.clone()
+    }
+
+    pub fn lcd(&self) -> Arc<Mutex<VmuLcd>> {
+        self.lcd.clone()
+    }
+}
+
+impl Default for Vmu {
+    fn default() -> Vmu {
+        Vmu::new()
+    }
+}
+
+/// Splits the location word of block commands into partition, phase
+/// and block. It is sent most significant byte first, like the rest of
+/// the frame, with the block number big endian.
+fn location(word: u32) -> (u8, usize, u16) {
+    let partition = word as u8;
+    let phase = (word >> 8) as u8 as usize;
+    let block = (((word >> 16) & 0xFF) << 8 | (word >> 24)) as u16;
+    (partition, phase, block)
+}
+
+impl MapleDevice for Vmu {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            functions: FUNCTION_CLOCK | FUNCTION_LCD | FUNCTION_STORAGE,
+            // Clock, 48x32 LCD, and 256 blocks of 512 bytes written in
+            // four accesses
+            function_data: [0x403F7E7E, 0x00100500, 0x00410F00],
+            area_code: 0xFF,
+            connector_direction: 0,
+            product_name: "Visual Memory",
+            license: SEGA_LICENSE,
+            standby_power: 0x007C,
+            max_power: 0x0082,
+        }
+    }
+
+    fn get_media_info(&mut self, function: u32, _partition: u32) -> Response {
+        match function {
+            FUNCTION_STORAGE => {
+                // The root block holds the layout of the file system
+                let flash = self.flash.lock().unwrap();
+                let mut data = vec![FUNCTION_STORAGE];
+                data.extend(words(&flash.block(ROOT_BLOCK)[0x40..0x58]));
+                Response::Data(data)
+            },
+            FUNCTION_LCD => Response::Data(vec![FUNCTION_LCD, words(&[LCD_WIDTH as u8 - 1, LCD_HEIGHT as u8 - 1, 0x10, 0])[0]]),
+            _ => Response::Error(FUNCTION_UNSUPPORTED)
+        }
+    }
+
+    fn block_read(&mut self, function: u32, location_word: u32) -> Response {
+        if function != FUNCTION_STORAGE {
+            return Response::Error(FUNCTION_UNSUPPORTED);
+        }
+
+        let (_, phase, block) = location(location_word);
+        if phase != 0 || block as usize >= BLOCKS {
+            return Response::Error(FILE_ERROR);
+        }
+
+        let mut data = vec![FUNCTION_STORAGE, location_word];
+        data.extend(words(self.flash.lock().unwrap().block(block)));
+        Response::Data(data)
+    }
+
+    fn block_write(&mut self, function: u32, location_word: u32, data: &[u32]) -> Response {
+        let (_, phase, block) = location(location_word);
+        match function {
+            FUNCTION_STORAGE => {
+                let length = BLOCK_SIZE / WRITE_PHASES;
+                if phase >= WRITE_PHASES || block as usize >= BLOCKS || data.len() * 4 != length {
+                    return Response::Error(FILE_ERROR);
+                }
+                let mut flash = self.flash.lock().unwrap();
+                flash.block_mut(block)[phase * length..(phase + 1) * length].copy_from_slice(&bytes(data));
+                Response::Ack
+            },
+            FUNCTION_LCD => {
+                let frame = bytes(data);
+                if frame.len() != LCD_FRAME_SIZE {
+                    return Response::Error(FILE_ERROR);
+                }
+                let mut lcd = self.lcd.lock().unwrap();
+                lcd.pixels.copy_from_slice(&frame);
+                lcd.frames += 1;
+                Response::Ack
+            },
+            _ => Response::Error(FUNCTION_UNSUPPORTED)
+        }
+    }
+
+    /// Completes a block write, which is when the flash is persisted
+    fn get_last_error(&mut self, function: u32, _location: u32) -> Response {
+        if function != FUNCTION_STORAGE {
+            return Response::Ack;
+        }
+
+        // The game is told the save failed; the host learns why by
+        // flushing itself
+        match self.flash.lock().unwrap().flush() {
+            Ok(()) => Response::Ack,
+            Err(_) => Response::Error(FILE_ERROR)
+        }
+    }
+
+    /// The clock's condition drives the buzzer, which isn't emulated
+    fn set_condition(&mut self, function: u32, _data: &[u32]) -> Response {
+        match function {
+            FUNCTION_CLOCK => Response::Ack,
+            _ => Response::Error(FUNCTION_UNSUPPORTED)
+        }
+    }
+}
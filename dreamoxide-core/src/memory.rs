@@ -1,12 +1,23 @@
 use MemoryField;
 
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use std::usize;
 use std::cmp;
 use std::iter;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::sync::MutexGuard;
+#[cfg(test)]
+use std::ops::{Deref, DerefMut};
 use latest::value::{Sender, Receiver};
 
+/// System RAM, 16 MB in area 3
+pub const RAM_BASE : usize = 0x0C000000;
+pub const RAM_SIZE : usize = 0x01000000;
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MemoryRange(pub usize, pub usize);
 
@@ -30,11 +41,48 @@ pub struct MappedIO {
     pub receiver: Receiver<u32>
 }
 
+/// A device whose registers or memory are serviced directly on the
+/// accessing thread. Unlike `MappedIO` no access can get lost, which
+/// makes it the right choice for FIFOs and device-local memory.
+pub trait MappedDevice: Send {
+    /// Reads `size` bytes (1, 2 or 4) from the given physical address
+    fn read(&mut self, address: usize, size: usize) -> u32;
+    /// Writes `size` bytes (1, 2 or 4) to the given physical address
+    fn write(&mut self, address: usize, value: u32, size: usize);
+}
+
+pub struct MappedDeviceRegion {
+    /// The memory address range for the mapped device region
+    pub range: MemoryRange,
+    /// The device servicing accesses to the region
+    pub device: Arc<Mutex<dyn MappedDevice>>
+}
+
+/// The kind of access a watchpoint stops at
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/// Bytes the debugger watches, by physical address
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: MemoryRange,
+    pub kind: WatchKind,
+}
+
 pub struct Memory {
     pub data : Box<[MemoryField]>,
     pub mapped: Vec<MappedIO>,
+    pub devices: Vec<MappedDeviceRegion>,
     pub min_mapped: usize,
-    pub max_mapped: usize
+    pub max_mapped: usize,
+    pub watchpoints: Vec<Watchpoint>,
+    /// The first watched access since it was last cleared, as the
+    /// address and the kind of the watchpoint
+    pub watch_hit: Cell<Option<(usize, WatchKind)>>,
 }
 
 impl Memory {
@@ -45,8 +93,11 @@ impl Memory {
             //data: (0..0x20000000).map(|_| MemoryField::MemoryCell(0)).collect(),
             data: iter::repeat(MemoryField::MemoryCell(0)).take(0x20000000).collect::<Vec<MemoryField>>().into_boxed_slice(),
             mapped: Vec::new(),
+            devices: Vec::new(),
             min_mapped: usize::MAX,
-            max_mapped: 0
+            max_mapped: 0,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
         }
     }
 
@@ -71,7 +122,7 @@ impl Memory {
     /// safe region or not.
     //#[inline]
     pub fn is_io_register(&self, pointer: usize) -> bool {
-        pointer > self.min_mapped && pointer < self.max_mapped
+        pointer >= self.min_mapped && pointer <= self.max_mapped
     }
 
     pub fn register_mapped_io(&mut self, mapped: MappedIO) {
@@ -82,6 +133,36 @@ impl Memory {
         self.mapped.sort_by(|a, b| a.range.cmp(&b.range));
     }
 
+    /// Maps the given range to a device that is serviced synchronously.
+    /// A device may be registered for several disjoint ranges.
+    pub fn register_mapped_device(&mut self, range: MemoryRange, device: Arc<Mutex<dyn MappedDevice>>) {
+        let MemoryRange(mi, ma) = range;
+        self.min_mapped = cmp::min(self.min_mapped, mi);
+        self.max_mapped = cmp::max(self.max_mapped, ma);
+        self.devices.push(MappedDeviceRegion { range, device });
+        self.devices.sort_by_key(|region| region.range);
+    }
+
+    pub fn try_device_write(&self, address: usize, value: u32, size: usize) -> bool {
+        let addr = Memory::map(address);
+        if !self.is_io_register(addr) { return false; }
+        match self.devices.iter().find(|region| region.range.is_within(addr)) {
+            Some(region) => {
+                region.device.lock().unwrap().write(addr, value, size);
+                true
+            },
+            _ => false
+        }
+    }
+
+    pub fn try_device_read(&self, address: usize, size: usize) -> Option<u32> {
+        let addr = Memory::map(address);
+        if !self.is_io_register(addr) { return None; }
+        self.devices.iter()
+            .find(|region| region.range.is_within(addr))
+            .map(|region| region.device.lock().unwrap().read(addr, size))
+    }
+
     //#[inline(always)]
     pub fn try_mapped_write(&self, address: usize, value: u32) -> bool {
         let addr = Memory::map(address);
@@ -121,9 +202,42 @@ impl Memory {
         val as i16 as i32
     }
 
+    /// Records the access if it touches a watchpoint. Only the first is
+    /// kept until the debugger clears it.
+    #[inline(always)]
+    fn watch(&self, address: usize, size: usize, write: bool) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, size, write);
+        }
+    }
+
+    fn check_watchpoints(&self, address: usize, size: usize, write: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let start = address & 0x1FFFFFFF;
+        let end = start + size - 1;
+        for watchpoint in self.watchpoints.iter() {
+            let MemoryRange(low, high) = watchpoint.range;
+            let matches = match watchpoint.kind {
+                WatchKind::Write  => write,
+                WatchKind::Read   => !write,
+                WatchKind::Access => true
+            };
+            if matches && start <= high && end >= low {
+                self.watch_hit.set(Some((start.max(low), watchpoint.kind)));
+                return;
+            }
+        }
+    }
+
     /// Reads an unsigned byte from memory
     #[inline(always)]
     pub fn read_u8(&self, address: usize) -> u8 {
+        self.watch(address, 1, false);
+        if let Some(v) = self.try_device_read(address, 1) {
+            return v as u8;
+        }
         let offset = address % 2;
         let val = self.read_u16(address);
 
@@ -137,11 +251,15 @@ impl Memory {
 
     #[inline(always)]
     pub fn read_u16(&self, address: usize) -> u16 {
+        self.watch(address, 2, false);
+        if let Some(v) = self.try_device_read(address, 2) {
+            return v as u16;
+        }
         if let Some(v) = self.try_mapped_read(address) {
             return v as u16;
         }
         match self.access(address) {
-            &MemoryField::MemoryCell(v) => v,
+            &MemoryField::MemoryCell(v) | &MemoryField::InstructionCell(_, v) => v,
             _ => panic!("Can only read from memory cell!")
         }
     }
@@ -149,13 +267,17 @@ impl Memory {
     #[inline(always)]
     pub fn read_u16_raw(&self, address: usize) -> u16 {
         match self.access(address) {
-            &MemoryField::MemoryCell(v) => v,
+            &MemoryField::MemoryCell(v) | &MemoryField::InstructionCell(_, v) => v,
             _ => panic!("Can only read from memory cell!")
         }
     }
 
     #[inline(always)]
     pub fn read_u32(&self, address: usize) -> u32 {
+        self.watch(address, 4, false);
+        if let Some(v) = self.try_device_read(address, 4) {
+            return v;
+        }
         if let Some(v) = self.try_mapped_read(address) {
             return v;
         }
@@ -167,6 +289,10 @@ impl Memory {
 
     //#[inline(always)]
     pub fn write_u8(&mut self, address: usize, value: u8) {
+        self.watch(address, 1, true);
+        if self.try_device_write(address, value as u32, 1) {
+            return;
+        }
         if self.try_mapped_write(address, value as u32) {
             return;
         }
@@ -181,6 +307,10 @@ impl Memory {
 
     //#[inline(always)]
     pub fn write_u16(&mut self, address: usize, value: u16) {
+        self.watch(address, 2, true);
+        if self.try_device_write(address, value as u32, 2) {
+            return;
+        }
         if self.try_mapped_write(address, value as u32) {
             return;
         }
@@ -189,6 +319,10 @@ impl Memory {
 
     //#[inline(always)]
     pub fn write_u32(&mut self, address: usize, value: u32) {
+        self.watch(address, 4, true);
+        if self.try_device_write(address, value, 4) {
+            return;
+        }
         if self.try_mapped_write(address, value) {
             return;
         }
@@ -210,20 +344,141 @@ impl Memory {
         &mut self.data[Memory::map(address) / 2]
     }
 
-    pub fn read_from_file(&mut self, name: &str, start: usize) -> usize {
-        let mut f = File::open(name).unwrap();
-        let mut fdata : Vec<u8> = Vec::new();
-        let mut size = f.read_to_end(&mut fdata).unwrap();
+    /// Copies bytes into memory cells, bypassing mapped regions and
+    /// watchpoints. Code that is executed has to be loaded this way, as
+    /// instructions are fetched from the cells. Meant for loaders and
+    /// booting only; buffers of system calls go through `write_bytes`.
+    /// A cell only partly covered at either end keeps its other byte.
+    pub fn load(&mut self, start: usize, data: &[u8]) {
+        let mut address = start;
+        let mut data = data;
+
+        if address % 2 == 1 && !data.is_empty() {
+            let cell = self.read_u16_raw(address - 1);
+            *self.access_mut(address - 1) = MemoryField::MemoryCell((cell & 0x00FF) | ((data[0] as u16) << 8));
+            address += 1;
+            data = &data[1..];
+        }
 
-        println!("Loading {} into memory ({})", name, size);
+        for pair in data.chunks(2) {
+            let v = match pair.len() {
+                2 => ((pair[1] as u16) << 8) | pair[0] as u16,
+                _ => (self.read_u16_raw(address) & 0xFF00) | pair[0] as u16
+            };
+            *self.access_mut(address) = MemoryField::MemoryCell(v);
+            address += 2;
+        }
+    }
 
-        for i in (0..size).step_by(2) {
-            let h : u8 = fdata[i];
-            let l : u8 = fdata[i + 1];
-            let v = ((l as u16) << 8) + (h as u16);
-            *self.access_mut(Memory::map(start + i)) = MemoryField::MemoryCell(v);
+    /// Stores bytes one at a time, as the guest's byte stores would,
+    /// through mapped regions and watchpoints
+    pub fn write_bytes(&mut self, start: usize, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let address = start + i;
+            self.watch(address, 1, true);
+            if self.try_device_write(address, byte as u32, 1) || self.try_mapped_write(address, byte as u32) {
+                continue;
+            }
+            let shift = (address % 2) * 8;
+            let cell = self.read_u16_raw(address);
+            *self.access_mut(address) = MemoryField::MemoryCell((cell & !(0xFF << shift)) | ((byte as u16) << shift));
         }
+    }
+
+    /// Reads bytes one at a time, as the guest's byte loads would
+    pub fn read_bytes(&self, start: usize, length: usize) -> Vec<u8> {
+        (0..length).map(|i| self.read_u8(start + i)).collect()
+    }
+
+    /// The bytes from an address to the end of system RAM, none when
+    /// the address isn't in it
+    pub fn ram_left(address: usize) -> usize {
+        let address = address & 0x1FFFFFFF;
+        if (RAM_BASE..RAM_BASE + RAM_SIZE).contains(&address) {
+            RAM_BASE + RAM_SIZE - address
+        } else {
+            0
+        }
+    }
+
+    /// Loads a whole file into memory at an address, returning its size
+    pub fn read_from_file(&mut self, name: &str, start: usize) -> io::Result<usize> {
+        let mut fdata : Vec<u8> = Vec::new();
+        let size = File::open(name)?.read_to_end(&mut fdata)?;
+        self.load(start, &fdata);
+        Ok(size)
+    }
+}
+
+/// Only one test Memory exists at a time, as each takes gigabytes
+#[cfg(test)]
+static TEST_MEMORY_LOCK : Mutex<()> = Mutex::new(());
+
+/// A Memory for unit tests, which holds the test lock while it lives
+#[cfg(test)]
+pub struct TestMemory {
+    mem: Memory,
+    // Dropped after the memory, so the next test starts with it freed
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl TestMemory {
+    pub fn new() -> TestMemory {
+        let lock = TEST_MEMORY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        TestMemory { mem: Memory::new(), _lock: lock }
+    }
+}
+
+#[cfg(test)]
+impl Default for TestMemory {
+    fn default() -> TestMemory {
+        TestMemory::new()
+    }
+}
+
+#[cfg(test)]
+impl Deref for TestMemory {
+    type Target = Memory;
+
+    fn deref(&self) -> &Memory {
+        &self.mem
+    }
+}
+
+#[cfg(test)]
+impl DerefMut for TestMemory {
+    fn deref_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+}
 
-        size
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE : usize = 0x8C010000;
+
+    // A single test, as every Memory takes gigabytes
+    #[test]
+    fn copies_bytes_at_odd_addresses() {
+        let mut mem = TestMemory::new();
+        mem.load(BASE, &[0xAA; 8]);
+
+        // The cells only partly covered keep their other byte
+        mem.load(BASE + 1, &[1, 2, 3]);
+        assert_eq!(mem.read_bytes(BASE, 6), vec![0xAA, 1, 2, 3, 0xAA, 0xAA]);
+        assert_eq!(mem.read_u16(BASE), 0x01AA);
+        assert_eq!(mem.read_u16(BASE + 2), 0x0302);
+
+        mem.load(BASE + 5, &[4]);
+        mem.load(BASE + 6, &[5]);
+        mem.load(BASE + 3, &[]);
+        assert_eq!(mem.read_bytes(BASE + 3, 5), vec![3, 0xAA, 4, 5, 0xAA]);
+
+        // Byte stores of the guest's kind land the same way
+        mem.write_bytes(BASE + 7, &[6, 7, 8]);
+        assert_eq!(mem.read_bytes(BASE + 6, 5), vec![5, 6, 7, 8, 0]);
+        assert_eq!(mem.read_u32(BASE + 8), 0x00000807);
     }
 }
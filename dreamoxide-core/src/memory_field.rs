@@ -2,7 +2,8 @@ pub use Instruction;
 
 #[derive(Copy, Clone)]
 pub enum MemoryField {
-    InstructionCell(Instruction),
+    /// A decoded instruction, with its encoding for when it is read
+    InstructionCell(Instruction, u16),
     MemoryCell(u16),
     /// This is just to yield a better memory alignment to 8 bytes
     Padding(u32),
@@ -11,7 +12,7 @@ pub enum MemoryField {
 impl MemoryField {
     pub fn is_instruction(&self) -> bool {
         match *self {
-            MemoryField::InstructionCell(_) => true,
+            MemoryField::InstructionCell(..) => true,
             _                               => false
         }
     }
@@ -25,7 +26,7 @@ impl MemoryField {
 
     pub fn get_instruction(&self) -> Option<Instruction> {
         match *self {
-            MemoryField::InstructionCell(inst) => Some(inst),
+            MemoryField::InstructionCell(inst, _) => Some(inst),
             _                                  => None
         }
     }
@@ -39,8 +40,8 @@ impl MemoryField {
 
     pub fn get_memory(&self) -> u16 {
         match *self {
-            MemoryField::MemoryCell(x) => x,
-            _                          => 0
+            MemoryField::MemoryCell(x) | MemoryField::InstructionCell(_, x) => x,
+            _                                                            => 0
         }
     }
 }
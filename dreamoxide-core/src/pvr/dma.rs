@@ -0,0 +1,169 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use asic::Interrupt;
+
+use std::sync::{Arc, Mutex};
+
+pub const SB_PDSTAP : usize = 0x005F7C00;
+pub const SB_PDSTAR : usize = 0x005F7C04;
+pub const SB_PDLEN  : usize = 0x005F7C08;
+pub const SB_PDDIR  : usize = 0x005F7C0C;
+pub const SB_PDTSEL : usize = 0x005F7C10;
+pub const SB_PDEN   : usize = 0x005F7C14;
+pub const SB_PDST   : usize = 0x005F7C18;
+
+pub const PVR_DMA_REGISTER_BASE : usize = 0x005F7C00;
+pub const PVR_DMA_REGISTER_END  : usize = 0x005F7C7F;
+
+/// The PVR DMA channel, copying between system memory and the PVR side
+/// of the bus: video memory, the TA FIFO or the YUV converter.
+///
+/// Writing SB_PDST only marks the transfer as pending. The owner of the
+/// memory carries it out with `run`, since a device cannot access the
+/// memory it is mapped into.
+pub struct PvrDma {
+    pub pdstap: u32,
+    pub pdstar: u32,
+    pub pdlen: u32,
+    pub pddir: u32,
+    pub pdtsel: u32,
+    pub pden: u32,
+    pub pdst: u32,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+impl PvrDma {
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<PvrDma>> {
+        let dma = Arc::new(Mutex::new(PvrDma {
+            pdstap: 0,
+            pdstar: 0,
+            pdlen: 0,
+            pddir: 0,
+            pdtsel: 0,
+            pden: 0,
+            pdst: 0,
+            asic,
+        }));
+
+        mem.register_mapped_device(MemoryRange(PVR_DMA_REGISTER_BASE, PVR_DMA_REGISTER_END), dma.clone());
+        dma
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pdst & 1 != 0
+    }
+
+    /// Performs a pending transfer and raises the completion interrupt
+    pub fn run(&mut self, mem: &mut Memory) {
+        if !self.is_pending() {
+            return;
+        }
+
+        let pvr = (self.pdstap & 0x1FFFFFE0) as usize;
+        let system = (self.pdstar & 0x1FFFFFE0) as usize;
+        let to_system = self.pddir & 1 != 0;
+
+        for word in 0..(self.pdlen as usize / 4) {
+            let offset = word * 4;
+            if to_system {
+                let value = mem.read_u32(pvr + offset);
+                mem.write_u32(system + offset, value);
+            } else {
+                let value = mem.read_u32(system + offset);
+                mem.write_u32(pvr + offset, value);
+            }
+        }
+
+        // The address registers are left pointing behind the transfer
+        self.pdstap = self.pdstap.wrapping_add(self.pdlen);
+        self.pdstar = self.pdstar.wrapping_add(self.pdlen);
+        self.pdlen = 0;
+        self.pdst = 0;
+        self.asic.lock().unwrap().raise(Interrupt::PvrDmaDone);
+    }
+}
+
+impl MappedDevice for PvrDma {
+    fn read(&mut self, address: usize, _size: usize) -> u32 {
+        match address & !3 {
+            SB_PDSTAP => self.pdstap,
+            SB_PDSTAR => self.pdstar,
+            SB_PDLEN  => self.pdlen,
+            SB_PDDIR  => self.pddir,
+            SB_PDTSEL => self.pdtsel,
+            SB_PDEN   => self.pden,
+            SB_PDST   => self.pdst,
+            _         => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, _size: usize) {
+        match address & !3 {
+            SB_PDSTAP => self.pdstap = value & 0x1FFFFFE0,
+            SB_PDSTAR => self.pdstar = value & 0x1FFFFFE0,
+            SB_PDLEN  => self.pdlen = value & 0x00FFFFE0,
+            SB_PDDIR  => self.pddir = value & 1,
+            SB_PDTSEL => self.pdtsel = value & 1,
+            SB_PDEN   => self.pden = value & 1,
+            SB_PDST if value & 1 != 0 && self.pden & 1 != 0 => self.pdst = 1,
+            _         => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::TestMemory;
+    use pvr::Pvr;
+
+    #[test]
+    fn copies_to_and_from_video_memory() {
+        let mut mem = TestMemory::new();
+        let asic = Asic::new(&mut mem);
+        let pvr = Pvr::new(&mut mem, asic.clone());
+        let dma = PvrDma::new(&mut mem, asic.clone());
+
+        for i in 0..16 {
+            mem.write_u32(0x0C001000 + i * 4, 0x11111111 * i as u32);
+        }
+
+        {
+            let mut dma = dma.lock().unwrap();
+            dma.write(SB_PDSTAP, 0x04002000, 4);
+            dma.write(SB_PDSTAR, 0x0C001000, 4);
+            dma.write(SB_PDLEN, 0x40, 4);
+            dma.write(SB_PDDIR, 0, 4);
+
+            // Nothing starts while the channel is disabled
+            dma.write(SB_PDST, 1, 4);
+            assert!(!dma.is_pending());
+            dma.write(SB_PDEN, 1, 4);
+            dma.write(SB_PDST, 1, 4);
+            dma.run(&mut mem);
+
+            assert_eq!(dma.read(SB_PDST, 4), 0);
+            assert_eq!(dma.read(SB_PDSTAP, 4), 0x04002040);
+            assert_eq!(dma.read(SB_PDSTAR, 4), 0x0C001040);
+        }
+
+        assert_eq!(pvr.lock().unwrap().vram.read_u32(0x2000 + 15 * 4), 0xFFFFFFFF);
+        assert!(asic.lock().unwrap().istnrm & (1 << Interrupt::PvrDmaDone as u32) != 0);
+
+        // And back into system memory
+        pvr.lock().unwrap().vram.write_u32(0x3000, 0xCAFEBABE);
+        {
+            let mut dma = dma.lock().unwrap();
+            dma.write(SB_PDSTAP, 0x04003000, 4);
+            dma.write(SB_PDSTAR, 0x0C002000, 4);
+            dma.write(SB_PDLEN, 0x20, 4);
+            dma.write(SB_PDDIR, 1, 4);
+            dma.write(SB_PDST, 1, 4);
+            dma.run(&mut mem);
+        }
+        assert_eq!(mem.read_u32(0x0C002000), 0xCAFEBABE);
+    }
+}
@@ -0,0 +1,202 @@
+use pvr::*;
+use pvr::vram::Vram;
+use image::Image;
+
+/// The pixel format the video output reads the framebuffer in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FramebufferFormat {
+    Rgb555,
+    Rgb565,
+    Rgb888,
+    Rgb0888,
+}
+
+impl FramebufferFormat {
+    pub fn from_u32(value: u32) -> FramebufferFormat {
+        match value & 0x3 {
+            0 => FramebufferFormat::Rgb555,
+            1 => FramebufferFormat::Rgb565,
+            2 => FramebufferFormat::Rgb888,
+            _ => FramebufferFormat::Rgb0888
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        match *self {
+            FramebufferFormat::Rgb555 | FramebufferFormat::Rgb565 => 2,
+            FramebufferFormat::Rgb888 => 3,
+            FramebufferFormat::Rgb0888 => 4
+        }
+    }
+}
+
+/// The display read-out settings, taken from FB_R_CTRL, FB_R_SIZE and
+/// the field start addresses
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FramebufferConfig {
+    pub enabled: bool,
+    pub format: FramebufferFormat,
+    pub line_double: bool,
+    pub interlaced: bool,
+    /// Low bits appended to 5 and 6 bit components
+    pub concat: u32,
+    /// Start of the first and second field on the 32-bit path
+    pub sof: [u32; 2],
+    /// Bytes per line, and from the start of one line to the next
+    pub line_size: u32,
+    pub line_pitch: u32,
+    /// Lines per field
+    pub lines: u32,
+    pub border: u32,
+}
+
+impl FramebufferConfig {
+    pub fn new(registers: &PvrRegisters) -> FramebufferConfig {
+        let ctrl = registers.get(FB_R_CTRL);
+        let size = registers.get(FB_R_SIZE);
+        let line_size = ((size & 0x3FF) + 1) * 4;
+        let modulus = (size >> 20) & 0x3FF;
+
+        FramebufferConfig {
+            enabled: ctrl & 1 != 0,
+            format: FramebufferFormat::from_u32(ctrl >> 2),
+            line_double: ctrl & (1 << 1) != 0,
+            interlaced: registers.get(SPG_CONTROL) & (1 << 4) != 0,
+            concat: (ctrl >> 4) & 0x7,
+            sof: [registers.get(FB_R_SOF1) & 0x00FFFFFC, registers.get(FB_R_SOF2) & 0x00FFFFFC],
+            line_size,
+            // The modulus counts 32-bit words between lines, plus one
+            line_pitch: line_size + if modulus > 0 { (modulus - 1) * 4 } else { 0 },
+            lines: ((size >> 10) & 0x3FF) + 1,
+            border: registers.get(VO_BORDER_COL) & 0x00FFFFFF,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.line_size / self.format.bytes_per_pixel()
+    }
+
+    /// Displayed lines, with both fields woven together
+    pub fn height(&self) -> u32 {
+        if self.interlaced || self.line_double { self.lines * 2 } else { self.lines }
+    }
+
+    /// Reads a pixel of a field as ARGB8888
+    pub fn pixel(&self, vram: &Vram, field: usize, x: u32, line: u32) -> u32 {
+        let address = (self.sof[field] + line * self.line_pitch + x * self.format.bytes_per_pixel()) as usize;
+        let c = self.concat;
+
+        let rgb = match self.format {
+            FramebufferFormat::Rgb555 => {
+                let p = vram.read_32bit_u16(address) as u32;
+                ((((p >> 10) & 0x1F) << 3 | c) << 16) | ((((p >> 5) & 0x1F) << 3 | c) << 8) | ((p & 0x1F) << 3 | c)
+            },
+            FramebufferFormat::Rgb565 => {
+                let p = vram.read_32bit_u16(address) as u32;
+                ((((p >> 11) & 0x1F) << 3 | c) << 16) | ((((p >> 5) & 0x3F) << 2 | (c >> 1)) << 8) | ((p & 0x1F) << 3 | c)
+            },
+            FramebufferFormat::Rgb888 => {
+                let byte = |i: usize| vram.read_u8(Vram::map_32bit(address + i)) as u32;
+                (byte(2) << 16) | (byte(1) << 8) | byte(0)
+            },
+            FramebufferFormat::Rgb0888 => vram.read_32bit_u32(address) & 0x00FFFFFF
+        };
+
+        0xFF000000 | rgb
+    }
+
+    /// Converts the displayed framebuffer into an image. A disabled
+    /// display shows the border color.
+    pub fn capture(&self, vram: &Vram) -> Image {
+        let mut image = Image::new(self.width(), self.height());
+
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let argb = if !self.enabled {
+                    0xFF000000 | self.border
+                } else if self.interlaced {
+                    // Even lines come from the first field, odd ones from the second
+                    self.pixel(vram, (y & 1) as usize, x, y / 2)
+                } else if self.line_double {
+                    self.pixel(vram, 0, x, y / 2)
+                } else {
+                    self.pixel(vram, 0, x, y)
+                };
+                image.set(x, y, argb);
+            }
+        }
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fb_size(words: u32, lines: u32, modulus: u32) -> u32 {
+        (modulus << 20) | ((lines - 1) << 10) | (words - 1)
+    }
+
+    #[test]
+    fn decodes_size_and_modulus() {
+        let mut registers = PvrRegisters::new();
+        registers.set(FB_R_CTRL, 1 | (1 << 2));
+        registers.set(FB_R_SIZE, fb_size(320, 480, 1));
+
+        let config = FramebufferConfig::new(&registers);
+        assert!(config.enabled);
+        assert_eq!(config.format, FramebufferFormat::Rgb565);
+        assert_eq!(config.line_size, 1280);
+        assert_eq!(config.line_pitch, 1280);
+        assert_eq!((config.width(), config.height()), (640, 480));
+
+        // Interlaced fields skip every other line of the full frame
+        registers.set(FB_R_SIZE, fb_size(320, 240, 321));
+        registers.set(SPG_CONTROL, 1 << 4);
+        let config = FramebufferConfig::new(&registers);
+        assert!(config.interlaced);
+        assert_eq!(config.line_pitch, 2560);
+        assert_eq!((config.width(), config.height()), (640, 480));
+    }
+
+    #[test]
+    fn weaves_interlaced_fields() {
+        let mut registers = PvrRegisters::new();
+        let mut vram = Vram::new();
+        registers.set(FB_R_CTRL, 1 | (3 << 2));
+        registers.set(FB_R_SIZE, fb_size(2, 2, 3));
+        registers.set(FB_R_SOF1, 0);
+        registers.set(FB_R_SOF2, 8);
+        registers.set(SPG_CONTROL, 1 << 4);
+
+        for line in 0..4 {
+            for x in 0..2 {
+                vram.write_32bit_u32(line * 8 + x * 4, 0x100 * line as u32 + x as u32);
+            }
+        }
+
+        let image = FramebufferConfig::new(&registers).capture(&vram);
+        assert_eq!((image.width, image.height), (2, 4));
+        for y in 0..4 {
+            assert_eq!(image.get(0, y), 0xFF000000 | (0x100 * y));
+            assert_eq!(image.get(1, y), 0xFF000001 | (0x100 * y));
+        }
+    }
+
+    #[test]
+    fn concat_fills_low_bits_and_border_shows_when_disabled() {
+        let mut registers = PvrRegisters::new();
+        let vram = Vram::new();
+        registers.set(FB_R_CTRL, 1 | (1 << 2) | (7 << 4));
+        registers.set(FB_R_SIZE, fb_size(1, 1, 1));
+        registers.set(VO_BORDER_COL, 0x123456);
+
+        let config = FramebufferConfig::new(&registers);
+        assert_eq!(config.pixel(&vram, 0, 0, 0), 0xFF070307);
+
+        registers.set(FB_R_CTRL, 0);
+        let image = FramebufferConfig::new(&registers).capture(&vram);
+        assert_eq!(image.get(0, 0), 0xFF123456);
+    }
+}
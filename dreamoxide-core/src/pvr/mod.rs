@@ -0,0 +1,289 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+pub use Asic;
+
+use pvr::vram::{Vram, VRAM_MASK};
+use pvr::ta::{Ta, TaConfig};
+use pvr::renderer::Renderer;
+use pvr::framebuffer::FramebufferConfig;
+use pvr::yuv::YuvConverter;
+use image::Image;
+use asic::Interrupt;
+
+use std::sync::{Arc, Mutex};
+
+pub mod vram;
+pub mod ta_parameter;
+pub mod ta;
+pub mod renderer;
+pub mod texture;
+pub mod framebuffer;
+pub mod yuv;
+pub mod dma;
+
+pub const PVR_REGISTER_BASE : usize = 0x005F8000;
+pub const PVR_REGISTER_END  : usize = 0x005F9FFF;
+
+pub const ID               : usize = 0x005F8000;
+pub const REVISION         : usize = 0x005F8004;
+pub const SOFTRESET        : usize = 0x005F8008;
+pub const STARTRENDER      : usize = 0x005F8014;
+pub const PARAM_BASE       : usize = 0x005F8020;
+pub const REGION_BASE      : usize = 0x005F802C;
+pub const SPAN_SORT_CFG    : usize = 0x005F8030;
+pub const VO_BORDER_COL    : usize = 0x005F8040;
+pub const FB_R_CTRL        : usize = 0x005F8044;
+pub const FB_W_CTRL        : usize = 0x005F8048;
+pub const FB_W_LINESTRIDE  : usize = 0x005F804C;
+pub const FB_R_SOF1        : usize = 0x005F8050;
+pub const FB_R_SOF2        : usize = 0x005F8054;
+pub const FB_R_SIZE        : usize = 0x005F805C;
+pub const FB_W_SOF1        : usize = 0x005F8060;
+pub const FB_W_SOF2        : usize = 0x005F8064;
+pub const FB_X_CLIP        : usize = 0x005F8068;
+pub const FB_Y_CLIP        : usize = 0x005F806C;
+pub const FPU_SHAD_SCALE   : usize = 0x005F8074;
+pub const FPU_CULL_VAL     : usize = 0x005F8078;
+pub const FPU_PARAM_CFG    : usize = 0x005F807C;
+pub const HALF_OFFSET      : usize = 0x005F8080;
+pub const FPU_PERP_VAL     : usize = 0x005F8084;
+pub const ISP_BACKGND_D    : usize = 0x005F8088;
+pub const ISP_BACKGND_T    : usize = 0x005F808C;
+pub const ISP_FEED_CFG     : usize = 0x005F8098;
+pub const FOG_COL_RAM      : usize = 0x005F80B0;
+pub const FOG_COL_VERT     : usize = 0x005F80B4;
+pub const FOG_DENSITY      : usize = 0x005F80B8;
+pub const FOG_CLAMP_MAX    : usize = 0x005F80BC;
+pub const FOG_CLAMP_MIN    : usize = 0x005F80C0;
+pub const SPG_TRIGGER_POS  : usize = 0x005F80C4;
+pub const SPG_CONTROL      : usize = 0x005F80D0;
+pub const TEXT_CONTROL     : usize = 0x005F80E4;
+pub const VO_CONTROL       : usize = 0x005F80E8;
+pub const SCALER_CTL       : usize = 0x005F80F4;
+pub const PAL_RAM_CTRL     : usize = 0x005F8108;
+pub const SPG_STATUS       : usize = 0x005F810C;
+pub const PT_ALPHA_REF     : usize = 0x005F811C;
+pub const TA_OL_BASE       : usize = 0x005F8124;
+pub const TA_ISP_BASE      : usize = 0x005F8128;
+pub const TA_OL_LIMIT      : usize = 0x005F812C;
+pub const TA_ISP_LIMIT     : usize = 0x005F8130;
+pub const TA_NEXT_OPB      : usize = 0x005F8134;
+pub const TA_ITP_CURRENT   : usize = 0x005F8138;
+pub const TA_GLOB_TILE_CLIP: usize = 0x005F813C;
+pub const TA_ALLOC_CTRL    : usize = 0x005F8140;
+pub const TA_LIST_INIT     : usize = 0x005F8144;
+pub const TA_YUV_TEX_BASE  : usize = 0x005F8148;
+pub const TA_YUV_TEX_CTRL  : usize = 0x005F814C;
+pub const TA_YUV_TEX_CNT   : usize = 0x005F8150;
+pub const TA_LIST_CONT     : usize = 0x005F8160;
+pub const TA_NEXT_OPB_INIT : usize = 0x005F8164;
+pub const FOG_TABLE        : usize = 0x005F8200;
+pub const PALETTE_RAM      : usize = 0x005F9000;
+
+/// Polygon path of the TA FIFO
+pub const TA_FIFO_BASE : usize = 0x10000000;
+pub const TA_FIFO_END  : usize = 0x107FFFFF;
+
+/// YUV converter path of the TA FIFO
+pub const YUV_FIFO_BASE : usize = 0x10800000;
+pub const YUV_FIFO_END  : usize = 0x10FFFFFF;
+
+/// Direct texture path of the TA FIFO, writing to video memory
+pub const TEXTURE_FIFO_BASE : usize = 0x11000000;
+pub const TEXTURE_FIFO_END  : usize = 0x11FFFFFF;
+
+/// The core register block, including fog table and palette RAM
+pub struct PvrRegisters {
+    pub values: Box<[u32]>,
+}
+
+impl PvrRegisters {
+    pub fn new() -> PvrRegisters {
+        let mut registers = PvrRegisters {
+            values: vec![0u32; (PVR_REGISTER_END + 1 - PVR_REGISTER_BASE) / 4].into_boxed_slice(),
+        };
+        registers.set(ID, 0x17FD11DB);
+        registers.set(REVISION, 0x00000011);
+        registers
+    }
+
+    /// Reads the register at the given address
+    #[inline(always)]
+    pub fn get(&self, address: usize) -> u32 {
+        self.values[(address - PVR_REGISTER_BASE) >> 2]
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, address: usize, value: u32) {
+        self.values[(address - PVR_REGISTER_BASE) >> 2] = value;
+    }
+}
+
+impl Default for PvrRegisters {
+    fn default() -> PvrRegisters {
+        PvrRegisters::new()
+    }
+}
+
+/// The PowerVR2 graphics core: its registers, the tile accelerator
+/// and the video memory both are working on.
+pub struct Pvr {
+    pub registers: PvrRegisters,
+    pub vram: Vram,
+    pub ta: Ta,
+    pub renderer: Renderer,
+    pub yuv: YuvConverter,
+    pub asic: Arc<Mutex<Asic>>,
+}
+
+impl Pvr {
+    /// Creates the graphics core and registers its registers, video
+    /// memory and TA FIFO with the memory controller
+    pub fn new(mem: &mut Memory, asic: Arc<Mutex<Asic>>) -> Arc<Mutex<Pvr>> {
+        let pvr = Arc::new(Mutex::new(Pvr {
+            registers: PvrRegisters::new(),
+            vram: Vram::new(),
+            ta: Ta::new(),
+            renderer: Renderer::new(),
+            yuv: YuvConverter::new(),
+            asic,
+        }));
+
+        mem.register_mapped_device(MemoryRange(PVR_REGISTER_BASE, PVR_REGISTER_END), pvr.clone());
+        // 64-bit and 32-bit access paths to video memory
+        mem.register_mapped_device(MemoryRange(0x04000000, 0x05FFFFFF), pvr.clone());
+        mem.register_mapped_device(MemoryRange(TA_FIFO_BASE, TEXTURE_FIFO_END), pvr.clone());
+
+        pvr
+    }
+
+    /// The TA state as configured by the registers
+    pub fn ta_config(&self) -> TaConfig {
+        TaConfig {
+            ol_base: self.registers.get(TA_OL_BASE) & 0x00FFFFE0,
+            ol_limit: self.registers.get(TA_OL_LIMIT) & 0x00FFFFE0,
+            isp_base: self.registers.get(TA_ISP_BASE) & 0x00FFFFFC,
+            isp_limit: self.registers.get(TA_ISP_LIMIT) & 0x00FFFFFC,
+            alloc_ctrl: self.registers.get(TA_ALLOC_CTRL),
+            glob_tile_clip: self.registers.get(TA_GLOB_TILE_CLIP),
+            next_opb_init: self.registers.get(TA_NEXT_OPB_INIT) & 0x00FFFFE0,
+        }
+    }
+
+    /// Feeds a word into the polygon path of the TA
+    pub fn write_ta_fifo(&mut self, value: u32) {
+        let mut asic = self.asic.lock().unwrap();
+        self.ta.write(value, &mut self.vram, &mut asic);
+    }
+
+    /// Feeds data into the YUV converter, raising the interrupt once a
+    /// texture is complete
+    pub fn write_yuv_fifo(&mut self, value: u32, size: usize) {
+        if self.yuv.write(value, size, &self.registers, &mut self.vram) {
+            self.asic.lock().unwrap().raise(Interrupt::YuvDone);
+        }
+    }
+
+    /// Renders the region array into the framebuffer, as triggered by STARTRENDER
+    pub fn start_render(&mut self) {
+        self.renderer.render(&self.registers, &mut self.vram);
+
+        let mut asic = self.asic.lock().unwrap();
+        asic.raise(Interrupt::RenderDoneIsp);
+        asic.raise(Interrupt::RenderDoneTsp);
+        asic.raise(Interrupt::RenderDoneVideo);
+    }
+
+    /// The display read-out settings
+    pub fn framebuffer_config(&self) -> FramebufferConfig {
+        FramebufferConfig::new(&self.registers)
+    }
+
+    /// Captures what is currently displayed
+    pub fn capture_frame(&self) -> Image {
+        self.framebuffer_config().capture(&self.vram)
+    }
+
+    /// Hash of the displayed frame, to check output without looking at it
+    pub fn frame_hash(&self) -> u64 {
+        self.capture_frame().hash()
+    }
+
+    fn read_register(&self, address: usize) -> u32 {
+        match address {
+            TA_NEXT_OPB    => self.ta.next_opb,
+            TA_ITP_CURRENT => self.ta.config.isp_base.wrapping_add(self.ta.itp_current),
+            TA_LIST_INIT   => 0,
+            TA_YUV_TEX_CNT => self.yuv.count,
+            _              => self.registers.get(address & !3)
+        }
+    }
+
+    fn write_register(&mut self, address: usize, value: u32) {
+        match address {
+            ID | REVISION => (),
+            SOFTRESET => {
+                // Bit 0 resets the TA
+                if value & 1 != 0 {
+                    self.ta = Ta::new();
+                }
+                self.registers.set(address, value & 0x7);
+            },
+            STARTRENDER => self.start_render(),
+            PAL_RAM_CTRL | PALETTE_RAM ..= PVR_REGISTER_END => {
+                self.registers.set(address & !3, value);
+                self.renderer.textures.invalidate_palette();
+            },
+            TA_LIST_INIT => {
+                if value & 0x80000000 != 0 {
+                    let config = self.ta_config();
+                    self.ta.list_init(config);
+                }
+            },
+            TA_YUV_TEX_BASE | TA_YUV_TEX_CTRL => {
+                self.registers.set(address, value);
+                self.yuv.reset();
+            },
+            TA_YUV_TEX_CNT => (),
+            TA_LIST_CONT => {
+                if value & 0x80000000 != 0 {
+                    self.ta.list_continue();
+                }
+            },
+            _ => self.registers.set(address & !3, value)
+        }
+    }
+}
+
+impl MappedDevice for Pvr {
+    fn read(&mut self, address: usize, size: usize) -> u32 {
+        match address {
+            PVR_REGISTER_BASE ..= PVR_REGISTER_END => self.read_register(address),
+            0x04000000 ..= 0x04FFFFFF => self.vram.read(address & VRAM_MASK, size),
+            0x05000000 ..= 0x05FFFFFF => match size {
+                1 => self.vram.read_u8(Vram::map_32bit(address & VRAM_MASK)) as u32,
+                2 => self.vram.read_32bit_u16(address) as u32,
+                _ => self.vram.read_32bit_u32(address)
+            },
+            // The FIFO paths are write only
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, size: usize) {
+        match address {
+            PVR_REGISTER_BASE ..= PVR_REGISTER_END => self.write_register(address, value),
+            0x04000000 ..= 0x04FFFFFF => self.vram.write(address & VRAM_MASK, value, size),
+            0x05000000 ..= 0x05FFFFFF => match size {
+                1 => self.vram.write_u8(Vram::map_32bit(address & VRAM_MASK), value as u8),
+                2 => self.vram.write_32bit_u16(address, value as u16),
+                _ => self.vram.write_32bit_u32(address, value)
+            },
+            TA_FIFO_BASE ..= TA_FIFO_END => self.write_ta_fifo(value),
+            YUV_FIFO_BASE ..= YUV_FIFO_END => self.write_yuv_fifo(value, size),
+            TEXTURE_FIFO_BASE ..= TEXTURE_FIFO_END => self.vram.write(address & VRAM_MASK, value, size),
+            _ => ()
+        }
+    }
+}
@@ -0,0 +1,1097 @@
+use pvr::*;
+use pvr::vram::Vram;
+use pvr::ta::TILE_SIZE;
+use pvr::texture::{Texture, TextureCache, TextureDescriptor, PixelFormat};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+const TILE_PIXELS : usize = (TILE_SIZE * TILE_SIZE) as usize;
+
+/// Upper bound of region array entries, to survive corrupt arrays
+const MAX_REGIONS : usize = 0x4000;
+
+/// Upper bound of object list words walked per list, to survive link loops
+const MAX_LIST_WORDS : usize = 0x10000;
+
+/// One entry of the region array, describing what to render into a tile
+#[derive(Copy, Clone, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub last: bool,
+    pub z_keep: bool,
+    pub presort: bool,
+    pub no_writeout: bool,
+    /// Object list addresses in the order opaque, opaque modifier volume,
+    /// translucent, translucent modifier volume and punch through
+    pub lists: [Option<u32>; 5],
+}
+
+/// An attribute that varies linearly over the screen
+#[derive(Copy, Clone, Debug)]
+struct Plane {
+    a: f32,
+    b: f32,
+    c: f32,
+}
+
+impl Plane {
+    /// Builds the plane through three (x, y, value) points
+    fn new(p: [[f32; 3]; 3]) -> Plane {
+        let (x1, y1) = (p[1][0] - p[0][0], p[1][1] - p[0][1]);
+        let (x2, y2) = (p[2][0] - p[0][0], p[2][1] - p[0][1]);
+        let (v1, v2) = (p[1][2] - p[0][2], p[2][2] - p[0][2]);
+        let det = x1 * y2 - x2 * y1;
+
+        if det == 0.0 {
+            return Plane::constant(p[0][2]);
+        }
+
+        let a = (v1 * y2 - v2 * y1) / det;
+        let b = (v2 * x1 - v1 * x2) / det;
+        Plane { a, b, c: p[0][2] - a * p[0][0] - b * p[0][1] }
+    }
+
+    fn constant(value: f32) -> Plane {
+        Plane { a: 0.0, b: 0.0, c: value }
+    }
+
+    #[inline(always)]
+    fn at(&self, x: f32, y: f32) -> f32 {
+        self.a * x + self.b * y + self.c
+    }
+}
+
+/// A vertex as stored in the ISP/TSP parameters
+#[derive(Copy, Clone, Debug)]
+struct RawVertex {
+    x: f32,
+    y: f32,
+    z: f32,
+    u: [f32; 2],
+    v: [f32; 2],
+    base: [u32; 2],
+    offset: [u32; 2],
+}
+
+/// Shading state of one volume of a polygon. All varying attributes are
+/// premultiplied with the depth to interpolate them perspective correct.
+#[derive(Copy, Clone, Debug)]
+struct Shading {
+    tsp: u32,
+    tcw: u32,
+    /// Index into the textures bound for the current render
+    texture: Option<usize>,
+    u: Plane,
+    v: Plane,
+    base: [Plane; 4],
+    offset: [Plane; 4],
+}
+
+/// A triangle after setup, ready to be tested against pixels
+#[derive(Copy, Clone, Debug)]
+struct Primitive {
+    isp: u32,
+    z: Plane,
+    /// Edge functions, a pixel is inside if all are positive
+    edges: [(f32, f32, f32, bool); 3],
+    /// The background plane covers the whole tile
+    covers_all: bool,
+    perspective: bool,
+    two_volume: bool,
+    volumes: [Shading; 2],
+}
+
+impl Primitive {
+    #[inline(always)]
+    fn covers(&self, x: f32, y: f32) -> bool {
+        if self.covers_all {
+            return true;
+        }
+
+        self.edges.iter().all(|&(a, b, c, tie)| {
+            let w = a * x + b * y + c;
+            w > 0.0 || (w == 0.0 && tie)
+        })
+    }
+
+    fn is_textured(&self) -> bool { self.isp & (1 << 25) != 0 }
+    fn has_offset(&self) -> bool { self.isp & (1 << 24) != 0 }
+    fn depth_mode(&self) -> u32 { self.isp >> 29 }
+    fn z_write_disabled(&self) -> bool { self.isp & (1 << 26) != 0 }
+}
+
+/// A triangle of a modifier volume
+#[derive(Copy, Clone, Debug)]
+struct VolumeTriangle {
+    instruction: u32,
+    z: Plane,
+    edges: [(f32, f32, f32, bool); 3],
+}
+
+/// Compares a new depth against the buffered one. Depths are 1/w,
+/// so larger values are closer to the viewer.
+#[inline(always)]
+fn depth_test(mode: u32, new: f32, old: f32) -> bool {
+    match mode {
+        0 => false,
+        1 => new < old,
+        2 => new == old,
+        3 => new <= old,
+        4 => new > old,
+        5 => new != old,
+        6 => new >= old,
+        _ => true
+    }
+}
+
+/// Edge functions of a triangle with positive area and a tie breaker,
+/// so pixels on an edge shared by two triangles are drawn only once
+fn edges(v: [[f32; 2]; 3]) -> [(f32, f32, f32, bool); 3] {
+    let mut e = [(0.0, 0.0, 0.0, false); 3];
+    for i in 0..3 {
+        let (p, q) = (v[i], v[(i + 1) % 3]);
+        let (dx, dy) = (q[0] - p[0], q[1] - p[1]);
+        // w(x, y) = dx * (y - py) - dy * (x - px)
+        e[i] = (-dy, dx, dy * p[0] - dx * p[1], dy > 0.0 || (dy == 0.0 && dx < 0.0));
+    }
+    e
+}
+
+#[inline(always)]
+fn unpack(argb: u32) -> [u32; 4] {
+    [argb >> 24, (argb >> 16) & 0xFF, (argb >> 8) & 0xFF, argb & 0xFF]
+}
+
+#[inline(always)]
+fn pack(c: [u32; 4]) -> u32 {
+    (cmp::min(c[0], 255) << 24) | (cmp::min(c[1], 255) << 16) | (cmp::min(c[2], 255) << 8) | cmp::min(c[3], 255)
+}
+
+/// Renders the display lists into the framebuffer. The renderer only works
+/// on the data structures in video memory, just like the hardware does.
+pub struct Renderer {
+    depth: Vec<f32>,
+    tags: Vec<usize>,
+    colors: Vec<u32>,
+    primitives: Vec<Primitive>,
+    volumes: Vec<VolumeTriangle>,
+    fragments: Vec<Vec<(f32, usize)>>,
+    pub textures: TextureCache,
+    bound: Vec<Arc<Texture>>,
+    bound_indices: HashMap<TextureDescriptor, usize>,
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            depth: vec![0.0; TILE_PIXELS],
+            tags: vec![0; TILE_PIXELS],
+            colors: vec![0; TILE_PIXELS],
+            primitives: Vec::new(),
+            volumes: Vec::new(),
+            fragments: (0..TILE_PIXELS).map(|_| Vec::new()).collect(),
+            textures: TextureCache::new(),
+            bound: Vec::new(),
+            bound_indices: HashMap::new(),
+        }
+    }
+
+    /// Reads the region array starting at REGION_BASE
+    pub fn regions(registers: &PvrRegisters, vram: &Vram) -> Vec<Region> {
+        let mut address = (registers.get(REGION_BASE) & 0x00FFFFFC) as usize;
+        let with_punch_through = registers.get(FPU_PARAM_CFG) & (1 << 21) != 0;
+        let mut regions = Vec::new();
+
+        while regions.len() < MAX_REGIONS {
+            let control = vram.read_32bit_u32(address);
+            let mut lists = [None; 5];
+            let count = if with_punch_through { 5 } else { 4 };
+
+            for (i, list) in lists.iter_mut().enumerate().take(count) {
+                let pointer = vram.read_32bit_u32(address + 4 + i * 4);
+                if pointer & 0x80000000 == 0 {
+                    *list = Some(pointer & 0x00FFFFFC);
+                }
+            }
+
+            let region = Region {
+                x: (control >> 2) & 0x3F,
+                y: (control >> 8) & 0x3F,
+                last: control & (1 << 31) != 0,
+                z_keep: control & (1 << 30) != 0,
+                presort: control & (1 << 29) != 0,
+                no_writeout: control & (1 << 28) != 0,
+                lists,
+            };
+            regions.push(region);
+
+            if region.last {
+                break;
+            }
+            address += (count + 1) * 4;
+        }
+
+        regions
+    }
+
+    /// Renders all regions as triggered by STARTRENDER
+    pub fn render(&mut self, registers: &PvrRegisters, vram: &mut Vram) {
+        // Textures may have been uploaded or rendered to since the last frame
+        self.textures.sync(vram);
+        self.bound.clear();
+        self.bound_indices.clear();
+
+        for region in Renderer::regions(registers, vram).iter() {
+            self.render_tile(registers, vram, region);
+        }
+    }
+
+    fn render_tile(&mut self, registers: &PvrRegisters, vram: &mut Vram, region: &Region) {
+        let param_base = registers.get(PARAM_BASE) & 0x00F00000;
+        let ox = (region.x * TILE_SIZE) as f32;
+        let oy = (region.y * TILE_SIZE) as f32;
+
+        self.primitives.clear();
+
+        if !region.z_keep {
+            let background = self.background(registers, vram, param_base);
+            self.primitives.push(background);
+            let depth = f32::from_bits(registers.get(ISP_BACKGND_D));
+            for i in 0..TILE_PIXELS {
+                self.depth[i] = depth;
+                self.tags[i] = 0;
+            }
+        } else {
+            // Keep the colors and depths of the previous pass over this tile
+            for i in 0..TILE_PIXELS {
+                self.tags[i] = usize::MAX;
+            }
+        }
+
+        // Opaque and punch through polygons determine visibility first
+        // and are shaded afterwards, once modifier volumes are known
+        if let Some(list) = region.lists[0] {
+            let first = self.primitives.len();
+            self.read_list(vram, list, param_base, registers, false);
+            for index in first..self.primitives.len() {
+                self.rasterize_opaque(index, ox, oy, None, registers);
+            }
+        }
+
+        if let Some(list) = region.lists[4] {
+            let first = self.primitives.len();
+            self.read_list(vram, list, param_base, registers, false);
+            let reference = registers.get(PT_ALPHA_REF) & 0xFF;
+            for index in first..self.primitives.len() {
+                self.rasterize_opaque(index, ox, oy, Some(reference), registers);
+            }
+        }
+
+        self.volumes.clear();
+        if let Some(list) = region.lists[1] {
+            self.read_list(vram, list, param_base, registers, true);
+        }
+
+        for i in 0..TILE_PIXELS {
+            let (x, y) = (ox + (i as u32 % TILE_SIZE) as f32 + 0.5, oy + (i as u32 / TILE_SIZE) as f32 + 0.5);
+            if self.tags[i] < self.primitives.len() {
+                let shadowed = self.in_volume(x, y, self.depth[i]);
+                let prim = self.primitives[self.tags[i]];
+                self.colors[i] = self.shade(&prim, shadowed, x, y, registers);
+            }
+        }
+
+        if let Some(list) = region.lists[2] {
+            let first = self.primitives.len();
+            self.read_list(vram, list, param_base, registers, false);
+
+            self.volumes.clear();
+            if let Some(volumes) = region.lists[3] {
+                self.read_list(vram, volumes, param_base, registers, true);
+            }
+
+            let presort = region.presort || registers.get(ISP_FEED_CFG) & 1 != 0;
+            if presort {
+                for index in first..self.primitives.len() {
+                    self.rasterize_translucent(index, ox, oy, registers);
+                }
+            } else {
+                self.autosort(first, ox, oy, registers);
+            }
+        }
+
+        if !region.no_writeout {
+            self.write_out(registers, vram, region);
+        }
+    }
+
+    /// Sets up the background plane referenced by ISP_BACKGND_T
+    fn background(&mut self, registers: &PvrRegisters, vram: &Vram, param_base: u32) -> Primitive {
+        let tag = registers.get(ISP_BACKGND_T);
+        let address = param_base + ((tag >> 3) & 0x1FFFFF) * 4;
+        let skip = (tag >> 24) & 0x7;
+        let two_volume = tag & (1 << 27) != 0;
+
+        let (isp, tsp, tcw, mut a) = Renderer::read_header(vram, address, two_volume);
+        let mut v = [RawVertex { x: 0.0, y: 0.0, z: 0.0, u: [0.0; 2], v: [0.0; 2], base: [0; 2], offset: [0; 2] }; 3];
+        for vertex in v.iter_mut() {
+            let (read, next) = Renderer::read_vertex(vram, a, isp, two_volume, skip);
+            *vertex = read;
+            a = next;
+        }
+
+        let mut prim = Renderer::setup(isp, tsp, tcw, two_volume, v);
+        prim.covers_all = true;
+        self.bind_textures(&mut prim, registers, vram);
+        prim
+    }
+
+    fn read_header(vram: &Vram, address: u32, two_volume: bool) -> (u32, [u32; 2], [u32; 2], u32) {
+        let a = address as usize;
+        let isp = vram.read_32bit_u32(a);
+        let tsp = [vram.read_32bit_u32(a + 4), if two_volume { vram.read_32bit_u32(a + 12) } else { 0 }];
+        let tcw = [vram.read_32bit_u32(a + 8), if two_volume { vram.read_32bit_u32(a + 16) } else { 0 }];
+
+        (isp, tsp, tcw, address + if two_volume { 20 } else { 12 })
+    }
+
+    fn read_vertex(vram: &Vram, address: u32, isp: u32, two_volume: bool, skip: u32) -> (RawVertex, u32) {
+        let textured = isp & (1 << 25) != 0;
+        let offset = textured && isp & (1 << 24) != 0;
+        let uv16 = isp & (1 << 22) != 0;
+        let f = |a: u32| vram.read_32bit_f32(a as usize);
+        let w = |a: u32| vram.read_32bit_u32(a as usize);
+
+        let mut vertex = RawVertex {
+            x: f(address),
+            y: f(address + 4),
+            z: f(address + 8),
+            u: [0.0; 2],
+            v: [0.0; 2],
+            base: [0; 2],
+            offset: [0; 2],
+        };
+
+        let mut a = address + 12;
+        for vol in 0..(if two_volume { 2 } else { 1 }) {
+            let start = a;
+            if textured {
+                if uv16 {
+                    let uv = w(a);
+                    vertex.u[vol] = f32::from_bits(uv & 0xFFFF0000);
+                    vertex.v[vol] = f32::from_bits(uv << 16);
+                    a += 4;
+                } else {
+                    vertex.u[vol] = f(a);
+                    vertex.v[vol] = f(a + 4);
+                    a += 8;
+                }
+            }
+            vertex.base[vol] = w(a);
+            a += 4;
+            if offset {
+                vertex.offset[vol] = w(a);
+            }
+            a = start + skip * 4;
+        }
+
+        (vertex, a)
+    }
+
+    /// Computes edge functions and attribute planes of a triangle
+    fn setup(isp: u32, tsp: [u32; 2], tcw: [u32; 2], two_volume: bool, v: [RawVertex; 3]) -> Primitive {
+        let gouraud = isp & (1 << 23) != 0;
+        // Without usable depths the attributes are interpolated affinely
+        let perspective = v.iter().all(|r| r.z > 0.0) && !(v[0].z == v[1].z && v[1].z == v[2].z);
+        let weight = |r: &RawVertex| if perspective { r.z } else { 1.0 };
+        let plane = |f: &dyn Fn(&RawVertex) -> f32| {
+            Plane::new([[v[0].x, v[0].y, f(&v[0]) * weight(&v[0])],
+                        [v[1].x, v[1].y, f(&v[1]) * weight(&v[1])],
+                        [v[2].x, v[2].y, f(&v[2]) * weight(&v[2])]])
+        };
+        let z = Plane::new([[v[0].x, v[0].y, v[0].z], [v[1].x, v[1].y, v[1].z], [v[2].x, v[2].y, v[2].z]]);
+
+        let mut volumes = [Shading {
+            tsp: 0,
+            tcw: 0,
+            texture: None,
+            u: Plane::constant(0.0),
+            v: Plane::constant(0.0),
+            base: [Plane::constant(0.0); 4],
+            offset: [Plane::constant(0.0); 4],
+        }; 2];
+
+        for vol in 0..(if two_volume { 2 } else { 1 }) {
+            let s = &mut volumes[vol];
+            s.tsp = tsp[vol];
+            s.tcw = tcw[vol];
+            s.u = plane(&|r: &RawVertex| r.u[vol]);
+            s.v = plane(&|r: &RawVertex| r.v[vol]);
+
+            for c in 0..4 {
+                let shift = 24 - c * 8;
+                if gouraud {
+                    s.base[c] = plane(&|r: &RawVertex| ((r.base[vol] >> shift) & 0xFF) as f32);
+                    s.offset[c] = plane(&|r: &RawVertex| ((r.offset[vol] >> shift) & 0xFF) as f32);
+                } else {
+                    // Flat shading takes the color of the last vertex
+                    s.base[c] = plane(&|_: &RawVertex| ((v[2].base[vol] >> shift) & 0xFF) as f32);
+                    s.offset[c] = plane(&|_: &RawVertex| ((v[2].offset[vol] >> shift) & 0xFF) as f32);
+                }
+            }
+        }
+
+        let area = (v[1].x - v[0].x) * (v[2].y - v[0].y) - (v[2].x - v[0].x) * (v[1].y - v[0].y);
+        let points = if area >= 0.0 {
+            [[v[0].x, v[0].y], [v[1].x, v[1].y], [v[2].x, v[2].y]]
+        } else {
+            [[v[0].x, v[0].y], [v[2].x, v[2].y], [v[1].x, v[1].y]]
+        };
+
+        Primitive {
+            isp,
+            z,
+            edges: edges(points),
+            covers_all: false,
+            perspective,
+            two_volume,
+            volumes,
+        }
+    }
+
+    /// Checks the culling mode of the ISP word against the signed area
+    fn is_culled(isp: u32, area: f32, registers: &PvrRegisters) -> bool {
+        let cull = f32::from_bits(registers.get(FPU_CULL_VAL));
+        match (isp >> 27) & 0x3 {
+            0 => false,
+            1 => area.abs() < cull,
+            2 => area.abs() < cull || area < 0.0,
+            _ => area.abs() < cull || area > 0.0
+        }
+    }
+
+    /// Walks an object list, setting up its triangles as primitives
+    /// or, for modifier volume lists, as volume triangles
+    fn read_list(&mut self, vram: &Vram, list: u32, param_base: u32, registers: &PvrRegisters, volume: bool) {
+        let mut address = list;
+        let mut words = 0;
+
+        while words < MAX_LIST_WORDS {
+            let entry = vram.read_32bit_u32(address as usize);
+            words += 1;
+
+            if entry & 0x80000000 == 0 {
+                // Triangle strip, with a mask of triangles touching this tile
+                let param = param_base + (entry & 0x1FFFFF) * 4;
+                let skip = (entry >> 21) & 0x7;
+                let two_volume = entry & (1 << 24) != 0;
+                let mask = (entry >> 25) & 0x3F;
+                self.read_strip(vram, param, skip, two_volume, mask, registers);
+                address += 4;
+                continue;
+            }
+
+            match entry >> 29 {
+                0x4 | 0x5 => {
+                    let param = param_base + (entry & 0x1FFFFF) * 4;
+                    if volume {
+                        self.read_volumes(vram, param, ((entry >> 25) & 0xF) + 1);
+                    } else {
+                        self.read_array(vram, param, entry, registers);
+                    }
+                    address += 4;
+                },
+                0x7 => {
+                    if entry & (1 << 28) != 0 {
+                        break;
+                    }
+                    address = entry & 0x00FFFFFC;
+                },
+                _ => break
+            }
+        }
+    }
+
+    fn read_strip(&mut self, vram: &Vram, param: u32, skip: u32, two_volume: bool, mask: u32, registers: &PvrRegisters) {
+        let (isp, tsp, tcw, mut a) = Renderer::read_header(vram, param, two_volume);
+        let count = (0..6).filter(|i| mask & (0x20 >> i) != 0).max().map_or(0, |i| i + 3);
+        let mut v = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (vertex, next) = Renderer::read_vertex(vram, a, isp, two_volume, skip);
+            v.push(vertex);
+            a = next;
+        }
+
+        for i in 0..6 {
+            if mask & (0x20 >> i) == 0 {
+                continue;
+            }
+
+            // Every other triangle of a strip has reversed winding
+            let tri = if i % 2 == 0 { [v[i], v[i + 1], v[i + 2]] } else { [v[i + 1], v[i], v[i + 2]] };
+            self.add_triangle((isp, tsp, tcw), two_volume, tri, registers, vram);
+        }
+    }
+
+    /// Reads the triangles or quads of a triangle or quad array entry
+    fn read_array(&mut self, vram: &Vram, param: u32, entry: u32, registers: &PvrRegisters) {
+        let quads = entry >> 29 == 0x5;
+        let skip = (entry >> 21) & 0x7;
+        let two_volume = entry & (1 << 24) != 0;
+        let count = ((entry >> 25) & 0xF) + 1;
+        let mut address = param;
+
+        for _ in 0..count {
+            let (isp, tsp, tcw, mut a) = Renderer::read_header(vram, address, two_volume);
+            let n = if quads { 4 } else { 3 };
+            let mut v = Vec::with_capacity(n);
+            for _ in 0..n {
+                let (vertex, next) = Renderer::read_vertex(vram, a, isp, two_volume, skip);
+                v.push(vertex);
+                a = next;
+            }
+
+            self.add_triangle((isp, tsp, tcw), two_volume, [v[0], v[1], v[2]], registers, vram);
+            if quads {
+                self.add_triangle((isp, tsp, tcw), two_volume, [v[0], v[2], v[3]], registers, vram);
+            }
+            address = a;
+        }
+    }
+
+    fn read_volumes(&mut self, vram: &Vram, param: u32, count: u32) {
+        let mut address = param as usize;
+
+        for _ in 0..count {
+            let isp = vram.read_32bit_u32(address);
+            let f = |i: usize| vram.read_32bit_f32(address + 4 + i * 4);
+            let p = [[f(0), f(1), f(2)], [f(3), f(4), f(5)], [f(6), f(7), f(8)]];
+            let area = (p[1][0] - p[0][0]) * (p[2][1] - p[0][1]) - (p[2][0] - p[0][0]) * (p[1][1] - p[0][1]);
+            let points = if area >= 0.0 {
+                [[p[0][0], p[0][1]], [p[1][0], p[1][1]], [p[2][0], p[2][1]]]
+            } else {
+                [[p[0][0], p[0][1]], [p[2][0], p[2][1]], [p[1][0], p[1][1]]]
+            };
+
+            self.volumes.push(VolumeTriangle {
+                instruction: isp >> 29,
+                z: Plane::new(p),
+                edges: edges(points),
+            });
+            address += 40;
+        }
+    }
+
+    /// Culls and sets up a triangle, the header holds its ISP, TSP and
+    /// texture control words as read by `read_header`
+    fn add_triangle(&mut self, header: (u32, [u32; 2], [u32; 2]), two_volume: bool, v: [RawVertex; 3], registers: &PvrRegisters, vram: &Vram) {
+        let (isp, tsp, tcw) = header;
+        let area = (v[1].x - v[0].x) * (v[2].y - v[0].y) - (v[2].x - v[0].x) * (v[1].y - v[0].y);
+        if area == 0.0 || Renderer::is_culled(isp, area, registers) {
+            return;
+        }
+
+        let mut prim = Renderer::setup(isp, tsp, tcw, two_volume, v);
+        self.bind_textures(&mut prim, registers, vram);
+        self.primitives.push(prim);
+    }
+
+    /// Looks up the textures of a primitive in the cache, so shading
+    /// only has to index the textures bound for this render
+    fn bind_textures(&mut self, prim: &mut Primitive, registers: &PvrRegisters, vram: &Vram) {
+        if !prim.is_textured() {
+            return;
+        }
+
+        for vol in 0..(if prim.two_volume { 2 } else { 1 }) {
+            let s = &mut prim.volumes[vol];
+            let desc = TextureDescriptor::new(s.tsp, s.tcw, registers.get(TEXT_CONTROL));
+
+            let index = match self.bound_indices.get(&desc) {
+                Some(&index) => index,
+                None => {
+                    self.bound.push(self.textures.get(desc, vram, registers));
+                    self.bound_indices.insert(desc, self.bound.len() - 1);
+                    self.bound.len() - 1
+                }
+            };
+            s.texture = Some(index);
+        }
+    }
+
+    /// Checks if a pixel at the given depth lies within a modifier volume
+    fn in_volume(&self, x: f32, y: f32, depth: f32) -> bool {
+        let mut inside = false;
+        let mut parity = false;
+
+        for tri in self.volumes.iter() {
+            let covered = tri.edges.iter().all(|&(a, b, c, tie)| {
+                let w = a * x + b * y + c;
+                w > 0.0 || (w == 0.0 && tie)
+            });
+            if covered && tri.z.at(x, y) >= depth {
+                parity = !parity;
+            }
+
+            // The last triangle of a volume says how to combine it
+            match tri.instruction {
+                1 => { inside |= parity; parity = false; },
+                2 => { inside |= !parity; parity = false; },
+                _ => ()
+            }
+        }
+
+        inside
+    }
+
+    fn rasterize_opaque(&mut self, index: usize, ox: f32, oy: f32, alpha_ref: Option<u32>, registers: &PvrRegisters) {
+        let prim = self.primitives[index];
+
+        for i in 0..TILE_PIXELS {
+            let (x, y) = (ox + (i as u32 % TILE_SIZE) as f32 + 0.5, oy + (i as u32 / TILE_SIZE) as f32 + 0.5);
+            if !prim.covers(x, y) {
+                continue;
+            }
+
+            let z = prim.z.at(x, y);
+            if !depth_test(prim.depth_mode(), z, self.depth[i]) {
+                continue;
+            }
+
+            if let Some(reference) = alpha_ref {
+                if self.shade(&prim, false, x, y, registers) >> 24 < reference {
+                    continue;
+                }
+            }
+
+            if !prim.z_write_disabled() {
+                self.depth[i] = z;
+            }
+            self.tags[i] = index;
+        }
+    }
+
+    fn rasterize_translucent(&mut self, index: usize, ox: f32, oy: f32, registers: &PvrRegisters) {
+        let prim = self.primitives[index];
+
+        for i in 0..TILE_PIXELS {
+            let (x, y) = (ox + (i as u32 % TILE_SIZE) as f32 + 0.5, oy + (i as u32 / TILE_SIZE) as f32 + 0.5);
+            if !prim.covers(x, y) {
+                continue;
+            }
+
+            let z = prim.z.at(x, y);
+            if !depth_test(prim.depth_mode(), z, self.depth[i]) {
+                continue;
+            }
+
+            if !prim.z_write_disabled() {
+                self.depth[i] = z;
+            }
+
+            let shadowed = self.in_volume(x, y, z);
+            let src = self.shade(&prim, shadowed, x, y, registers);
+            self.colors[i] = Renderer::blend(prim.volumes[if shadowed && prim.two_volume { 1 } else { 0 }].tsp, src, self.colors[i]);
+        }
+    }
+
+    /// Sorts translucent fragments per pixel from back to front before blending
+    fn autosort(&mut self, first: usize, ox: f32, oy: f32, registers: &PvrRegisters) {
+        for index in first..self.primitives.len() {
+            let prim = self.primitives[index];
+            for i in 0..TILE_PIXELS {
+                let (x, y) = (ox + (i as u32 % TILE_SIZE) as f32 + 0.5, oy + (i as u32 / TILE_SIZE) as f32 + 0.5);
+                if prim.covers(x, y) {
+                    let z = prim.z.at(x, y);
+                    if z >= self.depth[i] {
+                        self.fragments[i].push((z, index));
+                    }
+                }
+            }
+        }
+
+        for i in 0..TILE_PIXELS {
+            if self.fragments[i].is_empty() {
+                continue;
+            }
+
+            let (x, y) = (ox + (i as u32 % TILE_SIZE) as f32 + 0.5, oy + (i as u32 / TILE_SIZE) as f32 + 0.5);
+            let mut fragments = ::std::mem::take(&mut self.fragments[i]);
+            // A stable sort keeps submission order for equal depths
+            fragments.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+
+            for &(z, index) in fragments.iter() {
+                let prim = self.primitives[index];
+                let shadowed = self.in_volume(x, y, z);
+                let src = self.shade(&prim, shadowed, x, y, registers);
+                self.colors[i] = Renderer::blend(prim.volumes[if shadowed && prim.two_volume { 1 } else { 0 }].tsp, src, self.colors[i]);
+            }
+
+            fragments.clear();
+            self.fragments[i] = fragments;
+        }
+    }
+
+    /// Blends a source color over the accumulated one as set in the TSP word
+    fn blend(tsp: u32, src: u32, dst: u32) -> u32 {
+        let s = unpack(src);
+        let d = unpack(dst);
+
+        let factor = |instr: u32, other: [u32; 4]| -> [u32; 4] {
+            match instr {
+                0 => [0; 4],
+                1 => [255; 4],
+                2 => other,
+                3 => [255 - other[0], 255 - other[1], 255 - other[2], 255 - other[3]],
+                4 => [s[0]; 4],
+                5 => [255 - s[0]; 4],
+                6 => [d[0]; 4],
+                _ => [255 - d[0]; 4]
+            }
+        };
+
+        let sf = factor(tsp >> 29, d);
+        let df = factor((tsp >> 26) & 0x7, s);
+        let mut out = [0; 4];
+        for c in 0..4 {
+            out[c] = (s[c] * sf[c] + d[c] * df[c]) / 255;
+        }
+
+        pack(out)
+    }
+
+    /// Computes the final color of a pixel of a primitive
+    fn shade(&self, prim: &Primitive, shadowed: bool, x: f32, y: f32, registers: &PvrRegisters) -> u32 {
+        let vol = if shadowed && prim.two_volume { 1 } else { 0 };
+        let s = &prim.volumes[vol];
+        let w = if prim.perspective { 1.0 / prim.z.at(x, y) } else { 1.0 };
+        let channel = |p: &Plane| {
+            let c = p.at(x, y) * w;
+            if c >= 255.0 { 255 } else if c > 0.0 { c as u32 } else { 0 }
+        };
+
+        let mut base = [channel(&s.base[0]), channel(&s.base[1]), channel(&s.base[2]), channel(&s.base[3])];
+        let offset = [channel(&s.offset[0]), channel(&s.offset[1]), channel(&s.offset[2]), channel(&s.offset[3])];
+
+        if s.tsp & (1 << 20) == 0 {
+            base[0] = 255;
+        }
+
+        let mut color = base;
+        if let Some(index) = s.texture {
+            let texture = &self.bound[index];
+            let mut t = unpack(Renderer::sample(texture, prim, s, x, y));
+
+            if texture.desc.format() == PixelFormat::BumpMap {
+                t = Renderer::bump(t, offset);
+            }
+            if s.tsp & (1 << 19) != 0 {
+                t[0] = 255;
+            }
+
+            color = match (s.tsp >> 6) & 0x3 {
+                // Decal
+                0 => t,
+                // Modulate
+                1 => [t[0], t[1] * base[1] / 255, t[2] * base[2] / 255, t[3] * base[3] / 255],
+                // Decal alpha
+                2 => {
+                    let a = t[0];
+                    [base[0],
+                     (t[1] * a + base[1] * (255 - a)) / 255,
+                     (t[2] * a + base[2] * (255 - a)) / 255,
+                     (t[3] * a + base[3] * (255 - a)) / 255]
+                },
+                // Modulate alpha
+                _ => [t[0] * base[0] / 255, t[1] * base[1] / 255, t[2] * base[2] / 255, t[3] * base[3] / 255]
+            };
+
+            if prim.has_offset() && texture.desc.format() != PixelFormat::BumpMap {
+                for c in 1..4 {
+                    color[c] = cmp::min(color[c] + offset[c], 255);
+                }
+            }
+        }
+
+        // Polygons without a second volume are darkened instead
+        if shadowed && !prim.two_volume {
+            let shad = registers.get(FPU_SHAD_SCALE);
+            if shad & 0x100 != 0 {
+                let scale = shad & 0xFF;
+                for c in color.iter_mut().skip(1) {
+                    *c = *c * scale / 256;
+                }
+            }
+        }
+
+        match (s.tsp >> 22) & 0x3 {
+            0 => {
+                let fog = unpack(registers.get(FOG_COL_RAM));
+                let f = Renderer::fog_alpha(1.0 / prim.z.at(x, y), registers);
+                for c in 1..4 {
+                    color[c] = (fog[c] * f + color[c] * (255 - f)) / 255;
+                }
+            },
+            1 => {
+                let fog = unpack(registers.get(FOG_COL_VERT));
+                let f = offset[0];
+                for c in 1..4 {
+                    color[c] = (fog[c] * f + color[c] * (255 - f)) / 255;
+                }
+            },
+            3 => {
+                let fog = unpack(registers.get(FOG_COL_RAM));
+                color = [Renderer::fog_alpha(1.0 / prim.z.at(x, y), registers), fog[1], fog[2], fog[3]];
+            },
+            _ => ()
+        }
+
+        pack(color)
+    }
+
+    /// Looks up the fog table with the depth of a pixel
+    fn fog_alpha(w: f32, registers: &PvrRegisters) -> u32 {
+        let density = registers.get(FOG_DENSITY);
+        let mantissa = ((density >> 8) & 0xFF) as f32 / 128.0;
+        let exponent = (density & 0xFF) as u8 as i8 as i32;
+        let fog_w = (mantissa * 2f32.powi(exponent) * w).clamp(1.0, 255.999_98);
+
+        let bits = fog_w.to_bits();
+        let index = ((((bits >> 23) & 0xFF) + 1) & 7) << 4 | ((bits >> 19) & 0xF);
+        let blend = (bits >> 11) & 0xFF;
+        let entry = registers.get(FOG_TABLE + (index as usize) * 4);
+
+        ((entry & 0xFF) * blend + ((entry >> 8) & 0xFF) * (255 - blend)) >> 8
+    }
+
+    /// Samples the texture of a volume, picking the mipmap level from the
+    /// texel footprint of the pixel
+    fn sample(texture: &Texture, prim: &Primitive, s: &Shading, x: f32, y: f32) -> u32 {
+        let uv = |x: f32, y: f32| {
+            let w = if prim.perspective { 1.0 / prim.z.at(x, y) } else { 1.0 };
+            (s.u.at(x, y) * w, s.v.at(x, y) * w)
+        };
+        let (u, v) = uv(x, y);
+
+        let mut level = 0;
+        if texture.levels.len() > 1 {
+            let (ux, vx) = uv(x + 1.0, y);
+            let (uy, vy) = uv(x, y + 1.0);
+            let size = texture.desc.width as f32;
+            let footprint = ((ux - u).abs().max((vx - v).abs())).max((uy - u).abs().max((vy - v).abs())) * size;
+            // D adjust scales the footprint in steps of a quarter
+            let adjust = ((s.tsp >> 8) & 0xF) as f32 / 4.0;
+            let lod = (footprint * adjust).log2();
+            if lod > 0.0 {
+                // Levels are ordered from the largest texture down
+                level = cmp::min(lod.round() as usize, texture.levels.len() - 1);
+            }
+        }
+
+        texture.sample(s.tsp, level, u, v)
+    }
+
+    /// Lights a bump map texel with the parameters in the offset color
+    fn bump(t: [u32; 4], offset: [u32; 4]) -> [u32; 4] {
+        let s = t[1] as f32 / 256.0 * PI / 2.0;
+        let r = t[2] as f32 / 256.0 * PI * 2.0;
+        let q = offset[3] as f32 / 256.0 * PI * 2.0;
+        let k = |c: u32| c as f32 / 255.0;
+
+        let intensity = k(offset[0]) + k(offset[1]) * s.sin() + k(offset[2]) * s.cos() * (r - q).cos();
+        let alpha = (intensity * 255.0).clamp(0.0, 255.0) as u32;
+        [alpha, 255, 255, 255]
+    }
+
+    /// Writes the accumulated colors of a tile into the framebuffer
+    fn write_out(&self, registers: &PvrRegisters, vram: &mut Vram, region: &Region) {
+        let ctrl = registers.get(FB_W_CTRL);
+        let stride = ((registers.get(FB_W_LINESTRIDE) & 0x1FF) * 8) as usize;
+        let x_clip = registers.get(FB_X_CLIP);
+        let y_clip = registers.get(FB_Y_CLIP);
+        let interlaced = registers.get(SCALER_CTL) & (1 << 18) != 0;
+        let kval = (ctrl >> 8) & 0xFF;
+        let threshold = (ctrl >> 16) & 0xFF;
+
+        for i in 0..TILE_PIXELS {
+            let x = region.x * TILE_SIZE + i as u32 % TILE_SIZE;
+            let y = region.y * TILE_SIZE + i as u32 / TILE_SIZE;
+
+            if x < (x_clip & 0x7FF) || x > ((x_clip >> 16) & 0x7FF)
+                || y < (y_clip & 0x3FF) || y > ((y_clip >> 16) & 0x3FF) {
+                continue;
+            }
+
+            // Interlaced output sends every other line to the second field
+            let (sof, line) = if interlaced && y % 2 == 1 {
+                (registers.get(FB_W_SOF2), y / 2)
+            } else if interlaced {
+                (registers.get(FB_W_SOF1), y / 2)
+            } else {
+                (registers.get(FB_W_SOF1), y)
+            };
+
+            let c = unpack(self.colors[i]);
+            let line_address = (sof & 0x00FFFFFC) as usize + line as usize * stride;
+
+            match ctrl & 0x7 {
+                0 => {
+                    let p = ((kval >> 7) << 15) | ((c[1] >> 3) << 10) | ((c[2] >> 3) << 5) | (c[3] >> 3);
+                    vram.write_32bit_u16(line_address + x as usize * 2, p as u16);
+                },
+                1 => {
+                    let p = ((c[1] >> 3) << 11) | ((c[2] >> 2) << 5) | (c[3] >> 3);
+                    vram.write_32bit_u16(line_address + x as usize * 2, p as u16);
+                },
+                2 => {
+                    let p = ((c[0] >> 4) << 12) | ((c[1] >> 4) << 8) | ((c[2] >> 4) << 4) | (c[3] >> 4);
+                    vram.write_32bit_u16(line_address + x as usize * 2, p as u16);
+                },
+                3 => {
+                    let a = if c[0] >= threshold { 1 } else { 0 };
+                    let p = (a << 15) | ((c[1] >> 3) << 10) | ((c[2] >> 3) << 5) | (c[3] >> 3);
+                    vram.write_32bit_u16(line_address + x as usize * 2, p as u16);
+                },
+                4 => {
+                    let a = line_address + x as usize * 3;
+                    vram.write_u8(Vram::map_32bit(a), c[3] as u8);
+                    vram.write_u8(Vram::map_32bit(a + 1), c[2] as u8);
+                    vram.write_u8(Vram::map_32bit(a + 2), c[1] as u8);
+                },
+                5 => {
+                    let p = (kval << 24) | (c[1] << 16) | (c[2] << 8) | c[3];
+                    vram.write_32bit_u32(line_address + x as usize * 4, p);
+                },
+                _ => {
+                    vram.write_32bit_u32(line_address + x as usize * 4, pack(c));
+                }
+            }
+        }
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Asic;
+    use pvr::framebuffer::FramebufferConfig;
+    use pvr::ta::{Ta, TaConfig};
+
+    const WIDTH : u32 = 64;
+    const HEIGHT : u32 = 32;
+    const REGIONS : usize = 0x080000;
+    const PARAMS : u32 = 0x100000;
+    /// The background parameters, past those the TA stores
+    const BACKGROUND : u32 = 0x010000;
+    const FRAMEBUFFER : u32 = 0x200000;
+
+    /// Sends a gouraud shaded triangle over two tiles through the TA,
+    /// the way a game would
+    fn bin_triangle(vram: &mut Vram) {
+        let mut ta = Ta::new();
+        let mut asic = Asic {
+            istnrm: 0,
+            istext: 0,
+            isterr: 0,
+            iml_nrm: [0; 3],
+            iml_ext: [0; 3],
+            iml_err: [0; 3],
+        };
+        ta.list_init(TaConfig {
+            ol_base: 0,
+            ol_limit: 0x080000,
+            isp_base: PARAMS,
+            isp_limit: PARAMS + BACKGROUND,
+            alloc_ctrl: 1,
+            glob_tile_clip: 0x00000001,
+            next_opb_init: 0x1000,
+        });
+
+        // Depth mode greater or equal, no fog
+        let mut words = vec![0x80000002, 0xC0000000, 0x00800000, 0, 0, 0, 0, 0];
+        let vertices = [(4.0f32, 4.0f32, 0xFFFF0000u32), (60.0, 4.0, 0xFF00FF00), (4.0, 28.0, 0xFF0000FF)];
+        for (i, &(x, y, color)) in vertices.iter().enumerate() {
+            let pcw = if i == 2 { 0xF0000000 } else { 0xE0000000 };
+            words.extend_from_slice(&[pcw, x.to_bits(), y.to_bits(), 1.0f32.to_bits(), 0, 0, color, 0]);
+        }
+        words.extend_from_slice(&[0; 8]);
+
+        for &word in words.iter() {
+            ta.write(word, vram, &mut asic);
+        }
+        assert_eq!(asic.isterr, 0);
+    }
+
+    fn setup(registers: &mut PvrRegisters, vram: &mut Vram) {
+        // A grey background plane behind everything
+        let background = (PARAMS + BACKGROUND) as usize;
+        vram.write_32bit_u32(background, 0xF0000000);
+        vram.write_32bit_u32(background + 4, 0x00800000);
+        for i in 0..3 {
+            let vertex = background + 12 + i * 16;
+            vram.write_32bit_u32(vertex + 8, 0.0001f32.to_bits());
+            vram.write_32bit_u32(vertex + 12, 0xFF404040);
+        }
+        registers.set(ISP_BACKGND_T, (1 << 24) | ((BACKGROUND >> 2) << 3));
+        registers.set(ISP_BACKGND_D, 0.0001f32.to_bits());
+
+        // Both tiles take their opaque list from where the TA put it
+        for (i, &(x, list)) in [(0, 0u32), (1, 32)].iter().enumerate() {
+            let entry = REGIONS + i * 20;
+            let last = if i == 1 { 1 << 31 } else { 0 };
+            vram.write_32bit_u32(entry, last | (x << 2));
+            vram.write_32bit_u32(entry + 4, list);
+            for l in 1..4 {
+                vram.write_32bit_u32(entry + 4 + l * 4, 0x80000000);
+            }
+        }
+        registers.set(REGION_BASE, REGIONS as u32);
+        registers.set(PARAM_BASE, PARAMS);
+
+        // Packed ARGB8888 written out, and read back as RGB0888
+        registers.set(FB_W_CTRL, 6);
+        registers.set(FB_W_LINESTRIDE, WIDTH * 4 / 8);
+        registers.set(FB_W_SOF1, FRAMEBUFFER);
+        registers.set(FB_X_CLIP, (WIDTH - 1) << 16);
+        registers.set(FB_Y_CLIP, (HEIGHT - 1) << 16);
+        registers.set(FB_R_CTRL, 0xD);
+        registers.set(FB_R_SIZE, (1 << 20) | ((HEIGHT - 1) << 10) | (WIDTH - 1));
+        registers.set(FB_R_SOF1, FRAMEBUFFER);
+    }
+
+    #[test]
+    fn renders_a_golden_frame() {
+        let (mut registers, mut vram) = (PvrRegisters::new(), Vram::new());
+        bin_triangle(&mut vram);
+        setup(&mut registers, &mut vram);
+
+        let regions = Renderer::regions(&registers, &vram);
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[1].x, regions[1].last), (1, true));
+
+        Renderer::new().render(&registers, &mut vram);
+        let image = FramebufferConfig::new(&registers).capture(&vram);
+        assert_eq!((image.width, image.height), (WIDTH, HEIGHT));
+
+        // The corners of the triangle are close to the colors of its
+        // vertices, and pixels just outside of its edges are background
+        assert_eq!(image.get(4, 4), 0xFFF70205);
+        assert_eq!(image.get(58, 4), 0xFF01F805);
+        assert_eq!(image.get(4, 27), 0xFF0302F9);
+        for &(x, y) in [(0, 0), (3, 4), (4, 3), (59, 4), (40, 20), (WIDTH - 1, HEIGHT - 1)].iter() {
+            assert_eq!(image.get(x, y), 0xFF404040);
+        }
+        assert_eq!(image.hash(), 0xBDACFC3E5E4CE480);
+    }
+}
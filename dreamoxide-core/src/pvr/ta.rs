@@ -0,0 +1,630 @@
+use Asic;
+use asic::{Interrupt, ErrorInterrupt};
+use pvr::vram::Vram;
+use pvr::ta_parameter::{TaParser, TaParameter, ListType, PolygonHeader, SpriteHeader};
+use pvr::ta_parameter::{Vertex, SpriteVertex, ModifierVolumeTriangle};
+
+use std::cmp;
+
+/// Tiles are 32x32 pixels large
+pub const TILE_SIZE : u32 = 32;
+
+/// Object list entry types, found in bits 31-29
+pub const OBJECT_TRIANGLE_ARRAY : u32 = 0x80000000;
+pub const OBJECT_QUAD_ARRAY     : u32 = 0xA0000000;
+pub const OBJECT_BLOCK_LINK     : u32 = 0xE0000000;
+pub const OBJECT_END_OF_LIST    : u32 = 0xF0000000;
+
+/// Bits of the ISP/TSP instruction word taken from the parameter control word
+const ISP_PCW_BITS : u32 = 0x03C00000;
+
+/// Triangles per strip that fit into a single object list entry
+const MAX_STRIP_TRIANGLES : usize = 6;
+
+/// The register values the TA latches on list initialization
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TaConfig {
+    pub ol_base: u32,
+    pub ol_limit: u32,
+    pub isp_base: u32,
+    pub isp_limit: u32,
+    pub alloc_ctrl: u32,
+    pub glob_tile_clip: u32,
+    pub next_opb_init: u32,
+}
+
+impl TaConfig {
+    /// Width of the tile area in tiles
+    pub fn width(&self) -> u32 {
+        (self.glob_tile_clip & 0x3F) + 1
+    }
+
+    /// Height of the tile area in tiles
+    pub fn height(&self) -> u32 {
+        ((self.glob_tile_clip >> 16) & 0xF) + 1
+    }
+
+    /// Size of an object pointer block of the given list in words, 0 if the list is disabled
+    pub fn block_size(&self, list: ListType) -> u32 {
+        match (self.alloc_ctrl >> ((list as u32) * 4)) & 0x3 {
+            0 => 0,
+            1 => 8,
+            2 => 16,
+            _ => 32
+        }
+    }
+
+    /// Whether additional blocks are allocated towards lower addresses
+    pub fn is_opb_decreasing(&self) -> bool {
+        self.alloc_ctrl & (1 << 20) != 0
+    }
+
+    /// Address of the first object pointer block of a tile. The blocks of all
+    /// tiles of a list are laid out row by row, one list after another.
+    pub fn tile_list_address(&self, list: ListType, x: u32, y: u32) -> u32 {
+        let tiles = self.width() * self.height();
+        let mut address = self.ol_base;
+
+        for l in 0..(list as u32) {
+            address += tiles * self.block_size(ListType::from_u32(l).unwrap()) * 4;
+        }
+
+        address + (y * self.width() + x) * self.block_size(list) * 4
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TileList {
+    /// Address of the next free entry
+    pointer: u32,
+    /// Words left in the current block, including the one reserved for the link
+    remaining: u32,
+}
+
+/// The tile accelerator. It receives the parameters of the display lists,
+/// stores the ISP/TSP parameters in video memory and bins every polygon into
+/// the object lists of all tiles it touches.
+pub struct Ta {
+    pub parser: TaParser,
+    pub config: TaConfig,
+    /// Offset of the next ISP/TSP parameter, relative to the ISP base
+    pub itp_current: u32,
+    pub next_opb: u32,
+    tiles: Vec<Vec<TileList>>,
+    polygon: Option<PolygonHeader>,
+    sprite: Option<SpriteHeader>,
+    volume: Option<PolygonHeader>,
+    strip: Vec<Vertex>,
+    /// User clip rectangle in tiles, inclusive
+    user_clip: (u32, u32, u32, u32),
+}
+
+impl Ta {
+    pub fn new() -> Ta {
+        Ta {
+            parser: TaParser::new(),
+            config: TaConfig {
+                ol_base: 0,
+                ol_limit: 0,
+                isp_base: 0,
+                isp_limit: 0,
+                alloc_ctrl: 0,
+                glob_tile_clip: 0,
+                next_opb_init: 0,
+            },
+            itp_current: 0,
+            next_opb: 0,
+            tiles: Vec::new(),
+            polygon: None,
+            sprite: None,
+            volume: None,
+            strip: Vec::new(),
+            user_clip: (0, 0, 0, 0),
+        }
+    }
+
+    /// Starts a new set of display lists, as triggered by TA_LIST_INIT
+    pub fn list_init(&mut self, config: TaConfig) {
+        self.config = config;
+        self.itp_current = 0;
+        self.next_opb = config.next_opb_init;
+        self.parser.reset();
+        self.reset_state();
+
+        let (w, h) = (config.width(), config.height());
+        self.tiles = (0..5).map(|l| {
+            let list = ListType::from_u32(l).unwrap();
+            let size = config.block_size(list);
+            let mut tiles = Vec::new();
+            for y in 0..h {
+                for x in 0..w {
+                    tiles.push(TileList {
+                        pointer: config.tile_list_address(list, x, y),
+                        remaining: size,
+                    });
+                }
+            }
+            tiles
+        }).collect();
+    }
+
+    /// Continues adding lists after an end of list, as triggered by TA_LIST_CONT
+    pub fn list_continue(&mut self) {
+        self.parser.reset();
+        self.reset_state();
+    }
+
+    fn reset_state(&mut self) {
+        self.polygon = None;
+        self.sprite = None;
+        self.volume = None;
+        self.strip.clear();
+        self.user_clip = (0, 0, self.config.width() - 1, self.config.height() - 1);
+    }
+
+    /// Feeds a word written to the TA FIFO
+    pub fn write(&mut self, word: u32, vram: &mut Vram, asic: &mut Asic) {
+        if let Some(param) = self.parser.push(word) {
+            self.process(param, vram, asic);
+        }
+    }
+
+    /// Handles a single decoded parameter
+    pub fn process(&mut self, param: TaParameter, vram: &mut Vram, asic: &mut Asic) {
+        match param {
+            TaParameter::EndOfList(list) => self.end_of_list(list, vram, asic),
+            TaParameter::UserTileClip(x0, y0, x1, y1) => self.user_clip = (x0, y0, x1, y1),
+            TaParameter::ObjectListSet(entry, rect) => {
+                if let Some(list) = self.parser.current_list() {
+                    for y in rect[1]..cmp::min(rect[3] + 1, self.config.height()) {
+                        for x in rect[0]..cmp::min(rect[2] + 1, self.config.width()) {
+                            self.append(list, x, y, entry, vram, asic);
+                        }
+                    }
+                }
+            },
+            TaParameter::Polygon(header) => {
+                self.polygon = Some(header);
+                self.sprite = None;
+                self.strip.clear();
+            },
+            TaParameter::Sprite(header) => {
+                self.sprite = Some(header);
+                self.polygon = None;
+                self.strip.clear();
+            },
+            TaParameter::ModifierVolume(header) => self.volume = Some(header),
+            TaParameter::Vertex(vertex) => {
+                self.strip.push(vertex);
+                if vertex.end_of_strip || self.strip.len() == MAX_STRIP_TRIANGLES + 2 {
+                    self.flush_strip(vram, asic);
+                    if vertex.end_of_strip {
+                        self.strip.clear();
+                    } else {
+                        // Keep the last edge so the strip continues seamlessly
+                        let tail = self.strip.split_off(MAX_STRIP_TRIANGLES);
+                        self.strip = tail;
+                    }
+                }
+            },
+            TaParameter::SpriteVertex(sprite) => self.add_sprite(sprite, vram, asic),
+            TaParameter::ModifierVolumeVertex(triangle) => self.add_volume_triangle(triangle, vram, asic),
+            TaParameter::Illegal(_) => asic.raise_error(ErrorInterrupt::TaIllegalParameter),
+        }
+    }
+
+    /// The interrupt raised once the given list has been processed
+    pub fn list_interrupt(list: ListType) -> Interrupt {
+        match list {
+            ListType::Opaque                    => Interrupt::OpaqueListDone,
+            ListType::OpaqueModifierVolume      => Interrupt::OpaqueModifierVolumeListDone,
+            ListType::Translucent               => Interrupt::TranslucentListDone,
+            ListType::TranslucentModifierVolume => Interrupt::TranslucentModifierVolumeListDone,
+            ListType::PunchThrough              => Interrupt::PunchThroughListDone,
+        }
+    }
+
+    fn end_of_list(&mut self, list: ListType, vram: &mut Vram, asic: &mut Asic) {
+        if !self.strip.is_empty() {
+            self.flush_strip(vram, asic);
+        }
+
+        if let Some(tiles) = self.tiles.get(list as usize) {
+            for tile in tiles.iter() {
+                if tile.remaining > 0 {
+                    vram.write_32bit_u32(tile.pointer as usize, OBJECT_END_OF_LIST);
+                }
+            }
+        }
+
+        self.reset_state();
+        asic.raise(Ta::list_interrupt(list));
+    }
+
+    /// Stores a word of ISP/TSP parameters
+    fn write_param(&mut self, value: u32, vram: &mut Vram, asic: &mut Asic) {
+        let address = self.config.isp_base.wrapping_add(self.itp_current);
+        if address >= self.config.isp_limit {
+            asic.raise_error(ErrorInterrupt::TaIspParameterOverflow);
+            return;
+        }
+
+        vram.write_32bit_u32(address as usize, value);
+        self.itp_current += 4;
+    }
+
+    /// Adds an entry to the object list of a tile, linking in a new block when full
+    fn append(&mut self, list: ListType, x: u32, y: u32, entry: u32, vram: &mut Vram, asic: &mut Asic) {
+        let size = self.config.block_size(list);
+        if size == 0 {
+            return;
+        }
+
+        let index = (y * self.config.width() + x) as usize;
+        let mut tile = self.tiles[list as usize][index];
+
+        if tile.remaining <= 1 {
+            let block = if self.config.is_opb_decreasing() {
+                self.next_opb = self.next_opb.wrapping_sub(size * 4);
+                if self.next_opb < self.config.ol_limit {
+                    asic.raise_error(ErrorInterrupt::TaObjectListOverflow);
+                }
+                self.next_opb
+            } else {
+                let block = self.next_opb;
+                self.next_opb += size * 4;
+                if self.next_opb > self.config.ol_limit {
+                    asic.raise_error(ErrorInterrupt::TaObjectListOverflow);
+                }
+                block
+            };
+
+            vram.write_32bit_u32(tile.pointer as usize, OBJECT_BLOCK_LINK | (block & 0x00FFFFFC));
+            tile.pointer = block;
+            tile.remaining = size;
+        }
+
+        vram.write_32bit_u32(tile.pointer as usize, entry);
+        tile.pointer += 4;
+        tile.remaining -= 1;
+
+        self.tiles[list as usize][index] = tile;
+    }
+
+    /// Converts a screen space bounding box into an inclusive tile rectangle,
+    /// honoring the global and the user tile clip
+    fn tile_rect(&self, points: &[[f32; 2]], user_clip: u32) -> Option<(u32, u32, u32, u32)> {
+        let min_x = points.iter().fold(f32::MAX, |a, p| a.min(p[0]));
+        let max_x = points.iter().fold(f32::MIN, |a, p| a.max(p[0]));
+        let min_y = points.iter().fold(f32::MAX, |a, p| a.min(p[1]));
+        let max_y = points.iter().fold(f32::MIN, |a, p| a.max(p[1]));
+
+        let (w, h) = (self.config.width() as f32, self.config.height() as f32);
+        let tile = TILE_SIZE as f32;
+
+        if max_x < 0.0 || max_y < 0.0 || min_x >= w * tile || min_y >= h * tile || min_x > max_x {
+            return None;
+        }
+
+        let mut rect = ((min_x.max(0.0) / tile) as u32,
+                        (min_y.max(0.0) / tile) as u32,
+                        cmp::min((max_x / tile) as u32, w as u32 - 1),
+                        cmp::min((max_y / tile) as u32, h as u32 - 1));
+
+        // Clipping to the inside of the user clip can be done on the rectangle,
+        // clipping to the outside is done per tile while binning
+        if user_clip == 2 {
+            let (x0, y0, x1, y1) = self.user_clip;
+            rect = (cmp::max(rect.0, x0), cmp::max(rect.1, y0), cmp::min(rect.2, x1), cmp::min(rect.3, y1));
+            if rect.0 > rect.2 || rect.1 > rect.3 {
+                return None;
+            }
+        }
+
+        Some(rect)
+    }
+
+    fn is_clipped_outside(&self, x: u32, y: u32, user_clip: u32) -> bool {
+        let (x0, y0, x1, y1) = self.user_clip;
+        user_clip == 3 && x >= x0 && x <= x1 && y >= y0 && y <= y1
+    }
+
+    fn flush_strip(&mut self, vram: &mut Vram, asic: &mut Asic) {
+        let header = match self.polygon {
+            Some(h) => h,
+            None    => return
+        };
+        let list = match self.parser.current_list() {
+            Some(l) => l,
+            None    => return
+        };
+
+        let vertices = self.strip.clone();
+        if vertices.len() < 3 {
+            return;
+        }
+
+        let pcw = header.pcw;
+        let two_volume = pcw.is_two_volume();
+        let textured = pcw.is_textured();
+        let offset = textured && pcw.has_offset();
+        let uv16 = textured && pcw.is_uv16();
+
+        let mut pcw_bits = pcw.value & 0x2;
+        if textured { pcw_bits |= 0x8; }
+        if offset { pcw_bits |= 0x4; }
+        if uv16 { pcw_bits |= 0x1; }
+
+        let param = self.itp_current;
+        self.write_param((header.isp_tsp & !ISP_PCW_BITS) | (pcw_bits << 22), vram, asic);
+        self.write_param(header.tsp[0], vram, asic);
+        self.write_param(header.tcw[0], vram, asic);
+        if two_volume {
+            self.write_param(header.tsp[1], vram, asic);
+            self.write_param(header.tcw[1], vram, asic);
+        }
+
+        for v in vertices.iter() {
+            self.write_param(v.x.to_bits(), vram, asic);
+            self.write_param(v.y.to_bits(), vram, asic);
+            self.write_param(v.z.to_bits(), vram, asic);
+            for vol in 0..(if two_volume { 2 } else { 1 }) {
+                if uv16 {
+                    self.write_param((v.u[vol].to_bits() & 0xFFFF0000) | (v.v[vol].to_bits() >> 16), vram, asic);
+                } else if textured {
+                    self.write_param(v.u[vol].to_bits(), vram, asic);
+                    self.write_param(v.v[vol].to_bits(), vram, asic);
+                }
+                self.write_param(v.base_color[vol], vram, asic);
+                if offset {
+                    self.write_param(v.offset_color[vol], vram, asic);
+                }
+            }
+        }
+
+        let skip = (if uv16 { 1 } else if textured { 2 } else { 0 }) + 1 + (if offset { 1 } else { 0 });
+        let shadow = if two_volume { 1 << 24 } else { 0 };
+        let base_entry = shadow | (skip << 21) | ((param >> 2) & 0x1FFFFF);
+
+        // Bin every triangle of the strip separately, so each tile only
+        // gets the triangles actually touching it masked in
+        let triangles = vertices.len() - 2;
+        let rects : Vec<Option<(u32, u32, u32, u32)>> = (0..triangles).map(|i| {
+            let points = [[vertices[i].x, vertices[i].y],
+                          [vertices[i + 1].x, vertices[i + 1].y],
+                          [vertices[i + 2].x, vertices[i + 2].y]];
+            self.tile_rect(&points, pcw.user_clip())
+        }).collect();
+
+        let points : Vec<[f32; 2]> = vertices.iter().map(|v| [v.x, v.y]).collect();
+        if let Some((x0, y0, x1, y1)) = self.tile_rect(&points, pcw.user_clip()) {
+            for y in y0..(y1 + 1) {
+                for x in x0..(x1 + 1) {
+                    if self.is_clipped_outside(x, y, pcw.user_clip()) {
+                        continue;
+                    }
+
+                    let mut mask = 0;
+                    for (i, rect) in rects.iter().enumerate() {
+                        if let Some((rx0, ry0, rx1, ry1)) = *rect {
+                            if x >= rx0 && x <= rx1 && y >= ry0 && y <= ry1 {
+                                mask |= 1 << (5 - i);
+                            }
+                        }
+                    }
+
+                    if mask != 0 {
+                        self.append(list, x, y, base_entry | (mask << 25), vram, asic);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_sprite(&mut self, sprite: SpriteVertex, vram: &mut Vram, asic: &mut Asic) {
+        let header = match self.sprite {
+            Some(h) => h,
+            None    => return
+        };
+        let list = match self.parser.current_list() {
+            Some(l) => l,
+            None    => return
+        };
+
+        let pcw = header.pcw;
+        let textured = pcw.is_textured();
+        let offset = textured && pcw.has_offset();
+
+        // Sprites always use flat shading and 16 bit texture coordinates
+        let mut pcw_bits = 0;
+        if textured { pcw_bits |= 0x9; }
+        if offset { pcw_bits |= 0x4; }
+
+        let param = self.itp_current;
+        self.write_param((header.isp_tsp & !ISP_PCW_BITS) | (pcw_bits << 22), vram, asic);
+        self.write_param(header.tsp, vram, asic);
+        self.write_param(header.tcw, vram, asic);
+
+        for i in 0..4 {
+            let p = sprite.position[i];
+            self.write_param(p[0].to_bits(), vram, asic);
+            self.write_param(p[1].to_bits(), vram, asic);
+            self.write_param(p[2].to_bits(), vram, asic);
+            if textured {
+                let uv = sprite.uv[i];
+                self.write_param((uv[0].to_bits() & 0xFFFF0000) | (uv[1].to_bits() >> 16), vram, asic);
+            }
+            self.write_param(header.base_color, vram, asic);
+            if offset {
+                self.write_param(header.offset_color, vram, asic);
+            }
+        }
+
+        let skip = (if textured { 1 } else { 0 }) + 1 + (if offset { 1 } else { 0 });
+        let entry = OBJECT_QUAD_ARRAY | (skip << 21) | ((param >> 2) & 0x1FFFFF);
+        let points : Vec<[f32; 2]> = sprite.position.iter().map(|p| [p[0], p[1]]).collect();
+
+        if let Some((x0, y0, x1, y1)) = self.tile_rect(&points, pcw.user_clip()) {
+            for y in y0..(y1 + 1) {
+                for x in x0..(x1 + 1) {
+                    if !self.is_clipped_outside(x, y, pcw.user_clip()) {
+                        self.append(list, x, y, entry, vram, asic);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_volume_triangle(&mut self, triangle: ModifierVolumeTriangle, vram: &mut Vram, asic: &mut Asic) {
+        let header = match self.volume {
+            Some(h) => h,
+            None    => return
+        };
+        let list = match self.parser.current_list() {
+            Some(l) => l,
+            None    => return
+        };
+
+        let param = self.itp_current;
+        self.write_param(header.isp_tsp, vram, asic);
+        for p in triangle.position.iter() {
+            self.write_param(p[0].to_bits(), vram, asic);
+            self.write_param(p[1].to_bits(), vram, asic);
+            self.write_param(p[2].to_bits(), vram, asic);
+        }
+
+        // Volumes have to reach every tile they touch, even if only their
+        // closing triangle lies there, so they are never clipped by the user
+        let entry = OBJECT_TRIANGLE_ARRAY | ((param >> 2) & 0x1FFFFF);
+        let points : Vec<[f32; 2]> = triangle.position.iter().map(|p| [p[0], p[1]]).collect();
+
+        if let Some((x0, y0, x1, y1)) = self.tile_rect(&points, 0) {
+            for y in y0..(y1 + 1) {
+                for x in x0..(x1 + 1) {
+                    self.append(list, x, y, entry, vram, asic);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Ta {
+    fn default() -> Ta {
+        Ta::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2x2 tiles with eight word blocks for the opaque list only
+    const CONFIG : TaConfig = TaConfig {
+        ol_base: 0,
+        ol_limit: 0x100000,
+        isp_base: 0x200000,
+        isp_limit: 0x300000,
+        alloc_ctrl: 1,
+        glob_tile_clip: 0x00010001,
+        next_opb_init: 0x1000,
+    };
+
+    fn asic() -> Asic {
+        Asic {
+            istnrm: 0,
+            istext: 0,
+            isterr: 0,
+            iml_nrm: [0; 3],
+            iml_ext: [0; 3],
+            iml_err: [0; 3],
+        }
+    }
+
+    /// An opaque, packed color, flat shaded polygon
+    fn polygon() -> Vec<u32> {
+        vec![0x80000000, 0x80000000, 0x20800000, 0, 0, 0, 0, 0]
+    }
+
+    fn vertex(x: f32, y: f32, end_of_strip: bool) -> Vec<u32> {
+        let pcw = if end_of_strip { 0xF0000000 } else { 0xE0000000 };
+        vec![pcw, x.to_bits(), y.to_bits(), 1.0f32.to_bits(), 0, 0, 0xFFFF0000, 0]
+    }
+
+    fn end_of_list() -> Vec<u32> {
+        vec![0; 8]
+    }
+
+    fn run(stream: &[Vec<u32>]) -> (Ta, Vram, Asic) {
+        let (mut ta, mut vram, mut asic) = (Ta::new(), Vram::new(), asic());
+        ta.list_init(CONFIG);
+        for word in stream.iter().flat_map(|p| p.iter()) {
+            ta.write(*word, &mut vram, &mut asic);
+        }
+        (ta, vram, asic)
+    }
+
+    #[test]
+    fn bins_a_triangle_into_its_tile() {
+        let (ta, vram, asic) = run(&[polygon(),
+                                     vertex(2.0, 2.0, false),
+                                     vertex(20.0, 2.0, false),
+                                     vertex(2.0, 20.0, true),
+                                     end_of_list()]);
+
+        // One triangle strip with a skip of one word, masked to its first triangle
+        assert_eq!(vram.read_32bit_u32(0), 0x40200000);
+        assert_eq!(vram.read_32bit_u32(4), OBJECT_END_OF_LIST);
+        for &tile in [32, 64, 96].iter() {
+            assert_eq!(vram.read_32bit_u32(tile), OBJECT_END_OF_LIST);
+        }
+
+        assert_eq!(ta.itp_current, (3 + 3 * 4) * 4);
+        assert_eq!(vram.read_32bit_u32(0x200000 + 12), 2.0f32.to_bits());
+        assert_eq!(vram.read_32bit_u32(0x200000 + 24), 0xFFFF0000);
+        assert!(asic.is_pending(Interrupt::OpaqueListDone));
+        assert_eq!(asic.isterr, 0);
+    }
+
+    #[test]
+    fn masks_strip_triangles_per_tile() {
+        let (_, vram, _) = run(&[polygon(),
+                                 vertex(2.0, 2.0, false),
+                                 vertex(2.0, 20.0, false),
+                                 vertex(20.0, 2.0, false),
+                                 vertex(40.0, 20.0, true),
+                                 end_of_list()]);
+
+        // Only the second triangle reaches into the right tile
+        assert_eq!(vram.read_32bit_u32(0), 0x60200000);
+        assert_eq!(vram.read_32bit_u32(32), 0x20200000);
+        assert_eq!(vram.read_32bit_u32(36), OBJECT_END_OF_LIST);
+        assert_eq!(vram.read_32bit_u32(64), OBJECT_END_OF_LIST);
+    }
+
+    #[test]
+    fn links_a_new_block_when_full() {
+        let mut stream = vec![polygon()];
+        for _ in 0..8 {
+            stream.push(vertex(2.0, 2.0, false));
+            stream.push(vertex(20.0, 2.0, false));
+            stream.push(vertex(2.0, 20.0, true));
+        }
+        stream.push(end_of_list());
+        let (ta, vram, asic) = run(&stream);
+
+        for i in 0..7 {
+            assert_eq!(vram.read_32bit_u32(i * 4), 0x40200000 | (i as u32 * 15));
+        }
+        assert_eq!(vram.read_32bit_u32(28), OBJECT_BLOCK_LINK | 0x1000);
+        assert_eq!(vram.read_32bit_u32(0x1000), 0x40200000 | (7 * 15));
+        assert_eq!(vram.read_32bit_u32(0x1004), OBJECT_END_OF_LIST);
+        assert_eq!(ta.next_opb, 0x1020);
+        assert_eq!(asic.isterr, 0);
+    }
+
+    #[test]
+    fn rejects_a_vertex_outside_of_a_list() {
+        let (_, _, asic) = run(&[vertex(2.0, 2.0, true)]);
+        assert!(asic.isterr & (1 << (ErrorInterrupt::TaIllegalParameter as u32)) != 0);
+    }
+}
@@ -0,0 +1,639 @@
+/// Parameter types as found in bits 31-29 of the parameter control word
+pub const PARA_END_OF_LIST     : u32 = 0;
+pub const PARA_USER_TILE_CLIP  : u32 = 1;
+pub const PARA_OBJECT_LIST_SET : u32 = 2;
+pub const PARA_POLYGON         : u32 = 4;
+pub const PARA_SPRITE          : u32 = 5;
+pub const PARA_VERTEX          : u32 = 7;
+
+/// The five display lists the TA can bin polygons into. The
+/// discriminant is the value of the list type field.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ListType {
+    Opaque = 0,
+    OpaqueModifierVolume = 1,
+    Translucent = 2,
+    TranslucentModifierVolume = 3,
+    PunchThrough = 4,
+}
+
+impl ListType {
+    pub fn from_u32(value: u32) -> Option<ListType> {
+        match value {
+            0 => Some(ListType::Opaque),
+            1 => Some(ListType::OpaqueModifierVolume),
+            2 => Some(ListType::Translucent),
+            3 => Some(ListType::TranslucentModifierVolume),
+            4 => Some(ListType::PunchThrough),
+            _ => None
+        }
+    }
+
+    /// Checks if the list holds modifier volumes instead of polygons
+    pub fn is_modifier_volume(&self) -> bool {
+        matches!(*self, ListType::OpaqueModifierVolume | ListType::TranslucentModifierVolume)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorType {
+    Packed,
+    Float,
+    IntensityMode1,
+    IntensityMode2,
+}
+
+/// The first word of every parameter sent to the TA
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParameterControlWord {
+    pub value: u32
+}
+
+impl ParameterControlWord {
+    pub fn para_type(&self) -> u32 {
+        self.value >> 29
+    }
+
+    pub fn is_end_of_strip(&self) -> bool {
+        self.value & (1 << 28) != 0
+    }
+
+    pub fn list_type(&self) -> Option<ListType> {
+        ListType::from_u32((self.value >> 24) & 0x7)
+    }
+
+    pub fn is_group_enabled(&self) -> bool {
+        self.value & (1 << 23) != 0
+    }
+
+    pub fn user_clip(&self) -> u32 {
+        (self.value >> 16) & 0x3
+    }
+
+    pub fn is_shadow(&self) -> bool {
+        self.value & (1 << 7) != 0
+    }
+
+    /// Polygons with two volumes carry a second set of shading parameters
+    pub fn is_two_volume(&self) -> bool {
+        self.value & (1 << 6) != 0
+    }
+
+    pub fn color_type(&self) -> ColorType {
+        match (self.value >> 4) & 0x3 {
+            0 => ColorType::Packed,
+            1 => ColorType::Float,
+            2 => ColorType::IntensityMode1,
+            _ => ColorType::IntensityMode2
+        }
+    }
+
+    pub fn is_textured(&self) -> bool {
+        self.value & (1 << 3) != 0
+    }
+
+    pub fn has_offset(&self) -> bool {
+        self.value & (1 << 2) != 0
+    }
+
+    pub fn is_gouraud(&self) -> bool {
+        self.value & (1 << 1) != 0
+    }
+
+    pub fn is_uv16(&self) -> bool {
+        self.value & 1 != 0
+    }
+}
+
+/// A floating point ARGB color as sent by the application
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Color {
+    pub a: f32,
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Color {
+    pub fn from_words(words: &[u32]) -> Color {
+        Color {
+            a: f32::from_bits(words[0]),
+            r: f32::from_bits(words[1]),
+            g: f32::from_bits(words[2]),
+            b: f32::from_bits(words[3]),
+        }
+    }
+
+    /// Converts to the packed 8888 ARGB representation the ISP uses
+    pub fn to_packed(&self) -> u32 {
+        fn channel(v: f32) -> u32 {
+            let c = v * 255.0;
+            if c >= 255.0 { 255 } else if c > 0.0 { c as u32 } else { 0 }
+        }
+
+        (channel(self.a) << 24) | (channel(self.r) << 16) | (channel(self.g) << 8) | channel(self.b)
+    }
+
+    /// Scales the color channels by an intensity, keeping the alpha
+    pub fn intensity(&self, intensity: f32) -> u32 {
+        Color {
+            a: self.a,
+            r: self.r * intensity,
+            g: self.g * intensity,
+            b: self.b * intensity,
+        }.to_packed()
+    }
+}
+
+/// Global parameter starting a polygon or modifier volume in one of the lists
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PolygonHeader {
+    pub pcw: ParameterControlWord,
+    pub isp_tsp: u32,
+    pub tsp: [u32; 2],
+    pub tcw: [u32; 2],
+    /// Face colors of intensity mode 1, one per volume
+    pub face_color: [Color; 2],
+    pub face_offset_color: Color,
+}
+
+/// Global parameter starting a sprite. Colors are shared by all four corners.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SpriteHeader {
+    pub pcw: ParameterControlWord,
+    pub isp_tsp: u32,
+    pub tsp: u32,
+    pub tcw: u32,
+    pub base_color: u32,
+    pub offset_color: u32,
+}
+
+/// A polygon vertex with all colors converted to packed ARGB.
+/// The second entry of each array is only used by two volume polygons.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Vertex {
+    pub end_of_strip: bool,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub u: [f32; 2],
+    pub v: [f32; 2],
+    pub base_color: [u32; 2],
+    pub offset_color: [u32; 2],
+}
+
+/// The four corners of a sprite. The last corner has no depth or
+/// texture coordinate of its own, so they are derived from the others.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SpriteVertex {
+    pub end_of_strip: bool,
+    pub position: [[f32; 3]; 4],
+    pub uv: [[f32; 2]; 4],
+}
+
+/// One triangle of a modifier volume
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ModifierVolumeTriangle {
+    pub end_of_strip: bool,
+    pub position: [[f32; 3]; 3],
+}
+
+/// A fully decoded TA parameter
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TaParameter {
+    EndOfList(ListType),
+    /// Tile coordinates of the user clip rectangle, inclusive
+    UserTileClip(u32, u32, u32, u32),
+    /// An object list entry to store directly into all tiles of a rectangle
+    ObjectListSet(u32, [u32; 4]),
+    Polygon(PolygonHeader),
+    ModifierVolume(PolygonHeader),
+    Sprite(SpriteHeader),
+    Vertex(Vertex),
+    SpriteVertex(SpriteVertex),
+    ModifierVolumeVertex(ModifierVolumeTriangle),
+    /// A parameter the TA does not accept in the current state
+    Illegal(u32),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Global {
+    Polygon(PolygonHeader),
+    Sprite(SpriteHeader),
+    ModifierVolume,
+}
+
+/// Splits the FIFO stream into parameters. The parser keeps the state
+/// necessary to interpret vertices, such as the current list and the last
+/// global parameter, but does not touch video memory.
+pub struct TaParser {
+    buffer: Vec<u32>,
+    list: Option<ListType>,
+    global: Option<Global>,
+    /// Face colors latched by intensity mode 1 for use by mode 2
+    face_color: [Color; 2],
+    face_offset_color: Color,
+}
+
+impl TaParser {
+    pub fn new() -> TaParser {
+        let white = Color { a: 1.0, r: 1.0, g: 1.0, b: 1.0 };
+        TaParser {
+            buffer: Vec::with_capacity(16),
+            list: None,
+            global: None,
+            face_color: [white, white],
+            face_offset_color: Color { a: 0.0, r: 0.0, g: 0.0, b: 0.0 },
+        }
+    }
+
+    /// Forgets the current list, as done by a list initialization
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.list = None;
+        self.global = None;
+    }
+
+    /// The list currently being filled, if any
+    pub fn current_list(&self) -> Option<ListType> {
+        self.list
+    }
+
+    /// Decodes a whole captured FIFO stream
+    pub fn parse(words: &[u32]) -> Vec<TaParameter> {
+        let mut parser = TaParser::new();
+        words.iter().filter_map(|&w| parser.push(w)).collect()
+    }
+
+    /// Feeds a single word into the parser. A parameter is returned
+    /// as soon as all of its 32 or 64 bytes have been received.
+    pub fn push(&mut self, word: u32) -> Option<TaParameter> {
+        self.buffer.push(word);
+
+        if self.buffer.len() < 8 {
+            return None;
+        }
+
+        let pcw = ParameterControlWord { value: self.buffer[0] };
+        if self.buffer.len() < self.parameter_size(pcw) {
+            return None;
+        }
+
+        let words = self.buffer.clone();
+        self.buffer.clear();
+
+        Some(self.decode(pcw, &words))
+    }
+
+    /// Number of words making up the parameter started by the given control word
+    fn parameter_size(&self, pcw: ParameterControlWord) -> usize {
+        match pcw.para_type() {
+            PARA_POLYGON => {
+                let list = self.list.or(pcw.list_type());
+                if list.is_some_and(|l| l.is_modifier_volume()) {
+                    8
+                } else if pcw.color_type() == ColorType::IntensityMode1
+                    && (pcw.is_two_volume() || (pcw.is_textured() && pcw.has_offset())) {
+                    16
+                } else {
+                    8
+                }
+            },
+            PARA_VERTEX => match self.vertex_type() {
+                Some(5) | Some(6) | Some(11) | Some(12) | Some(13)
+                    | Some(14) | Some(15) | Some(16) | Some(17) => 16,
+                _ => 8
+            },
+            _ => 8
+        }
+    }
+
+    /// Determines the vertex parameter type (0-17) from the last global parameter
+    pub fn vertex_type(&self) -> Option<u32> {
+        match self.global {
+            Some(Global::ModifierVolume) => Some(17),
+            Some(Global::Sprite(ref h)) => Some(if h.pcw.is_textured() { 16 } else { 15 }),
+            Some(Global::Polygon(ref h)) => {
+                let pcw = h.pcw;
+                let color = pcw.color_type();
+                let intensity = color == ColorType::IntensityMode1 || color == ColorType::IntensityMode2;
+                let uv16 = pcw.is_uv16();
+
+                Some(match (pcw.is_textured(), pcw.is_two_volume()) {
+                    (false, false) => match color {
+                        ColorType::Packed => 0,
+                        ColorType::Float  => 1,
+                        _                 => 2
+                    },
+                    (false, true) => if intensity { 10 } else { 9 },
+                    (true, false) => match color {
+                        ColorType::Packed => if uv16 { 4 } else { 3 },
+                        ColorType::Float  => if uv16 { 6 } else { 5 },
+                        _                 => if uv16 { 8 } else { 7 }
+                    },
+                    (true, true) => match (intensity, uv16) {
+                        (false, false) => 11,
+                        (false, true)  => 12,
+                        (true, false)  => 13,
+                        (true, true)   => 14
+                    }
+                })
+            },
+            None => None
+        }
+    }
+
+    fn decode(&mut self, pcw: ParameterControlWord, words: &[u32]) -> TaParameter {
+        match pcw.para_type() {
+            PARA_END_OF_LIST => {
+                let list = self.list;
+                self.list = None;
+                self.global = None;
+                match list {
+                    Some(l) => TaParameter::EndOfList(l),
+                    None    => TaParameter::Illegal(pcw.value)
+                }
+            },
+            PARA_USER_TILE_CLIP => TaParameter::UserTileClip(words[4] & 0x3F, words[5] & 0xF,
+                                                             words[6] & 0x3F, words[7] & 0xF),
+            PARA_OBJECT_LIST_SET => TaParameter::ObjectListSet(words[1], [words[4], words[5], words[6], words[7]]),
+            PARA_POLYGON | PARA_SPRITE => {
+                // The list type is only latched by the first global parameter of a list
+                if self.list.is_none() {
+                    self.list = pcw.list_type();
+                }
+
+                match self.list {
+                    Some(list) if list.is_modifier_volume() => {
+                        self.global = Some(Global::ModifierVolume);
+                        TaParameter::ModifierVolume(self.decode_polygon(pcw, words))
+                    },
+                    Some(_) if pcw.para_type() == PARA_SPRITE => {
+                        let header = SpriteHeader {
+                            pcw,
+                            isp_tsp: words[1],
+                            tsp: words[2],
+                            tcw: words[3],
+                            base_color: words[4],
+                            offset_color: words[5],
+                        };
+                        self.global = Some(Global::Sprite(header));
+                        TaParameter::Sprite(header)
+                    },
+                    Some(_) => {
+                        let header = self.decode_polygon(pcw, words);
+                        self.global = Some(Global::Polygon(header));
+                        TaParameter::Polygon(header)
+                    },
+                    None => TaParameter::Illegal(pcw.value)
+                }
+            },
+            PARA_VERTEX => match self.vertex_type() {
+                Some(17)  => TaParameter::ModifierVolumeVertex(self.decode_modifier_volume(pcw, words)),
+                Some(15)  => TaParameter::SpriteVertex(self.decode_sprite(pcw, words, false)),
+                Some(16)  => TaParameter::SpriteVertex(self.decode_sprite(pcw, words, true)),
+                Some(ty)  => TaParameter::Vertex(self.decode_vertex(ty, pcw, words)),
+                None      => TaParameter::Illegal(pcw.value)
+            },
+            _ => TaParameter::Illegal(pcw.value)
+        }
+    }
+
+    fn decode_polygon(&mut self, pcw: ParameterControlWord, words: &[u32]) -> PolygonHeader {
+        let mut header = PolygonHeader {
+            pcw,
+            isp_tsp: words[1],
+            tsp: [words[2], 0],
+            tcw: [words[3], 0],
+            face_color: self.face_color,
+            face_offset_color: self.face_offset_color,
+        };
+
+        if self.list.is_some_and(|l| l.is_modifier_volume()) {
+            return header;
+        }
+
+        if pcw.is_two_volume() {
+            header.tsp[1] = words[4];
+            header.tcw[1] = words[5];
+        }
+
+        if pcw.color_type() == ColorType::IntensityMode1 {
+            if pcw.is_two_volume() {
+                header.face_color = [Color::from_words(&words[8..12]), Color::from_words(&words[12..16])];
+            } else if pcw.is_textured() && pcw.has_offset() {
+                header.face_color[0] = Color::from_words(&words[8..12]);
+                header.face_offset_color = Color::from_words(&words[12..16]);
+            } else {
+                header.face_color[0] = Color::from_words(&words[4..8]);
+            }
+
+            self.face_color = header.face_color;
+            self.face_offset_color = header.face_offset_color;
+        }
+
+        header
+    }
+
+    fn decode_vertex(&self, ty: u32, pcw: ParameterControlWord, w: &[u32]) -> Vertex {
+        let header = match self.global {
+            Some(Global::Polygon(ref h)) => *h,
+            _ => unreachable!()
+        };
+        let f = |i: usize| f32::from_bits(w[i]);
+        let uv16 = |i: usize| (f32::from_bits(w[i] & 0xFFFF0000), f32::from_bits(w[i] << 16));
+        let face = header.face_color;
+        let offset = header.face_offset_color;
+        let keep_offset = header.pcw.has_offset();
+
+        let mut vertex = Vertex {
+            end_of_strip: pcw.is_end_of_strip(),
+            x: f(1),
+            y: f(2),
+            z: f(3),
+            u: [0.0; 2],
+            v: [0.0; 2],
+            base_color: [0; 2],
+            offset_color: [0; 2],
+        };
+
+        match ty {
+            0 => vertex.base_color[0] = w[6],
+            1 => vertex.base_color[0] = Color::from_words(&w[4..8]).to_packed(),
+            2 => vertex.base_color[0] = face[0].intensity(f(6)),
+            3 | 4 | 7 | 8 => {
+                if ty == 3 || ty == 7 {
+                    vertex.u[0] = f(4);
+                    vertex.v[0] = f(5);
+                } else {
+                    let (u, v) = uv16(4);
+                    vertex.u[0] = u;
+                    vertex.v[0] = v;
+                }
+                if ty < 7 {
+                    vertex.base_color[0] = w[6];
+                    vertex.offset_color[0] = w[7];
+                } else {
+                    vertex.base_color[0] = face[0].intensity(f(6));
+                    vertex.offset_color[0] = offset.intensity(f(7));
+                }
+            },
+            5 | 6 => {
+                if ty == 5 {
+                    vertex.u[0] = f(4);
+                    vertex.v[0] = f(5);
+                } else {
+                    let (u, v) = uv16(4);
+                    vertex.u[0] = u;
+                    vertex.v[0] = v;
+                }
+                vertex.base_color[0] = Color::from_words(&w[8..12]).to_packed();
+                vertex.offset_color[0] = Color::from_words(&w[12..16]).to_packed();
+            },
+            9 => {
+                vertex.base_color = [w[4], w[5]];
+            },
+            10 => {
+                vertex.base_color = [face[0].intensity(f(4)), face[1].intensity(f(5))];
+            },
+            _ => {
+                // Textured two volume vertices, 11 to 14
+                for (vol, face) in face.iter().enumerate() {
+                    let base = 4 + vol * 4;
+                    if ty == 11 || ty == 13 {
+                        vertex.u[vol] = f(base);
+                        vertex.v[vol] = f(base + 1);
+                    } else {
+                        let (u, v) = uv16(base);
+                        vertex.u[vol] = u;
+                        vertex.v[vol] = v;
+                    }
+                    if ty <= 12 {
+                        vertex.base_color[vol] = w[base + 2];
+                        vertex.offset_color[vol] = w[base + 3];
+                    } else {
+                        vertex.base_color[vol] = face.intensity(f(base + 2));
+                        vertex.offset_color[vol] = offset.intensity(f(base + 3));
+                    }
+                }
+            }
+        }
+
+        if !keep_offset {
+            vertex.offset_color = [0; 2];
+        }
+
+        vertex
+    }
+
+    fn decode_sprite(&self, pcw: ParameterControlWord, w: &[u32], textured: bool) -> SpriteVertex {
+        let f = |i: usize| f32::from_bits(w[i]);
+        let uv16 = |word: u32| [f32::from_bits(word & 0xFFFF0000), f32::from_bits(word << 16)];
+
+        let a = [f(1), f(2), f(3)];
+        let b = [f(4), f(5), f(6)];
+        let c = [f(7), f(8), f(9)];
+        let mut d = [f(10), f(11), 0.0];
+
+        // D has no depth of its own, it lies on the plane spanned by A, B and C
+        let (ab, ac) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+        let nz = ab[0] * ac[1] - ab[1] * ac[0];
+        d[2] = if nz != 0.0 {
+            let nx = ab[1] * ac[2] - ab[2] * ac[1];
+            let ny = ab[2] * ac[0] - ab[0] * ac[2];
+            a[2] - (nx * (d[0] - a[0]) + ny * (d[1] - a[1])) / nz
+        } else {
+            a[2]
+        };
+
+        let mut uv = [[0.0; 2]; 4];
+        if textured {
+            uv[0] = uv16(w[13]);
+            uv[1] = uv16(w[14]);
+            uv[2] = uv16(w[15]);
+            uv[3] = [uv[0][0] + uv[2][0] - uv[1][0], uv[0][1] + uv[2][1] - uv[1][1]];
+        }
+
+        SpriteVertex {
+            end_of_strip: pcw.is_end_of_strip(),
+            position: [a, b, c, d],
+            uv,
+        }
+    }
+
+    fn decode_modifier_volume(&self, pcw: ParameterControlWord, w: &[u32]) -> ModifierVolumeTriangle {
+        let f = |i: usize| f32::from_bits(w[i]);
+
+        ModifierVolumeTriangle {
+            end_of_strip: pcw.is_end_of_strip(),
+            position: [[f(1), f(2), f(3)], [f(4), f(5), f(6)], [f(7), f(8), f(9)]],
+        }
+    }
+}
+
+impl Default for TaParser {
+    fn default() -> TaParser {
+        TaParser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_polygon_strip() {
+        let mut words = vec![0x80000000, 0x80000000, 0x20800000, 0x12345678, 0, 0, 0, 0];
+        for (i, &pcw) in [0xE0000000u32, 0xE0000000, 0xF0000000].iter().enumerate() {
+            words.extend_from_slice(&[pcw, (i as f32).to_bits(), 2.0f32.to_bits(), 0.5f32.to_bits(),
+                                      0, 0, 0xFF00FF00, 0]);
+        }
+        words.extend_from_slice(&[0; 8]);
+
+        let params = TaParser::parse(&words);
+        assert_eq!(params.len(), 5);
+        match params[0] {
+            TaParameter::Polygon(header) => {
+                assert_eq!(header.isp_tsp, 0x80000000);
+                assert_eq!(header.tsp[0], 0x20800000);
+                assert_eq!(header.tcw[0], 0x12345678);
+            },
+            other => panic!("expected a polygon, got {:?}", other)
+        }
+        for i in 0..3 {
+            match params[i + 1] {
+                TaParameter::Vertex(v) => {
+                    assert_eq!((v.x, v.y, v.z), (i as f32, 2.0, 0.5));
+                    assert_eq!(v.base_color[0], 0xFF00FF00);
+                    assert_eq!(v.end_of_strip, i == 2);
+                },
+                other => panic!("expected a vertex, got {:?}", other)
+            }
+        }
+        assert_eq!(params[4], TaParameter::EndOfList(ListType::Opaque));
+    }
+
+    #[test]
+    fn waits_for_long_vertices() {
+        // Textured vertices with floating point colors take 64 bytes
+        let mut parser = TaParser::new();
+        for &w in [0x80000018u32, 0, 0, 0, 0, 0, 0, 0].iter() {
+            parser.push(w);
+        }
+        assert_eq!(parser.vertex_type(), Some(5));
+
+        let mut vertex = vec![0xF0000000, 0, 0, 0, 0, 0, 0, 0,
+                              1.0f32.to_bits(), 1.0f32.to_bits(), 0, 0, 0, 0, 0, 0];
+        let last = vertex.pop().unwrap();
+        assert!(vertex.into_iter().all(|w| parser.push(w).is_none()));
+        match parser.push(last) {
+            Some(TaParameter::Vertex(v)) => assert_eq!(v.base_color[0], 0xFFFF0000),
+            other => panic!("expected a vertex, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rejects_end_of_list_outside_of_a_list() {
+        assert_eq!(TaParser::parse(&[0; 8]), vec![TaParameter::Illegal(0)]);
+    }
+}
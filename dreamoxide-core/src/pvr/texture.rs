@@ -0,0 +1,552 @@
+use pvr::*;
+use pvr::vram::{Vram, VRAM_MASK, VRAM_PAGE_SHIFT};
+use image::Image;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Number of entries in the palette RAM
+pub const PALETTE_ENTRIES : usize = 1024;
+
+/// Size of a VQ codebook, 256 entries of 8 bytes
+const CODEBOOK_SIZE : u32 = 2048;
+
+/// Texels in front of the smallest level of mipmapped textures
+const MIPMAP_PADDING : u32 = 3;
+
+/// The pixel format field of the texture control word
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Argb1555,
+    Rgb565,
+    Argb4444,
+    Yuv422,
+    BumpMap,
+    Palette4,
+    Palette8,
+    Reserved,
+}
+
+impl PixelFormat {
+    pub fn from_u32(value: u32) -> PixelFormat {
+        match value & 0x7 {
+            0 => PixelFormat::Argb1555,
+            1 => PixelFormat::Rgb565,
+            2 => PixelFormat::Argb4444,
+            3 => PixelFormat::Yuv422,
+            4 => PixelFormat::BumpMap,
+            5 => PixelFormat::Palette4,
+            6 => PixelFormat::Palette8,
+            _ => PixelFormat::Reserved
+        }
+    }
+
+    pub fn is_palette(&self) -> bool {
+        *self == PixelFormat::Palette4 || *self == PixelFormat::Palette8
+    }
+
+    pub fn bits_per_pixel(&self) -> u32 {
+        match *self {
+            PixelFormat::Palette4 => 4,
+            PixelFormat::Palette8 => 8,
+            _                     => 16
+        }
+    }
+
+    /// Texels covered by one VQ codebook entry
+    fn vq_block(&self) -> (u32, u32) {
+        match *self {
+            PixelFormat::Palette4 => (4, 4),
+            PixelFormat::Palette8 => (2, 4),
+            _                     => (2, 2)
+        }
+    }
+}
+
+/// Everything needed to locate and decode a texture, taken from the TSP
+/// and texture control words of a polygon
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TextureDescriptor {
+    pub tcw: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Distance between lines in texels, only differs from the width
+    /// for stride textures
+    pub stride: u32,
+}
+
+impl TextureDescriptor {
+    pub fn new(tsp: u32, tcw: u32, text_control: u32) -> TextureDescriptor {
+        let mut desc = TextureDescriptor {
+            tcw,
+            width: 8 << ((tsp >> 3) & 0x7),
+            height: 8 << (tsp & 0x7),
+            stride: 0,
+        };
+
+        if desc.is_mipmapped() {
+            desc.height = desc.width;
+        }
+        desc.stride = if desc.is_stride() { (text_control & 0x1F) * 32 } else { desc.width };
+        desc
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::from_u32(self.tcw >> 27)
+    }
+
+    /// Byte offset of the texture on the 64-bit path
+    pub fn address(&self) -> u32 {
+        (self.tcw & 0x1FFFFF) << 3
+    }
+
+    pub fn is_vq(&self) -> bool {
+        self.tcw & (1 << 30) != 0
+    }
+
+    /// Palettized textures are always twiddled, the bits are used for
+    /// the palette selector instead
+    pub fn is_twiddled(&self) -> bool {
+        self.format().is_palette() || self.is_vq() || self.tcw & (1 << 26) == 0
+    }
+
+    pub fn is_mipmapped(&self) -> bool {
+        self.tcw & (1 << 31) != 0 && self.is_twiddled()
+    }
+
+    pub fn is_stride(&self) -> bool {
+        !self.is_twiddled() && self.tcw & (1 << 25) != 0
+    }
+
+    /// First palette entry used by palettized textures
+    pub fn palette_base(&self) -> usize {
+        let selector = ((self.tcw >> 21) & 0x3F) as usize;
+        match self.format() {
+            PixelFormat::Palette4 => selector << 4,
+            PixelFormat::Palette8 => (selector >> 4) << 8,
+            _                     => 0
+        }
+    }
+
+    /// Number of mipmap levels, the largest being the first
+    pub fn levels(&self) -> u32 {
+        if self.is_mipmapped() { self.width.trailing_zeros() + 1 } else { 1 }
+    }
+
+    /// Byte offset of the texel data of a level, relative to the texture
+    /// address and behind the codebook for VQ textures
+    fn level_offset(&self, level: u32) -> u32 {
+        if !self.is_mipmapped() {
+            return 0;
+        }
+
+        // Levels are stored from the smallest up
+        let format = self.format();
+        let (bw, bh) = if self.is_vq() { format.vq_block() } else { (1, 1) };
+        let mut offset = if self.is_vq() { 0 } else { MIPMAP_PADDING };
+        for k in (level + 1..self.levels()).rev() {
+            let size = self.width >> k;
+            offset += cmp::max(1, (size / bw) * (size / bh));
+        }
+
+        if self.is_vq() { offset } else { offset * format.bits_per_pixel() / 8 }
+    }
+
+    /// Number of bytes the texture occupies in video memory
+    pub fn size(&self) -> u32 {
+        let format = self.format();
+        if self.is_vq() {
+            let (bw, bh) = format.vq_block();
+            CODEBOOK_SIZE + self.level_offset(0) + cmp::max(1, (self.width / bw) * (self.height / bh))
+        } else {
+            self.level_offset(0) + self.stride * self.height * format.bits_per_pixel() / 8
+        }
+    }
+
+    /// Reads the raw value of a texel, a 16 bit color or a palette index
+    fn fetch(&self, vram: &Vram, level: u32, x: u32, y: u32) -> u32 {
+        let format = self.format();
+        let (width, height) = (self.width >> level, self.height >> level);
+        let base = self.address() as usize;
+
+        let (texel, data) = if self.is_vq() {
+            // Each index selects a codebook entry covering a block of texels
+            let (bw, bh) = format.vq_block();
+            let blocks = twiddle(x / bw, y / bh, width / bw, height / bh);
+            let data = base + (CODEBOOK_SIZE + self.level_offset(level) + blocks) as usize;
+            let code = vram.read_u8(data) as u32;
+            (code * bw * bh + twiddle(x % bw, y % bh, bw, bh), base)
+        } else if self.is_twiddled() {
+            (twiddle(x, y, width, height), base + self.level_offset(level) as usize)
+        } else {
+            (y * self.stride + x, base)
+        };
+
+        match format.bits_per_pixel() {
+            4 => {
+                let byte = vram.read_u8((data + (texel >> 1) as usize) & VRAM_MASK) as u32;
+                if texel & 1 != 0 { byte >> 4 } else { byte & 0xF }
+            },
+            8 => vram.read_u8((data + texel as usize) & VRAM_MASK) as u32,
+            _ => vram.read_u16((data + texel as usize * 2) & VRAM_MASK) as u32
+        }
+    }
+}
+
+/// Interleaves the bits of both coordinates the way twiddled textures are stored
+pub fn twiddle(u: u32, v: u32, width: u32, height: u32) -> u32 {
+    let min = cmp::min(width, height);
+    let mut result = 0;
+    let mut bit = 0;
+
+    while (1 << bit) < min {
+        result |= ((v >> bit) & 1) << (2 * bit);
+        result |= ((u >> bit) & 1) << (2 * bit + 1);
+        bit += 1;
+    }
+
+    result | (((u | v) >> bit) << (2 * bit))
+}
+
+#[inline(always)]
+fn pack(a: u32, r: u32, g: u32, b: u32) -> u32 {
+    (a << 24) | (r << 16) | (g << 8) | b
+}
+
+#[inline(always)]
+fn expand5(c: u32) -> u32 { (c << 3) | (c >> 2) }
+
+#[inline(always)]
+fn expand6(c: u32) -> u32 { (c << 2) | (c >> 4) }
+
+#[inline(always)]
+fn expand4(c: u32) -> u32 { (c << 4) | c }
+
+/// Converts a 16 bit texel of the given direct color format to ARGB8888
+pub fn convert_texel(format: PixelFormat, t: u32) -> u32 {
+    match format {
+        PixelFormat::Rgb565 => pack(255, expand5((t >> 11) & 0x1F), expand6((t >> 5) & 0x3F), expand5(t & 0x1F)),
+        PixelFormat::Argb4444 => pack(expand4((t >> 12) & 0xF), expand4((t >> 8) & 0xF), expand4((t >> 4) & 0xF), expand4(t & 0xF)),
+        // Bump maps hold the elevation S and rotation R of the normal
+        PixelFormat::BumpMap => pack(255, (t >> 8) & 0xFF, t & 0xFF, 0),
+        _ => pack(if t & 0x8000 != 0 { 255 } else { 0 }, expand5((t >> 10) & 0x1F), expand5((t >> 5) & 0x1F), expand5(t & 0x1F))
+    }
+}
+
+/// Converts a pair of horizontally adjacent YUV422 texels, which share
+/// their chroma, to ARGB8888
+pub fn convert_yuv422(t0: u32, t1: u32) -> (u32, u32) {
+    let u = (t0 & 0xFF) as f32 - 128.0;
+    let v = (t1 & 0xFF) as f32 - 128.0;
+    let rgb = |y: u32| {
+        let y = y as f32;
+        let clamp = |c: f32| if c >= 255.0 { 255 } else if c > 0.0 { c as u32 } else { 0 };
+        pack(255, clamp(y + 1.375 * v), clamp(y - 0.34375 * u - 0.6875 * v), clamp(y + 1.71875 * u))
+    };
+    (rgb(t0 >> 8), rgb(t1 >> 8))
+}
+
+/// Reads the palette RAM in the format selected by PAL_RAM_CTRL
+pub fn palette(registers: &PvrRegisters) -> Vec<u32> {
+    let format = registers.get(PAL_RAM_CTRL) & 0x3;
+    (0..PALETTE_ENTRIES).map(|i| {
+        let entry = registers.get(PALETTE_RAM + i * 4);
+        match format {
+            0 => convert_texel(PixelFormat::Argb1555, entry & 0xFFFF),
+            1 => convert_texel(PixelFormat::Rgb565, entry & 0xFFFF),
+            2 => convert_texel(PixelFormat::Argb4444, entry & 0xFFFF),
+            _ => entry
+        }
+    }).collect()
+}
+
+/// A texture decoded to ARGB8888, with all of its mipmap levels
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub desc: TextureDescriptor,
+    /// Levels from the largest down to 1x1
+    pub levels: Vec<Vec<u32>>,
+}
+
+impl Texture {
+    /// Decodes a texture from video memory. Palettized textures are
+    /// resolved with the given palette.
+    pub fn decode(desc: TextureDescriptor, vram: &Vram, palette: &[u32]) -> Texture {
+        let format = desc.format();
+        let mut levels = Vec::with_capacity(desc.levels() as usize);
+
+        for level in 0..desc.levels() {
+            let (width, height) = (desc.width >> level, desc.height >> level);
+            let mut texels = Vec::with_capacity((width * height) as usize);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let raw = desc.fetch(vram, level, x, y);
+                    texels.push(match format {
+                        PixelFormat::Palette4 | PixelFormat::Palette8 =>
+                            palette[(desc.palette_base() + raw as usize) % PALETTE_ENTRIES],
+                        // Converted below, once both texels of a pair are known
+                        PixelFormat::Yuv422 => raw,
+                        _ => convert_texel(format, raw)
+                    });
+                }
+            }
+
+            if format == PixelFormat::Yuv422 {
+                for pair in texels.chunks_mut(2) {
+                    if pair.len() == 2 {
+                        let (a, b) = convert_yuv422(pair[0], pair[1]);
+                        pair[0] = a;
+                        pair[1] = b;
+                    } else {
+                        pair[0] = convert_yuv422(pair[0], pair[0]).0;
+                    }
+                }
+            }
+
+            levels.push(texels);
+        }
+
+        Texture { desc, levels }
+    }
+
+    pub fn level_size(&self, level: usize) -> (u32, u32) {
+        (self.desc.width >> level, self.desc.height >> level)
+    }
+
+    #[inline(always)]
+    pub fn texel(&self, level: usize, x: u32, y: u32) -> u32 {
+        let (width, _) = self.level_size(level);
+        self.levels[level][(y * width + x) as usize]
+    }
+
+    /// Samples a level at normalized coordinates, wrapping and filtering
+    /// as selected by the TSP word
+    pub fn sample(&self, tsp: u32, level: usize, u: f32, v: f32) -> u32 {
+        let level = cmp::min(level, self.levels.len() - 1);
+        let (width, height) = self.level_size(level);
+        let (clamp_u, clamp_v) = (tsp & (1 << 16) != 0, tsp & (1 << 15) != 0);
+        let (flip_u, flip_v) = (tsp & (1 << 18) != 0, tsp & (1 << 17) != 0);
+        let (x, y) = (u * width as f32, v * height as f32);
+
+        if (tsp >> 13) & 0x3 == 0 {
+            return self.texel(level, wrap(x.floor() as i32, width, clamp_u, flip_u), wrap(y.floor() as i32, height, clamp_v, flip_v));
+        }
+
+        // Bilinear filtering between the four closest texels
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let (u0, u1) = (wrap(x0, width, clamp_u, flip_u), wrap(x0 + 1, width, clamp_u, flip_u));
+        let (v0, v1) = (wrap(y0, height, clamp_v, flip_v), wrap(y0 + 1, height, clamp_v, flip_v));
+        let t = [self.texel(level, u0, v0), self.texel(level, u1, v0), self.texel(level, u0, v1), self.texel(level, u1, v1)];
+        let w = [(1.0 - fx) * (1.0 - fy), fx * (1.0 - fy), (1.0 - fx) * fy, fx * fy];
+
+        let mut result = 0;
+        for &shift in [0u32, 8, 16, 24].iter() {
+            let c = (0..4).fold(0.0, |acc, i| acc + ((t[i] >> shift) & 0xFF) as f32 * w[i]);
+            result |= cmp::min((c + 0.5) as u32, 255) << shift;
+        }
+        result
+    }
+
+    /// Copies a level into an image, for example to dump it as a PNG
+    pub fn to_image(&self, level: usize) -> Image {
+        let (width, height) = self.level_size(level);
+        Image {
+            width,
+            height,
+            pixels: self.levels[level].clone(),
+        }
+    }
+}
+
+/// Applies the clamp and flip modes to a texel coordinate
+#[inline(always)]
+fn wrap(t: i32, size: u32, clamp: bool, flip: bool) -> u32 {
+    let size = size as i32;
+    if clamp {
+        cmp::max(0, cmp::min(t, size - 1)) as u32
+    } else if flip {
+        let m = t.rem_euclid(2 * size);
+        (if m >= size { 2 * size - 1 - m } else { m }) as u32
+    } else {
+        t.rem_euclid(size) as u32
+    }
+}
+
+/// Keeps decoded textures around until the video memory backing them
+/// is written to
+pub struct TextureCache {
+    entries: HashMap<TextureDescriptor, Arc<Texture>>,
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Drops every texture overlapping a page written since the last sync
+    pub fn sync(&mut self, vram: &mut Vram) {
+        let pages = vram.take_dirty_pages();
+        if pages.is_empty() || self.entries.is_empty() {
+            return;
+        }
+
+        self.entries.retain(|desc, _| {
+            let first = desc.address() as usize >> VRAM_PAGE_SHIFT;
+            let last = (desc.address() + desc.size() - 1) as usize >> VRAM_PAGE_SHIFT;
+            !pages.iter().any(|&page| page >= first && page <= last)
+        });
+    }
+
+    /// Drops all palettized textures after the palette RAM changed
+    pub fn invalidate_palette(&mut self) {
+        self.entries.retain(|desc, _| !desc.format().is_palette());
+    }
+
+    /// Returns the decoded texture, decoding it on first use
+    pub fn get(&mut self, desc: TextureDescriptor, vram: &Vram, registers: &PvrRegisters) -> Arc<Texture> {
+        self.entries.entry(desc).or_insert_with(|| {
+            let palette = if desc.format().is_palette() { palette(registers) } else { Vec::new() };
+            Arc::new(Texture::decode(desc, vram, &palette))
+        }).clone()
+    }
+}
+
+impl Default for TextureCache {
+    fn default() -> TextureCache {
+        TextureCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWIDDLED_MIPMAP : u32 = 1 << 31;
+    const VQ              : u32 = 1 << 30;
+    const RGB565          : u32 = 1 << 27;
+
+    fn texture_word(flags: u32, address: u32) -> u32 {
+        flags | (address >> 3)
+    }
+
+    #[test]
+    fn twiddle_interleaves_coordinates() {
+        assert_eq!(twiddle(0, 0, 8, 8), 0);
+        assert_eq!(twiddle(0, 1, 8, 8), 1);
+        assert_eq!(twiddle(1, 0, 8, 8), 2);
+        assert_eq!(twiddle(1, 1, 8, 8), 3);
+        assert_eq!(twiddle(2, 0, 8, 8), 8);
+        assert_eq!(twiddle(7, 7, 8, 8), 63);
+
+        // The larger side continues past the square in whole squares
+        assert_eq!(twiddle(8, 0, 16, 8), 64);
+        assert_eq!(twiddle(9, 1, 16, 8), 67);
+        assert_eq!(twiddle(0, 8, 8, 16), 64);
+    }
+
+    #[test]
+    fn mipmap_offsets_start_behind_padding() {
+        // 8x8 mipmapped ARGB1555
+        let desc = TextureDescriptor::new(0, texture_word(TWIDDLED_MIPMAP, 0), 0);
+        assert_eq!(desc.levels(), 4);
+        assert_eq!(desc.level_offset(3), 0x06);
+        assert_eq!(desc.level_offset(2), 0x08);
+        assert_eq!(desc.level_offset(1), 0x10);
+        assert_eq!(desc.level_offset(0), 0x30);
+        assert_eq!(desc.size(), 0x30 + 8 * 8 * 2);
+    }
+
+    #[test]
+    fn vq_mipmap_offsets_count_indices() {
+        let desc = TextureDescriptor::new(0, texture_word(TWIDDLED_MIPMAP | VQ | RGB565, 0), 0);
+        assert_eq!(desc.level_offset(3), 0);
+        assert_eq!(desc.level_offset(2), 1);
+        assert_eq!(desc.level_offset(1), 2);
+        assert_eq!(desc.level_offset(0), 6);
+        assert_eq!(desc.size(), CODEBOOK_SIZE + 6 + 16);
+    }
+
+    #[test]
+    fn decodes_twiddled_mipmap_levels() {
+        let mut vram = Vram::new();
+        let desc = TextureDescriptor::new(0, texture_word(TWIDDLED_MIPMAP, 0x1000), 0);
+        vram.write_u16(0x1000 + 0x06, 0xFFFF);
+        vram.write_u16(0x1000 + 0x08 + 2 * 2, 0x801F);
+        vram.write_u16(0x1000 + 0x30 + 2 * 8, 0xFC00);
+
+        let texture = Texture::decode(desc, &vram, &[]);
+        assert_eq!(texture.levels.len(), 4);
+        assert_eq!(texture.texel(3, 0, 0), 0xFFFFFFFF);
+        assert_eq!(texture.texel(2, 1, 0), 0xFF0000FF);
+        assert_eq!(texture.texel(2, 0, 1), 0x00000000);
+        assert_eq!(texture.texel(0, 2, 0), 0xFFFF0000);
+    }
+
+    #[test]
+    fn decodes_vq_blocks_through_the_codebook() {
+        let mut vram = Vram::new();
+        let desc = TextureDescriptor::new(0, texture_word(VQ | RGB565, 0), 0);
+
+        // Entry 1, in twiddled order within its 2x2 block
+        for (i, &texel) in [0xF800u16, 0x07E0, 0x001F, 0xFFFF].iter().enumerate() {
+            vram.write_u16(8 + i * 2, texel);
+        }
+        // The block at (1, 0) uses entry 1, all others entry 0
+        vram.write_u8(CODEBOOK_SIZE as usize + 2, 1);
+
+        let texture = Texture::decode(desc, &vram, &[]);
+        assert_eq!(texture.texel(0, 0, 0), 0xFF000000);
+        assert_eq!(texture.texel(0, 2, 0), 0xFFFF0000);
+        assert_eq!(texture.texel(0, 2, 1), 0xFF00FF00);
+        assert_eq!(texture.texel(0, 3, 0), 0xFF0000FF);
+        assert_eq!(texture.texel(0, 3, 1), 0xFFFFFFFF);
+        assert_eq!(texture.texel(0, 4, 0), 0xFF000000);
+    }
+
+    #[test]
+    fn cache_drops_textures_on_dirty_pages() {
+        let mut vram = Vram::new();
+        let registers = PvrRegisters::new();
+        let mut cache = TextureCache::new();
+        let desc = TextureDescriptor::new(0, texture_word(TWIDDLED_MIPMAP, 0x2000), 0);
+
+        vram.write_u16(0x2000 + 0x30, 0x8000);
+        cache.sync(&mut vram);
+        let first = cache.get(desc, &vram, &registers);
+        assert_eq!(cache.len(), 1);
+        assert!(Arc::ptr_eq(&first, &cache.get(desc, &vram, &registers)));
+
+        // Writes elsewhere leave the texture alone
+        vram.write_u32(0x4000, 0);
+        cache.sync(&mut vram);
+        assert_eq!(cache.len(), 1);
+
+        // Palette changes only concern palettized textures
+        cache.invalidate_palette();
+        assert_eq!(cache.len(), 1);
+
+        vram.write_u16(0x2000 + 0x30, 0xFC00);
+        cache.sync(&mut vram);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(desc, &vram, &registers).texel(0, 0, 0), 0xFFFF0000);
+    }
+}
@@ -0,0 +1,151 @@
+
+pub const VRAM_SIZE : usize = 0x00800000;
+pub const VRAM_MASK : usize = VRAM_SIZE - 1;
+
+/// Granularity at which writes are tracked
+pub const VRAM_PAGE_SHIFT : usize = 12;
+pub const VRAM_PAGES      : usize = VRAM_SIZE >> VRAM_PAGE_SHIFT;
+
+/// The 8MB of video memory. Data is stored the way the 64-bit bus sees
+/// it, which is also the layout textures are fetched from. The 32-bit
+/// path interleaves both 4MB banks every four bytes.
+pub struct Vram {
+    pub data: Box<[u8]>,
+    /// One bit per page written since the last call to `take_dirty_pages`
+    dirty: Vec<u64>,
+}
+
+impl Vram {
+    pub fn new() -> Vram {
+        Vram {
+            data: vec![0; VRAM_SIZE].into_boxed_slice(),
+            dirty: vec![0; VRAM_PAGES / 64],
+        }
+    }
+
+    #[inline(always)]
+    fn mark_dirty(&mut self, offset: usize) {
+        let page = (offset & VRAM_MASK) >> VRAM_PAGE_SHIFT;
+        self.dirty[page >> 6] |= 1 << (page & 63);
+    }
+
+    /// Returns the pages written since the last call and forgets about them
+    pub fn take_dirty_pages(&mut self) -> Vec<usize> {
+        let mut pages = Vec::new();
+        for (i, word) in self.dirty.iter_mut().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                pages.push(i * 64 + bit);
+                bits &= bits - 1;
+            }
+            *word = 0;
+        }
+        pages
+    }
+
+    /// Translates an offset on the 32-bit path to the offset on the 64-bit path
+    #[inline(always)]
+    pub fn map_32bit(offset: usize) -> usize {
+        let bank = (offset >> 22) & 1;
+        (offset & 0x3) | ((offset & 0x003FFFFC) << 1) | (bank << 2)
+    }
+
+    #[inline(always)]
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.data[offset & VRAM_MASK]
+    }
+
+    #[inline(always)]
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        let o = offset & VRAM_MASK & !1;
+        (self.data[o] as u16) | ((self.data[o + 1] as u16) << 8)
+    }
+
+    #[inline(always)]
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let o = offset & VRAM_MASK & !3;
+        (self.data[o] as u32)
+            | ((self.data[o + 1] as u32) << 8)
+            | ((self.data[o + 2] as u32) << 16)
+            | ((self.data[o + 3] as u32) << 24)
+    }
+
+    #[inline(always)]
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        (self.read_u32(offset) as u64) | ((self.read_u32(offset + 4) as u64) << 32)
+    }
+
+    #[inline(always)]
+    pub fn write_u8(&mut self, offset: usize, value: u8) {
+        self.mark_dirty(offset);
+        self.data[offset & VRAM_MASK] = value;
+    }
+
+    #[inline(always)]
+    pub fn write_u16(&mut self, offset: usize, value: u16) {
+        self.mark_dirty(offset);
+        let o = offset & VRAM_MASK & !1;
+        self.data[o] = value as u8;
+        self.data[o + 1] = (value >> 8) as u8;
+    }
+
+    #[inline(always)]
+    pub fn write_u32(&mut self, offset: usize, value: u32) {
+        self.mark_dirty(offset);
+        let o = offset & VRAM_MASK & !3;
+        self.data[o] = value as u8;
+        self.data[o + 1] = (value >> 8) as u8;
+        self.data[o + 2] = (value >> 16) as u8;
+        self.data[o + 3] = (value >> 24) as u8;
+    }
+
+    #[inline(always)]
+    pub fn read_32bit_u16(&self, offset: usize) -> u16 {
+        self.read_u16(Vram::map_32bit(offset & VRAM_MASK))
+    }
+
+    #[inline(always)]
+    pub fn read_32bit_u32(&self, offset: usize) -> u32 {
+        self.read_u32(Vram::map_32bit(offset & VRAM_MASK))
+    }
+
+    #[inline(always)]
+    pub fn read_32bit_f32(&self, offset: usize) -> f32 {
+        f32::from_bits(self.read_32bit_u32(offset))
+    }
+
+    #[inline(always)]
+    pub fn write_32bit_u16(&mut self, offset: usize, value: u16) {
+        self.write_u16(Vram::map_32bit(offset & VRAM_MASK), value);
+    }
+
+    #[inline(always)]
+    pub fn write_32bit_u32(&mut self, offset: usize, value: u32) {
+        self.write_u32(Vram::map_32bit(offset & VRAM_MASK), value);
+    }
+
+    /// Reads from the given offset with the width of the original access
+    pub fn read(&self, offset: usize, size: usize) -> u32 {
+        match size {
+            1 => self.read_u8(offset) as u32,
+            2 => self.read_u16(offset) as u32,
+            _ => self.read_u32(offset)
+        }
+    }
+
+    /// Writes to the given offset with the width of the original access
+    pub fn write(&mut self, offset: usize, value: u32, size: usize) {
+        match size {
+            1 => self.write_u8(offset, value as u8),
+            2 => self.write_u16(offset, value as u16),
+            _ => self.write_u32(offset, value)
+        }
+    }
+}
+
+impl Default for Vram {
+    fn default() -> Vram {
+        Vram::new()
+    }
+}
@@ -0,0 +1,162 @@
+use pvr::*;
+use pvr::vram::Vram;
+
+/// Bytes of a macroblock with chroma subsampled in both directions
+pub const MACROBLOCK_420_SIZE : usize = 384;
+
+/// Bytes of a macroblock with chroma subsampled horizontally only
+pub const MACROBLOCK_422_SIZE : usize = 512;
+
+/// Bytes of a converted 16x16 macroblock in YUV422 texture format
+const OUTPUT_MACROBLOCK_SIZE : u32 = 512;
+
+/// The YUV converter of the TA. It takes macroblocks of planar U, V and
+/// Y data from the YUV FIFO and writes them to texture memory as YUV422
+/// texels, laid out as set in TA_YUV_TEX_CTRL.
+pub struct YuvConverter {
+    buffer: Vec<u8>,
+    /// Macroblocks converted for the current texture
+    pub count: u32,
+}
+
+impl YuvConverter {
+    pub fn new() -> YuvConverter {
+        YuvConverter {
+            buffer: Vec::with_capacity(MACROBLOCK_422_SIZE),
+            count: 0,
+        }
+    }
+
+    /// Starts over with a new texture, as done when TA_YUV_TEX_BASE or
+    /// TA_YUV_TEX_CTRL are written
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.count = 0;
+    }
+
+    /// Feeds data written to the FIFO. Returns true once the last
+    /// macroblock of the texture has been converted.
+    pub fn write(&mut self, value: u32, size: usize, registers: &PvrRegisters, vram: &mut Vram) -> bool {
+        for i in 0..size {
+            self.buffer.push((value >> (i * 8)) as u8);
+        }
+
+        let ctrl = registers.get(TA_YUV_TEX_CTRL);
+        let yuv422 = ctrl & (1 << 16) != 0;
+        let macroblock_size = if yuv422 { MACROBLOCK_422_SIZE } else { MACROBLOCK_420_SIZE };
+        if self.buffer.len() < macroblock_size {
+            return false;
+        }
+
+        let base = registers.get(TA_YUV_TEX_BASE) & 0x00FFFFF8;
+        self.convert(ctrl, base, yuv422, vram);
+        self.buffer.drain(..macroblock_size);
+        self.count += 1;
+
+        let columns = (ctrl & 0x3F) + 1;
+        let rows = ((ctrl >> 8) & 0x3F) + 1;
+        if self.count >= columns * rows {
+            self.count = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Converts the buffered macroblock into its place in the texture
+    fn convert(&self, ctrl: u32, base: u32, yuv422: bool, vram: &mut Vram) {
+        let columns = (ctrl & 0x3F) + 1;
+        let (mx, my) = (self.count % columns, self.count / columns);
+
+        // Either one texture spanning all macroblocks, or a sequence
+        // of 16x16 textures
+        let (origin, stride) = if ctrl & (1 << 24) != 0 {
+            (base + self.count * OUTPUT_MACROBLOCK_SIZE, 32)
+        } else {
+            let stride = columns * 32;
+            (base + my * 16 * stride + mx * 32, stride)
+        };
+
+        let chroma_size = if yuv422 { 128 } else { 64 };
+        let (u, v, luma) = (&self.buffer[0..chroma_size], &self.buffer[chroma_size..chroma_size * 2], &self.buffer[chroma_size * 2..]);
+
+        for y in 0..16usize {
+            // Luma is stored as four 8x8 blocks, left to right and top to bottom
+            let luma_at = |x: usize| luma[((y / 8) * 2 + x / 8) * 64 + (y % 8) * 8 + x % 8] as u16;
+            let chroma_line = if yuv422 { y } else { y / 2 };
+
+            for pair in 0..8usize {
+                let x = pair * 2;
+                let c = chroma_line * 8 + pair;
+                let address = (origin + y as u32 * stride + x as u32 * 2) as usize;
+                vram.write_u16(address, (luma_at(x) << 8) | u[c] as u16);
+                vram.write_u16(address + 2, (luma_at(x + 1) << 8) | v[c] as u16);
+            }
+        }
+    }
+}
+
+impl Default for YuvConverter {
+    fn default() -> YuvConverter {
+        YuvConverter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE : u32 = 0x00100000;
+
+    /// Feeds a macroblock with U and V counting up from 0x00 and 0x80
+    /// and every luma byte holding its index
+    fn feed(converter: &mut YuvConverter, chroma_size: usize, registers: &PvrRegisters, vram: &mut Vram) -> bool {
+        let bytes: Vec<u8> = (0..chroma_size).map(|i| i as u8)
+            .chain((0..chroma_size).map(|i| 0x80 + i as u8))
+            .chain((0..256).map(|i| i as u8))
+            .collect();
+        let mut done = false;
+        for word in bytes.chunks(4) {
+            let value = word.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32);
+            done = converter.write(value, 4, registers, vram);
+        }
+        done
+    }
+
+    #[test]
+    fn converts_a_420_macroblock() {
+        let mut registers = PvrRegisters::new();
+        let mut vram = Vram::new();
+        let mut converter = YuvConverter::new();
+        registers.set(TA_YUV_TEX_BASE, BASE);
+        registers.set(TA_YUV_TEX_CTRL, 0);
+
+        assert!(feed(&mut converter, 64, &registers, &mut vram));
+        assert_eq!(converter.count, 0);
+
+        // Texel (10, 9) lies in the fourth luma block and chroma line 4
+        let address = (BASE + 9 * 32 + 10 * 2) as usize;
+        assert_eq!(vram.read_u16(address), (202 << 8) | 37);
+        assert_eq!(vram.read_u16(address + 2), (203 << 8) | (0x80 + 37));
+        assert_eq!(vram.read_u16(BASE as usize), 0x0000);
+        assert_eq!(vram.read_u16(BASE as usize + 2), 0x0180);
+    }
+
+    #[test]
+    fn places_422_macroblocks_side_by_side() {
+        let mut registers = PvrRegisters::new();
+        let mut vram = Vram::new();
+        let mut converter = YuvConverter::new();
+        registers.set(TA_YUV_TEX_BASE, BASE);
+        registers.set(TA_YUV_TEX_CTRL, (1 << 16) | 1);
+
+        assert!(!feed(&mut converter, 128, &registers, &mut vram));
+        assert_eq!(converter.count, 1);
+        assert!(feed(&mut converter, 128, &registers, &mut vram));
+
+        // Chroma is not shared between lines, and the second macroblock
+        // starts 16 texels to the right on a 32 texel wide texture
+        let address = (BASE + 32 + 9 * 64 + 10 * 2) as usize;
+        assert_eq!(vram.read_u16(address), (202 << 8) | 77);
+        assert_eq!(vram.read_u16(address + 2), (203 << 8) | (0x80 + 77));
+    }
+}
@@ -0,0 +1,414 @@
+pub use MappedDevice;
+pub use MemoryRange;
+pub use Memory;
+
+use disc::image::invalid_data;
+use image::crc32_update;
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Area 0 starts with the boot ROM, followed by the flash
+pub const BOOT_ROM_BASE : usize = 0x00000000;
+pub const BOOT_ROM_END  : usize = 0x001FFFFF;
+pub const BOOT_ROM_SIZE : usize = 0x200000;
+
+pub const FLASH_BASE : usize = 0x00200000;
+pub const FLASH_END  : usize = 0x0021FFFF;
+pub const FLASH_SIZE : usize = 0x20000;
+
+/// Boot ROMs known by the CRC-32 of their dump
+const KNOWN_BOOT_ROMS : [(u32, &str); 2] = [
+    (0x89F2B1A1, "1.01d"),
+    (0x5454841F, "1.004"),
+];
+
+/// The factory partition, holding the console's region
+pub const FLASH_FACTORY_PARTITION : usize = 0x1A000;
+
+/// Partitions as the system calls number them, as offset and size
+pub const FLASH_PARTITIONS : [(usize, usize); 5] = [
+    (0x1A000, 0x2000),
+    (0x18000, 0x2000),
+    (0x1C000, 0x4000),
+    (0x10000, 0x8000),
+    (0x00000, 0x10000),
+];
+
+/// Sectors the flash erases at once, as offset and size. The chip is a
+/// top boot block part with smaller sectors at the end.
+const FLASH_SECTORS : [(usize, usize); 5] = [
+    (0x00000, 0x10000),
+    (0x10000, 0x8000),
+    (0x18000, 0x2000),
+    (0x1A000, 0x2000),
+    (0x1C000, 0x4000),
+];
+
+/// Identification read in autoselect mode
+const FLASH_MANUFACTURER : u8 = 0x04;
+const FLASH_DEVICE       : u8 = 0x50;
+
+/// Addresses and data of the command sequences
+const UNLOCK_ADDRESS_1 : usize = 0x5555;
+const UNLOCK_ADDRESS_2 : usize = 0x2AAA;
+const UNLOCK_DATA_1    : u8 = 0xAA;
+const UNLOCK_DATA_2    : u8 = 0x55;
+const CMD_PROGRAM      : u8 = 0xA0;
+const CMD_ERASE        : u8 = 0x80;
+const CMD_AUTOSELECT   : u8 = 0x90;
+const CMD_CHIP_ERASE   : u8 = 0x10;
+const CMD_SECTOR_ERASE : u8 = 0x30;
+const CMD_RESET        : u8 = 0xF0;
+
+/// Reads a whole file, telling which one in the error
+fn read_file(path: &Path, what: &str) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read {} {}: {}", what, path.display(), e)))?;
+    Ok(data)
+}
+
+fn read_bytes(data: &[u8], offset: usize, size: usize) -> u32 {
+    (0..size).fold(0, |value, i| value | ((data[(offset + i) % data.len()] as u32) << (i * 8)))
+}
+
+/// The 2 MB boot ROM. Writes to it are ignored.
+pub struct BootRom {
+    pub data: Vec<u8>,
+}
+
+impl BootRom {
+    /// Takes a dump, checking that it is a whole boot ROM and one of the
+    /// known dumps
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<BootRom> {
+        if data.len() != BOOT_ROM_SIZE {
+            return Err(invalid_data(&format!("a boot ROM has {} bytes, this one {}", BOOT_ROM_SIZE, data.len())));
+        }
+        if data.iter().all(|&b| b == data[0]) {
+            return Err(invalid_data("the boot ROM is blank"));
+        }
+
+        // A dump read as 16-bit words on a big endian machine is a
+        // common mistake that deserves its own message
+        let swapped: Vec<u8> = data.chunks(2).flat_map(|w| vec![w[1], w[0]]).collect();
+        if KNOWN_BOOT_ROMS.iter().any(|&(crc, _)| crc == crc32(&swapped)) {
+            return Err(invalid_data("the boot ROM is byte swapped"));
+        }
+
+        let rom = BootRom { data };
+        if rom.version().is_none() {
+            let known: Vec<String> = KNOWN_BOOT_ROMS.iter().map(|&(crc, version)| format!("{:08X} ({})", crc, version)).collect();
+            return Err(invalid_data(&format!("unknown boot ROM with CRC-32 {:08X}, known dumps are {}", rom.crc32(), known.join(", "))));
+        }
+        Ok(rom)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<BootRom> {
+        let path = path.as_ref();
+        BootRom::from_bytes(read_file(path, "boot ROM")?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.data)
+    }
+
+    /// The version of the dump, found by its CRC-32
+    pub fn version(&self) -> Option<&'static str> {
+        let crc = self.crc32();
+        KNOWN_BOOT_ROMS.iter().find(|&&(c, _)| c == crc).map(|&(_, version)| version)
+    }
+}
+
+impl MappedDevice for BootRom {
+    fn read(&mut self, address: usize, size: usize) -> u32 {
+        read_bytes(&self.data, address - BOOT_ROM_BASE, size)
+    }
+
+    fn write(&mut self, _address: usize, _value: u32, _size: usize) {}
+}
+
+/// Where the flash is in a command sequence
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FlashState {
+    Read,
+    Unlocked1,
+    Unlocked2,
+    /// The next write programs data
+    Program,
+    EraseSetup,
+    EraseUnlocked1,
+    EraseUnlocked2,
+    /// Reads return the identification
+    Autoselect,
+}
+
+/// The 128 KB flash holding the settings and the console's factory
+/// data. It is read like memory, while programming and erasing take
+/// the AMD command sequences: two unlock cycles, then the command.
+/// Programming can only clear bits, erasing sets a sector to 0xFF.
+pub struct Flash {
+    pub data: Vec<u8>,
+    state: FlashState,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl Flash {
+    /// Takes a flash image, checking its size and that the factory data
+    /// is still there
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<Flash> {
+        if data.len() != FLASH_SIZE {
+            return Err(invalid_data(&format!("a flash image has {} bytes, this one {}", FLASH_SIZE, data.len())));
+        }
+        let factory = &data[FLASH_FACTORY_PARTITION..FLASH_FACTORY_PARTITION + 0x2000];
+        if factory.iter().all(|&b| b == 0xFF) || factory.iter().all(|&b| b == 0) {
+            return Err(invalid_data("the factory partition of the flash image is erased"));
+        }
+
+        Ok(Flash {
+            data,
+            state: FlashState::Read,
+            path: None,
+            dirty: false,
+        })
+    }
+
+    /// An erased flash, for running without a dump of one
+    pub fn blank() -> Flash {
+        Flash {
+            data: vec![0xFF; FLASH_SIZE],
+            state: FlashState::Read,
+            path: None,
+            dirty: false,
+        }
+    }
+
+    /// Opens a flash image, to which `flush` writes back the changes
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Flash> {
+        let path = path.as_ref();
+        let mut flash = Flash::from_bytes(read_file(path, "flash image")?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        flash.path = Some(path.to_path_buf());
+        Ok(flash)
+    }
+
+    /// Saves the image if it changed since it was opened or last saved
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let (true, Some(path)) = (self.dirty, self.path.as_ref()) {
+            File::create(path)?.write_all(&self.data)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: usize, size: usize) {
+        for byte in self.data[offset..offset + size].iter_mut() {
+            *byte = 0xFF;
+        }
+        self.dirty = true;
+    }
+
+    /// Erases the sector containing an offset
+    pub fn erase_sector(&mut self, offset: usize) {
+        if let Some(&(start, size)) = FLASH_SECTORS.iter().find(|&&(start, size)| offset >= start && offset < start + size) {
+            self.erase(start, size);
+        }
+    }
+
+    /// Programs bytes at an offset, which can only clear bits. Returns
+    /// the number of bytes that fit the flash.
+    pub fn program(&mut self, offset: usize, data: &[u8]) -> usize {
+        let mut written = 0;
+        for (byte, &value) in self.data.iter_mut().skip(offset).zip(data.iter()) {
+            // Only a change needs saving
+            if *byte & value != *byte {
+                *byte &= value;
+                self.dirty = true;
+            }
+            written += 1;
+        }
+        written
+    }
+
+    fn command(&mut self, offset: usize, value: u8) {
+        let address = offset & 0x7FFF;
+        self.state = match (self.state, address, value) {
+            (_, _, CMD_RESET) => FlashState::Read,
+            (FlashState::Read, UNLOCK_ADDRESS_1, UNLOCK_DATA_1) => FlashState::Unlocked1,
+            (FlashState::Autoselect, UNLOCK_ADDRESS_1, UNLOCK_DATA_1) => FlashState::Unlocked1,
+            (FlashState::Unlocked1, UNLOCK_ADDRESS_2, UNLOCK_DATA_2) => FlashState::Unlocked2,
+            (FlashState::Unlocked2, UNLOCK_ADDRESS_1, CMD_PROGRAM) => FlashState::Program,
+            (FlashState::Unlocked2, UNLOCK_ADDRESS_1, CMD_ERASE) => FlashState::EraseSetup,
+            (FlashState::Unlocked2, UNLOCK_ADDRESS_1, CMD_AUTOSELECT) => FlashState::Autoselect,
+            (FlashState::EraseSetup, UNLOCK_ADDRESS_1, UNLOCK_DATA_1) => FlashState::EraseUnlocked1,
+            (FlashState::EraseUnlocked1, UNLOCK_ADDRESS_2, UNLOCK_DATA_2) => FlashState::EraseUnlocked2,
+            (FlashState::EraseUnlocked2, UNLOCK_ADDRESS_1, CMD_CHIP_ERASE) => {
+                self.erase(0, FLASH_SIZE);
+                FlashState::Read
+            },
+            (FlashState::EraseUnlocked2, _, CMD_SECTOR_ERASE) => {
+                self.erase_sector(offset);
+                FlashState::Read
+            },
+            // A broken sequence goes back to reading
+            _ => FlashState::Read
+        };
+    }
+}
+
+impl MappedDevice for Flash {
+    fn read(&mut self, address: usize, size: usize) -> u32 {
+        let offset = address - FLASH_BASE;
+        match self.state {
+            FlashState::Autoselect => match offset & 0xFF {
+                0 => FLASH_MANUFACTURER as u32,
+                1 => FLASH_DEVICE as u32,
+                _ => 0
+            },
+            _ => read_bytes(&self.data, offset, size)
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u32, size: usize) {
+        let offset = address - FLASH_BASE;
+        if self.state != FlashState::Program {
+            self.command(offset, value as u8);
+            return;
+        }
+
+        let bytes: Vec<u8> = (0..size).map(|i| (value >> (i * 8)) as u8).collect();
+        self.program(offset, &bytes);
+        self.state = FlashState::Read;
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xFFFFFFFF, data) ^ 0xFFFFFFFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlock(flash: &mut Flash) {
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, UNLOCK_DATA_1 as u32, 1);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_2, UNLOCK_DATA_2 as u32, 1);
+    }
+
+    fn erase(flash: &mut Flash, address: usize, command: u8) {
+        unlock(flash);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_ERASE as u32, 1);
+        unlock(flash);
+        flash.write(address, command as u32, 1);
+    }
+
+    #[test]
+    fn programs_only_after_unlocking() {
+        let mut flash = Flash::blank();
+        flash.write(FLASH_BASE + 0x100, 0x12, 1);
+        assert_eq!(flash.data[0x100], 0xFF);
+
+        unlock(&mut flash);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_PROGRAM as u32, 1);
+        flash.write(FLASH_BASE + 0x100, 0x1234, 2);
+        assert_eq!(flash.read(FLASH_BASE + 0x100, 2), 0x1234);
+
+        // Programming ends after one write, and can only clear bits
+        flash.write(FLASH_BASE + 0x100, 0xFF, 1);
+        assert_eq!(flash.data[0x100], 0x34);
+        unlock(&mut flash);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_PROGRAM as u32, 1);
+        flash.write(FLASH_BASE + 0x100, 0xC3, 1);
+        assert_eq!(flash.data[0x100], 0x00);
+    }
+
+    #[test]
+    fn erases_sectors_and_the_chip() {
+        let mut flash = Flash::blank();
+        flash.program(0, &[0; FLASH_SIZE]);
+
+        erase(&mut flash, FLASH_BASE + 0x1B000, CMD_SECTOR_ERASE);
+        assert!(flash.data[0x1A000..0x1C000].iter().all(|&b| b == 0xFF));
+        assert_eq!((flash.data[0x19FFF], flash.data[0x1C000]), (0, 0));
+
+        erase(&mut flash, FLASH_BASE + UNLOCK_ADDRESS_1, CMD_CHIP_ERASE);
+        assert!(flash.data.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn identifies_itself_until_reset() {
+        let mut flash = Flash::blank();
+        unlock(&mut flash);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_AUTOSELECT as u32, 1);
+        assert_eq!(flash.read(FLASH_BASE, 1), FLASH_MANUFACTURER as u32);
+        assert_eq!(flash.read(FLASH_BASE + 1, 1), FLASH_DEVICE as u32);
+
+        flash.write(FLASH_BASE, CMD_RESET as u32, 1);
+        assert_eq!(flash.read(FLASH_BASE, 1), 0xFF);
+    }
+
+    #[test]
+    fn drops_broken_sequences() {
+        let mut flash = Flash::blank();
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, UNLOCK_DATA_1 as u32, 1);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, UNLOCK_DATA_2 as u32, 1);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_PROGRAM as u32, 1);
+        flash.write(FLASH_BASE + 0x100, 0, 1);
+        assert_eq!(flash.data[0x100], 0xFF);
+
+        // An erase has to be unlocked a second time
+        unlock(&mut flash);
+        flash.write(FLASH_BASE + UNLOCK_ADDRESS_1, CMD_ERASE as u32, 1);
+        flash.program(0, &[0]);
+        flash.write(FLASH_BASE, CMD_SECTOR_ERASE as u32, 1);
+        assert_eq!(flash.data[0], 0);
+    }
+
+    #[test]
+    fn saves_only_changes() {
+        let mut flash = Flash::blank();
+        assert_eq!(flash.program(0x100, &[0xFF, 0xFF]), 2);
+        assert!(!flash.dirty);
+        assert_eq!(flash.program(FLASH_SIZE - 1, &[0x0F, 0x00]), 1);
+        assert!(flash.dirty);
+        flash.flush().unwrap();
+
+        // Clearing bits already clear changes nothing
+        assert_eq!(flash.program(FLASH_SIZE - 1, &[0x3F]), 1);
+        assert!(!flash.dirty);
+        flash.erase_sector(0);
+        assert!(flash.dirty);
+    }
+
+    #[test]
+    fn refuses_unknown_boot_roms() {
+        assert!(BootRom::from_bytes(vec![0x5A; BOOT_ROM_SIZE - 2]).is_err());
+        assert!(BootRom::from_bytes(vec![0x5A; BOOT_ROM_SIZE]).is_err());
+
+        let data: Vec<u8> = (0..BOOT_ROM_SIZE).map(|i| (i * 7 / 3) as u8).collect();
+        let crc = crc32(&data);
+        let error = BootRom::from_bytes(data).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // The message names the dump and the known ones
+        let message = error.to_string();
+        assert!(message.contains(&format!("{:08X}", crc)));
+        assert!(message.contains("89F2B1A1 (1.01d)") && message.contains("5454841F (1.004)"));
+    }
+
+    #[test]
+    fn computes_the_crc_32_of_dumps() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn refuses_an_erased_factory_partition() {
+        assert!(Flash::from_bytes(vec![0xFF; FLASH_SIZE]).is_err());
+        assert!(Flash::from_bytes(vec![0x5A; FLASH_SIZE - 1]).is_err());
+        assert!(Flash::from_bytes(vec![0x5A; FLASH_SIZE]).is_ok());
+    }
+}
@@ -0,0 +1,88 @@
+use audio::SAMPLE_RATE;
+
+/// Clock of the SH-4 core, the unit of time of the scheduler
+pub const SH4_CLOCK : u64 = 200_000_000;
+
+/// SH-4 cycles between two output samples of the sound chip
+pub const CYCLES_PER_SAMPLE : u64 = SH4_CLOCK / SAMPLE_RATE as u64;
+
+/// Work that devices need done at a point in time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Runs the sound CPU for one sample period and mixes a sample
+    AicaSample,
+    /// Advances the real-time clock by a second
+    RtcTick,
+    /// Streams the next sector of CD audio, 75 times a second
+    CddaSector,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Scheduled {
+    time: u64,
+    event: Event,
+}
+
+/// Orders device events against the SH-4's cycle count. The owner of
+/// the CPU advances the time after each instruction and dispatches the
+/// events that became due, so other processors run interleaved with it.
+pub struct Scheduler {
+    /// SH-4 cycles since power on
+    pub now: u64,
+    /// Pending events, the earliest first
+    events: Vec<Scheduled>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            now: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// Schedules an event the given number of cycles from now
+    pub fn schedule(&mut self, delay: u64, event: Event) {
+        let time = self.now + delay;
+        self.schedule_at(time, event);
+    }
+
+    /// Schedules an event at an absolute time. Events due at the same
+    /// time are dispatched in the order they were scheduled.
+    pub fn schedule_at(&mut self, time: u64, event: Event) {
+        let index = self.events.iter().position(|e| e.time > time).unwrap_or(self.events.len());
+        self.events.insert(index, Scheduled { time, event });
+    }
+
+    /// Removes all pending occurrences of an event
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|e| e.event != event);
+    }
+
+    pub fn is_scheduled(&self, event: Event) -> bool {
+        self.events.iter().any(|e| e.event == event)
+    }
+
+    /// Time of the earliest pending event
+    pub fn next_time(&self) -> Option<u64> {
+        self.events.first().map(|e| e.time)
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// Takes the earliest event that is due, if any
+    pub fn pop_due(&mut self) -> Option<Event> {
+        match self.events.first() {
+            Some(e) if e.time <= self.now => Some(self.events.remove(0).event),
+            _ => None
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}